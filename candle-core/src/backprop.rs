@@ -16,6 +16,89 @@ fn broadcast_back(arg: &Tensor, node: &Tensor, reduced_dims: &[usize]) -> Result
     }
 }
 
+// The indexes `start`, `start + stride`, ... of the `count` elements visited by a pooling window
+// at offset `start`.
+fn strided_indexes(
+    start: usize,
+    count: usize,
+    stride: usize,
+    dev: &crate::Device,
+) -> Result<Tensor> {
+    let indexes = (0..count)
+        .map(|i| (start + i * stride) as u32)
+        .collect::<Vec<_>>();
+    Tensor::from_vec(indexes, count, dev)
+}
+
+// The source indexes used by nearest-neighbor upsampling, this must match the forward pass of the
+// backends.
+fn nearest_indexes(src_sz: usize, dst_sz: usize, dev: &crate::Device) -> Result<Tensor> {
+    let scale = src_sz as f64 / dst_sz as f64;
+    let indexes = (0..dst_sz)
+        .map(|idx| usize::min(src_sz - 1, (idx as f64 * scale) as usize) as u32)
+        .collect::<Vec<_>>();
+    Tensor::from_vec(indexes, dst_sz, dev)
+}
+
+// The `(dst_sz, src_sz)` matrix `m` such that bilinear upsampling along a single dimension is
+// `dst = m . src`, this must match the forward pass of the backends.
+fn bilinear_matrix(
+    src_sz: usize,
+    dst_sz: usize,
+    align_corners: bool,
+    scale_factor: Option<f64>,
+    arg: &Tensor,
+) -> Result<Tensor> {
+    let scale = if align_corners {
+        if dst_sz > 1 {
+            (src_sz - 1) as f64 / (dst_sz - 1) as f64
+        } else {
+            0.0
+        }
+    } else if let Some(scale_factor) = scale_factor {
+        1.0 / scale_factor
+    } else {
+        src_sz as f64 / dst_sz as f64
+    };
+    let mut m = vec![0f64; dst_sz * src_sz];
+    for dst_idx in 0..dst_sz {
+        let src = if align_corners {
+            scale * dst_idx as f64
+        } else {
+            scale * (dst_idx as f64 + 0.5) - 0.5
+        };
+        let src = src.max(0.0);
+        let i0 = src.floor() as usize;
+        let i1 = (i0 + 1).min(src_sz - 1);
+        let weight = (src - i0 as f64).clamp(0.0, 1.0);
+        m[dst_idx * src_sz + i0] += 1.0 - weight;
+        m[dst_idx * src_sz + i1] += weight;
+    }
+    Tensor::from_vec(m, (dst_sz, src_sz), arg.device())?.to_dtype(arg.dtype())
+}
+
+thread_local! {
+    static GRAD_ENABLED: std::cell::Cell<bool> = const { std::cell::Cell::new(true) };
+}
+
+/// Returns false when running within [`no_grad`].
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(|b| b.get())
+}
+
+/// Runs `f` without tracking any operation, the tensors created within `f` have no backprop
+/// graph, even when they depend on some variables.
+pub fn no_grad<T, F: FnOnce() -> T>(f: F) -> T {
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            GRAD_ENABLED.with(|b| b.set(self.0))
+        }
+    }
+    let _restore = Restore(GRAD_ENABLED.with(|b| b.replace(false)));
+    f()
+}
+
 thread_local! {
     static CANDLE_GRAD_DO_NOT_DETACH: bool = {
         match std::env::var("CANDLE_GRAD_DO_NOT_DETACH") {
@@ -85,6 +168,16 @@ impl Tensor {
                         kernel: rhs,
                         ..
                     }
+                    | Op::Conv3D {
+                        arg: lhs,
+                        kernel: rhs,
+                        ..
+                    }
+                    | Op::ConvTranspose3D {
+                        arg: lhs,
+                        kernel: rhs,
+                        ..
+                    }
                     | Op::CustomOp2(lhs, rhs, _)
                     | Op::Binary(lhs, rhs, _)
                     | Op::Gather(lhs, rhs, _)
@@ -121,6 +214,8 @@ impl Tensor {
                     | Op::UpsampleBilinear2D { arg: node, .. }
                     | Op::AvgPool2D { arg: node, .. }
                     | Op::MaxPool2D { arg: node, .. }
+                    | Op::AvgPool3D { arg: node, .. }
+                    | Op::MaxPool3D { arg: node, .. }
                     | Op::Copy(node)
                     | Op::Broadcast(node)
                     | Op::Cmp(node, _)
@@ -137,6 +232,12 @@ impl Tensor {
                         track_grad |= tg;
                         nodes
                     }
+                    Op::Checkpoint(node, _) => {
+                        let (_tg, nodes) = walk(node, nodes, already_seen);
+                        // The module may use variables that do not appear in its input.
+                        track_grad = true;
+                        nodes
+                    }
                     Op::ToDType(node) => {
                         if node.dtype().is_float() {
                             let (tg, nodes) = walk(node, nodes, already_seen);
@@ -163,6 +264,17 @@ impl Tensor {
     }
 
     pub fn backward(&self) -> Result<GradStore> {
+        self.backward_impl(false)
+    }
+
+    /// Same as `backward` but the operations used to compute the gradients are themselves
+    /// tracked, so that the returned gradients can be differentiated again. This can be used for
+    /// higher order derivatives, e.g. gradient penalties or Hessian-vector products.
+    pub fn backward_create_graph(&self) -> Result<GradStore> {
+        self.backward_impl(true)
+    }
+
+    fn backward_impl(&self, create_graph: bool) -> Result<GradStore> {
         let sorted_nodes = self.sorted_nodes();
         let mut grads = GradStore::new();
         grads.insert(self, self.ones_like()?.contiguous()?);
@@ -176,9 +288,9 @@ impl Tensor {
             // https://github.com/huggingface/candle/issues/1241
             // Ideally, we would make these operations in place where possible to ensure that we
             // do not have to allocate too often. Here we just call `.detach` to avoid computing
-            // the backprop graph of the backprop itself. This would be an issue for second order
-            // derivatives but these are out of scope at the moment.
-            let do_not_detach = CANDLE_GRAD_DO_NOT_DETACH.with(|b| *b);
+            // the backprop graph of the backprop itself, unless this graph is explicitly
+            // requested for computing higher order derivatives.
+            let do_not_detach = create_graph || CANDLE_GRAD_DO_NOT_DETACH.with(|b| *b);
             let grad = if do_not_detach { grad } else { grad.detach() };
             if let Some(op) = node.op() {
                 match op {
@@ -311,9 +423,32 @@ impl Tensor {
                         };
                         *sum_grad = sum_grad.add(&grad_kernel)?;
                     }
-                    Op::ConvTranspose1D { .. } => Err(Error::BackwardNotSupported {
-                        op: "conv-transpose1d",
-                    })?,
+                    Op::ConvTranspose1D {
+                        arg,
+                        kernel,
+                        padding,
+                        stride,
+                        dilation,
+                        output_padding: _output_padding,
+                    } => {
+                        let grad_arg = grad.conv1d(kernel, *padding, *stride, *dilation, 1)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = grad
+                            .transpose(0, 1)?
+                            .conv1d(&arg.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0) = kernel.dims3()?;
+                        let (_, _, g_k0) = grad_kernel.dims3()?;
+                        let grad_kernel = if g_k0 != k0 {
+                            grad_kernel.narrow(2, 0, k0)?
+                        } else {
+                            grad_kernel
+                        };
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
                     Op::ConvTranspose2D {
                         arg,
                         kernel,
@@ -340,18 +475,169 @@ impl Tensor {
                         };
                         *sum_grad = sum_grad.add(&grad_kernel)?;
                     }
-                    Op::AvgPool2D {
+                    Op::Conv3D {
+                        arg,
+                        kernel,
+                        padding,
+                        stride,
+                        dilation,
+                    } => {
+                        // The transposed convolution returns the gradient for the elements that
+                        // are covered by the kernel windows, the trailing elements that may not
+                        // be covered get a zero gradient.
+                        let grad_arg =
+                            grad.conv_transpose3d(kernel, *padding, 0, *stride, *dilation, 1)?;
+                        let mut grad_arg = grad_arg;
+                        for dim in 2..5 {
+                            let missing = arg.dim(dim)? - grad_arg.dim(dim)?;
+                            if missing > 0 {
+                                grad_arg = grad_arg.pad_with_zeros(dim, 0, missing)?;
+                            }
+                        }
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = arg
+                            .transpose(0, 1)?
+                            .conv3d(&grad.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0, k1, k2) = kernel.dims5()?;
+                        let grad_kernel = grad_kernel
+                            .narrow(2, 0, k0)?
+                            .narrow(3, 0, k1)?
+                            .narrow(4, 0, k2)?;
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
+                    Op::ConvTranspose3D {
+                        arg,
+                        kernel,
+                        padding,
+                        stride,
+                        dilation,
+                        output_padding: _output_padding,
+                    } => {
+                        let grad_arg = grad.conv3d(kernel, *padding, *stride, *dilation, 1)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = grad
+                            .transpose(0, 1)?
+                            .conv3d(&arg.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0, k1, k2) = kernel.dims5()?;
+                        let grad_kernel = grad_kernel
+                            .narrow(2, 0, k0)?
+                            .narrow(3, 0, k1)?
+                            .narrow(4, 0, k2)?;
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
+                    Op::AvgPool3D {
                         arg,
                         kernel_size,
                         stride,
                     } => {
-                        if kernel_size != stride {
-                            crate::bail!("backward not supported for avgpool2d if ksize {kernel_size:?} != stride {stride:?}")
+                        // Each element gets the gradient of all the windows that contain it, this
+                        // is done separately on each dimension as the kernel weights are uniform.
+                        let (n, c, d, h, w) = arg.dims5()?;
+                        let (_, _, d_out, h_out, w_out) = grad.dims5()?;
+                        let scale = 1f64 / (kernel_size.0 * kernel_size.1 * kernel_size.2) as f64;
+                        let grad = (&grad * scale)?;
+                        let mut grad_d =
+                            Tensor::zeros((n, c, d, h_out, w_out), grad.dtype(), grad.device())?;
+                        for offset in 0..kernel_size.0 {
+                            let idxs = strided_indexes(offset, d_out, stride.0, grad.device())?;
+                            grad_d = grad_d.index_add(&idxs, &grad, 2)?;
+                        }
+                        let mut grad_h =
+                            Tensor::zeros((n, c, d, h, w_out), grad.dtype(), grad.device())?;
+                        for offset in 0..kernel_size.1 {
+                            let idxs = strided_indexes(offset, h_out, stride.1, grad.device())?;
+                            grad_h = grad_h.index_add(&idxs, &grad_d, 3)?;
+                        }
+                        let mut grad_arg = arg.zeros_like()?;
+                        for offset in 0..kernel_size.2 {
+                            let idxs = strided_indexes(offset, w_out, stride.2, grad.device())?;
+                            grad_arg = grad_arg.index_add(&idxs, &grad_h, 4)?;
                         }
-                        let (_n, _c, h, w) = arg.dims4()?;
-                        let grad_arg = grad.upsample_nearest2d(h, w)?;
-                        let grad_arg =
-                            (grad_arg * (1f64 / (kernel_size.0 * kernel_size.1) as f64))?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+                    }
+                    Op::MaxPool3D {
+                        arg,
+                        kernel_size,
+                        stride,
+                    } => {
+                        // Same as for MaxPool2D, the gradient is split between the maximums of
+                        // each window.
+                        let (n, c, _d, h, w) = arg.dims5()?;
+                        let (_, _, d_out, h_out, w_out) = grad.dims5()?;
+                        let dev = arg.device();
+                        let mut masks = vec![];
+                        let mut num_max = grad.zeros_like()?;
+                        for offset_d in 0..kernel_size.0 {
+                            let idxs_d = strided_indexes(offset_d, d_out, stride.0, dev)?;
+                            let arg_d = arg.index_select(&idxs_d, 2)?;
+                            for offset_h in 0..kernel_size.1 {
+                                let idxs_h = strided_indexes(offset_h, h_out, stride.1, dev)?;
+                                let arg_dh = arg_d.index_select(&idxs_h, 3)?;
+                                for offset_w in 0..kernel_size.2 {
+                                    let idxs_w = strided_indexes(offset_w, w_out, stride.2, dev)?;
+                                    let mask = arg_dh
+                                        .index_select(&idxs_w, 4)?
+                                        .eq(*node)?
+                                        .to_dtype(arg.dtype())?;
+                                    num_max = num_max.add(&mask)?;
+                                    masks.push((idxs_d.clone(), idxs_h.clone(), idxs_w, mask))
+                                }
+                            }
+                        }
+                        let grad = grad.div(&num_max)?;
+                        let mut grad_arg = arg.zeros_like()?;
+                        for (idxs_d, idxs_h, idxs_w, mask) in masks.iter() {
+                            let grad_w = Tensor::zeros(
+                                (n, c, d_out, h_out, w),
+                                grad.dtype(),
+                                grad.device(),
+                            )?
+                            .index_add(idxs_w, &grad.mul(mask)?, 4)?;
+                            let grad_h =
+                                Tensor::zeros((n, c, d_out, h, w), grad.dtype(), grad.device())?
+                                    .index_add(idxs_h, &grad_w, 3)?;
+                            grad_arg = grad_arg.index_add(idxs_d, &grad_h, 2)?;
+                        }
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+                    }
+                    Op::AvgPool2D {
+                        arg,
+                        kernel_size,
+                        stride,
+                    } => {
+                        let (n, c, h, w) = arg.dims4()?;
+                        let scale = 1f64 / (kernel_size.0 * kernel_size.1) as f64;
+                        let grad_arg = if kernel_size == stride {
+                            (grad.upsample_nearest2d(h, w)? * scale)?
+                        } else {
+                            // The windows may overlap or skip some elements, each element gets
+                            // the gradient of all the windows that contain it. As the kernel
+                            // weights are uniform, this can be done separately on each dimension.
+                            let (_, _, h_out, w_out) = grad.dims4()?;
+                            let grad = (&grad * scale)?;
+                            let mut grad_h =
+                                Tensor::zeros((n, c, h, w_out), grad.dtype(), grad.device())?;
+                            for offset in 0..kernel_size.0 {
+                                let idxs = strided_indexes(offset, h_out, stride.0, grad.device())?;
+                                grad_h = grad_h.index_add(&idxs, &grad, 2)?;
+                            }
+                            let mut grad_arg = arg.zeros_like()?;
+                            for offset in 0..kernel_size.1 {
+                                let idxs = strided_indexes(offset, w_out, stride.1, grad.device())?;
+                                grad_arg = grad_arg.index_add(&idxs, &grad_h, 3)?;
+                            }
+                            grad_arg
+                        };
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&grad_arg)?;
                     }
@@ -360,56 +646,102 @@ impl Tensor {
                         kernel_size,
                         stride,
                     } => {
-                        if kernel_size != stride {
-                            crate::bail!("backward not supported for maxpool2d if ksize {kernel_size:?} != stride {stride:?}")
+                        let (n, c, _h, w) = arg.dims4()?;
+                        let (_, _, h_out, w_out) = grad.dims4()?;
+                        // For each offset within the pooling window, we extract the elements at
+                        // this offset for all the windows and compute a mask where a 1 means that
+                        // the element is the maximum of its window. The gradient is then split
+                        // between the maximums of each window (multiple max may exist) and
+                        // scattered back to the elements positions.
+                        let mut masks = Vec::with_capacity(kernel_size.0 * kernel_size.1);
+                        let mut num_max = grad.zeros_like()?;
+                        for offset_h in 0..kernel_size.0 {
+                            let idxs_h = strided_indexes(offset_h, h_out, stride.0, arg.device())?;
+                            let arg_h = arg.index_select(&idxs_h, 2)?;
+                            for offset_w in 0..kernel_size.1 {
+                                let idxs_w =
+                                    strided_indexes(offset_w, w_out, stride.1, arg.device())?;
+                                let mask = arg_h
+                                    .index_select(&idxs_w, 3)?
+                                    .eq(*node)?
+                                    .to_dtype(arg.dtype())?;
+                                num_max = num_max.add(&mask)?;
+                                masks.push((idxs_h.clone(), idxs_w, mask))
+                            }
+                        }
+                        let grad = grad.div(&num_max)?;
+                        let mut grad_arg = arg.zeros_like()?;
+                        for (idxs_h, idxs_w, mask) in masks.iter() {
+                            let grad_w =
+                                Tensor::zeros((n, c, h_out, w), grad.dtype(), grad.device())?
+                                    .index_add(idxs_w, &grad.mul(mask)?, 3)?;
+                            grad_arg = grad_arg.index_add(idxs_h, &grad_w, 2)?;
                         }
-                        let (_n, _c, h, w) = arg.dims4()?;
-                        // For computing the max-pool gradient, we compute a mask where a 1 means
-                        // that the element is the maximum, then we apply this mask to the
-                        // upsampled gradient (taking into account that multiple max may exist so
-                        // we scale the gradient for this case).
-                        let node_upsampled = node.upsample_nearest2d(h, w)?;
-                        let mask = arg.eq(&node_upsampled)?.to_dtype(arg.dtype())?;
-                        let avg = mask.avg_pool2d_with_stride(*kernel_size, *stride)?;
-                        let grad_arg = ((grad * avg)?.upsample_nearest2d(h, w)? * mask)?;
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&grad_arg)?;
                     }
                     Op::UpsampleNearest1D { arg, target_size } => {
                         let (_n, c, size) = arg.dims3()?;
                         if target_size % size != 0 {
-                            crate::bail!("backward not supported for non integer upscaling factors")
-                        }
-                        let scale = target_size / size;
+                            // Each element gets the gradient of all the elements that were copied
+                            // from it.
+                            let idxs = nearest_indexes(size, *target_size, arg.device())?;
+                            let grad_arg = arg.zeros_like()?.index_add(&idxs, &grad, 2)?;
+                            let sum_grad = grads.or_insert(arg)?;
+                            *sum_grad = sum_grad.add(&grad_arg)?;
+                        } else {
+                            let scale = target_size / size;
 
-                        let kernel = Tensor::ones((c, 1, scale), arg.dtype(), arg.device())?;
-                        let conv_sum = grad.conv1d(&kernel, 0, scale, 1, c)?;
-                        let sum_grad = grads.or_insert(arg)?;
-                        *sum_grad = conv_sum;
+                            let kernel = Tensor::ones((c, 1, scale), arg.dtype(), arg.device())?;
+                            let conv_sum = grad.conv1d(&kernel, 0, scale, 1, c)?;
+                            let sum_grad = grads.or_insert(arg)?;
+                            *sum_grad = conv_sum;
+                        }
                     }
                     Op::UpsampleNearest2D {
                         arg,
                         target_h,
                         target_w,
                     } => {
-                        let (_n, c, h, w) = arg.dims4()?;
-                        if target_h % h != 0 || target_w % w != 0 {
-                            crate::bail!("backward not supported for non integer upscaling factors")
+                        let (n, c, h, w) = arg.dims4()?;
+                        if target_h % h != 0 || target_w % w != 0 || target_h / h != target_w / w {
+                            // Non integer or non uniform upscaling factors, each element gets the
+                            // gradient of all the elements that were copied from it.
+                            let idxs_w = nearest_indexes(w, *target_w, arg.device())?;
+                            let grad_w =
+                                Tensor::zeros((n, c, *target_h, w), grad.dtype(), grad.device())?
+                                    .index_add(&idxs_w, &grad, 3)?;
+                            let idxs_h = nearest_indexes(h, *target_h, arg.device())?;
+                            let grad_arg = arg.zeros_like()?.index_add(&idxs_h, &grad_w, 2)?;
+                            let sum_grad = grads.or_insert(arg)?;
+                            *sum_grad = sum_grad.add(&grad_arg)?;
+                        } else {
+                            let scale_h = target_h / h;
+                            let scale_w = target_w / w;
+                            let kernel =
+                                Tensor::ones((c, 1, scale_h, scale_w), arg.dtype(), arg.device())?;
+                            let conv_sum = grad.conv2d(&kernel, 0, scale_h, 1, c)?;
+                            let sum_grad = grads.or_insert(arg)?;
+                            *sum_grad = conv_sum;
                         }
-                        let scale_h = target_h / h;
-                        let scale_w = target_w / w;
-
-                        if scale_h != scale_w {
-                            crate::bail!("backward not supported for non uniform upscaling factors")
-                        };
-                        let kernel =
-                            Tensor::ones((c, 1, scale_h, scale_w), arg.dtype(), arg.device())?;
-                        let conv_sum = grad.conv2d(&kernel, 0, scale_h, 1, c)?;
-                        let sum_grad = grads.or_insert(arg)?;
-                        *sum_grad = conv_sum;
                     }
-                    Op::UpsampleBilinear2D { .. } => {
-                        crate::bail!("backward not supported for upsample_bilinear2d")
+                    Op::UpsampleBilinear2D {
+                        arg,
+                        target_h,
+                        target_w,
+                        align_corners,
+                        scale_h,
+                        scale_w,
+                    } => {
+                        // Bilinear upsampling is separable, with `m_h` and `m_w` the
+                        // interpolation matrices for each dimension the forward pass is
+                        // `m_h . arg . m_w^T` so the gradient is `m_h^T . grad . m_w`.
+                        let (_n, _c, h, w) = arg.dims4()?;
+                        let m_h = bilinear_matrix(h, *target_h, *align_corners, *scale_h, arg)?;
+                        let m_w = bilinear_matrix(w, *target_w, *align_corners, *scale_w, arg)?;
+                        let grad_arg = m_h.t()?.broadcast_matmul(&grad.broadcast_matmul(&m_w)?)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
                     }
                     Op::SliceScatter0(lhs, rhs, start_rhs) => {
                         let rhs_sum_grad = grads.or_insert(rhs)?;
@@ -712,6 +1044,30 @@ impl Tensor {
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&arg_grad)?
                     }
+                    Op::Checkpoint(arg, module) => {
+                        // Run the forward pass of the segment again, this time tracking the
+                        // operations, and backpropagate through it. The input is replaced with
+                        // a fresh variable so that the graph that produced it is not walked.
+                        let xs = if arg.dtype().is_float() {
+                            crate::Var::from_tensor(&arg.detach())?.into_inner()
+                        } else {
+                            arg.detach()
+                        };
+                        let ys = module.forward(&xs)?;
+                        let segment_grads =
+                            ys.mul(&grad)?.sum_all()?.backward_impl(create_graph)?;
+                        // The segment gradients that are left are the ones for the leaves of the
+                        // segment, i.e. its input and the variables used by the module.
+                        for (id, leaf_grad) in segment_grads.0.into_iter() {
+                            let id = if id == xs.id() { arg.id() } else { id };
+                            match grads.0.get_mut(&id) {
+                                Some(sum_grad) => *sum_grad = sum_grad.add(&leaf_grad)?,
+                                None => {
+                                    grads.0.insert(id, leaf_grad);
+                                }
+                            }
+                        }
+                    }
                     Op::Permute(arg, dims) => {
                         let mut inv_dims = vec![0; dims.len()];
                         for (i, &dim_idx) in dims.iter().enumerate() {
@@ -729,12 +1085,12 @@ impl Tensor {
 }
 
 /// A store for gradients, associating a tensor id to the corresponding gradient tensor, used for back propagation.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct GradStore(HashMap<TensorId, Tensor>);
 
 impl GradStore {
     /// Create a new gradient store
-    fn new() -> Self {
+    pub fn new() -> Self {
         GradStore(HashMap::new())
     }
 
@@ -782,3 +1138,125 @@ impl GradStore {
         self.0.keys()
     }
 }
+
+// Runs `f` on variables holding the values of `inputs`, so that the gradients with respect to
+// these inputs can be retrieved.
+fn with_vars<F: FnOnce(&[Tensor]) -> Result<Tensor>>(
+    f: F,
+    inputs: &[Tensor],
+) -> Result<(Vec<crate::Var>, Tensor)> {
+    let vars = inputs
+        .iter()
+        .map(crate::Var::from_tensor)
+        .collect::<Result<Vec<_>>>()?;
+    let xs = vars
+        .iter()
+        .map(|v| v.as_tensor().clone())
+        .collect::<Vec<_>>();
+    let ys = f(&xs)?;
+    Ok((vars, ys))
+}
+
+fn grads_for(grads: &GradStore, vars: &[crate::Var]) -> Result<Vec<Tensor>> {
+    vars.iter()
+        .map(|v| match grads.get(v) {
+            Some(grad) => Ok(grad.clone()),
+            None => v.zeros_like(),
+        })
+        .collect()
+}
+
+/// Computes the gradients of the scalar returned by `f` with respect to each of the `inputs`.
+///
+/// The value of `f` is returned together with the gradients. When `create_graph` is set, the
+/// gradients are tracked and can be differentiated again. The inputs are treated as leaves, i.e.
+/// gradients are not propagated to the tensors the inputs are derived from.
+pub fn grad<F: FnOnce(&[Tensor]) -> Result<Tensor>>(
+    f: F,
+    inputs: &[Tensor],
+    create_graph: bool,
+) -> Result<(Tensor, Vec<Tensor>)> {
+    let (vars, ys) = with_vars(f, inputs)?;
+    if ys.elem_count() != 1 {
+        crate::bail!("grad requires a scalar output, got shape {:?}", ys.shape())
+    }
+    let grads = if create_graph {
+        ys.backward_create_graph()?
+    } else {
+        ys.backward()?
+    };
+    let grads = grads_for(&grads, &vars)?;
+    Ok((ys, grads))
+}
+
+/// Vector-Jacobian product, returns the value of `f` and `v^T . J` for each of the inputs where
+/// `J` is the jacobian of `f`. `v` must have the same shape as the output of `f`.
+pub fn vjp<F: FnOnce(&[Tensor]) -> Result<Tensor>>(
+    f: F,
+    inputs: &[Tensor],
+    v: &Tensor,
+) -> Result<(Tensor, Vec<Tensor>)> {
+    let (vars, ys) = with_vars(f, inputs)?;
+    let grads = ys.mul(v)?.sum_all()?.backward()?;
+    let grads = grads_for(&grads, &vars)?;
+    Ok((ys, grads))
+}
+
+/// Jacobian-vector product, returns the value of `f` and `J . v` where `J` is the jacobian of `f`
+/// and `v` contains a tangent for each of the inputs.
+///
+/// This uses the double-vjp trick: `u -> J^T . u` is linear in `u` and its own vjp with `v` is
+/// `J . v`, so the gradient graph of the first vjp has to be created.
+pub fn jvp<F: FnOnce(&[Tensor]) -> Result<Tensor>>(
+    f: F,
+    inputs: &[Tensor],
+    v: &[Tensor],
+) -> Result<(Tensor, Tensor)> {
+    if inputs.len() != v.len() {
+        crate::bail!(
+            "jvp expects one tangent per input, got {} inputs and {} tangents",
+            inputs.len(),
+            v.len()
+        )
+    }
+    let (vars, ys) = with_vars(f, inputs)?;
+    let u = crate::Var::zeros(ys.shape(), ys.dtype(), ys.device())?;
+    let vjp = ys.mul(&u)?.sum_all()?.backward_create_graph()?;
+    let vjp = grads_for(&vjp, &vars)?;
+    let mut dot = Tensor::zeros((), ys.dtype(), ys.device())?;
+    for (vjp, v) in vjp.iter().zip(v.iter()) {
+        dot = dot.add(&vjp.mul(v)?.sum_all()?)?;
+    }
+    let jvp = match dot.backward()?.get(&u) {
+        Some(jvp) => jvp.clone(),
+        None => ys.zeros_like()?,
+    };
+    Ok((ys.detach(), jvp))
+}
+
+/// Hessian-vector product, returns the value of the scalar function `f` and `H . v` for each of
+/// the inputs where `H` is the hessian of `f`.
+pub fn hvp<F: FnOnce(&[Tensor]) -> Result<Tensor>>(
+    f: F,
+    inputs: &[Tensor],
+    v: &[Tensor],
+) -> Result<(Tensor, Vec<Tensor>)> {
+    if inputs.len() != v.len() {
+        crate::bail!(
+            "hvp expects one vector per input, got {} inputs and {} vectors",
+            inputs.len(),
+            v.len()
+        )
+    }
+    let (vars, ys) = with_vars(f, inputs)?;
+    if ys.elem_count() != 1 {
+        crate::bail!("hvp requires a scalar output, got shape {:?}", ys.shape())
+    }
+    let grads = grads_for(&ys.backward_create_graph()?, &vars)?;
+    let mut dot = Tensor::zeros((), ys.dtype(), ys.device())?;
+    for (grad, v) in grads.iter().zip(v.iter()) {
+        dot = dot.add(&grad.mul(v)?.sum_all()?)?;
+    }
+    let hvp = grads_for(&dot.backward()?, &vars)?;
+    Ok((ys.detach(), hvp))
+}
@@ -0,0 +1,237 @@
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    conv::{ParamsConv3D, ParamsConvTranspose3D},
+    cpu_backend::Map2,
+    shape::dims5,
+    Layout, Result, WithDType,
+};
+
+/// Copies the input to a contiguous buffer using a channels-last layout, i.e.
+/// `(b_size, d, h, w, c_in)`, so that the dot-products over the input channels operate on
+/// contiguous slices.
+fn channels_last<T: WithDType>(
+    inp: &[T],
+    inp_l: &Layout,
+    (b_size, c_in, i_d, i_h, i_w): (usize, usize, usize, usize, usize),
+) -> Result<Vec<T>> {
+    let inp = &inp[inp_l.start_offset()..];
+    let (inp_s0, inp_s1, inp_s2, inp_s3, inp_s4) = dims5(inp_l.stride())?;
+    let mut inp_cont = Vec::with_capacity(b_size * i_d * i_h * i_w * c_in);
+    for b_idx in 0..b_size {
+        for d_idx in 0..i_d {
+            for h_idx in 0..i_h {
+                for w_idx in 0..i_w {
+                    for c_idx in 0..c_in {
+                        let src_idx = b_idx * inp_s0
+                            + c_idx * inp_s1
+                            + d_idx * inp_s2
+                            + h_idx * inp_s3
+                            + w_idx * inp_s4;
+                        inp_cont.push(inp[src_idx])
+                    }
+                }
+            }
+        }
+    }
+    Ok(inp_cont)
+}
+
+// Returns the input index for a given output index and kernel offset, or `None` if this
+// position falls in the padding.
+#[inline(always)]
+fn src_index(dst_idx: usize, offset: usize, i_size: usize, p: &ParamsConv3D) -> Option<usize> {
+    let src_idx = p.stride * dst_idx + offset * p.dilation;
+    if src_idx < p.padding || src_idx >= i_size + p.padding {
+        None
+    } else {
+        Some(src_idx - p.padding)
+    }
+}
+
+pub(super) struct Conv3D<'a>(pub(super) &'a ParamsConv3D);
+
+impl Map2 for Conv3D<'_> {
+    const OP: &'static str = "conv3d";
+    fn f<T: WithDType>(&self, inp: &[T], inp_l: &Layout, k: &[T], k_l: &Layout) -> Result<Vec<T>> {
+        let p = self.0;
+        let (i_d, i_h, i_w) = (p.i_d, p.i_h, p.i_w);
+        let inp_cont = channels_last(inp, inp_l, (p.b_size, p.c_in, i_d, i_h, i_w))?;
+        let cont_s0 = i_d * i_h * i_w * p.c_in;
+        let cont_s1 = i_h * i_w * p.c_in;
+        let cont_s2 = i_w * p.c_in;
+        let cont_s3 = p.c_in;
+        let k = &k[k_l.start_offset()..];
+        let (k_s0, k_s1, k_s2, k_s3, k_s4) = dims5(k_l.stride())?;
+        let (out_d, out_h, out_w) = (p.out_d(), p.out_h(), p.out_w());
+
+        // Output shape: [b_size, c_out, out_d, out_h, out_w].
+        let dst = vec![T::zero(); p.b_size * p.c_out * out_d * out_h * out_w];
+
+        (0..p.c_out).into_par_iter().for_each(|dst_c_idx| {
+            // The kernel for this output channel with a (k_d, k_h, k_w, c_in) layout.
+            let mut k_cont = Vec::with_capacity(p.k_d * p.k_h * p.k_w * p.c_in);
+            for offset_d in 0..p.k_d {
+                for offset_h in 0..p.k_h {
+                    for offset_w in 0..p.k_w {
+                        for c_in_idx in 0..p.c_in {
+                            let k_idx = dst_c_idx * k_s0
+                                + c_in_idx * k_s1
+                                + offset_d * k_s2
+                                + offset_h * k_s3
+                                + offset_w * k_s4;
+                            k_cont.push(k[k_idx])
+                        }
+                    }
+                }
+            }
+            for b_idx in 0..p.b_size {
+                let dst_idx = (b_idx * p.c_out + dst_c_idx) * out_d * out_h * out_w;
+                for dst_d in 0..out_d {
+                    for dst_h in 0..out_h {
+                        for dst_w in 0..out_w {
+                            let mut sum = T::zero();
+                            for offset_d in 0..p.k_d {
+                                let src_d = match src_index(dst_d, offset_d, i_d, p) {
+                                    None => continue,
+                                    Some(src_d) => src_d,
+                                };
+                                for offset_h in 0..p.k_h {
+                                    let src_h = match src_index(dst_h, offset_h, i_h, p) {
+                                        None => continue,
+                                        Some(src_h) => src_h,
+                                    };
+                                    let k_offset = (offset_d * p.k_h + offset_h) * p.k_w;
+                                    for offset_w in 0..p.k_w {
+                                        let src_w = match src_index(dst_w, offset_w, i_w, p) {
+                                            None => continue,
+                                            Some(src_w) => src_w,
+                                        };
+                                        let inp_idx = b_idx * cont_s0
+                                            + src_d * cont_s1
+                                            + src_h * cont_s2
+                                            + src_w * cont_s3;
+                                        let k_idx = (k_offset + offset_w) * p.c_in;
+                                        let mut d = T::zero();
+                                        unsafe {
+                                            T::vec_dot(
+                                                inp_cont[inp_idx..].as_ptr(),
+                                                k_cont[k_idx..].as_ptr(),
+                                                &mut d,
+                                                p.c_in,
+                                            )
+                                        }
+                                        sum += d
+                                    }
+                                }
+                            }
+                            let dst_idx = dst_idx + (dst_d * out_h + dst_h) * out_w + dst_w;
+                            // Safety: dst_idx are uniques per dst_c_idx which is used to
+                            // parallelise the different tasks so no two threads can try to
+                            // write at the same location.
+                            unsafe {
+                                let ptr = dst.as_ptr().add(dst_idx) as *mut T;
+                                *ptr = sum
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Ok(dst)
+    }
+}
+
+pub(super) struct ConvTranspose3D<'a>(pub(super) &'a ParamsConvTranspose3D);
+
+impl Map2 for ConvTranspose3D<'_> {
+    const OP: &'static str = "conv_transpose3d";
+    fn f<T: WithDType>(&self, inp: &[T], inp_l: &Layout, k: &[T], k_l: &Layout) -> Result<Vec<T>> {
+        let p = self.0;
+        let (i_d, i_h, i_w) = (p.i_d, p.i_h, p.i_w);
+        let inp_cont = channels_last(inp, inp_l, (p.b_size, p.c_in, i_d, i_h, i_w))?;
+        let cont_s0 = i_d * i_h * i_w * p.c_in;
+        let cont_s1 = i_h * i_w * p.c_in;
+        let cont_s2 = i_w * p.c_in;
+        let cont_s3 = p.c_in;
+        let k = &k[k_l.start_offset()..];
+        let (k_s0, k_s1, k_s2, k_s3, k_s4) = dims5(k_l.stride())?;
+        let (out_d, out_h, out_w) = (p.out_d(), p.out_h(), p.out_w());
+
+        // Output shape: [b_size, c_out, out_d, out_h, out_w].
+        let dst = vec![T::zero(); p.b_size * p.c_out * out_d * out_h * out_w];
+
+        // Returns the output index for a given input index and kernel offset, or `None` if this
+        // position is outside of the output.
+        let dst_index = |src_idx: usize, offset: usize, out_size: usize| {
+            let dst_idx = src_idx * p.stride + offset * p.dilation;
+            if dst_idx < p.padding || dst_idx - p.padding >= out_size {
+                None
+            } else {
+                Some(dst_idx - p.padding)
+            }
+        };
+
+        (0..p.c_out).into_par_iter().for_each(|dst_c_idx| {
+            for offset_d in 0..p.k_d {
+                for offset_h in 0..p.k_h {
+                    for offset_w in 0..p.k_w {
+                        let k_cont = (0..p.c_in)
+                            .map(|c_in_idx| {
+                                k[c_in_idx * k_s0
+                                    + dst_c_idx * k_s1
+                                    + offset_d * k_s2
+                                    + offset_h * k_s3
+                                    + offset_w * k_s4]
+                            })
+                            .collect::<Vec<_>>();
+                        for b_idx in 0..p.b_size {
+                            let dst_idx = (b_idx * p.c_out + dst_c_idx) * out_d * out_h * out_w;
+                            for inp_d in 0..i_d {
+                                let out_z = match dst_index(inp_d, offset_d, out_d) {
+                                    None => continue,
+                                    Some(out_z) => out_z,
+                                };
+                                for inp_h in 0..i_h {
+                                    let out_y = match dst_index(inp_h, offset_h, out_h) {
+                                        None => continue,
+                                        Some(out_y) => out_y,
+                                    };
+                                    for inp_w in 0..i_w {
+                                        let out_x = match dst_index(inp_w, offset_w, out_w) {
+                                            None => continue,
+                                            Some(out_x) => out_x,
+                                        };
+                                        let inp_idx = b_idx * cont_s0
+                                            + inp_d * cont_s1
+                                            + inp_h * cont_s2
+                                            + inp_w * cont_s3;
+                                        let mut d = T::zero();
+                                        unsafe {
+                                            T::vec_dot(
+                                                inp_cont[inp_idx..].as_ptr(),
+                                                k_cont.as_ptr(),
+                                                &mut d,
+                                                p.c_in,
+                                            )
+                                        }
+                                        let dst_idx =
+                                            dst_idx + (out_z * out_h + out_y) * out_w + out_x;
+                                        // Safety: dst_idx are uniques per dst_c_idx which is used
+                                        // to parallelise the different tasks so no two threads
+                                        // can try to write at the same location.
+                                        unsafe {
+                                            let ptr = dst.as_ptr().add(dst_idx) as *mut T;
+                                            *ptr += d
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Ok(dst)
+    }
+}
@@ -12,6 +12,8 @@ pub use utils::{
 };
 mod conv2d;
 use conv2d::Conv2D;
+mod conv3d;
+use conv3d::{Conv3D, ConvTranspose3D};
 
 const USE_IM2COL_CONV1D: bool = true;
 const USE_COL2IM_CONV1D_TR: bool = true;
@@ -399,6 +401,108 @@ impl Map1 for MaxPool2D {
     }
 }
 
+struct AvgPool3D((usize, usize, usize), (usize, usize, usize));
+
+impl Map1 for AvgPool3D {
+    fn f<T: WithDType>(&self, src: &[T], layout: &Layout) -> Result<Vec<T>> {
+        // https://pytorch.org/docs/stable/generated/torch.nn.AvgPool3d.html
+        let (k_d, k_h, k_w) = self.0;
+        let (s_d, s_h, s_w) = self.1;
+        let (b_sz, c, d, h, w) = layout.shape().dims5()?;
+        let stride = layout.stride();
+        let (stride_d, stride_h, stride_w) = (stride[2], stride[3], stride[4]);
+        let d_out = (d - k_d) / s_d + 1;
+        let h_out = (h - k_h) / s_h + 1;
+        let w_out = (w - k_w) / s_w + 1;
+        let src_index = layout.start_offset();
+        let mut dst = vec![T::zero(); b_sz * c * d_out * h_out * w_out];
+        let scale = 1f64 / (k_d * k_h * k_w) as f64;
+        let scale = T::from_f64(scale);
+        for b_idx in 0..b_sz {
+            let dst = &mut dst[b_idx * c * d_out * h_out * w_out..];
+            let src_index = src_index + b_idx * stride[0];
+            for c_idx in 0..c {
+                let dst = &mut dst[c_idx * d_out * h_out * w_out..];
+                let src_index = src_index + c_idx * stride[1];
+                for d_idx in 0..d_out {
+                    for h_idx in 0..h_out {
+                        for w_idx in 0..w_out {
+                            let mut sum = T::zero();
+                            for l in 0..k_d {
+                                for m in 0..k_h {
+                                    for n in 0..k_w {
+                                        let l = s_d * d_idx + l;
+                                        let m = s_h * h_idx + m;
+                                        let n = s_w * w_idx + n;
+                                        sum += src
+                                            [src_index + l * stride_d + m * stride_h + n * stride_w]
+                                    }
+                                }
+                            }
+                            dst[(d_idx * h_out + h_idx) * w_out + w_idx] = sum * scale;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
+struct MaxPool3D((usize, usize, usize), (usize, usize, usize));
+
+impl Map1 for MaxPool3D {
+    fn f<T: WithDType>(&self, src: &[T], layout: &Layout) -> Result<Vec<T>> {
+        // https://pytorch.org/docs/stable/generated/torch.nn.MaxPool3d.html
+        let (k_d, k_h, k_w) = self.0;
+        let (s_d, s_h, s_w) = self.1;
+        let (b_sz, c, d, h, w) = layout.shape().dims5()?;
+        let stride = layout.stride();
+        let (stride_d, stride_h, stride_w) = (stride[2], stride[3], stride[4]);
+        let d_out = (d - k_d) / s_d + 1;
+        let h_out = (h - k_h) / s_h + 1;
+        let w_out = (w - k_w) / s_w + 1;
+        let src_index = layout.start_offset();
+        let mut dst = vec![T::zero(); b_sz * c * d_out * h_out * w_out];
+        for b_idx in 0..b_sz {
+            let dst = &mut dst[b_idx * c * d_out * h_out * w_out..];
+            let src_index = src_index + b_idx * stride[0];
+            for c_idx in 0..c {
+                let dst = &mut dst[c_idx * d_out * h_out * w_out..];
+                let src_index = src_index + c_idx * stride[1];
+                for d_idx in 0..d_out {
+                    for h_idx in 0..h_out {
+                        for w_idx in 0..w_out {
+                            let mut largest = src[src_index
+                                + s_d * d_idx * stride_d
+                                + s_h * h_idx * stride_h
+                                + s_w * w_idx * stride_w];
+                            for l in 0..k_d {
+                                for m in 0..k_h {
+                                    for n in 0..k_w {
+                                        let l = s_d * d_idx + l;
+                                        let m = s_h * h_idx + m;
+                                        let n = s_w * w_idx + n;
+                                        let v = src[src_index
+                                            + l * stride_d
+                                            + m * stride_h
+                                            + n * stride_w];
+                                        if largest < v {
+                                            largest = v
+                                        }
+                                    }
+                                }
+                            }
+                            dst[(d_idx * h_out + h_idx) * w_out + w_idx] = largest;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
 struct UpsampleNearest1D(usize);
 
 impl Map1 for UpsampleNearest1D {
@@ -2348,6 +2452,24 @@ impl BackendStorage for CpuStorage {
         MaxPool2D(kernel_size, stride).map(self, layout)
     }
 
+    fn avg_pool3d(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    ) -> Result<Self> {
+        AvgPool3D(kernel_size, stride).map(self, layout)
+    }
+
+    fn max_pool3d(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    ) -> Result<Self> {
+        MaxPool3D(kernel_size, stride).map(self, layout)
+    }
+
     fn upsample_nearest1d(&self, layout: &Layout, sz: usize) -> Result<Self> {
         UpsampleNearest1D(sz).map(self, layout)
     }
@@ -2839,6 +2961,26 @@ impl BackendStorage for CpuStorage {
         ConvTranspose2D(params).map(self, l, kernel, kernel_l)
     }
 
+    fn conv3d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConv3D,
+    ) -> Result<Self> {
+        Conv3D(params).map(self, l, kernel, kernel_l)
+    }
+
+    fn conv_transpose3d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConvTranspose3D,
+    ) -> Result<Self> {
+        ConvTranspose3D(params).map(self, l, kernel, kernel_l)
+    }
+
     fn index_select(&self, ids: &Self, l: &Layout, ids_l: &Layout, dim: usize) -> Result<Self> {
         match ids {
             Self::U8(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
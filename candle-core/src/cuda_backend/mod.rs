@@ -2023,6 +2023,44 @@ impl BackendStorage for CudaStorage {
         Ok(Self { slice, device })
     }
 
+    fn conv3d(
+        &self,
+        _l: &Layout,
+        _kernel: &Self,
+        _kernel_l: &Layout,
+        _params: &crate::conv::ParamsConv3D,
+    ) -> Result<Self> {
+        crate::bail!("conv3d is not supported on cuda")
+    }
+
+    fn conv_transpose3d(
+        &self,
+        _l: &Layout,
+        _kernel: &Self,
+        _kernel_l: &Layout,
+        _params: &crate::conv::ParamsConvTranspose3D,
+    ) -> Result<Self> {
+        crate::bail!("conv-transpose3d is not supported on cuda")
+    }
+
+    fn avg_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self> {
+        crate::bail!("avg-pool3d is not supported on cuda")
+    }
+
+    fn max_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self> {
+        crate::bail!("max-pool3d is not supported on cuda")
+    }
+
     fn upsample_nearest1d(&self, _: &Layout, _out_sz: usize) -> Result<Self> {
         crate::bail!("upsample-nearest1d is not supported on cuda")
     }
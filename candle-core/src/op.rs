@@ -125,6 +125,25 @@ pub enum Op {
         dilation: usize,
     },
 
+    #[allow(dead_code)]
+    Conv3D {
+        arg: Tensor,
+        kernel: Tensor,
+        padding: usize,
+        stride: usize,
+        dilation: usize,
+    },
+
+    #[allow(dead_code)]
+    ConvTranspose3D {
+        arg: Tensor,
+        kernel: Tensor,
+        padding: usize,
+        output_padding: usize,
+        stride: usize,
+        dilation: usize,
+    },
+
     AvgPool2D {
         arg: Tensor,
         kernel_size: (usize, usize),
@@ -137,6 +156,18 @@ pub enum Op {
         stride: (usize, usize),
     },
 
+    AvgPool3D {
+        arg: Tensor,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    },
+
+    MaxPool3D {
+        arg: Tensor,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    },
+
     UpsampleNearest1D {
         arg: Tensor,
         target_size: usize,
@@ -151,6 +182,8 @@ pub enum Op {
         target_h: usize,
         target_w: usize,
         align_corners: bool,
+        scale_h: Option<f64>,
+        scale_w: Option<f64>,
     },
 
     Cat(Vec<Tensor>, usize),
@@ -187,6 +220,9 @@ pub enum Op {
         Tensor,
         std::sync::Arc<Box<dyn crate::CustomOp3 + Send + Sync>>,
     ),
+    /// A segment of the graph where only the input is kept, the forward pass of the module is
+    /// run again during the backward pass.
+    Checkpoint(Tensor, std::sync::Arc<Box<dyn crate::Module + Send + Sync>>),
 }
 
 pub trait UnaryOpT {
@@ -1083,6 +1119,21 @@ impl BackpropOp {
         Self(op)
     }
 
+    // Checkpointed segments are tracked when their input is or when the module uses some
+    // variables that do not appear in its input, `uses_vars` is only set when grad is enabled.
+    pub(crate) fn checkpoint(
+        arg: &Tensor,
+        uses_vars: bool,
+        module: std::sync::Arc<Box<dyn crate::Module + Send + Sync>>,
+    ) -> Self {
+        let op = if arg.track_op() || uses_vars {
+            Some(Op::Checkpoint(arg.clone(), module))
+        } else {
+            None
+        };
+        Self(op)
+    }
+
     pub(crate) fn is_none(&self) -> bool {
         self.0.is_none()
     }
@@ -1,5 +1,7 @@
+use super::iq_grids::KVALUES_IQ4NL;
 use super::k_quants::{
-    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ5K, BlockQ6K, BlockQ8K, BlockQ8_0, QK8_0, QK_K,
+    BlockIQ4NL, BlockIQ4XS, BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ5K, BlockQ6K, BlockQ8K,
+    BlockQ8_0, BlockTQ2_0, QK4_NL, QK8_0, QK_K,
 };
 use byteorder::{ByteOrder, LittleEndian};
 use half::f16;
@@ -667,3 +669,102 @@ pub(crate) fn vec_dot_q8k_q8k(n: usize, xs: &[BlockQ8K], ys: &[BlockQ8K]) -> f32
         hsum_float_8(acc)
     }
 }
+
+// Looks up the values of the non-linear iq4 grid for 32 nibbles, the low nibbles are used for the
+// first 16 values and the high nibbles for the last 16.
+#[inline(always)]
+unsafe fn iq4_values_32(values: __m128i, rsi: *const u8) -> __m256i {
+    let m4b = _mm_set1_epi8(0xF);
+    let bits = _mm_loadu_si128(rsi as *const __m128i);
+    let lo = _mm_shuffle_epi8(values, _mm_and_si128(bits, m4b));
+    let hi = _mm_shuffle_epi8(values, _mm_and_si128(_mm_srli_epi16(bits, 4), m4b));
+    mm256_set_m128i(hi, lo)
+}
+
+#[inline(always)]
+pub(crate) fn vec_dot_iq4nl_q8_0(n: usize, xs: &[BlockIQ4NL], ys: &[BlockQ8_0]) -> f32 {
+    debug_assert!(
+        n.is_multiple_of(QK4_NL),
+        "vec_dot_iq4nl_q8_0: {n} is not divisible by {QK4_NL}"
+    );
+    unsafe {
+        let values = _mm_loadu_si128(KVALUES_IQ4NL.as_ptr() as *const __m128i);
+        let mut acc = _mm256_setzero_ps();
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let d = _mm256_set1_ps(f16::to_f32(x.d) * f16::to_f32(y.d));
+            let bx = iq4_values_32(values, x.qs.as_ptr());
+            let by = _mm256_loadu_si256(y.qs.as_ptr() as *const __m256i);
+            let q = mul_sum_i8_pairs_float(bx, by);
+            acc = _mm256_fmadd_ps(d, q, acc);
+        }
+        hsum_float_8(acc)
+    }
+}
+
+#[inline(always)]
+pub(crate) fn vec_dot_iq4xs_q8k(n: usize, xs: &[BlockIQ4XS], ys: &[BlockQ8K]) -> f32 {
+    debug_assert!(
+        n.is_multiple_of(QK_K),
+        "vec_dot_iq4xs_q8k: {n} is not divisible by {QK_K}"
+    );
+    unsafe {
+        let values = _mm_loadu_si128(KVALUES_IQ4NL.as_ptr() as *const __m128i);
+        let mut acc = _mm256_setzero_ps();
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut sumi = _mm256_setzero_si256();
+            for ib in 0..QK_K / 32 {
+                let bx = iq4_values_32(values, x.qs.as_ptr().add(16 * ib));
+                let by = _mm256_loadu_si256(y.qs.as_ptr().add(32 * ib) as *const __m256i);
+                let p16 = _mm256_maddubs_epi16(_mm256_sign_epi8(bx, bx), _mm256_sign_epi8(by, bx));
+                let ls = _mm256_set1_epi16((x.scale(ib) - 32) as i16);
+                sumi = _mm256_add_epi32(sumi, _mm256_madd_epi16(p16, ls));
+            }
+            let d = _mm256_set1_ps(f16::to_f32(x.d) * y.d);
+            acc = _mm256_fmadd_ps(d, _mm256_cvtepi32_ps(sumi), acc);
+        }
+        hsum_float_8(acc)
+    }
+}
+
+#[inline(always)]
+pub(crate) fn vec_dot_tq2_0_q8k(n: usize, xs: &[BlockTQ2_0], ys: &[BlockQ8K]) -> f32 {
+    debug_assert!(
+        n.is_multiple_of(QK_K),
+        "vec_dot_tq2_0_q8k: {n} is not divisible by {QK_K}"
+    );
+    unsafe {
+        let m3 = _mm256_set1_epi8(3);
+        let mut acc = _mm256_setzero_ps();
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            // The values are stored as 0, 1, 2 so the products fit in 16 bits, the sum of the q8
+            // values is subtracted at the end to account for the offset.
+            let mut sumi = _mm256_setzero_si256();
+            for j in (0..QK_K / 4).step_by(32) {
+                let qx = _mm256_loadu_si256(x.qs.as_ptr().add(j) as *const __m256i);
+                let qy = y.qs.as_ptr().add(4 * j);
+                let qx0 = _mm256_and_si256(qx, m3);
+                let qx1 = _mm256_and_si256(_mm256_srli_epi16(qx, 2), m3);
+                let qx2 = _mm256_and_si256(_mm256_srli_epi16(qx, 4), m3);
+                let qx3 = _mm256_and_si256(_mm256_srli_epi16(qx, 6), m3);
+                let qy0 = _mm256_loadu_si256(qy as *const __m256i);
+                let qy1 = _mm256_loadu_si256(qy.add(32) as *const __m256i);
+                let qy2 = _mm256_loadu_si256(qy.add(64) as *const __m256i);
+                let qy3 = _mm256_loadu_si256(qy.add(96) as *const __m256i);
+                let p01 = _mm256_add_epi16(
+                    _mm256_maddubs_epi16(qx0, qy0),
+                    _mm256_maddubs_epi16(qx1, qy1),
+                );
+                let p23 = _mm256_add_epi16(
+                    _mm256_maddubs_epi16(qx2, qy2),
+                    _mm256_maddubs_epi16(qx3, qy3),
+                );
+                sumi = _mm256_add_epi16(sumi, _mm256_add_epi16(p01, p23));
+            }
+            let ysum = _mm256_loadu_si256(y.bsums.as_ptr() as *const __m256i);
+            let sumi = _mm256_madd_epi16(_mm256_sub_epi16(sumi, ysum), _mm256_set1_epi16(1));
+            let d = _mm256_set1_ps(f16::to_f32(x.d) * y.d);
+            acc = _mm256_fmadd_ps(d, _mm256_cvtepi32_ps(sumi), acc);
+        }
+        hsum_float_8(acc)
+    }
+}
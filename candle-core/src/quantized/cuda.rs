@@ -595,6 +595,14 @@ impl QCudaStorage {
             GgmlDType::Q5K => deq::<crate::quantized::BlockQ5K>(&buffer, block_len, &mut out),
             GgmlDType::Q6K => deq::<crate::quantized::BlockQ6K>(&buffer, block_len, &mut out),
             GgmlDType::Q8K => deq::<crate::quantized::BlockQ8K>(&buffer, block_len, &mut out),
+            GgmlDType::IQ2XXS => deq::<crate::quantized::BlockIQ2XXS>(&buffer, block_len, &mut out),
+            GgmlDType::IQ2XS => deq::<crate::quantized::BlockIQ2XS>(&buffer, block_len, &mut out),
+            GgmlDType::IQ3XXS => deq::<crate::quantized::BlockIQ3XXS>(&buffer, block_len, &mut out),
+            GgmlDType::IQ3S => deq::<crate::quantized::BlockIQ3S>(&buffer, block_len, &mut out),
+            GgmlDType::IQ4NL => deq::<crate::quantized::BlockIQ4NL>(&buffer, block_len, &mut out),
+            GgmlDType::IQ4XS => deq::<crate::quantized::BlockIQ4XS>(&buffer, block_len, &mut out),
+            GgmlDType::TQ1_0 => deq::<crate::quantized::BlockTQ1_0>(&buffer, block_len, &mut out),
+            GgmlDType::TQ2_0 => deq::<crate::quantized::BlockTQ2_0>(&buffer, block_len, &mut out),
         }
 
         self.device
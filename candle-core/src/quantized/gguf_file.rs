@@ -54,21 +54,30 @@ pub struct TensorInfo {
     pub offset: u64,
 }
 
+/// The size in bytes of the data for a tensor with the given dtype and shape.
+fn tensor_size_in_bytes(ggml_dtype: GgmlDType, shape: &crate::Shape) -> Result<usize> {
+    let tensor_elems = shape.elem_count();
+    let block_size = ggml_dtype.block_size();
+    if !tensor_elems.is_multiple_of(block_size) {
+        crate::bail!(
+            "the number of elements {tensor_elems} is not divisible by the block size {block_size}"
+        )
+    }
+    Ok(tensor_elems / block_size * ggml_dtype.type_size())
+}
+
 impl TensorInfo {
+    pub fn size_in_bytes(&self) -> Result<usize> {
+        tensor_size_in_bytes(self.ggml_dtype, &self.shape)
+    }
+
     pub fn read<R: std::io::Seek + std::io::Read>(
         &self,
         reader: &mut R,
         tensor_data_offset: u64,
         device: &Device,
     ) -> Result<QTensor> {
-        let tensor_elems = self.shape.elem_count();
-        let block_size = self.ggml_dtype.block_size();
-        if !tensor_elems.is_multiple_of(block_size) {
-            crate::bail!(
-            "the number of elements {tensor_elems} is not divisible by the block size {block_size}"
-        )
-        }
-        let size_in_bytes = tensor_elems / block_size * self.ggml_dtype.type_size();
+        let size_in_bytes = self.size_in_bytes()?;
         let mut raw_data = vec![0u8; size_in_bytes];
         reader.seek(std::io::SeekFrom::Start(tensor_data_offset + self.offset))?;
         reader.read_exact(&mut raw_data)?;
@@ -79,6 +88,23 @@ impl TensorInfo {
             device,
         )
     }
+
+    /// Copies the raw tensor data to `w`, the data is neither decoded nor loaded in memory as a
+    /// whole.
+    pub fn copy_data<R: std::io::Seek + std::io::Read, W: std::io::Write>(
+        &self,
+        reader: &mut R,
+        tensor_data_offset: u64,
+        w: &mut W,
+    ) -> Result<()> {
+        let size_in_bytes = self.size_in_bytes()? as u64;
+        reader.seek(std::io::SeekFrom::Start(tensor_data_offset + self.offset))?;
+        let copied = std::io::copy(&mut reader.by_ref().take(size_in_bytes), w)?;
+        if copied != size_in_bytes {
+            crate::bail!("unexpected end of file, got {copied} bytes out of {size_in_bytes}")
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -392,6 +418,23 @@ impl ValueType {
     }
 }
 
+/// The alignment of the tensor data, as specified by the `general.alignment` metadata.
+fn alignment(value: Option<&Value>) -> Result<u64> {
+    let alignment = match value {
+        Some(Value::U8(v)) => *v as u64,
+        Some(Value::U16(v)) => *v as u64,
+        Some(Value::U32(v)) => *v as u64,
+        Some(Value::I8(v)) if *v >= 0 => *v as u64,
+        Some(Value::I16(v)) if *v >= 0 => *v as u64,
+        Some(Value::I32(v)) if *v >= 0 => *v as u64,
+        _ => DEFAULT_ALIGNMENT,
+    };
+    if alignment == 0 {
+        crate::bail!("invalid general.alignment 0")
+    }
+    Ok(alignment)
+}
+
 impl Content {
     pub fn read<R: std::io::Seek + std::io::Read>(reader: &mut R) -> Result<Self> {
         let magic = VersionedMagic::read(reader)?;
@@ -449,15 +492,7 @@ impl Content {
             );
         }
         let position = reader.stream_position()?;
-        let alignment = match metadata.get("general.alignment") {
-            Some(Value::U8(v)) => *v as u64,
-            Some(Value::U16(v)) => *v as u64,
-            Some(Value::U32(v)) => *v as u64,
-            Some(Value::I8(v)) if *v >= 0 => *v as u64,
-            Some(Value::I16(v)) if *v >= 0 => *v as u64,
-            Some(Value::I32(v)) if *v >= 0 => *v as u64,
-            _ => DEFAULT_ALIGNMENT,
-        };
+        let alignment = alignment(metadata.get("general.alignment"))?;
         let tensor_data_offset = position.div_ceil(alignment) * alignment;
         Ok(Self {
             magic,
@@ -493,6 +528,36 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     metadata: &[(&str, &Value)],
     tensors: &[(&str, &QTensor)],
 ) -> Result<()> {
+    let shapes = tensors
+        .iter()
+        .map(|(name, tensor)| (*name, tensor.dtype(), tensor.shape()))
+        .collect::<Vec<_>>();
+    write_with(w, metadata, &shapes, |idx, w| {
+        w.write_all(&tensors[idx].1.data()?)?;
+        Ok(())
+    })
+}
+
+/// Writes a gguf file, the data for the tensor at index `idx` in `tensors` is written by
+/// `write_data(idx, w)`. This can be used to copy tensors from other gguf files without loading
+/// them in memory, see [`TensorInfo::copy_data`].
+pub fn write_with<W, F>(
+    w: &mut W,
+    metadata: &[(&str, &Value)],
+    tensors: &[(&str, GgmlDType, &crate::Shape)],
+    mut write_data: F,
+) -> Result<()>
+where
+    W: std::io::Seek + std::io::Write,
+    F: FnMut(usize, &mut W) -> Result<()>,
+{
+    let alignment_value = metadata
+        .iter()
+        .find(|(name, _)| *name == "general.alignment")
+        .map(|(_, value)| *value);
+    let alignment = alignment(alignment_value)? as usize;
+    let padding = |size: usize| (alignment - size % alignment) % alignment;
+
     w.write_u32::<LittleEndian>(0x46554747)?;
     w.write_u32::<LittleEndian>(2)?; // version 2.
     w.write_u64::<LittleEndian>(tensors.len() as u64)?;
@@ -504,36 +569,36 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     }
     let mut offset = 0usize;
     let mut offsets = Vec::with_capacity(tensors.len());
-    for (name, tensor) in tensors.iter() {
+    for &(name, dtype, shape) in tensors.iter() {
         write_string(w, name)?;
-        let dims = tensor.shape().dims();
+        let dims = shape.dims();
         w.write_u32::<LittleEndian>(dims.len() as u32)?;
         for &dim in dims.iter().rev() {
             w.write_u64::<LittleEndian>(dim as u64)?;
         }
-        w.write_u32::<LittleEndian>(tensor.dtype().to_u32())?;
+        w.write_u32::<LittleEndian>(dtype.to_u32())?;
         w.write_u64::<LittleEndian>(offset as u64)?;
-        offsets.push(offset);
-        let size_in_bytes = tensor.storage_size_in_bytes();
-        let padding = 31 - (31 + size_in_bytes) % 32;
-        offset += size_in_bytes + padding;
+        let size_in_bytes = tensor_size_in_bytes(dtype, shape)?;
+        offsets.push((offset, size_in_bytes));
+        offset += size_in_bytes + padding(size_in_bytes);
     }
     let pos = w.stream_position()? as usize;
-    let padding = 31 - (31 + pos) % 32;
-    w.write_all(&vec![0u8; padding])?;
+    w.write_all(&vec![0u8; padding(pos)])?;
     let tensor_start_pos = w.stream_position()? as usize;
-    for (offset, (_name, tensor)) in offsets.iter().zip(tensors.iter()) {
+    for (idx, &(offset, size_in_bytes)) in offsets.iter().enumerate() {
         let pos = w.stream_position()? as usize;
         if tensor_start_pos + offset != pos {
             crate::bail!(
                 "internal error, unexpected current position {tensor_start_pos} {offset} {pos}"
             )
         }
-        let data = tensor.data()?;
-        let size_in_bytes = data.len();
-        w.write_all(&data)?;
-        let padding = 31 - (31 + size_in_bytes) % 32;
-        w.write_all(&vec![0u8; padding])?;
+        write_data(idx, w)?;
+        let written = w.stream_position()? as usize - pos;
+        if written != size_in_bytes {
+            let name = tensors[idx].0;
+            crate::bail!("unexpected data size for {name}, {written} <> {size_in_bytes}")
+        }
+        w.write_all(&vec![0u8; padding(size_in_bytes)])?;
     }
     Ok(())
 }
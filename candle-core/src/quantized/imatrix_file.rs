@@ -1,9 +1,9 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Write};
 use std::path::Path;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::Result;
 
@@ -83,3 +83,66 @@ pub fn load_imatrix<P: AsRef<Path>>(fname: P) -> Result<HashMap<String, Vec<f32>
 
     Ok(all_data)
 }
+
+/// An importance matrix entry as stored in the llama.cpp imatrix format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImatrixEntry {
+    /// Mean of the squared activations for each input column.
+    pub values: Vec<f32>,
+    /// Number of chunks of the calibration dataset that were used to compute the values.
+    pub ncall: usize,
+}
+
+/// Writes an importance matrix using the llama.cpp format, the entries are written sorted by name
+/// and can be read back with [`load_imatrix`].
+pub fn save_imatrix<P: AsRef<Path>>(
+    fname: P,
+    entries: &HashMap<String, ImatrixEntry>,
+    dataset: &str,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    write_imatrix(&mut buffer, entries, dataset)?;
+    let mut file = File::create(&fname).map_err(|e| {
+        crate::Error::msg(format!(
+            "Failed to create {}: {}",
+            fname.as_ref().display(),
+            e
+        ))
+    })?;
+    file.write_all(&buffer).map_err(|e| {
+        crate::Error::msg(format!(
+            "Failed to write file {}: {}",
+            fname.as_ref().display(),
+            e
+        ))
+    })?;
+    Ok(())
+}
+
+fn write_imatrix<W: Write>(
+    w: &mut W,
+    entries: &HashMap<String, ImatrixEntry>,
+    dataset: &str,
+) -> std::io::Result<()> {
+    let mut names = entries.keys().collect::<Vec<_>>();
+    names.sort();
+    w.write_i32::<LittleEndian>(names.len() as i32)?;
+    for name in names {
+        let entry = &entries[name];
+        w.write_i32::<LittleEndian>(name.len() as i32)?;
+        w.write_all(name.as_bytes())?;
+        w.write_i32::<LittleEndian>(entry.ncall as i32)?;
+        w.write_i32::<LittleEndian>(entry.values.len() as i32)?;
+        // The values are stored multiplied by the number of calls, see `load_imatrix`.
+        let ncall = entry.ncall.max(1) as f32;
+        for v in entry.values.iter() {
+            w.write_f32::<LittleEndian>(v * ncall)?;
+        }
+    }
+    // Trailer used by llama.cpp: the number of processed chunks and the dataset name.
+    let last_call = entries.values().map(|e| e.ncall).max().unwrap_or(0);
+    w.write_i32::<LittleEndian>(last_call as i32)?;
+    w.write_i32::<LittleEndian>(dataset.len() as i32)?;
+    w.write_all(dataset.as_bytes())?;
+    Ok(())
+}
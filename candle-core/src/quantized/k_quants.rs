@@ -1,3 +1,6 @@
+use super::iq_grids::{
+    IQ2XS_GRID, IQ2XXS_GRID, IQ3S_GRID, IQ3XXS_GRID, KMASK_IQ2XS, KSIGNS_IQ2XS, KVALUES_IQ4NL,
+};
 use super::utils::{
     get_scale_min_k4, group_for_dequantization, group_for_quantization, make_q3_quants,
     make_qkx1_quants, make_qx_quants, nearest_int,
@@ -19,6 +22,7 @@ pub const QK5_0: usize = 32;
 pub const QK5_1: usize = 32;
 pub const QK8_0: usize = 32;
 pub const QK8_1: usize = 32;
+pub const QK4_NL: usize = 32;
 
 pub trait GgmlType: Sized + Clone + Send + Sync {
     const DTYPE: GgmlDType;
@@ -169,6 +173,79 @@ pub struct BlockQ8K {
 }
 const _: () = assert!(4 + QK_K + QK_K / 16 * 2 == std::mem::size_of::<BlockQ8K>());
 
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ2XXS {
+    pub(crate) d: f16,
+    pub(crate) qs: [u16; QK_K / 8],
+}
+const _: () = assert!(2 + QK_K / 4 == std::mem::size_of::<BlockIQ2XXS>());
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ2XS {
+    pub(crate) d: f16,
+    pub(crate) qs: [u16; QK_K / 8],
+    pub(crate) scales: [u8; QK_K / 32],
+}
+const _: () = assert!(2 + QK_K / 4 + QK_K / 32 == std::mem::size_of::<BlockIQ2XS>());
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ3XXS {
+    pub(crate) d: f16,
+    pub(crate) qs: [u8; 3 * QK_K / 8],
+}
+const _: () = assert!(2 + 3 * QK_K / 8 == std::mem::size_of::<BlockIQ3XXS>());
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ3S {
+    pub(crate) d: f16,
+    pub(crate) qs: [u8; QK_K / 4],
+    pub(crate) qh: [u8; QK_K / 32],
+    pub(crate) signs: [u8; QK_K / 8],
+    pub(crate) scales: [u8; QK_K / 64],
+}
+const _: () =
+    assert!(2 + QK_K / 4 + QK_K / 32 + QK_K / 8 + QK_K / 64 == std::mem::size_of::<BlockIQ3S>());
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ4NL {
+    pub(crate) d: f16,
+    pub(crate) qs: [u8; QK4_NL / 2],
+}
+const _: () = assert!(std::mem::size_of::<BlockIQ4NL>() == 18);
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ4XS {
+    pub(crate) d: f16,
+    pub(crate) scales_h: u16,
+    pub(crate) scales_l: [u8; QK_K / 64],
+    pub(crate) qs: [u8; QK_K / 2],
+}
+const _: () = assert!(2 + 2 + QK_K / 64 + QK_K / 2 == std::mem::size_of::<BlockIQ4XS>());
+
+// Ternary quantization, each byte of `qs` holds 5 trits and each byte of `qh` holds 4 trits.
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockTQ1_0 {
+    pub(crate) qs: [u8; (QK_K - 4 * QK_K / 64) / 5],
+    pub(crate) qh: [u8; QK_K / 64],
+    pub(crate) d: f16,
+}
+const _: () = assert!(std::mem::size_of::<BlockTQ1_0>() == 54);
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockTQ2_0 {
+    pub(crate) qs: [u8; QK_K / 4],
+    pub(crate) d: f16,
+}
+const _: () = assert!(QK_K / 4 + 2 == std::mem::size_of::<BlockTQ2_0>());
+
 impl GgmlType for BlockQ4_0 {
     const DTYPE: GgmlDType = GgmlDType::Q4_0;
     const BLCK_SIZE: usize = QK4_0;
@@ -2264,6 +2341,674 @@ impl GgmlType for BlockQ8K {
     }
 }
 
+// The i-quants and ternary types below follow the reference implementations from ggml-quants.c
+// https://github.com/ggml-org/llama.cpp/blob/aa3ee0eb0b80efca126cedf9bcb4fb5864b46ce3/ggml/src/ggml-quants.c
+impl GgmlType for BlockIQ2XXS {
+    const DTYPE: GgmlDType = GgmlDType::IQ2XXS;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK_K),
+            "vec_dot_iq2xxs_q8k: {n} is not divisible by {QK_K}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut bsum = 0i32;
+            for (qs, q8) in x.qs.chunks_exact(4).zip(y.qs.chunks_exact(32)) {
+                let aux0 = qs[0] as u32 | (qs[1] as u32) << 16;
+                let aux1 = qs[2] as u32 | (qs[3] as u32) << 16;
+                let ls = 2 * (aux1 >> 28) as i32 + 1;
+                let mut sumi = 0i32;
+                for (l, q8) in q8.chunks_exact(8).enumerate() {
+                    let grid = IQ2XXS_GRID[((aux0 >> (8 * l)) & 0xff) as usize].to_le_bytes();
+                    let signs = KSIGNS_IQ2XS[((aux1 >> (7 * l)) & 127) as usize];
+                    sumi += signed_dot(&grid, signs, q8);
+                }
+                bsum += sumi * ls;
+            }
+            sumf += x.d.to_f32() * y.d * bsum as f32;
+        }
+        0.125 * sumf
+    }
+
+    fn from_float(_xs: &[f32], _ys: &mut [Self]) {
+        panic!("quantization is not supported for {:?}", Self::DTYPE)
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            let d = x.d.to_f32();
+            for (qs, ys) in x.qs.chunks_exact(4).zip(ys.chunks_exact_mut(32)) {
+                let aux0 = qs[0] as u32 | (qs[1] as u32) << 16;
+                let aux1 = qs[2] as u32 | (qs[3] as u32) << 16;
+                let db = d * (0.5 + (aux1 >> 28) as f32) * 0.25;
+                for (l, ys) in ys.chunks_exact_mut(8).enumerate() {
+                    let grid = IQ2XXS_GRID[((aux0 >> (8 * l)) & 0xff) as usize].to_le_bytes();
+                    let signs = KSIGNS_IQ2XS[((aux1 >> (7 * l)) & 127) as usize];
+                    signed_dequantize(&grid, signs, db, ys);
+                }
+            }
+        }
+    }
+}
+
+impl GgmlType for BlockIQ2XS {
+    const DTYPE: GgmlDType = GgmlDType::IQ2XS;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK_K),
+            "vec_dot_iq2xs_q8k: {n} is not divisible by {QK_K}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut bsum = 0i32;
+            for (ib32, q8) in y.qs.chunks_exact(32).enumerate() {
+                let ls1 = 2 * (x.scales[ib32] & 0xf) as i32 + 1;
+                let ls2 = 2 * (x.scales[ib32] >> 4) as i32 + 1;
+                for (l, q8) in q8.chunks_exact(8).enumerate() {
+                    let q = x.qs[4 * ib32 + l];
+                    let grid = IQ2XS_GRID[(q & 511) as usize].to_le_bytes();
+                    let signs = KSIGNS_IQ2XS[(q >> 9) as usize];
+                    let ls = if l < 2 { ls1 } else { ls2 };
+                    bsum += signed_dot(&grid, signs, q8) * ls;
+                }
+            }
+            sumf += x.d.to_f32() * y.d * bsum as f32;
+        }
+        0.125 * sumf
+    }
+
+    fn from_float(_xs: &[f32], _ys: &mut [Self]) {
+        panic!("quantization is not supported for {:?}", Self::DTYPE)
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            let d = x.d.to_f32();
+            for (ib32, ys) in ys.chunks_exact_mut(32).enumerate() {
+                let db1 = d * (0.5 + (x.scales[ib32] & 0xf) as f32) * 0.25;
+                let db2 = d * (0.5 + (x.scales[ib32] >> 4) as f32) * 0.25;
+                for (l, ys) in ys.chunks_exact_mut(8).enumerate() {
+                    let q = x.qs[4 * ib32 + l];
+                    let grid = IQ2XS_GRID[(q & 511) as usize].to_le_bytes();
+                    let signs = KSIGNS_IQ2XS[(q >> 9) as usize];
+                    let db = if l < 2 { db1 } else { db2 };
+                    signed_dequantize(&grid, signs, db, ys);
+                }
+            }
+        }
+    }
+}
+
+impl GgmlType for BlockIQ3XXS {
+    const DTYPE: GgmlDType = GgmlDType::IQ3XXS;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK_K),
+            "vec_dot_iq3xxs_q8k: {n} is not divisible by {QK_K}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let (q3, scales_and_signs) = x.qs.split_at(QK_K / 4);
+            let mut bsum = 0i32;
+            for (ib32, q8) in y.qs.chunks_exact(32).enumerate() {
+                let aux32 = LittleEndian::read_u32(&scales_and_signs[4 * ib32..]);
+                let ls = 2 * (aux32 >> 28) as i32 + 1;
+                let mut sumi = 0i32;
+                for (l, q8) in q8.chunks_exact(8).enumerate() {
+                    let q3 = &q3[8 * ib32 + 2 * l..];
+                    let grid = iq3_grid_pair(&IQ3XXS_GRID, q3[0] as usize, q3[1] as usize);
+                    let signs = KSIGNS_IQ2XS[((aux32 >> (7 * l)) & 127) as usize];
+                    sumi += signed_dot(&grid, signs, q8);
+                }
+                bsum += sumi * ls;
+            }
+            sumf += x.d.to_f32() * y.d * bsum as f32;
+        }
+        0.25 * sumf
+    }
+
+    fn from_float(_xs: &[f32], _ys: &mut [Self]) {
+        panic!("quantization is not supported for {:?}", Self::DTYPE)
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            let d = x.d.to_f32();
+            let (q3, scales_and_signs) = x.qs.split_at(QK_K / 4);
+            for (ib32, ys) in ys.chunks_exact_mut(32).enumerate() {
+                let aux32 = LittleEndian::read_u32(&scales_and_signs[4 * ib32..]);
+                let db = d * (0.5 + (aux32 >> 28) as f32) * 0.5;
+                for (l, ys) in ys.chunks_exact_mut(8).enumerate() {
+                    let q3 = &q3[8 * ib32 + 2 * l..];
+                    let grid = iq3_grid_pair(&IQ3XXS_GRID, q3[0] as usize, q3[1] as usize);
+                    let signs = KSIGNS_IQ2XS[((aux32 >> (7 * l)) & 127) as usize];
+                    signed_dequantize(&grid, signs, db, ys);
+                }
+            }
+        }
+    }
+}
+
+impl GgmlType for BlockIQ3S {
+    const DTYPE: GgmlDType = GgmlDType::IQ3S;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK_K),
+            "vec_dot_iq3s_q8k: {n} is not divisible by {QK_K}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut bsum = 0i32;
+            for (ib32, q8) in y.qs.chunks_exact(32).enumerate() {
+                let scale = x.scales[ib32 / 2] >> (4 * (ib32 % 2));
+                let ls = 2 * (scale & 0xf) as i32 + 1;
+                let mut sumi = 0i32;
+                for (l, q8) in q8.chunks_exact(8).enumerate() {
+                    let grid = x.grid(ib32, l);
+                    sumi += signed_dot(&grid, x.signs[4 * ib32 + l], q8);
+                }
+                bsum += sumi * ls;
+            }
+            sumf += x.d.to_f32() * y.d * bsum as f32;
+        }
+        sumf
+    }
+
+    fn from_float(_xs: &[f32], _ys: &mut [Self]) {
+        panic!("quantization is not supported for {:?}", Self::DTYPE)
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            let d = x.d.to_f32();
+            for (ib32, ys) in ys.chunks_exact_mut(32).enumerate() {
+                let scale = x.scales[ib32 / 2] >> (4 * (ib32 % 2));
+                let db = d * (1 + 2 * (scale & 0xf) as i32) as f32;
+                for (l, ys) in ys.chunks_exact_mut(8).enumerate() {
+                    let grid = x.grid(ib32, l);
+                    signed_dequantize(&grid, x.signs[4 * ib32 + l], db, ys);
+                }
+            }
+        }
+    }
+}
+
+impl BlockIQ3S {
+    // The 8 unsigned grid values for the `l`-th group of 8 values in the `ib32`-th sub-block, the
+    // 9th bit of each grid index is stored in `qh`.
+    fn grid(&self, ib32: usize, l: usize) -> [u8; 8] {
+        let qh = self.qh[ib32] as usize;
+        let qs = &self.qs[8 * ib32 + 2 * l..];
+        let i1 = qs[0] as usize | ((qh << (8 - 2 * l)) & 256);
+        let i2 = qs[1] as usize | ((qh << (7 - 2 * l)) & 256);
+        iq3_grid_pair(&IQ3S_GRID, i1, i2)
+    }
+}
+
+impl GgmlType for BlockIQ4NL {
+    const DTYPE: GgmlDType = GgmlDType::IQ4NL;
+    const BLCK_SIZE: usize = QK4_NL;
+    type VecDotType = BlockQ8_0;
+
+    #[allow(unreachable_code)]
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        #[cfg(target_feature = "avx2")]
+        return super::avx::vec_dot_iq4nl_q8_0(n, xs, ys);
+
+        #[cfg(target_feature = "neon")]
+        return super::neon::vec_dot_iq4nl_q8_0(n, xs, ys);
+
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK4_NL),
+            "vec_dot_iq4nl_q8_0: {n} is not divisible by {QK4_NL}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let sumi = iq4_dot(&x.qs, &y.qs);
+            sumf += x.d.to_f32() * y.d.to_f32() * sumi as f32;
+        }
+        sumf
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) {
+        Self::quantize(xs, ys, None)
+    }
+
+    fn from_float_imatrix(xs: &[f32], ys: &mut [Self], imatrix_weights: &[f32], n_per_row: usize) {
+        Self::quantize(xs, ys, Some((imatrix_weights, n_per_row)))
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            iq4_dequantize(&x.qs, x.d.to_f32(), ys);
+        }
+    }
+}
+
+impl BlockIQ4NL {
+    fn quantize(xs: &[f32], ys: &mut [Self], imatrix: Option<(&[f32], usize)>) {
+        let mut weights = [0f32; QK4_NL];
+        for (blk_idx, (block, x)) in group_for_quantization(xs, ys).into_iter().enumerate() {
+            iq4_weights(x, &mut weights, blk_idx, imatrix);
+            let d = make_iq4_scale(x, &weights);
+            block.d = f16::from_f32(d);
+            iq4_quantize(x, d, &mut block.qs);
+        }
+    }
+}
+
+impl GgmlType for BlockIQ4XS {
+    const DTYPE: GgmlDType = GgmlDType::IQ4XS;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    #[allow(unreachable_code)]
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        #[cfg(target_feature = "avx2")]
+        return super::avx::vec_dot_iq4xs_q8k(n, xs, ys);
+
+        #[cfg(target_feature = "neon")]
+        return super::neon::vec_dot_iq4xs_q8k(n, xs, ys);
+
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK_K),
+            "vec_dot_iq4xs_q8k: {n} is not divisible by {QK_K}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut sumi = 0i32;
+            for (ib, (qs, q8)) in x.qs.chunks_exact(16).zip(y.qs.chunks_exact(32)).enumerate() {
+                sumi += (x.scale(ib) - 32) * iq4_dot(qs, q8);
+            }
+            sumf += x.d.to_f32() * y.d * sumi as f32;
+        }
+        sumf
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) {
+        Self::quantize(xs, ys, None)
+    }
+
+    fn from_float_imatrix(xs: &[f32], ys: &mut [Self], imatrix_weights: &[f32], n_per_row: usize) {
+        Self::quantize(xs, ys, Some((imatrix_weights, n_per_row)))
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            let d = x.d.to_f32();
+            for (ib, ys) in ys.chunks_exact_mut(32).enumerate() {
+                let qs = &x.qs[16 * ib..16 * (ib + 1)];
+                iq4_dequantize(qs, d * (x.scale(ib) - 32) as f32, ys);
+            }
+        }
+    }
+}
+
+impl BlockIQ4XS {
+    // The 6-bit scale of the `ib`-th sub-block, with an offset of 32.
+    pub(crate) fn scale(&self, ib: usize) -> i32 {
+        let ls_l = (self.scales_l[ib / 2] >> (4 * (ib % 2))) & 0xf;
+        let ls_h = (self.scales_h >> (2 * ib)) & 3;
+        (ls_l as u16 | (ls_h << 4)) as i32
+    }
+
+    fn quantize(xs: &[f32], ys: &mut [Self], imatrix: Option<(&[f32], usize)>) {
+        let mut weights = [0f32; QK_K];
+        for (blk_idx, (block, x)) in group_for_quantization(xs, ys).into_iter().enumerate() {
+            iq4_weights(x, &mut weights, blk_idx, imatrix);
+            let mut scales = [0f32; QK_K / 32];
+            let mut max_scale = 0f32;
+            for (ib, (x, weights)) in x.chunks_exact(32).zip(weights.chunks_exact(32)).enumerate() {
+                scales[ib] = make_iq4_scale(x, weights);
+                if scales[ib].abs() > max_scale.abs() {
+                    max_scale = scales[ib]
+                }
+            }
+            let d = -max_scale / 32.;
+            let id = if d != 0. { 1. / d } else { 0. };
+            block.d = f16::from_f32(d);
+            block.scales_h = 0;
+            block.scales_l = [0; QK_K / 64];
+            for (ib, x) in x.chunks_exact(32).enumerate() {
+                let l = nearest_int(id * scales[ib]).clamp(-32, 31);
+                iq4_quantize(x, d * l as f32, &mut block.qs[16 * ib..16 * (ib + 1)]);
+                let l = (l + 32) as u8;
+                block.scales_l[ib / 2] |= (l & 0xf) << (4 * (ib % 2));
+                block.scales_h |= ((l >> 4) as u16) << (2 * ib);
+            }
+        }
+    }
+}
+
+impl GgmlType for BlockTQ1_0 {
+    const DTYPE: GgmlDType = GgmlDType::TQ1_0;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK_K),
+            "vec_dot_tq1_0_q8k: {n} is not divisible by {QK_K}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let sumi = x
+                .trits()
+                .iter()
+                .zip(y.qs.iter())
+                .map(|(&t, &q)| t as i32 * q as i32)
+                .sum::<i32>();
+            sumf += x.d.to_f32() * y.d * sumi as f32;
+        }
+        sumf
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) {
+        for (block, x) in group_for_quantization(xs, ys) {
+            let amax = x.iter().fold(0f32, |amax, x| amax.max(x.abs()));
+            let id = if amax != 0. { 1. / amax } else { 0. };
+            block.d = f16::from_f32(amax);
+            // Map -1, 0, 1 to 0, 1, 2.
+            let trit = |x: f32| ((x * id).round() as i32 + 1) as u16;
+            let (x0, x) = x.split_at(5 * 32);
+            let (x1, x2) = x.split_at(5 * 16);
+            for m in 0..32 {
+                block.qs[m] = pack_trits((0..5).map(|n| trit(x0[m + 32 * n])));
+            }
+            for m in 0..16 {
+                block.qs[32 + m] = pack_trits((0..5).map(|n| trit(x1[m + 16 * n])));
+            }
+            for j in 0..QK_K / 64 {
+                // The last trit is unused so that the 4 values are the most significant ones.
+                let trits = (0..4).map(|n| trit(x2[j + 4 * n]));
+                block.qh[j] = pack_trits(trits.chain(std::iter::once(0)));
+            }
+        }
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            let d = x.d.to_f32();
+            for (y, &t) in ys.iter_mut().zip(x.trits().iter()) {
+                *y = t as f32 * d
+            }
+        }
+    }
+}
+
+impl BlockTQ1_0 {
+    // Unpacks the ternary values, the values stored in a byte are 32 (or 16 or 4) elements apart.
+    fn trits(&self) -> [i8; QK_K] {
+        let mut trits = [0i8; QK_K];
+        let mut idx = 0;
+        for (qs, n_trits) in [(&self.qs[..32], 5), (&self.qs[32..], 5), (&self.qh[..], 4)] {
+            for n in 0..n_trits {
+                for &q in qs.iter() {
+                    trits[idx] = unpack_trit(q, n);
+                    idx += 1;
+                }
+            }
+        }
+        trits
+    }
+}
+
+impl GgmlType for BlockTQ2_0 {
+    const DTYPE: GgmlDType = GgmlDType::TQ2_0;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    #[allow(unreachable_code)]
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        #[cfg(target_feature = "avx2")]
+        return super::avx::vec_dot_tq2_0_q8k(n, xs, ys);
+
+        #[cfg(target_feature = "neon")]
+        return super::neon::vec_dot_tq2_0_q8k(n, xs, ys);
+
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> f32 {
+        debug_assert!(
+            n.is_multiple_of(QK_K),
+            "vec_dot_tq2_0_q8k: {n} is not divisible by {QK_K}"
+        );
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut sumi = 0i32;
+            for (qs, q8) in x.qs.chunks_exact(32).zip(y.qs.chunks_exact(128)) {
+                for (l, q8) in q8.chunks_exact(32).enumerate() {
+                    for (&q, &y) in qs.iter().zip(q8.iter()) {
+                        sumi += (((q >> (2 * l)) & 3) as i32 - 1) * y as i32
+                    }
+                }
+            }
+            sumf += x.d.to_f32() * y.d * sumi as f32;
+        }
+        sumf
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) {
+        for (block, x) in group_for_quantization(xs, ys) {
+            let amax = x.iter().fold(0f32, |amax, x| amax.max(x.abs()));
+            let id = if amax != 0. { 1. / amax } else { 0. };
+            block.d = f16::from_f32(amax);
+            for (qs, x) in block.qs.chunks_exact_mut(32).zip(x.chunks_exact(128)) {
+                for (m, q) in qs.iter_mut().enumerate() {
+                    *q = 0;
+                    for n in 0..4 {
+                        // Map -1, 0, 1 to 0, 1, 2.
+                        let xi = (x[m + 32 * n] * id).round() as i32 + 1;
+                        *q |= ((xi & 3) as u8) << (2 * n);
+                    }
+                }
+            }
+        }
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (x, ys) in group_for_dequantization(xs, ys) {
+            let d = x.d.to_f32();
+            for (qs, ys) in x.qs.chunks_exact(32).zip(ys.chunks_exact_mut(128)) {
+                for (l, ys) in ys.chunks_exact_mut(32).enumerate() {
+                    for (y, &q) in ys.iter_mut().zip(qs.iter()) {
+                        *y = (((q >> (2 * l)) & 3) as i32 - 1) as f32 * d
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Dot product between 8 grid values with the signs given by the `signs` bitmask and some q8 values.
+fn signed_dot(grid: &[u8; 8], signs: u8, q8: &[i8]) -> i32 {
+    let mut sumi = 0i32;
+    for j in 0..8 {
+        let v = grid[j] as i32 * q8[j] as i32;
+        sumi += if signs & KMASK_IQ2XS[j] != 0 { -v } else { v }
+    }
+    sumi
+}
+
+fn signed_dequantize(grid: &[u8; 8], signs: u8, d: f32, ys: &mut [f32]) {
+    for j in 0..8 {
+        let v = d * grid[j] as f32;
+        ys[j] = if signs & KMASK_IQ2XS[j] != 0 { -v } else { v }
+    }
+}
+
+// The iq3 grids store 4 values per entry, this concatenates two of them.
+fn iq3_grid_pair(grid: &[u32], i1: usize, i2: usize) -> [u8; 8] {
+    let mut res = [0u8; 8];
+    res[..4].copy_from_slice(&grid[i1].to_le_bytes());
+    res[4..].copy_from_slice(&grid[i2].to_le_bytes());
+    res
+}
+
+const GROUP_MAX_EPS: f32 = 1e-15;
+
+// Dot product between 32 values using the non-linear iq4 grid, the first 16 values are stored
+// in the low nibbles of `qs` and the last 16 in the high nibbles.
+fn iq4_dot(qs: &[u8], q8: &[i8]) -> i32 {
+    let mut sumi = 0i32;
+    for j in 0..16 {
+        sumi += KVALUES_IQ4NL[(qs[j] & 0xf) as usize] as i32 * q8[j] as i32;
+        sumi += KVALUES_IQ4NL[(qs[j] >> 4) as usize] as i32 * q8[j + 16] as i32;
+    }
+    sumi
+}
+
+fn iq4_dequantize(qs: &[u8], d: f32, ys: &mut [f32]) {
+    for j in 0..16 {
+        ys[j] = d * KVALUES_IQ4NL[(qs[j] & 0xf) as usize] as f32;
+        ys[j + 16] = d * KVALUES_IQ4NL[(qs[j] >> 4) as usize] as f32;
+    }
+}
+
+fn iq4_quantize(xs: &[f32], d: f32, qs: &mut [u8]) {
+    let id = if d != 0. { 1. / d } else { 0. };
+    for j in 0..16 {
+        qs[j] = best_index_iq4nl(id * xs[j]) | (best_index_iq4nl(id * xs[j + 16]) << 4);
+    }
+}
+
+// The index of the closest value to `x` in the non-linear iq4 grid.
+fn best_index_iq4nl(x: f32) -> u8 {
+    let values = &KVALUES_IQ4NL;
+    if x <= values[0] as f32 {
+        return 0;
+    }
+    if x >= values[15] as f32 {
+        return 15;
+    }
+    let (mut ml, mut mu) = (0, 15);
+    while mu - ml > 1 {
+        let mav = (ml + mu) / 2;
+        if x < values[mav] as f32 {
+            mu = mav
+        } else {
+            ml = mav
+        }
+    }
+    if x - (values[mu - 1] as f32) < values[mu] as f32 - x {
+        (mu - 1) as u8
+    } else {
+        mu as u8
+    }
+}
+
+// The weights used when searching for the iq4 scales, `xs` is a whole block and `blk_idx` its
+// index in the tensor.
+fn iq4_weights(xs: &[f32], weights: &mut [f32], blk_idx: usize, imatrix: Option<(&[f32], usize)>) {
+    match imatrix {
+        None => {
+            for (w, &x) in weights.iter_mut().zip(xs.iter()) {
+                *w = x * x
+            }
+        }
+        Some((imatrix_weights, n_per_row)) => {
+            let sigma2 = 2. * xs.iter().map(|x| x * x).sum::<f32>() / xs.len() as f32;
+            let offset = (blk_idx % (n_per_row / xs.len())) * xs.len();
+            for (j, (w, &x)) in weights.iter_mut().zip(xs.iter()).enumerate() {
+                *w = imatrix_weights[offset + j] * (sigma2 + x * x).sqrt()
+            }
+        }
+    }
+}
+
+// Finds the scale minimizing the weighted quantization error of 32 values on the non-linear iq4
+// grid, both positive and negative scales are tried as the grid is not symmetric. This follows
+// `quantize_row_iq4_nl_impl` from ggml.
+fn make_iq4_scale(xs: &[f32], weights: &[f32]) -> f32 {
+    let (mut amax, mut max) = (0f32, 0f32);
+    for &x in xs.iter() {
+        if x.abs() > amax {
+            amax = x.abs();
+            max = x;
+        }
+    }
+    if amax < GROUP_MAX_EPS {
+        return 0.;
+    }
+    let sums = |id: f32| {
+        let (mut sumqx, mut sumq2) = (0f32, 0f32);
+        for (&x, &w) in xs.iter().zip(weights.iter()) {
+            let q = KVALUES_IQ4NL[best_index_iq4nl(id * x) as usize] as f32;
+            sumqx += w * q * x;
+            sumq2 += w * q * q;
+        }
+        (sumqx, sumq2)
+    };
+    let v0 = KVALUES_IQ4NL[0] as f32;
+    let (sumqx, sumq2) = sums(-v0 / max);
+    let mut d = if sumq2 > 0. { sumqx / sumq2 } else { 0. };
+    let mut best = d * sumqx;
+    for itry in -7..=7 {
+        let (sumqx, sumq2) = sums((itry as f32 + v0) / max);
+        if sumq2 > 0. && sumqx * sumqx > best * sumq2 {
+            d = sumqx / sumq2;
+            best = d * sumqx;
+        }
+    }
+    d
+}
+
+const POW3: [u8; 6] = [1, 3, 9, 27, 81, 243];
+
+// Packs 5 trits (0, 1 or 2) in a byte, the first trit being the most significant one. The value is
+// scaled to 256 / 243 so that each trit can be extracted with a multiplication, see `unpack_trit`.
+fn pack_trits(trits: impl Iterator<Item = u16>) -> u8 {
+    let q = trits.fold(0u16, |q, t| q * 3 + t);
+    ((q * 256 + 242) / 243) as u8
+}
+
+// Extracts the `n`-th trit of a byte packed with `pack_trits` and maps it back to -1, 0 or 1.
+fn unpack_trit(q: u8, n: usize) -> i8 {
+    let q = q.wrapping_mul(POW3[n]);
+    ((q as u16 * 3) >> 8) as i8 - 1
+}
+
 // https://github.com/ggml-org/llama.cpp/blob/aa3ee0eb0b80efca126cedf9bcb4fb5864b46ce3/ggml/src/ggml-cpu/ggml-cpu.c#L1205
 pub fn matmul<T: GgmlType>(
     (m, k, n): (usize, usize, usize),
@@ -2500,6 +3245,27 @@ macro_rules! verify_block_sizes {
 }
 
 verify_block_sizes!(
-    BlockQ4_0, BlockQ4_1, BlockQ5_0, BlockQ5_1, BlockQ8_0, BlockQ8_1, BlockQ2K, BlockQ3K, BlockQ4K,
-    BlockQ5K, BlockQ6K, BlockQ8K, f32, f16, bf16
+    BlockQ4_0,
+    BlockQ4_1,
+    BlockQ5_0,
+    BlockQ5_1,
+    BlockQ8_0,
+    BlockQ8_1,
+    BlockQ2K,
+    BlockQ3K,
+    BlockQ4K,
+    BlockQ5K,
+    BlockQ6K,
+    BlockQ8K,
+    BlockIQ2XXS,
+    BlockIQ2XS,
+    BlockIQ3XXS,
+    BlockIQ3S,
+    BlockIQ4NL,
+    BlockIQ4XS,
+    BlockTQ1_0,
+    BlockTQ2_0,
+    f32,
+    f16,
+    bf16
 );
@@ -11,6 +11,7 @@ mod dummy_metal;
 pub mod ggml_file;
 pub mod gguf_file;
 pub mod imatrix_file;
+mod iq_grids;
 pub mod k_quants;
 #[cfg(feature = "metal")]
 pub mod metal;
@@ -99,6 +100,14 @@ impl QStorage {
                 GgmlDType::Q5K => metal::load_quantized(d, as_t_slice::<BlockQ5K>(data)),
                 GgmlDType::Q6K => metal::load_quantized(d, as_t_slice::<BlockQ6K>(data)),
                 GgmlDType::Q8K => metal::load_quantized(d, as_t_slice::<BlockQ8K>(data)),
+                GgmlDType::IQ2XXS => metal::load_quantized(d, as_t_slice::<BlockIQ2XXS>(data)),
+                GgmlDType::IQ2XS => metal::load_quantized(d, as_t_slice::<BlockIQ2XS>(data)),
+                GgmlDType::IQ3XXS => metal::load_quantized(d, as_t_slice::<BlockIQ3XXS>(data)),
+                GgmlDType::IQ3S => metal::load_quantized(d, as_t_slice::<BlockIQ3S>(data)),
+                GgmlDType::IQ4NL => metal::load_quantized(d, as_t_slice::<BlockIQ4NL>(data)),
+                GgmlDType::IQ4XS => metal::load_quantized(d, as_t_slice::<BlockIQ4XS>(data)),
+                GgmlDType::TQ1_0 => metal::load_quantized(d, as_t_slice::<BlockTQ1_0>(data)),
+                GgmlDType::TQ2_0 => metal::load_quantized(d, as_t_slice::<BlockTQ2_0>(data)),
                 GgmlDType::BF16 => metal::load_quantized(d, as_t_slice::<bf16>(data)),
             },
             Device::Cuda(d) => match dtype {
@@ -116,6 +125,14 @@ impl QStorage {
                 GgmlDType::Q5K => cuda::load_quantized(d, as_t_slice::<BlockQ5K>(data)),
                 GgmlDType::Q6K => cuda::load_quantized(d, as_t_slice::<BlockQ6K>(data)),
                 GgmlDType::Q8K => cuda::load_quantized(d, as_t_slice::<BlockQ8K>(data)),
+                GgmlDType::IQ2XXS => cuda::load_quantized(d, as_t_slice::<BlockIQ2XXS>(data)),
+                GgmlDType::IQ2XS => cuda::load_quantized(d, as_t_slice::<BlockIQ2XS>(data)),
+                GgmlDType::IQ3XXS => cuda::load_quantized(d, as_t_slice::<BlockIQ3XXS>(data)),
+                GgmlDType::IQ3S => cuda::load_quantized(d, as_t_slice::<BlockIQ3S>(data)),
+                GgmlDType::IQ4NL => cuda::load_quantized(d, as_t_slice::<BlockIQ4NL>(data)),
+                GgmlDType::IQ4XS => cuda::load_quantized(d, as_t_slice::<BlockIQ4XS>(data)),
+                GgmlDType::TQ1_0 => cuda::load_quantized(d, as_t_slice::<BlockTQ1_0>(data)),
+                GgmlDType::TQ2_0 => cuda::load_quantized(d, as_t_slice::<BlockTQ2_0>(data)),
                 GgmlDType::BF16 => cuda::load_quantized(d, as_t_slice::<bf16>(data)),
             },
         }
@@ -267,6 +284,14 @@ pub enum GgmlDType {
     Q5K,
     Q6K,
     Q8K,
+    IQ2XXS,
+    IQ2XS,
+    IQ3XXS,
+    IQ3S,
+    IQ4NL,
+    IQ4XS,
+    TQ1_0,
+    TQ2_0,
 }
 
 impl GgmlDType {
@@ -286,8 +311,16 @@ impl GgmlDType {
             13 => Self::Q5K,
             14 => Self::Q6K,
             15 => Self::Q8K,
+            16 => Self::IQ2XXS,
+            17 => Self::IQ2XS,
+            18 => Self::IQ3XXS,
+            20 => Self::IQ4NL,
+            21 => Self::IQ3S,
+            23 => Self::IQ4XS,
             // https://github.com/ggerganov/ggml/blob/29d87fc6676e7ed0cdfdec0804b06001d9c2bb44/include/ggml.h#L389
             30 => Self::BF16,
+            34 => Self::TQ1_0,
+            35 => Self::TQ2_0,
             _ => crate::bail!("unknown dtype for tensor {u}"),
         };
         Ok(dtype)
@@ -309,8 +342,16 @@ impl GgmlDType {
             Self::Q5K => 13,
             Self::Q6K => 14,
             Self::Q8K => 15,
+            Self::IQ2XXS => 16,
+            Self::IQ2XS => 17,
+            Self::IQ3XXS => 18,
+            Self::IQ4NL => 20,
+            Self::IQ3S => 21,
+            Self::IQ4XS => 23,
             // https://github.com/ggerganov/ggml/blob/29d87fc6676e7ed0cdfdec0804b06001d9c2bb44/include/ggml.h#L389
             Self::BF16 => 30,
+            Self::TQ1_0 => 34,
+            Self::TQ2_0 => 35,
         }
     }
 
@@ -331,6 +372,35 @@ impl GgmlDType {
             Self::Q5K => Box::new(vec![BlockQ5K::zeros(); elem_count / BlockQ5K::BLCK_SIZE]),
             Self::Q6K => Box::new(vec![BlockQ6K::zeros(); elem_count / BlockQ6K::BLCK_SIZE]),
             Self::Q8K => Box::new(vec![BlockQ8K::zeros(); elem_count / BlockQ8K::BLCK_SIZE]),
+            Self::IQ2XXS => Box::new(vec![
+                BlockIQ2XXS::zeros();
+                elem_count / BlockIQ2XXS::BLCK_SIZE
+            ]),
+            Self::IQ2XS => Box::new(vec![
+                BlockIQ2XS::zeros();
+                elem_count / BlockIQ2XS::BLCK_SIZE
+            ]),
+            Self::IQ3XXS => Box::new(vec![
+                BlockIQ3XXS::zeros();
+                elem_count / BlockIQ3XXS::BLCK_SIZE
+            ]),
+            Self::IQ3S => Box::new(vec![BlockIQ3S::zeros(); elem_count / BlockIQ3S::BLCK_SIZE]),
+            Self::IQ4NL => Box::new(vec![
+                BlockIQ4NL::zeros();
+                elem_count / BlockIQ4NL::BLCK_SIZE
+            ]),
+            Self::IQ4XS => Box::new(vec![
+                BlockIQ4XS::zeros();
+                elem_count / BlockIQ4XS::BLCK_SIZE
+            ]),
+            Self::TQ1_0 => Box::new(vec![
+                BlockTQ1_0::zeros();
+                elem_count / BlockTQ1_0::BLCK_SIZE
+            ]),
+            Self::TQ2_0 => Box::new(vec![
+                BlockTQ2_0::zeros();
+                elem_count / BlockTQ2_0::BLCK_SIZE
+            ]),
             Self::BF16 => Box::new(vec![bf16::zeros(); elem_count]),
         }
     }
@@ -351,6 +421,14 @@ impl GgmlDType {
             Self::Q5K => Box::new(as_t_slice::<BlockQ5K>(data).to_vec()),
             Self::Q6K => Box::new(as_t_slice::<BlockQ6K>(data).to_vec()),
             Self::Q8K => Box::new(as_t_slice::<BlockQ8K>(data).to_vec()),
+            Self::IQ2XXS => Box::new(as_t_slice::<BlockIQ2XXS>(data).to_vec()),
+            Self::IQ2XS => Box::new(as_t_slice::<BlockIQ2XS>(data).to_vec()),
+            Self::IQ3XXS => Box::new(as_t_slice::<BlockIQ3XXS>(data).to_vec()),
+            Self::IQ3S => Box::new(as_t_slice::<BlockIQ3S>(data).to_vec()),
+            Self::IQ4NL => Box::new(as_t_slice::<BlockIQ4NL>(data).to_vec()),
+            Self::IQ4XS => Box::new(as_t_slice::<BlockIQ4XS>(data).to_vec()),
+            Self::TQ1_0 => Box::new(as_t_slice::<BlockTQ1_0>(data).to_vec()),
+            Self::TQ2_0 => Box::new(as_t_slice::<BlockTQ2_0>(data).to_vec()),
             Self::BF16 => Box::new(as_t_slice::<bf16>(data).to_vec()),
         }
     }
@@ -374,7 +452,37 @@ impl GgmlDType {
             Self::Q5K => std::mem::size_of::<BlockQ5K>(),
             Self::Q6K => std::mem::size_of::<BlockQ6K>(),
             Self::Q8K => std::mem::size_of::<BlockQ8K>(),
-        }
+            Self::IQ2XXS => std::mem::size_of::<BlockIQ2XXS>(),
+            Self::IQ2XS => std::mem::size_of::<BlockIQ2XS>(),
+            Self::IQ3XXS => std::mem::size_of::<BlockIQ3XXS>(),
+            Self::IQ3S => std::mem::size_of::<BlockIQ3S>(),
+            Self::IQ4NL => std::mem::size_of::<BlockIQ4NL>(),
+            Self::IQ4XS => std::mem::size_of::<BlockIQ4XS>(),
+            Self::TQ1_0 => std::mem::size_of::<BlockTQ1_0>(),
+            Self::TQ2_0 => std::mem::size_of::<BlockTQ2_0>(),
+        }
+    }
+
+    /// Whether tensors can be quantized to this dtype, the i-quants that rely on a lattice grid
+    /// can only be dequantized.
+    pub fn supports_quantization(&self) -> bool {
+        !matches!(self, Self::IQ2XXS | Self::IQ2XS | Self::IQ3XXS | Self::IQ3S)
+    }
+
+    /// Whether the cuda and metal backends have some quantized matmul kernels for this dtype,
+    /// tensors using other dtypes get dequantized when used on these devices.
+    pub fn has_gpu_kernels(&self) -> bool {
+        !matches!(
+            self,
+            Self::IQ2XXS
+                | Self::IQ2XS
+                | Self::IQ3XXS
+                | Self::IQ3S
+                | Self::IQ4NL
+                | Self::IQ4XS
+                | Self::TQ1_0
+                | Self::TQ2_0
+        )
     }
 
     /// The block size, i.e. the number of elements stored in each block.
@@ -388,7 +496,20 @@ impl GgmlDType {
             Self::Q5_1 => k_quants::QK5_1,
             Self::Q8_0 => k_quants::QK8_0,
             Self::Q8_1 => k_quants::QK8_1,
-            Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => k_quants::QK_K,
+            Self::IQ4NL => k_quants::QK4_NL,
+            Self::Q2K
+            | Self::Q3K
+            | Self::Q4K
+            | Self::Q5K
+            | Self::Q6K
+            | Self::Q8K
+            | Self::IQ2XXS
+            | Self::IQ2XS
+            | Self::IQ3XXS
+            | Self::IQ3S
+            | Self::IQ4XS
+            | Self::TQ1_0
+            | Self::TQ2_0 => k_quants::QK_K,
         }
     }
 }
@@ -472,6 +593,13 @@ fn check_shape(shape: &Shape, block_size: usize) -> Result<()> {
     Ok(())
 }
 
+fn check_quantization(dtype: GgmlDType) -> Result<()> {
+    if !dtype.supports_quantization() {
+        crate::bail!("quantization to {dtype:?} is not supported")
+    }
+    Ok(())
+}
+
 impl QTensor {
     pub fn new<S: Into<Shape>>(storage: QStorage, shape: S) -> Result<Self> {
         let shape = shape.into();
@@ -483,6 +611,7 @@ impl QTensor {
         let shape = src.shape();
         let block_size = dtype.block_size();
         check_shape(shape, block_size)?;
+        check_quantization(dtype)?;
         let src = src.to_dtype(crate::DType::F32)?.flatten_all()?;
         let elem_count = shape.elem_count();
         if !elem_count.is_multiple_of(block_size) {
@@ -518,6 +647,7 @@ impl QTensor {
         let shape = src.shape();
         let block_size = dtype.block_size();
         check_shape(shape, block_size)?;
+        check_quantization(dtype)?;
         let src = src.to_dtype(crate::DType::F32)?.flatten_all()?;
         let elem_count = shape.elem_count();
         if !elem_count.is_multiple_of(block_size) {
@@ -560,6 +690,7 @@ impl QTensor {
         let shape = src.shape();
         let block_size = dtype.block_size();
         check_shape(shape, block_size)?;
+        check_quantization(dtype)?;
         let src = src.to_dtype(crate::DType::F32)?.flatten_all()?;
         let elem_count = shape.elem_count();
         if !elem_count.is_multiple_of(block_size) {
@@ -588,6 +719,7 @@ impl QTensor {
         let shape = src.shape();
         let block_size = dtype.block_size();
         check_shape(shape, block_size)?;
+        check_quantization(dtype)?;
         let src = src.to_dtype(crate::DType::F32)?.flatten_all()?;
         let elem_count = shape.elem_count();
         if !elem_count.is_multiple_of(block_size) {
@@ -723,6 +855,7 @@ impl QMatMul {
     pub fn from_arc(qtensor: std::sync::Arc<QTensor>) -> Result<Self> {
         let dequantize = match qtensor.dtype() {
             GgmlDType::F32 | GgmlDType::F16 | GgmlDType::BF16 => true,
+            dtype if !dtype.has_gpu_kernels() && !qtensor.device().is_cpu() => true,
             _ => DEQUANTIZE_ALL.with(|b| *b),
         };
         let t = if dequantize {
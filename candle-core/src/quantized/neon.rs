@@ -1,5 +1,7 @@
+use super::iq_grids::KVALUES_IQ4NL;
 use super::k_quants::{
-    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ5K, BlockQ6K, BlockQ8K, BlockQ8_0, QK8_0, QK_K,
+    BlockIQ4NL, BlockIQ4XS, BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ5K, BlockQ6K, BlockQ8K,
+    BlockQ8_0, BlockTQ2_0, QK4_NL, QK8_0, QK_K,
 };
 use byteorder::{ByteOrder, LittleEndian};
 
@@ -613,3 +615,85 @@ unsafe fn multiply_accum_with_scale(
     let p2 = vdotq_s32(q2bytes.1, q8bytes.1);
     vaddvq_s32(p1) * aux[is + index] as i32 + vaddvq_s32(p2) * aux[is + 1 + index] as i32
 }
+
+#[inline(always)]
+pub(crate) fn vec_dot_iq4nl_q8_0(n: usize, xs: &[BlockIQ4NL], ys: &[BlockQ8_0]) -> f32 {
+    debug_assert!(
+        n.is_multiple_of(QK4_NL),
+        "vec_dot_iq4nl_q8_0: {n} is not divisible by {QK4_NL}"
+    );
+    unsafe {
+        let values = vld1q_s8(KVALUES_IQ4NL.as_ptr());
+        let m4b = vdupq_n_u8(0x0F);
+        let mut sumv = vdupq_n_f32(0.0f32);
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let q4bits = vld1q_u8(x.qs.as_ptr());
+            let q4l = vqtbl1q_s8(values, vandq_u8(q4bits, m4b));
+            let q4h = vqtbl1q_s8(values, vshrq_n_u8(q4bits, 4));
+            let q8l = vld1q_s8(y.qs.as_ptr());
+            let q8h = vld1q_s8(y.qs.as_ptr().add(16));
+            let p = vaddq_s32(vdotq_s32(q4l, q8l), vdotq_s32(q4h, q8h));
+            sumv = vmlaq_n_f32(sumv, vcvtq_f32_s32(p), x.d.to_f32() * y.d.to_f32());
+        }
+        vaddvq_f32(sumv)
+    }
+}
+
+#[inline(always)]
+pub(crate) fn vec_dot_iq4xs_q8k(n: usize, xs: &[BlockIQ4XS], ys: &[BlockQ8K]) -> f32 {
+    debug_assert!(
+        n.is_multiple_of(QK_K),
+        "vec_dot_iq4xs_q8k: {n} is not divisible by {QK_K}"
+    );
+    let mut sumf = 0f32;
+    unsafe {
+        let values = vld1q_s8(KVALUES_IQ4NL.as_ptr());
+        let m4b = vdupq_n_u8(0x0F);
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut sumi = 0i32;
+            for ib in 0..QK_K / 32 {
+                let q4bits = vld1q_u8(x.qs.as_ptr().add(16 * ib));
+                let q4l = vqtbl1q_s8(values, vandq_u8(q4bits, m4b));
+                let q4h = vqtbl1q_s8(values, vshrq_n_u8(q4bits, 4));
+                let q8l = vld1q_s8(y.qs.as_ptr().add(32 * ib));
+                let q8h = vld1q_s8(y.qs.as_ptr().add(32 * ib + 16));
+                let p = vaddq_s32(vdotq_s32(q4l, q8l), vdotq_s32(q4h, q8h));
+                sumi += vaddvq_s32(p) * (x.scale(ib) - 32);
+            }
+            sumf += x.d.to_f32() * y.d * sumi as f32
+        }
+    }
+    sumf
+}
+
+#[inline(always)]
+pub(crate) fn vec_dot_tq2_0_q8k(n: usize, xs: &[BlockTQ2_0], ys: &[BlockQ8K]) -> f32 {
+    debug_assert!(
+        n.is_multiple_of(QK_K),
+        "vec_dot_tq2_0_q8k: {n} is not divisible by {QK_K}"
+    );
+    let mut sumf = 0f32;
+    unsafe {
+        let m3 = vdupq_n_u8(3);
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            // The values are stored as 0, 1, 2, the sum of the q8 values is subtracted at the end
+            // to account for the offset.
+            let mut sumi = vdupq_n_s32(0);
+            for j in (0..QK_K / 4).step_by(16) {
+                let qx = vld1q_u8(x.qs.as_ptr().add(j));
+                let qy = y.qs.as_ptr().add(4 * (j / 32) * 32 + j % 32);
+                let qx0 = vreinterpretq_s8_u8(vandq_u8(qx, m3));
+                let qx1 = vreinterpretq_s8_u8(vandq_u8(vshrq_n_u8(qx, 2), m3));
+                let qx2 = vreinterpretq_s8_u8(vandq_u8(vshrq_n_u8(qx, 4), m3));
+                let qx3 = vreinterpretq_s8_u8(vshrq_n_u8(qx, 6));
+                sumi = vaddq_s32(sumi, vdotq_s32(qx0, vld1q_s8(qy)));
+                sumi = vaddq_s32(sumi, vdotq_s32(qx1, vld1q_s8(qy.add(32))));
+                sumi = vaddq_s32(sumi, vdotq_s32(qx2, vld1q_s8(qy.add(64))));
+                sumi = vaddq_s32(sumi, vdotq_s32(qx3, vld1q_s8(qy.add(96))));
+            }
+            let ysum = y.bsums.iter().map(|&b| b as i32).sum::<i32>();
+            sumf += x.d.to_f32() * y.d * (vaddvq_s32(sumi) - ysum) as f32
+        }
+    }
+    sumf
+}
@@ -588,9 +588,10 @@ impl Tensor {
     }
 
     /// Returns true if the computation graph should track this op, that is if it is
-    /// a variable or if it has some variable as dependencies.
+    /// a variable or if it has some variable as dependencies. Nothing is tracked within
+    /// [`crate::backprop::no_grad`].
     pub fn track_op(&self) -> bool {
-        self.is_variable || self.op.is_some()
+        (self.is_variable || self.op.is_some()) && crate::backprop::is_grad_enabled()
     }
 
     /// Creates a fresh tensor structure based on a storage and a shape.
@@ -1253,6 +1254,8 @@ impl Tensor {
             target_h,
             target_w,
             align_corners,
+            scale_h: None,
+            scale_w: None,
         });
         // Pass None for scale factors (size mode)
         let storage = self.storage().upsample_bilinear2d(
@@ -1311,6 +1314,8 @@ impl Tensor {
             target_h: height_out,
             target_w: width_out,
             align_corners,
+            scale_h: Some(scale_h),
+            scale_w: Some(scale_w),
         });
 
         // Pass original scale factors (scale_factor mode)
@@ -1407,6 +1412,94 @@ impl Tensor {
         Ok(from_storage(storage, (n, c, h_out, w_out), op, false))
     }
 
+    /// 3D average pooling over an input tensor with multiple channels.
+    ///
+    /// The input tensor should have five dimensions, `(batch, channels, d, h, w)`, the returned
+    /// tensor also has five dimensions, `(batch, channels, d', h', w')`. The pooling is performed
+    /// on the three last dimensions using a kernel of size `sz`. The returned element is the
+    /// average value over the kernel window.
+    pub fn avg_pool3d<T: crate::ToUsize3>(&self, sz: T) -> Result<Self> {
+        let sz = sz.to_usize3();
+        self.avg_pool3d_with_stride(sz, sz)
+    }
+
+    /// Same as `avg_pool3d` but with a `stride` that can be set to a value different from the
+    /// kernel size.
+    pub fn avg_pool3d_with_stride<T: crate::ToUsize3>(
+        &self,
+        kernel_size: T,
+        stride: T,
+    ) -> Result<Self> {
+        let kernel_size = kernel_size.to_usize3();
+        let stride = stride.to_usize3();
+        let (n, c, d, h, w) = self.dims5()?;
+        if d < kernel_size.0 || h < kernel_size.1 || w < kernel_size.2 {
+            bail!("kernel-size {kernel_size:?} is larger than the input size {d},{h},{w}")
+        }
+        // https://pytorch.org/docs/stable/generated/torch.nn.AvgPool3d.html
+        let d_out = (d - kernel_size.0) / stride.0 + 1;
+        let h_out = (h - kernel_size.1) / stride.1 + 1;
+        let w_out = (w - kernel_size.2) / stride.2 + 1;
+        let op = BackpropOp::new1(self, |arg| Op::AvgPool3D {
+            arg,
+            kernel_size,
+            stride,
+        });
+        let storage = self
+            .storage()
+            .avg_pool3d(self.layout(), kernel_size, stride)?;
+        Ok(from_storage(
+            storage,
+            (n, c, d_out, h_out, w_out),
+            op,
+            false,
+        ))
+    }
+
+    /// 3D max pooling over an input tensor with multiple channels.
+    ///
+    /// The input tensor should have five dimensions, `(batch, channels, d, h, w)`, the returned
+    /// tensor also has five dimensions, `(batch, channels, d', h', w')`. The pooling is performed
+    /// on the three last dimensions using a kernel of size `sz`, the returned element is the
+    /// maximum value over the kernel window.
+    pub fn max_pool3d<T: crate::ToUsize3>(&self, sz: T) -> Result<Self> {
+        let sz = sz.to_usize3();
+        self.max_pool3d_with_stride(sz, sz)
+    }
+
+    /// Same as `max_pool3d` but with a `stride` that can be set to a value different from the
+    /// kernel size.
+    pub fn max_pool3d_with_stride<T: crate::ToUsize3>(
+        &self,
+        kernel_size: T,
+        stride: T,
+    ) -> Result<Self> {
+        let kernel_size = kernel_size.to_usize3();
+        let stride = stride.to_usize3();
+        let (n, c, d, h, w) = self.dims5()?;
+        if d < kernel_size.0 || h < kernel_size.1 || w < kernel_size.2 {
+            bail!("kernel-size {kernel_size:?} is larger than the input size {d},{h},{w}")
+        }
+        // https://pytorch.org/docs/stable/generated/torch.nn.MaxPool3d.html
+        let d_out = (d - kernel_size.0) / stride.0 + 1;
+        let h_out = (h - kernel_size.1) / stride.1 + 1;
+        let w_out = (w - kernel_size.2) / stride.2 + 1;
+        let op = BackpropOp::new1(self, |arg| Op::MaxPool3D {
+            arg,
+            kernel_size,
+            stride,
+        });
+        let storage = self
+            .storage()
+            .max_pool3d(self.layout(), kernel_size, stride)?;
+        Ok(from_storage(
+            storage,
+            (n, c, d_out, h_out, w_out),
+            op,
+            false,
+        ))
+    }
+
     /// Computes the dot product of two 1D tensors.
     ///
     /// - If inputs are 1D vectors (`[n]`), returns their scalar dot product.
@@ -2364,6 +2457,42 @@ impl Tensor {
         }
     }
 
+    /// Applies `module` to this tensor without keeping the intermediate values of the forward
+    /// pass, these are recomputed when backpropagating through the result. This trades some
+    /// compute for a lower memory usage when training.
+    ///
+    /// Nothing is recorded when neither this tensor nor the module variables require tracking,
+    /// e.g. at inference. The recomputed forward pass draws new random values so modules using
+    /// some randomness like dropout are not supported in a checkpointed segment.
+    pub fn checkpoint<M: 'static + crate::Module + Send + Sync>(&self, module: M) -> Result<Self> {
+        self.checkpoint_arc(Arc::new(Box::new(module)))
+    }
+
+    /// Same as `checkpoint` for a module that is already shared.
+    pub fn checkpoint_arc(
+        &self,
+        module: Arc<Box<dyn crate::Module + Send + Sync>>,
+    ) -> Result<Self> {
+        // The forward pass is run on a detached input so that the output is only tracked when
+        // the module uses some variables. The graph of the segment is dropped when returning so
+        // its intermediate values are not kept alive.
+        let ys = module.forward(&self.detach())?;
+        let op = BackpropOp::checkpoint(self, ys.track_op(), module);
+        if op.is_none() {
+            return Ok(ys.detach());
+        }
+        let tensor_ = Tensor_ {
+            id: TensorId::new(),
+            storage: ys.storage.clone(),
+            layout: ys.layout.clone(),
+            op,
+            is_variable: false,
+            dtype: ys.dtype,
+            device: ys.device.clone(),
+        };
+        Ok(Tensor(Arc::new(tensor_)))
+    }
+
     /// If the target device is the same as the tensor device, only a shallow copy is performed.
     pub fn to_device(&self, device: &Device) -> Result<Tensor> {
         if self.device().same_device(device) {
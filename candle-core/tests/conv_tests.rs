@@ -860,6 +860,113 @@ fn conv2d_grad(dev: &Device) -> Result<()> {
     Ok(())
 }
 
+// 3D convolutions are only available on the cpu for now, with a kernel of depth 1 they match the
+// 2D convolutions applied to each depth slice.
+#[test]
+fn conv3d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 2. * 3. * 4. * 5., dev)?
+        .affine(0.1, -2.)?
+        .sin()?
+        .reshape((2, 3, 1, 4, 5))?;
+    let w = Tensor::arange(0f32, 2. * 3. * 3. * 3., dev)?
+        .cos()?
+        .reshape((2, 3, 1, 3, 3))?;
+    let res = t.conv3d(&w, 0, 1, 1, 1)?;
+    assert_eq!(res.dims(), [2, 2, 1, 2, 3]);
+    let expected = t.squeeze(2)?.conv2d(&w.squeeze(2)?, 0, 1, 1, 1)?;
+    assert_eq!(
+        test_utils::to_vec3_round(&res.squeeze(2)?.flatten_from(2)?, 4)?,
+        test_utils::to_vec3_round(&expected.flatten_from(2)?, 4)?
+    );
+
+    // The depth dimension gets padded too, the padded slices only see zeros.
+    let res = t.conv3d(&w, 1, 1, 1, 1)?;
+    assert_eq!(res.dims(), [2, 2, 3, 4, 5]);
+    let expected = t.squeeze(2)?.conv2d(&w.squeeze(2)?, 1, 1, 1, 1)?;
+    for (d_idx, expected) in [
+        expected.zeros_like()?,
+        expected.clone(),
+        expected.zeros_like()?,
+    ]
+    .iter()
+    .enumerate()
+    {
+        assert_eq!(
+            test_utils::to_vec3_round(&res.i((.., .., d_idx))?.flatten_from(2)?, 4)?,
+            test_utils::to_vec3_round(&expected.flatten_from(2)?, 4)?
+        );
+    }
+
+    // With identical depth slices, the result is the 2D convolution scaled by the kernel depth.
+    let t = t.repeat((1, 1, 3, 1, 1))?;
+    let w = w.repeat((1, 1, 2, 1, 1))?;
+    let res = t.conv3d(&w, 0, 1, 1, 1)?;
+    assert_eq!(res.dims(), [2, 2, 2, 2, 3]);
+    let expected = (t.i((.., .., 0))?.conv2d(&w.i((.., .., 0))?, 0, 1, 1, 1)? * 2.)?;
+    assert_eq!(
+        test_utils::to_vec3_round(&res.i((.., .., 1))?.flatten_from(2)?, 4)?,
+        test_utils::to_vec3_round(&expected.flatten_from(2)?, 4)?
+    );
+
+    // Grouped convolutions.
+    let t = Tensor::arange(0f32, 4. * 2. * 3. * 3., dev)?
+        .sin()?
+        .reshape((1, 4, 2, 3, 3))?;
+    let w = Tensor::arange(0f32, 2. * 2. * 8., dev)?
+        .cos()?
+        .reshape((2, 2, 2, 2, 2))?;
+    let res = t.conv3d(&w, 0, 1, 1, 2)?;
+    let expected = Tensor::cat(
+        &[
+            t.narrow(1, 0, 2)?.conv3d(&w.narrow(0, 0, 1)?, 0, 1, 1, 1)?,
+            t.narrow(1, 2, 2)?.conv3d(&w.narrow(0, 1, 1)?, 0, 1, 1, 1)?,
+        ],
+        1,
+    )?;
+    assert_eq!(
+        test_utils::to_vec3_round(&res.flatten_from(2)?, 4)?,
+        test_utils::to_vec3_round(&expected.flatten_from(2)?, 4)?
+    );
+    Ok(())
+}
+
+#[test]
+fn conv_transpose3d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 3. * 4. * 5., dev)?
+        .affine(0.1, -2.)?
+        .sin()?
+        .reshape((1, 3, 1, 4, 5))?;
+    let w = Tensor::arange(0f32, 3. * 2. * 3. * 3., dev)?
+        .cos()?
+        .reshape((3, 2, 1, 3, 3))?;
+    for (output_padding, stride, dilation) in [(0, 1, 1), (1, 2, 1), (0, 1, 2)] {
+        let res = t.conv_transpose3d(&w, 0, output_padding, stride, dilation, 1)?;
+        let expected =
+            t.squeeze(2)?
+                .conv_transpose2d(&w.squeeze(2)?, 0, output_padding, stride, dilation)?;
+        assert_eq!(res.dim(2)?, 1 + output_padding);
+        assert_eq!(
+            test_utils::to_vec3_round(&res.i((.., .., 0))?.flatten_from(2)?, 4)?,
+            test_utils::to_vec3_round(&expected.flatten_from(2)?, 4)?
+        );
+    }
+
+    // The padding removes elements on both sides of each spatial dimension.
+    let t = t.repeat((1, 1, 3, 1, 1))?;
+    let w = w.repeat((1, 1, 2, 1, 1))?;
+    let res = t.conv_transpose3d(&w, 0, 0, 2, 1, 1)?;
+    assert_eq!(res.dims(), [1, 2, 6, 9, 11]);
+    let expected = res.narrow(2, 1, 4)?.narrow(3, 1, 7)?.narrow(4, 1, 9)?;
+    let res = t.conv_transpose3d(&w, 1, 0, 2, 1, 1)?;
+    assert_eq!(
+        test_utils::to_vec3_round(&res.flatten_from(2)?, 4)?,
+        test_utils::to_vec3_round(&expected.flatten_from(2)?, 4)?
+    );
+    Ok(())
+}
+
 test_device!(conv1d, conv1d_cpu, conv1d_gpu, conv1d_metal);
 test_device!(
     conv1d_small,
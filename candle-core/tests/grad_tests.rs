@@ -535,6 +535,193 @@ fn test_flip_backprop() -> Result<()> {
     Ok(())
 }
 
+// Deterministic input with distinct values, so that max-pooling has no ties.
+fn fd_input<S: Into<Shape>>(shape: S) -> Result<Tensor> {
+    let shape = shape.into();
+    let data = (0..shape.elem_count())
+        .map(|i| ((i * 37) % 101) as f64 / 10. - 5.)
+        .collect::<Vec<_>>();
+    Ok(Tensor::from_vec(data, shape, &Device::Cpu)?)
+}
+
+// Compares the gradient computed by backprop for `sum(f(x) * w)` with a finite difference
+// estimate, `w` being fixed weights so that each output element contributes differently.
+fn check_grad_fd<F: Fn(&Tensor) -> candle_core::Result<Tensor>>(x: &Tensor, f: F) -> Result<()> {
+    let x_var = Var::from_tensor(x)?;
+    let ys = f(x_var.as_tensor())?;
+    let w = Tensor::arange(0f64, ys.elem_count() as f64, x.device())?
+        .affine(0.37, 0.1)?
+        .sin()?
+        .reshape(ys.shape())?;
+    let loss = ys.mul(&w)?.sum_all()?;
+    let grads = loss.backward()?;
+    let grad = grads.get(&x_var).context("no grad for x")?;
+    assert_eq!(grad.dims(), x.dims());
+    let grad = grad.flatten_all()?.to_vec1::<f64>()?;
+    let xs = x.flatten_all()?.to_vec1::<f64>()?;
+    let eps = 1e-4;
+    let eval = |xs: Vec<f64>| -> Result<f64> {
+        let x = Tensor::from_vec(xs, x.shape(), x.device())?;
+        Ok(f(&x)?.mul(&w)?.sum_all()?.to_scalar::<f64>()?)
+    };
+    for (i, grad) in grad.iter().enumerate() {
+        let mut xs_plus = xs.clone();
+        xs_plus[i] += eps;
+        let mut xs_minus = xs.clone();
+        xs_minus[i] -= eps;
+        let fd = (eval(xs_plus)? - eval(xs_minus)?) / (2. * eps);
+        assert!(
+            (fd - grad).abs() < 1e-6,
+            "index {i}: fd {fd} backprop {grad}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn conv_transpose1d_grad_fd() -> Result<()> {
+    let x = fd_input((2, 4, 5))?;
+    let kernel = (fd_input((4, 3, 3))? * 0.1)?;
+    // (padding, output_padding, stride, dilation)
+    for (p, op, s, d) in [(1, 1, 2, 2), (0, 0, 1, 1), (2, 1, 3, 1)] {
+        check_grad_fd(&x, |x| x.conv_transpose1d(&kernel, p, op, s, d, 1))?;
+        check_grad_fd(&kernel, |k| x.conv_transpose1d(k, p, op, s, d, 1))?;
+    }
+    let kernel = (fd_input((4, 2, 3))? * 0.1)?;
+    check_grad_fd(&x, |x| x.conv_transpose1d(&kernel, 1, 0, 2, 1, 2))?;
+    check_grad_fd(&kernel, |k| x.conv_transpose1d(k, 1, 0, 2, 1, 2))?;
+    Ok(())
+}
+
+#[test]
+fn pool2d_grad_fd() -> Result<()> {
+    let x = fd_input((1, 2, 7, 8))?;
+    for (kernel_size, stride) in [((3, 2), (2, 3)), ((3, 3), (1, 1)), ((2, 2), (2, 2))] {
+        check_grad_fd(&x, |x| x.avg_pool2d_with_stride(kernel_size, stride))?;
+        check_grad_fd(&x, |x| x.max_pool2d_with_stride(kernel_size, stride))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn conv3d_grad_fd() -> Result<()> {
+    let x = fd_input((1, 2, 3, 4, 5))?;
+    let kernel = (fd_input((3, 2, 2, 2, 3))? * 0.1)?;
+    // (padding, stride, dilation)
+    for (p, s, d) in [(0, 1, 1), (1, 2, 1), (1, 1, 2)] {
+        check_grad_fd(&x, |x| x.conv3d(&kernel, p, s, d, 1))?;
+        check_grad_fd(&kernel, |k| x.conv3d(k, p, s, d, 1))?;
+    }
+    let kernel = (fd_input((2, 3, 2, 2, 2))? * 0.1)?;
+    // (padding, output_padding, stride, dilation)
+    for (p, op, s, d) in [(0, 0, 1, 1), (1, 1, 2, 1), (1, 0, 1, 2)] {
+        check_grad_fd(&x, |x| x.conv_transpose3d(&kernel, p, op, s, d, 1))?;
+        check_grad_fd(&kernel, |k| x.conv_transpose3d(k, p, op, s, d, 1))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn pool3d_grad_fd() -> Result<()> {
+    let x = fd_input((1, 1, 4, 5, 5))?;
+    for (kernel_size, stride) in [((2, 3, 2), (2, 1, 3)), ((2, 2, 2), (2, 2, 2))] {
+        check_grad_fd(&x, |x| x.avg_pool3d_with_stride(kernel_size, stride))?;
+        check_grad_fd(&x, |x| x.max_pool3d_with_stride(kernel_size, stride))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn upsample_grad_fd() -> Result<()> {
+    let x = fd_input((1, 2, 5))?;
+    for target_size in [7, 3, 10] {
+        check_grad_fd(&x, |x| x.interpolate1d(target_size))?;
+    }
+    let x = fd_input((1, 2, 3, 4))?;
+    for (target_h, target_w) in [(5, 8), (6, 12), (2, 3), (6, 8)] {
+        check_grad_fd(&x, |x| x.interpolate2d(target_h, target_w))?;
+    }
+    for align_corners in [false, true] {
+        for (target_h, target_w) in [(5, 7), (6, 8), (2, 3)] {
+            check_grad_fd(&x, |x| {
+                x.upsample_bilinear2d(target_h, target_w, align_corners)
+            })?;
+        }
+        check_grad_fd(&x, |x| {
+            x.upsample_bilinear2d_with_scale(1.7, 2.3, align_corners)
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn second_order_grad() -> Result<()> {
+    let x = Var::new(&[1f32, 2., 3.], &Device::Cpu)?;
+    let y = x.mul(&x)?.mul(&x)?.sum_all()?;
+    let grads = y.backward_create_graph()?;
+    let dy_dx = grads.get(&x).context("no grad for x")?;
+    assert_eq!(dy_dx.to_vec1::<f32>()?, [3., 12., 27.]);
+    let grads = dy_dx.sum_all()?.backward()?;
+    let d2y_dx2 = grads.get(&x).context("no grad for x")?;
+    assert_eq!(d2y_dx2.to_vec1::<f32>()?, [6., 12., 18.]);
+    Ok(())
+}
+
+#[test]
+fn gradient_penalty() -> Result<()> {
+    // d = sum(w * x^2), the penalty is |dd/dx|^2 = sum(4 w^2 x^2) with gradient 8 w x^2 for w.
+    let w = Var::new(&[0.5f32, -1.], &Device::Cpu)?;
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let d = w.mul(&x)?.mul(&x)?.sum_all()?;
+    let grads = d.backward_create_graph()?;
+    let dd_dx = grads.get(&x).context("no grad for x")?;
+    let penalty = dd_dx.sqr()?.sum_all()?;
+    assert_eq!(penalty.to_vec0::<f32>()?, 17.);
+    let grads = penalty.backward()?;
+    let grad_w = grads.get(&w).context("no grad for w")?;
+    assert_eq!(grad_w.to_vec1::<f32>()?, [4., -32.]);
+    Ok(())
+}
+
+#[test]
+fn functional_grads() -> Result<()> {
+    use candle_core::backprop::{grad, hvp, jvp, vjp};
+    let a = Tensor::new(&[1f32, 2.], &Device::Cpu)?;
+    let b = Tensor::new(&[3f32, -1.], &Device::Cpu)?;
+    let f = |xs: &[Tensor]| xs[0].sqr()?.mul(&xs[1])?.sum_all();
+    let (y, grads) = grad(f, &[a.clone(), b.clone()], false)?;
+    assert_eq!(y.to_vec0::<f32>()?, -1.);
+    assert_eq!(grads[0].to_vec1::<f32>()?, [6., -4.]);
+    assert_eq!(grads[1].to_vec1::<f32>()?, [1., 4.]);
+    // Inputs that do not contribute to the output get a zero gradient.
+    let (_, grads) = grad(|xs| xs[0].sum_all(), &[a.clone(), b.clone()], false)?;
+    assert_eq!(grads[1].to_vec1::<f32>()?, [0., 0.]);
+    assert!(grad(|xs| xs[0].sqr(), &[a.clone()], false).is_err());
+
+    // f(x) = x^2 has a diagonal jacobian 2x.
+    let v = Tensor::new(&[1f32, -2.], &Device::Cpu)?;
+    let (y, grads) = vjp(|xs| xs[0].sqr(), &[a.clone()], &v)?;
+    assert_eq!(y.to_vec1::<f32>()?, [1., 4.]);
+    assert_eq!(grads[0].to_vec1::<f32>()?, [2., -8.]);
+    let (y, jv) = jvp(|xs| xs[0].sqr(), &[a.clone()], &[v.clone()])?;
+    assert_eq!(y.to_vec1::<f32>()?, [1., 4.]);
+    assert_eq!(jv.to_vec1::<f32>()?, [2., -8.]);
+    // g(a, b) = a * b has jacobian [diag(b), diag(a)].
+    let (_, jv) = jvp(
+        |xs| xs[0].mul(&xs[1]),
+        &[a.clone(), b.clone()],
+        &[v.clone(), v.clone()],
+    )?;
+    assert_eq!(jv.to_vec1::<f32>()?, [4., -2.]);
+
+    // The hessian of sum(a^2 * b) is [[diag(2b), diag(2a)], [diag(2a), 0]].
+    let (y, hv) = hvp(f, &[a.clone(), b.clone()], &[v.clone(), v.clone()])?;
+    assert_eq!(y.to_vec0::<f32>()?, -1.);
+    assert_eq!(hv[0].to_vec1::<f32>()?, [8., -4.]);
+    assert_eq!(hv[1].to_vec1::<f32>()?, [2., -8.]);
+    Ok(())
+}
+
 test_device!(
     simple_grad,
     simple_grad_cpu,
@@ -101,6 +101,27 @@ fn upsample_nearest2d(dev: &Device) -> Result<()> {
     Ok(())
 }
 
+// 3D pooling is only available on the cpu for now.
+#[test]
+fn pool3d() -> Result<()> {
+    let t = Tensor::arange(0f32, 32., &Device::Cpu)?.reshape((1, 1, 2, 4, 4))?;
+    let pool = t.avg_pool3d(2)?.flatten_all()?;
+    assert_eq!(pool.to_vec1::<f32>()?, [10.5, 12.5, 18.5, 20.5]);
+    let pool = t.max_pool3d(2)?.flatten_all()?;
+    assert_eq!(pool.to_vec1::<f32>()?, [21., 23., 29., 31.]);
+
+    let pool = t.avg_pool3d_with_stride((1, 2, 3), (1, 2, 1))?;
+    assert_eq!(pool.dims(), [1, 1, 2, 2, 2]);
+    assert_eq!(
+        pool.flatten_all()?.to_vec1::<f32>()?,
+        [3., 4., 11., 12., 19., 20., 27., 28.]
+    );
+    let pool = t.max_pool3d_with_stride((2, 3, 2), (1, 1, 2))?;
+    assert_eq!(pool.dims(), [1, 1, 1, 2, 2]);
+    assert_eq!(pool.flatten_all()?.to_vec1::<f32>()?, [25., 27., 29., 31.]);
+    Ok(())
+}
+
 test_device!(avg_pool2d, avg_pool2d_cpu, avg_pool2d_gpu, avg_pool2d_metal);
 test_device!(
     avg_pool2d_pytorch,
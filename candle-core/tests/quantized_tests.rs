@@ -1036,6 +1036,7 @@ fn ggml_reference_matmul_error(dtype: GgmlDType) -> Result<f32> {
 
         // Not from the ggml repo.
         GgmlDType::Q8K => 0.00065,
+        _ => bail!("No GGML results for quantization type {dtype:?}"),
     };
     Ok(err)
 }
@@ -1400,3 +1401,199 @@ fn quantized_matmul_q8k() -> Result<()> {
     ggml_matmul_error_test::<BlockQ8K>()?;
     Ok(())
 }
+
+// Builds a tensor with random quants, the scale of each block is set to a fixed value so that the
+// random bytes cannot result in a non-finite f16.
+fn random_qtensor(dtype: GgmlDType, (n, k): (usize, usize)) -> Result<quantized::QTensor> {
+    let mut rng = StdRng::seed_from_u64(314159265358979);
+    let type_size = dtype.type_size();
+    let mut data = vec![0u8; n * k / dtype.block_size() * type_size];
+    rng.fill_bytes(&mut data);
+    let d_offset = match dtype {
+        GgmlDType::TQ1_0 | GgmlDType::TQ2_0 => type_size - 2,
+        _ => 0,
+    };
+    for block in data.chunks_exact_mut(type_size) {
+        block[d_offset..d_offset + 2].copy_from_slice(&half::f16::from_f32(0.01).to_le_bytes());
+    }
+    let storage = quantized::QStorage::from_data(data.into(), &Device::Cpu, dtype)?;
+    quantized::QTensor::new(storage, (n, k))
+}
+
+#[test]
+fn iq_dequantize() -> Result<()> {
+    let cpu = &Device::Cpu;
+    let one = half::f16::from_f32(1.0).to_le_bytes();
+    // A single block where all the quants are zero, i.e. the first grid values get used.
+    let dequantize = |dtype: GgmlDType, data: Vec<u8>| -> Result<Vec<f32>> {
+        let block_size = dtype.block_size();
+        let storage = quantized::QStorage::from_data(data.into(), cpu, dtype)?;
+        let qtensor = quantized::QTensor::new(storage, (block_size,))?;
+        qtensor.dequantize(cpu)?.to_vec1::<f32>()
+    };
+    for (dtype, expected) in [
+        (GgmlDType::IQ2XXS, 1.0),
+        (GgmlDType::IQ2XS, 1.0),
+        (GgmlDType::IQ3XXS, 1.0),
+        (GgmlDType::IQ3S, 1.0),
+        (GgmlDType::IQ4NL, -127.0),
+        (GgmlDType::IQ4XS, 4064.0),
+        (GgmlDType::TQ1_0, -1.0),
+        (GgmlDType::TQ2_0, -1.0),
+    ] {
+        let mut data = vec![0u8; dtype.type_size()];
+        let d_offset = match dtype {
+            GgmlDType::TQ1_0 | GgmlDType::TQ2_0 => data.len() - 2,
+            _ => 0,
+        };
+        data[d_offset..d_offset + 2].copy_from_slice(&one);
+        let values = dequantize(dtype, data)?;
+        assert_eq!(values, vec![expected; dtype.block_size()], "{dtype:?}");
+    }
+
+    // The sign bitmask 129 (index 1) flips the first and last values of the first group.
+    let mut data = vec![0u8; GgmlDType::IQ2XXS.type_size()];
+    data[..2].copy_from_slice(&one);
+    data[6] = 1;
+    let values = dequantize(GgmlDType::IQ2XXS, data)?;
+    assert_eq!(values[..9], [-1., 1., 1., 1., 1., 1., 1., -1., 1.]);
+    assert!(values[9..].iter().all(|&v| v == 1.));
+
+    // The iq4 grid is non-linear, the low nibbles are used for the first half of the block.
+    let mut data = vec![0u8; GgmlDType::IQ4NL.type_size()];
+    data[..2].copy_from_slice(&half::f16::from_f32(0.5).to_le_bytes());
+    for j in 0..16 {
+        data[2 + j] = j as u8 | ((15 - j as u8) << 4);
+    }
+    let values = dequantize(GgmlDType::IQ4NL, data)?;
+    assert_eq!(
+        values[..16],
+        [
+            -63.5, -52.0, -41.5, -32.5, -24.5, -17.5, -11.0, -5.0, 0.5, 6.5, 12.5, 19.0, 26.5,
+            34.5, 44.5, 56.5
+        ]
+    );
+    assert_eq!(values[16], 56.5);
+    assert_eq!(values[31], -63.5);
+    Ok(())
+}
+
+#[test]
+fn iq_quantize() -> Result<()> {
+    let cpu = &Device::Cpu;
+    // Ternary values are represented exactly.
+    let src = (0..1024)
+        .map(|i| ((i * 7) % 3) as f32 * 0.5 - 0.5)
+        .collect::<Vec<_>>();
+    let src = Tensor::from_slice(&src, (4, 256), cpu)?;
+    for dtype in [GgmlDType::TQ1_0, GgmlDType::TQ2_0] {
+        let quant = quantized::QTensor::quantize(&src, dtype)?;
+        let dst = quant.dequantize(cpu)?;
+        assert_eq!(dst.to_vec2::<f32>()?, src.to_vec2::<f32>()?, "{dtype:?}");
+    }
+
+    ggml_quantization_error_test(GgmlDType::IQ4NL, cpu, GGML_MAX_QUANTIZATION_TOTAL_ERROR)?;
+    ggml_quantization_error_test(GgmlDType::IQ4XS, cpu, GGML_MAX_QUANTIZATION_TOTAL_ERROR)?;
+
+    let src = Tensor::randn(0f32, 1f32, (4, 256), cpu)?;
+    let imatrix = (0..256).map(|i| 1. + (i % 7) as f32).collect::<Vec<_>>();
+    for dtype in [GgmlDType::IQ4NL, GgmlDType::IQ4XS] {
+        let quant = quantized::QTensor::quantize_imatrix(&src, &imatrix, dtype)?;
+        let dst = quant.dequantize(cpu)?;
+        let error = calculate_rmse(
+            &src.flatten_all()?.to_vec1::<f32>()?,
+            &dst.flatten_all()?.to_vec1::<f32>()?,
+        );
+        assert!(
+            error < GGML_MAX_QUANTIZATION_TOTAL_ERROR,
+            "{dtype:?} {error}"
+        );
+    }
+
+    // The types based on a lattice grid can only be dequantized.
+    assert!(quantized::QTensor::quantize(&src, GgmlDType::IQ2XXS).is_err());
+    assert!(quantized::QTensor::quantize(&src, GgmlDType::IQ3S).is_err());
+    Ok(())
+}
+
+#[test]
+fn iq_matmul() -> Result<()> {
+    let cpu = &Device::Cpu;
+    let (m, k, n) = (3, 512, 8);
+    let xs = Tensor::randn(0f32, 1f32, (m, k), cpu)?;
+    for dtype in [
+        GgmlDType::IQ2XXS,
+        GgmlDType::IQ2XS,
+        GgmlDType::IQ3XXS,
+        GgmlDType::IQ3S,
+        GgmlDType::IQ4NL,
+        GgmlDType::IQ4XS,
+        GgmlDType::TQ1_0,
+        GgmlDType::TQ2_0,
+    ] {
+        let ws = random_qtensor(dtype, (n, k))?;
+        // The lhs gets quantized to the vec-dot type before the dot products.
+        let vec_dot_dtype = match dtype {
+            GgmlDType::IQ4NL => GgmlDType::Q8_0,
+            _ => GgmlDType::Q8K,
+        };
+        let xs_q = quantized::QTensor::quantize(&xs, vec_dot_dtype)?.dequantize(cpu)?;
+        let expected = xs_q.matmul(&ws.dequantize(cpu)?.t()?)?;
+        let ys = quantized::QMatMul::from_qtensor(ws)?.forward(&xs)?;
+        let scale = expected.abs()?.max_all()?.to_vec0::<f32>()?;
+        let diff = (ys - &expected)?.abs()?.max_all()?.to_vec0::<f32>()?;
+        assert!(diff <= 1e-5 * scale, "{dtype:?} {diff} {scale}");
+    }
+    Ok(())
+}
+
+#[test]
+fn gguf_copy_data() -> Result<()> {
+    use quantized::gguf_file;
+    let cpu = &Device::Cpu;
+    let t1 = Tensor::arange(0f32, 256., cpu)?.reshape((2, 128))?;
+    let t1 = quantized::QTensor::quantize(&t1, GgmlDType::Q8_0)?;
+    let t2 = Tensor::new(&[1f32, 2., 3.], cpu)?;
+    let t2 = quantized::QTensor::quantize(&t2, GgmlDType::F32)?;
+    let alignment = gguf_file::Value::U32(64);
+    let mut file = std::io::Cursor::new(vec![]);
+    gguf_file::write(
+        &mut file,
+        &[("general.alignment", &alignment)],
+        &[("a", &t1), ("b", &t2)],
+    )?;
+    file.set_position(0);
+    let content = gguf_file::Content::read(&mut file)?;
+    assert_eq!(content.tensor_data_offset % 64, 0);
+    // The 272 bytes of the Q8_0 tensor are padded to the alignment.
+    assert_eq!(content.tensor_infos["b"].offset, 320);
+
+    // Copy the raw data to a new file with the tensors renamed and in a different order.
+    let infos = [&content.tensor_infos["b"], &content.tensor_infos["a"]];
+    let shapes = [
+        ("c", infos[0].ggml_dtype, &infos[0].shape),
+        ("d", infos[1].ggml_dtype, &infos[1].shape),
+    ];
+    let mut out = std::io::Cursor::new(vec![]);
+    gguf_file::write_with(&mut out, &[], &shapes, |idx, w| {
+        infos[idx].copy_data(&mut file, content.tensor_data_offset, w)
+    })?;
+    out.set_position(0);
+    let copy = gguf_file::Content::read(&mut out)?;
+    let c = copy.tensor(&mut out, "c", cpu)?.dequantize(cpu)?;
+    assert_eq!(c.to_vec1::<f32>()?, [1., 2., 3.]);
+    let d = copy.tensor(&mut out, "d", cpu)?;
+    assert_eq!(d.dtype(), GgmlDType::Q8_0);
+    assert_eq!(d.data()?, t1.data()?);
+
+    // A zero alignment would result in a division by zero when padding the tensor data.
+    let alignment = gguf_file::Value::U32(0);
+    let mut out = std::io::Cursor::new(vec![]);
+    let res = gguf_file::write(
+        &mut out,
+        &[("general.alignment", &alignment)],
+        &[("a", &t1)],
+    );
+    assert!(res.is_err());
+    Ok(())
+}
@@ -0,0 +1,94 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use clap::Parser;
+use tokenizers::Tokenizer;
+
+use candle::quantized::gguf_file;
+use candle::Tensor;
+use candle_transformers::imatrix::Imatrix;
+use candle_transformers::models::quantized_llama::ModelWeights;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// GGUF file to load, the model has to use the llama architecture.
+    #[arg(long)]
+    model: String,
+
+    /// The tokenizer config in json format.
+    #[arg(long)]
+    tokenizer: String,
+
+    /// The calibration text file.
+    #[arg(long)]
+    text: String,
+
+    /// The output file, in the llama.cpp imatrix format.
+    #[arg(long, default_value = "imatrix.dat")]
+    out_file: String,
+
+    /// The number of tokens in each chunk of the calibration text.
+    #[arg(long, default_value_t = 512)]
+    ctx_size: usize,
+
+    /// The maximum number of chunks to process, all the chunks are processed by default.
+    #[arg(long)]
+    chunks: Option<usize>,
+
+    /// Run on CPU rather than GPU even if a GPU is available.
+    #[arg(long)]
+    cpu: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+
+    let mut file = std::fs::File::open(&args.model)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&args.model))?;
+    let mut model = ModelWeights::from_gguf(content, &mut file, &device)?;
+    println!("model built");
+
+    let tokenizer = Tokenizer::from_file(&args.tokenizer).map_err(anyhow::Error::msg)?;
+    let text = std::fs::read_to_string(&args.text)?;
+    let tokens = tokenizer
+        .encode(text, true)
+        .map_err(anyhow::Error::msg)?
+        .get_ids()
+        .to_vec();
+    let n_chunks = tokens.len() / args.ctx_size;
+    let n_chunks = args.chunks.map_or(n_chunks, |c| usize::min(c, n_chunks));
+    if n_chunks == 0 {
+        anyhow::bail!(
+            "not enough tokens in {}, {} < {}",
+            args.text,
+            tokens.len(),
+            args.ctx_size
+        )
+    }
+    println!("{} tokens, processing {n_chunks} chunks", tokens.len());
+
+    let mut imatrix = Imatrix::new();
+    let start = std::time::Instant::now();
+    for (chunk_idx, chunk) in tokens
+        .chunks_exact(args.ctx_size)
+        .take(n_chunks)
+        .enumerate()
+    {
+        let input = Tensor::new(chunk, &device)?.unsqueeze(0)?;
+        // Using a position of 0 resets the kv cache so that each chunk is processed separately.
+        imatrix.collect(|| model.forward(&input, 0))?;
+        println!(
+            "chunk {}/{n_chunks} processed, {:.2}s",
+            chunk_idx + 1,
+            start.elapsed().as_secs_f32()
+        );
+    }
+    imatrix.save(&args.out_file, &args.text)?;
+    println!("{} entries written to {}", imatrix.len(), args.out_file);
+    Ok(())
+}
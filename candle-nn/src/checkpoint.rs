@@ -0,0 +1,41 @@
+//! Gradient checkpointing, a.k.a. activation recomputation.
+//!
+//! The backward pass usually requires all the intermediate values of the forward pass to be kept
+//! in memory. A checkpointed segment only keeps its input and runs its forward pass again when
+//! backpropagating, see [`candle::Tensor::checkpoint`].
+use candle::{Module, Result, Tensor};
+use std::sync::Arc;
+
+/// Applies `module` to `xs` as a checkpointed segment.
+///
+/// Modules are cheap to clone as their weights are shared, so a clone of a layer can be passed
+/// here, e.g. `checkpoint(block.clone(), &xs)`.
+pub fn checkpoint<M: 'static + Module + Send + Sync>(module: M, xs: &Tensor) -> Result<Tensor> {
+    xs.checkpoint(module)
+}
+
+/// A module whose forward pass is checkpointed, this can be used to wrap the blocks of a model.
+#[derive(Clone)]
+pub struct Checkpoint {
+    module: Arc<Box<dyn Module + Send + Sync>>,
+}
+
+impl std::fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "checkpoint")
+    }
+}
+
+impl Checkpoint {
+    pub fn new<M: 'static + Module + Send + Sync>(module: M) -> Self {
+        Self {
+            module: Arc::new(Box::new(module)),
+        }
+    }
+}
+
+impl Module for Checkpoint {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.checkpoint_arc(self.module.clone())
+    }
+}
@@ -0,0 +1,175 @@
+//! Utilities operating on the gradients returned by the backward pass.
+//!
+//! These functions take a [`GradStore`] together with the variables of interest, typically the
+//! variables that are passed to an optimizer, and can be used before calling
+//! [`crate::Optimizer::step`].
+use candle::backprop::GradStore;
+use candle::{DType, Result, Tensor, Var};
+
+fn sum_over_grads<F: Fn(&Tensor) -> Result<Tensor>>(
+    grads: &GradStore,
+    vars: &[Var],
+    f: F,
+) -> Result<f64> {
+    // The sums are computed in F32, or in F64 when some of the gradients use it so as not to lose
+    // precision.
+    let dtype = if vars
+        .iter()
+        .any(|var| grads.get(var).is_some_and(|g| g.dtype() == DType::F64))
+    {
+        DType::F64
+    } else {
+        DType::F32
+    };
+    let mut total: Option<Tensor> = None;
+    for var in vars.iter() {
+        if let Some(grad) = grads.get(var) {
+            let v = f(&grad.to_dtype(dtype)?)?.sum_all()?;
+            total = match total {
+                None => Some(v),
+                Some(total) => Some((total + v)?),
+            }
+        }
+    }
+    match total {
+        None => Ok(0.),
+        Some(total) => total.to_dtype(DType::F64)?.to_scalar::<f64>(),
+    }
+}
+
+/// Returns the L2 norm of all the gradients for `vars`, as if they were concatenated into a
+/// single vector.
+pub fn global_norm(grads: &GradStore, vars: &[Var]) -> Result<f64> {
+    let sum_sqr = sum_over_grads(grads, vars, |g| g.sqr())?;
+    Ok(sum_sqr.sqrt())
+}
+
+/// Returns true if any gradient for `vars` contains a NaN or an infinite value.
+pub fn has_non_finite(grads: &GradStore, vars: &[Var]) -> Result<bool> {
+    // Multiplying by zero maps finite values to zero and NaN/infinite values to NaN.
+    let sum = sum_over_grads(grads, vars, |g| g * 0.)?;
+    Ok(!sum.is_finite())
+}
+
+/// Rescales the gradients for `vars` in place so that their global L2 norm is at most
+/// `max_norm`. The global norm before clipping is returned.
+pub fn clip_grad_norm(grads: &mut GradStore, vars: &[Var], max_norm: f64) -> Result<f64> {
+    let total_norm = global_norm(grads, vars)?;
+    let clip_coef = max_norm / (total_norm + 1e-6);
+    if clip_coef < 1. {
+        scale_grads(grads, vars, clip_coef)?;
+    }
+    Ok(total_norm)
+}
+
+/// Clamps the gradients for `vars` in place to be between `-clip_value` and `clip_value`.
+pub fn clip_grad_value(grads: &mut GradStore, vars: &[Var], clip_value: f64) -> Result<()> {
+    for var in vars.iter() {
+        if let Some(grad) = grads.get(var) {
+            let grad = grad.clamp(-clip_value, clip_value)?;
+            grads.insert(var, grad);
+        }
+    }
+    Ok(())
+}
+
+/// Multiplies the gradients for `vars` in place by `scale`.
+pub fn scale_grads(grads: &mut GradStore, vars: &[Var], scale: f64) -> Result<()> {
+    for var in vars.iter() {
+        if let Some(grad) = grads.get(var) {
+            let grad = (grad * scale)?;
+            grads.insert(var, grad);
+        }
+    }
+    Ok(())
+}
+
+/// Adds the gradients for `vars` from `src` to the ones in `dst`. When a variable only has a
+/// gradient in `src`, this gradient is copied over to `dst`.
+pub fn add_grads(dst: &mut GradStore, src: &GradStore, vars: &[Var]) -> Result<()> {
+    for var in vars.iter() {
+        if let Some(src_grad) = src.get(var) {
+            let grad = match dst.get(var) {
+                None => src_grad.clone(),
+                Some(dst_grad) => (dst_grad + src_grad)?,
+            };
+            dst.insert(var, grad);
+        }
+    }
+    Ok(())
+}
+
+/// Accumulates gradients over multiple micro-batches before running an optimizer step.
+///
+/// ```ignore
+/// let mut acc = GradAccumulator::new(varmap.all_vars());
+/// for micro_batch in micro_batches {
+///     let loss = model.forward(&micro_batch)?;
+///     acc.accumulate(loss.backward()?)?;
+/// }
+/// if let Some(grads) = acc.finish(true)? {
+///     optimizer.step(&grads)?;
+/// }
+/// ```
+#[derive(Debug)]
+pub struct GradAccumulator {
+    vars: Vec<Var>,
+    // One buffer per variable, the gradients of the micro-batches are added to it in place so that
+    // the other gradients of the backward pass are not kept alive.
+    grads: Vec<Option<Var>>,
+    num_steps: usize,
+}
+
+impl GradAccumulator {
+    pub fn new(vars: Vec<Var>) -> Self {
+        let grads = vec![None; vars.len()];
+        Self {
+            vars,
+            grads,
+            num_steps: 0,
+        }
+    }
+
+    /// Adds the gradients from a micro-batch.
+    pub fn accumulate(&mut self, grads: GradStore) -> Result<()> {
+        for (var, acc) in self.vars.iter().zip(self.grads.iter_mut()) {
+            if let Some(grad) = grads.get(var) {
+                match acc {
+                    None => *acc = Some(Var::from_tensor(&grad.detach().copy()?)?),
+                    Some(acc) => acc.set(&(acc.as_tensor() + grad)?)?,
+                }
+            }
+        }
+        self.num_steps += 1;
+        Ok(())
+    }
+
+    /// The number of micro-batches accumulated since the last call to `finish`.
+    pub fn num_steps(&self) -> usize {
+        self.num_steps
+    }
+
+    /// Returns the accumulated gradients, averaged over the micro-batches if `average` is set,
+    /// and resets the accumulator. `None` is returned if no gradients have been accumulated.
+    pub fn finish(&mut self, average: bool) -> Result<Option<GradStore>> {
+        let num_steps = std::mem::take(&mut self.num_steps);
+        if num_steps == 0 {
+            return Ok(None);
+        }
+        let mut grads = GradStore::new();
+        for (var, acc) in self.vars.iter().zip(self.grads.iter_mut()) {
+            // The buffers are moved out as the returned gradients must not be updated by the
+            // next micro-batches.
+            if let Some(acc) = acc.take() {
+                let acc = acc.as_detached_tensor();
+                let acc = if average && num_steps > 1 {
+                    (acc * (1. / num_steps as f64))?
+                } else {
+                    acc
+                };
+                grads.insert(var, acc);
+            }
+        }
+        Ok(Some(grads))
+    }
+}
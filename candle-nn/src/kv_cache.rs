@@ -1,6 +1,9 @@
 //! Cache Implementations
 //!
-use candle::{DType, Device, Result, Tensor};
+use candle::quantized::k_quants::{BlockQ4_0, BlockQ8_0};
+use candle::quantized::GgmlType;
+use candle::{DType, Device, Result, Tensor, D};
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Clone)]
 pub struct Cache {
@@ -55,6 +58,18 @@ impl Cache {
         self.all_data = None;
     }
 
+    /// Drops the positions past `seq_len`, e.g. to roll back some generated tokens.
+    pub fn truncate(&mut self, seq_len: usize) -> Result<()> {
+        if seq_len > self.current_seq_len {
+            candle::bail!(
+                "cannot truncate a cache of length {} to {seq_len}",
+                self.current_seq_len
+            )
+        }
+        self.current_seq_len = seq_len;
+        Ok(())
+    }
+
     pub fn append(&mut self, src: &Tensor) -> Result<()> {
         let seq_len = src.dim(self.dim)?;
         // This doesn't seem very idiomatic but because the creation can fail, it's tricky to use
@@ -148,6 +163,315 @@ impl KvCache {
         self.k.reset();
         self.v.reset();
     }
+
+    /// Drops the positions past `seq_len`.
+    pub fn truncate(&mut self, seq_len: usize) -> Result<()> {
+        self.k.truncate(seq_len)?;
+        self.v.truncate(seq_len)
+    }
+
+    /// The keys and values currently in the cache, `None` if the cache is empty.
+    pub fn state(&self) -> Result<Option<(Tensor, Tensor)>> {
+        Ok(self.k()?.zip(self.v()?))
+    }
+
+    /// Replaces the content of the cache with some keys and values, e.g. from [`KvCache::state`].
+    pub fn restore(&mut self, k: &Tensor, v: &Tensor) -> Result<()> {
+        self.reset();
+        self.k.append(&k.contiguous()?)?;
+        self.v.append(&v.contiguous()?)
+    }
+}
+
+/// The storage format of a [`QuantizedKvCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvCacheDType {
+    /// 8-bit blocks of 32 values with a f16 scale.
+    Q8_0,
+    /// 4-bit blocks of 32 values with a f16 scale.
+    Q4_0,
+    /// 8-bit floats, 4 bits of exponent and 3 bits of mantissa.
+    F8E4M3,
+}
+
+impl KvCacheDType {
+    /// The number of values that are quantized together.
+    pub fn block_size(&self) -> usize {
+        match self {
+            Self::Q8_0 => BlockQ8_0::BLCK_SIZE,
+            Self::Q4_0 => BlockQ4_0::BLCK_SIZE,
+            Self::F8E4M3 => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum QuantizedData {
+    // The quantized values packed in u8 and the f16 scales of the blocks, both caches have the
+    // shape `(seq_len, blocks_per_pos, _)`.
+    Blocks { qs: Cache, scales: Cache },
+    F8E4M3(Cache),
+}
+
+// The number of values for each position along `dim`.
+fn elems_per_pos(dims: &[usize], dim: usize) -> usize {
+    dims.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != dim)
+        .map(|(_, d)| *d)
+        .product()
+}
+
+// The inverse of the block scales, zero for the blocks that only contain zeros.
+fn inv_scales(d: &Tensor) -> Result<Tensor> {
+    d.ne(0f32)?.where_cond(&d.recip()?, d)
+}
+
+// Quantizes `src` to the packed values and the block scales, `dim` is the sequence dimension.
+fn quantize_blocks(src: &Tensor, dim: usize, dtype: KvCacheDType) -> Result<(Tensor, Tensor)> {
+    let block_size = dtype.block_size();
+    let xs = src.transpose(0, dim)?.to_dtype(DType::F32)?;
+    let xs = xs.reshape((xs.dim(0)?, (), block_size))?;
+    match dtype {
+        KvCacheDType::Q8_0 => {
+            let d = (xs.abs()?.max_keepdim(D::Minus1)? / 127.)?;
+            let qs = xs.broadcast_mul(&inv_scales(&d)?)?.round()?;
+            // The signed values are offset so as to be stored as u8.
+            let qs = (qs + 128.)?.to_dtype(DType::U8)?;
+            Ok((qs, d.to_dtype(DType::F16)?))
+        }
+        KvCacheDType::Q4_0 => {
+            // The scale is based on the value with the largest magnitude, sign included.
+            let max = xs.gather(&xs.abs()?.argmax_keepdim(D::Minus1)?, D::Minus1)?;
+            let d = (max / -8.)?;
+            let qs = (xs.broadcast_mul(&inv_scales(&d)?)? + 8.5)?
+                .floor()?
+                .clamp(0f32, 15f32)?;
+            // The first half of the block is stored in the low nibbles.
+            let half = block_size / 2;
+            let lo = qs.narrow(D::Minus1, 0, half)?;
+            let hi = qs.narrow(D::Minus1, half, half)?;
+            let qs = (lo + (hi * 16.)?)?.to_dtype(DType::U8)?;
+            Ok((qs, d.to_dtype(DType::F16)?))
+        }
+        KvCacheDType::F8E4M3 => candle::bail!("F8E4M3 caches are not block quantized"),
+    }
+}
+
+/// A cache that stores its data in a quantized format, the data is dequantized to the dtype of
+/// the appended tensors when read back.
+///
+/// With the block formats, the values are quantized in the order obtained by swapping `dim`
+/// with the first dimension, i.e. along `head_dim` for `(batch, heads, seq, head_dim)` tensors,
+/// using the same scheme as the ggml `Q8_0` and `Q4_0` blocks. The number of values per position
+/// along `dim` has to be a multiple of the block size. The quantization and dequantization both
+/// happen on the device of the appended tensors, where the quantized data is stored.
+#[derive(Debug, Clone)]
+pub struct QuantizedCache {
+    data: QuantizedData,
+    // The dims, dtype and device of the appended tensors, set on the first call to append.
+    src: Option<(Vec<usize>, DType, Device)>,
+    dtype: KvCacheDType,
+    dim: usize,
+    current_seq_len: usize,
+    max_seq_len: usize,
+}
+
+impl QuantizedCache {
+    pub fn new(dim: usize, max_seq_len: usize, dtype: KvCacheDType) -> Self {
+        let data = match dtype {
+            KvCacheDType::Q8_0 | KvCacheDType::Q4_0 => QuantizedData::Blocks {
+                qs: Cache::new(0, max_seq_len),
+                scales: Cache::new(0, max_seq_len),
+            },
+            KvCacheDType::F8E4M3 => QuantizedData::F8E4M3(Cache::new(dim, max_seq_len)),
+        };
+        Self {
+            data,
+            src: None,
+            dtype,
+            dim,
+            current_seq_len: 0,
+            max_seq_len,
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn dtype(&self) -> KvCacheDType {
+        self.dtype
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.current_seq_len
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// The size used by the quantized data for the current sequence.
+    pub fn storage_size_in_bytes(&self) -> usize {
+        let elems = match &self.src {
+            None => return 0,
+            Some((dims, _, _)) => elems_per_pos(dims, self.dim) * self.current_seq_len,
+        };
+        match self.dtype {
+            KvCacheDType::Q8_0 => elems / BlockQ8_0::BLCK_SIZE * std::mem::size_of::<BlockQ8_0>(),
+            KvCacheDType::Q4_0 => elems / BlockQ4_0::BLCK_SIZE * std::mem::size_of::<BlockQ4_0>(),
+            KvCacheDType::F8E4M3 => elems,
+        }
+    }
+
+    fn dequantize(&self, dims: &[usize], dtype: DType, device: &Device) -> Result<Tensor> {
+        let (qs, scales) = match &self.data {
+            QuantizedData::Blocks { qs, scales } => (qs.current_data()?, scales.current_data()?),
+            QuantizedData::F8E4M3(cache) => {
+                return match cache.current_data()? {
+                    None => {
+                        let mut shape = dims.to_vec();
+                        shape[self.dim] = 0;
+                        Tensor::zeros(shape, dtype, device)
+                    }
+                    Some(data) => data.to_dtype(dtype),
+                }
+            }
+        };
+        let mut shape = dims.to_vec();
+        shape[self.dim] = self.current_seq_len;
+        shape.swap(0, self.dim);
+        let (qs, scales) = match qs.zip(scales) {
+            None => return Tensor::zeros(shape, dtype, device)?.transpose(0, self.dim),
+            Some(v) => v,
+        };
+        let qs = qs.to_dtype(DType::F32)?;
+        let qs = match self.dtype {
+            KvCacheDType::Q4_0 => {
+                let hi = (&qs / 16.)?.floor()?;
+                let lo = (qs - (&hi * 16.)?)?;
+                (Tensor::cat(&[lo, hi], D::Minus1)? - 8.)?
+            }
+            _ => (qs - 128.)?,
+        };
+        qs.broadcast_mul(&scales.to_dtype(DType::F32)?)?
+            .reshape(shape)?
+            .to_dtype(dtype)?
+            .transpose(0, self.dim)?
+            .contiguous()
+    }
+
+    pub fn current_data(&self) -> Result<Option<Tensor>> {
+        match self.src.as_ref() {
+            None => Ok(None),
+            Some((dims, dtype, device)) => Ok(Some(self.dequantize(dims, *dtype, device)?)),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.dim, self.max_seq_len, self.dtype)
+    }
+
+    /// Appends `src` to the cache and returns the dequantized data for the whole sequence.
+    pub fn append(&mut self, src: &Tensor) -> Result<Tensor> {
+        let seq_len = src.dim(self.dim)?;
+        let dims = src.dims();
+        let elems_per_pos = elems_per_pos(dims, self.dim);
+        match &self.src {
+            None => {
+                let block_size = self.dtype.block_size();
+                if !elems_per_pos.is_multiple_of(block_size) {
+                    candle::bail!(
+                        "{:?} cache requires a multiple of {block_size} values per position, got {elems_per_pos}",
+                        self.dtype
+                    )
+                }
+                self.src = Some((dims.to_vec(), src.dtype(), src.device().clone()))
+            }
+            Some((prev_dims, _, _)) => {
+                let same_dims = prev_dims.len() == dims.len()
+                    && (0..dims.len()).all(|i| i == self.dim || prev_dims[i] == dims[i]);
+                if !same_dims {
+                    candle::bail!(
+                        "shape mismatch in quantized cache, {prev_dims:?} <> {dims:?} on dim {}",
+                        self.dim
+                    )
+                }
+            }
+        }
+        match &mut self.data {
+            QuantizedData::F8E4M3(cache) => cache.append(&src.to_dtype(DType::F8E4M3)?)?,
+            QuantizedData::Blocks { qs, scales } => {
+                let (new_qs, new_scales) = quantize_blocks(src, self.dim, self.dtype)?;
+                qs.append(&new_qs)?;
+                scales.append(&new_scales)?;
+            }
+        }
+        self.current_seq_len += seq_len;
+        let (dims, dtype, device) = self.src.as_ref().unwrap();
+        self.dequantize(dims, *dtype, device)
+    }
+}
+
+/// A key-value cache storing its data quantized, see [`QuantizedCache`]. This has the same api as
+/// [`KvCache`] so can be used as a replacement to reduce the memory used for long sequences, at
+/// the cost of dequantizing the whole cache on each step.
+#[derive(Debug, Clone)]
+pub struct QuantizedKvCache {
+    k: QuantizedCache,
+    v: QuantizedCache,
+}
+
+impl QuantizedKvCache {
+    pub fn new(dim: usize, max_seq_len: usize, dtype: KvCacheDType) -> Self {
+        let k = QuantizedCache::new(dim, max_seq_len, dtype);
+        let v = QuantizedCache::new(dim, max_seq_len, dtype);
+        Self { k, v }
+    }
+
+    pub fn k_cache(&self) -> &QuantizedCache {
+        &self.k
+    }
+
+    pub fn v_cache(&self) -> &QuantizedCache {
+        &self.v
+    }
+
+    pub fn k_cache_mut(&mut self) -> &mut QuantizedCache {
+        &mut self.k
+    }
+
+    pub fn v_cache_mut(&mut self) -> &mut QuantizedCache {
+        &mut self.v
+    }
+
+    pub fn dtype(&self) -> KvCacheDType {
+        self.k.dtype()
+    }
+
+    pub fn k(&self) -> Result<Option<Tensor>> {
+        self.k.current_data()
+    }
+
+    pub fn v(&self) -> Result<Option<Tensor>> {
+        self.v.current_data()
+    }
+
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let out_k = self.k.append(k)?;
+        let out_v = self.v.append(v)?;
+        Ok((out_k, out_v))
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.k.current_seq_len()
+    }
+
+    pub fn reset(&mut self) {
+        self.k.reset();
+        self.v.reset();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -760,6 +1084,40 @@ impl ConcatKvCache {
         self.v = None;
     }
 
+    /// Drop the positions past `seq_len`
+    ///
+    /// Returns an error if the cache holds less than `seq_len` positions.
+    pub fn truncate(&mut self, seq_len: usize) -> Result<()> {
+        let current_seq_len = self.current_seq_len();
+        if seq_len > current_seq_len {
+            candle::bail!("cannot truncate a cache of length {current_seq_len} to {seq_len}")
+        }
+        if seq_len == 0 {
+            self.reset();
+            return Ok(());
+        }
+        for t in [self.k.as_mut(), self.v.as_mut()].into_iter().flatten() {
+            *t = t.narrow(self.dim, 0, seq_len)?
+        }
+        Ok(())
+    }
+
+    /// Replace the content of the cache with some keys and values
+    ///
+    /// The tensors are used as is, they share their storage with `k` and `v`.
+    pub fn restore(&mut self, k: &Tensor, v: &Tensor) -> Result<()> {
+        if k.dim(self.dim)? != v.dim(self.dim)? {
+            candle::bail!(
+                "sequence length mismatch between k {:?} and v {:?}",
+                k.shape(),
+                v.shape()
+            )
+        }
+        self.k = Some(k.contiguous()?);
+        self.v = Some(v.contiguous()?);
+        Ok(())
+    }
+
     /// Get reference to current K cache data
     ///
     /// Returns `None` if the cache is empty.
@@ -799,6 +1157,338 @@ impl ConcatKvCache {
     }
 }
 
+/// A snapshot of the keys and values cached by all the layers of a model.
+///
+/// This can be used to skip processing the same prompt multiple times, e.g. a system prompt
+/// shared by multiple conversations. The snapshot can be serialized in the safetensors format.
+#[derive(Debug, Clone)]
+pub struct KvState {
+    layers: Vec<(Tensor, Tensor)>,
+    dim: usize,
+}
+
+impl KvState {
+    /// Creates a snapshot from the keys and values of each layer, `dim` is the sequence
+    /// dimension which has to have the same size for all the tensors.
+    pub fn new(layers: Vec<(Tensor, Tensor)>, dim: usize) -> Result<Self> {
+        let mut seq_len = None;
+        for (k, v) in layers.iter() {
+            let k_len = k.dim(dim)?;
+            if v.dim(dim)? != k_len || seq_len.is_some_and(|s| s != k_len) {
+                candle::bail!("inconsistent sequence lengths in kv state")
+            }
+            seq_len = Some(k_len)
+        }
+        Ok(Self { layers, dim })
+    }
+
+    pub fn layers(&self) -> &[(Tensor, Tensor)] {
+        &self.layers
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.layers.first().map_or(0, |(k, _)| k.dims()[self.dim])
+    }
+
+    /// The snapshot for the first `seq_len` positions.
+    pub fn truncate(&self, seq_len: usize) -> Result<Self> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|(k, v)| {
+                Ok((
+                    k.narrow(self.dim, 0, seq_len)?,
+                    v.narrow(self.dim, 0, seq_len)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            layers,
+            dim: self.dim,
+        })
+    }
+
+    /// Serializes the snapshot in the safetensors format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let tensors = self
+            .layers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (k, v))| [(format!("layers.{i}.k"), k), (format!("layers.{i}.v"), v)])
+            .collect::<Vec<_>>();
+        let metadata = HashMap::from([
+            ("dim".to_string(), self.dim.to_string()),
+            ("n_layers".to_string(), self.layers.len().to_string()),
+        ]);
+        Ok(safetensors::serialize(tensors, Some(metadata))?)
+    }
+
+    pub fn from_bytes(data: &[u8], device: &Device) -> Result<Self> {
+        let (_, metadata) = safetensors::SafeTensors::read_metadata(data)?;
+        let get = |key: &str| -> Result<usize> {
+            let value = metadata.metadata().as_ref().and_then(|m| m.get(key));
+            match value.map(|v| v.parse::<usize>()) {
+                Some(Ok(v)) => Ok(v),
+                _ => candle::bail!("missing or invalid {key} in kv state metadata"),
+            }
+        };
+        let (dim, n_layers) = (get("dim")?, get("n_layers")?);
+        let mut tensors = candle::safetensors::load_buffer(data, device)?;
+        let mut get = |name: String| match tensors.remove(&name) {
+            Some(t) => Ok(t),
+            None => candle::bail!("missing tensor {name} in kv state"),
+        };
+        let layers = (0..n_layers)
+            .map(|i| Ok((get(format!("layers.{i}.k"))?, get(format!("layers.{i}.v"))?)))
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(layers, dim)
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, p: P) -> Result<()> {
+        std::fs::write(p, self.to_bytes()?)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(p: P, device: &Device) -> Result<Self> {
+        let data = std::fs::read(p)?;
+        Self::from_bytes(&data, device)
+    }
+}
+
+#[derive(Debug, Default)]
+struct PrefixNode {
+    // The tokens on the edge from the parent node, only empty for the root.
+    tokens: Vec<u32>,
+    parent: usize,
+    // The children indexed by the first token of their edge.
+    children: HashMap<u32, usize>,
+    // The snapshot for the tokens leading to this node, with the step at which it was last used.
+    state: Option<(KvState, u64)>,
+}
+
+/// A cache of [`KvState`] snapshots indexed by the tokens they were computed on.
+///
+/// A lookup returns the snapshot for the longest cached prefix of the tokens, the snapshots for
+/// longer sequences are truncated to the common prefix. Once `max_entries` snapshots are stored,
+/// inserting a new one evicts the least recently used.
+///
+/// The snapshots are stored in a radix tree whose edges hold the token sequences shared by the
+/// snapshots below them, so its depth is bounded by the number of snapshots rather than by the
+/// prompt lengths.
+///
+/// ```ignore
+/// let (state, index_pos) = match prefix_cache.get(&tokens[..tokens.len() - 1])? {
+///     None => (None, 0),
+///     Some(state) => { let len = state.seq_len(); (Some(state), len) }
+/// };
+/// ```
+#[derive(Debug)]
+pub struct PrefixCache {
+    // The tree nodes, the root is at index 0 and the unused slots are listed in `free`.
+    nodes: Vec<PrefixNode>,
+    free: Vec<usize>,
+    // The nodes holding a snapshot indexed by the step at which the snapshot was last used.
+    lru: BTreeMap<u64, usize>,
+    max_entries: usize,
+    step: u64,
+}
+
+fn common_prefix_len(lhs: &[u32], rhs: &[u32]) -> usize {
+    lhs.iter()
+        .zip(rhs.iter())
+        .take_while(|(l, r)| l == r)
+        .count()
+}
+
+impl PrefixCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            nodes: vec![PrefixNode::default()],
+            free: vec![],
+            lru: BTreeMap::new(),
+            max_entries,
+            step: 0,
+        }
+    }
+
+    /// The number of snapshots in the cache.
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lru.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes = vec![PrefixNode::default()];
+        self.free.clear();
+        self.lru.clear();
+    }
+
+    fn new_node(&mut self, tokens: Vec<u32>, parent: usize) -> usize {
+        let node = PrefixNode {
+            tokens,
+            parent,
+            ..PrefixNode::default()
+        };
+        match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn free_node(&mut self, idx: usize) {
+        self.nodes[idx] = PrefixNode::default();
+        self.free.push(idx)
+    }
+
+    // Sets the step at which the snapshot of a node was last used.
+    fn touch(&mut self, idx: usize) {
+        self.step += 1;
+        if let Some((_, step)) = self.nodes[idx].state.as_mut() {
+            self.lru.remove(step);
+            *step = self.step;
+            self.lru.insert(self.step, idx);
+        }
+    }
+
+    // Removes the nodes without a snapshot that have become useless, starting from `idx` and
+    // going up: the leaves are dropped and the nodes with a single child are merged into it.
+    fn prune(&mut self, mut idx: usize) {
+        while idx != 0 && self.nodes[idx].state.is_none() {
+            let parent = self.nodes[idx].parent;
+            let first_token = self.nodes[idx].tokens[0];
+            match self.nodes[idx].children.len() {
+                0 => {
+                    self.nodes[parent].children.remove(&first_token);
+                    self.free_node(idx);
+                    idx = parent
+                }
+                1 => {
+                    let child = *self.nodes[idx].children.values().next().unwrap();
+                    let mut tokens = std::mem::take(&mut self.nodes[idx].tokens);
+                    tokens.extend_from_slice(&self.nodes[child].tokens);
+                    self.nodes[child].tokens = tokens;
+                    self.nodes[child].parent = parent;
+                    self.nodes[parent].children.insert(first_token, child);
+                    self.free_node(idx);
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Adds the snapshot computed on `tokens`, replacing the previous one for the same tokens.
+    pub fn insert(&mut self, tokens: &[u32], state: KvState) -> Result<()> {
+        if state.seq_len() != tokens.len() {
+            candle::bail!(
+                "kv state of length {} for {} tokens",
+                state.seq_len(),
+                tokens.len()
+            )
+        }
+        if self.max_entries == 0 || tokens.is_empty() {
+            return Ok(());
+        }
+        let mut idx = 0;
+        let mut pos = 0;
+        while pos < tokens.len() {
+            let rest = &tokens[pos..];
+            let child = match self.nodes[idx].children.get(&rest[0]) {
+                Some(&child) => child,
+                None => {
+                    let child = self.new_node(rest.to_vec(), idx);
+                    self.nodes[idx].children.insert(rest[0], child);
+                    idx = child;
+                    break;
+                }
+            };
+            let common = common_prefix_len(&self.nodes[child].tokens, rest);
+            if common < self.nodes[child].tokens.len() {
+                // Split the edge, the new node gets the common part of the tokens.
+                let suffix = self.nodes[child].tokens.split_off(common);
+                let prefix = std::mem::take(&mut self.nodes[child].tokens);
+                let mid = self.new_node(prefix, idx);
+                self.nodes[mid].children.insert(suffix[0], child);
+                self.nodes[child].tokens = suffix;
+                self.nodes[child].parent = mid;
+                self.nodes[idx].children.insert(rest[0], mid);
+                idx = mid
+            } else {
+                idx = child
+            }
+            pos += common
+        }
+        if let Some((_, step)) = self.nodes[idx].state.replace((state, 0)) {
+            self.lru.remove(&step);
+        }
+        self.touch(idx);
+        while self.lru.len() > self.max_entries {
+            let Some((_, idx)) = self.lru.pop_first() else {
+                break;
+            };
+            self.nodes[idx].state = None;
+            self.prune(idx)
+        }
+        Ok(())
+    }
+
+    /// Returns the snapshot for the longest prefix of `tokens` that is in the cache, use
+    /// [`KvState::seq_len`] to get the number of tokens covered by the snapshot.
+    pub fn get(&mut self, tokens: &[u32]) -> Result<Option<KvState>> {
+        let mut idx = 0;
+        let mut depth = 0;
+        while depth < tokens.len() {
+            let child = match self.nodes[idx].children.get(&tokens[depth]) {
+                None => break,
+                Some(&child) => child,
+            };
+            let edge = &self.nodes[child].tokens;
+            let common = common_prefix_len(edge, &tokens[depth..]);
+            let partial = common < edge.len();
+            idx = child;
+            depth += common;
+            if partial {
+                break;
+            }
+        }
+        if depth == 0 {
+            return Ok(None);
+        }
+        // All the snapshots below this node start with the matched prefix, and as the leaves
+        // always hold a snapshot one is found by following any path down.
+        while self.nodes[idx].state.is_none() {
+            match self.nodes[idx].children.values().next() {
+                None => return Ok(None),
+                Some(&child) => idx = child,
+            }
+        }
+        self.touch(idx);
+        let state = match &self.nodes[idx].state {
+            None => return Ok(None),
+            Some((state, _)) => state,
+        };
+        let state = if state.seq_len() == depth {
+            state.clone()
+        } else {
+            state.truncate(depth)?
+        };
+        Ok(Some(state))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -17,20 +17,25 @@
 
 pub mod activation;
 pub mod batch_norm;
+pub mod checkpoint;
 pub mod conv;
 pub mod cpu_flash_attention;
 pub mod embedding;
 pub mod encoding;
 pub mod func;
+pub mod grad;
 pub mod group_norm;
 pub mod init;
 pub mod kv_cache;
 pub mod layer_norm;
 pub mod linear;
 pub mod loss;
+pub mod lr_scheduler;
+pub mod mixed_precision;
 pub mod moe;
 pub mod ops;
 pub mod optim;
+pub mod paged_kv_cache;
 pub mod rnn;
 pub mod rotary_emb;
 pub mod sampling;
@@ -40,10 +45,13 @@ pub mod var_map;
 
 pub use activation::{prelu, Activation, PReLU};
 pub use batch_norm::{batch_norm, BatchNorm, BatchNormConfig};
+pub use checkpoint::{checkpoint, Checkpoint};
 pub use conv::{
-    conv1d, conv1d_no_bias, conv2d, conv2d_no_bias, conv_transpose1d, conv_transpose1d_no_bias,
-    conv_transpose2d, conv_transpose2d_no_bias, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig,
+    conv1d, conv1d_no_bias, conv2d, conv2d_no_bias, conv3d, conv3d_no_bias, conv_transpose1d,
+    conv_transpose1d_no_bias, conv_transpose2d, conv_transpose2d_no_bias, conv_transpose3d,
+    conv_transpose3d_no_bias, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, Conv3d, Conv3dConfig,
     ConvTranspose1d, ConvTranspose1dConfig, ConvTranspose2d, ConvTranspose2dConfig,
+    ConvTranspose3d, ConvTranspose3dConfig,
 };
 pub use embedding::{embedding, Embedding};
 pub use func::{func, func_t, Func, FuncT};
@@ -53,8 +61,13 @@ pub use layer_norm::{
     layer_norm, layer_norm_no_bias, rms_norm, LayerNorm, LayerNormConfig, RmsNorm,
 };
 pub use linear::{linear, linear_b, linear_no_bias, Linear};
+pub use lr_scheduler::{LrScheduler, Scheduled};
+pub use mixed_precision::{GradScaler, MixedPrecision, ParamsGradScaler};
 pub use ops::Dropout;
-pub use optim::{AdamW, Optimizer, ParamsAdamW, SGD};
+pub use optim::{
+    Adafactor, Adagrad, Adam, AdamW, Lion, Optimizer, ParamsAdafactor, ParamsAdagrad, ParamsAdam,
+    ParamsAdamW, ParamsLion, ParamsRMSprop, ParamsSGD, RMSprop, StatefulOptimizer, SGD,
+};
 pub use rnn::{gru, lstm, GRUConfig, LSTMConfig, GRU, LSTM, RNN};
 pub use sequential::{seq, Sequential};
 pub use var_builder::VarBuilder;
@@ -0,0 +1,412 @@
+//! Learning rate schedulers.
+//!
+//! A scheduler computes the learning rate to use at each step (or epoch) of a training loop, it
+//! can be combined with any [`Optimizer`] through [`Scheduled`].
+use crate::optim::Optimizer;
+use candle::{Result, Tensor};
+
+/// The interface learning rate schedulers should implement.
+pub trait LrScheduler {
+    /// The learning rate for the current step.
+    fn get_lr(&self) -> f64;
+
+    /// Advances the scheduler by one step, this can be called after each batch or after each
+    /// epoch depending on the granularity of the schedule.
+    fn step(&mut self);
+
+    /// Advances the scheduler by one step using a metric such as the validation loss. Only metric
+    /// based schedulers like [`ReduceLrOnPlateau`] use the metric, the default implementation
+    /// ignores it.
+    fn step_with_metric(&mut self, _metric: f64) {
+        self.step()
+    }
+}
+
+/// Decays the learning rate by `gamma` every `step_size` steps.
+#[derive(Clone, Debug)]
+pub struct StepLr {
+    pub base_lr: f64,
+    pub step_size: usize,
+    pub gamma: f64,
+    step_t: usize,
+}
+
+impl StepLr {
+    pub fn new(base_lr: f64, step_size: usize, gamma: f64) -> Result<Self> {
+        if step_size == 0 {
+            candle::bail!("step_size should be strictly positive")
+        }
+        Ok(Self {
+            base_lr,
+            step_size,
+            gamma,
+            step_t: 0,
+        })
+    }
+}
+
+impl LrScheduler for StepLr {
+    fn get_lr(&self) -> f64 {
+        self.base_lr * self.gamma.powi((self.step_t / self.step_size) as i32)
+    }
+
+    fn step(&mut self) {
+        self.step_t += 1
+    }
+}
+
+/// Decays the learning rate by `gamma` on every step.
+#[derive(Clone, Debug)]
+pub struct ExponentialLr {
+    pub base_lr: f64,
+    pub gamma: f64,
+    step_t: usize,
+}
+
+impl ExponentialLr {
+    pub fn new(base_lr: f64, gamma: f64) -> Self {
+        Self {
+            base_lr,
+            gamma,
+            step_t: 0,
+        }
+    }
+}
+
+impl LrScheduler for ExponentialLr {
+    fn get_lr(&self) -> f64 {
+        self.base_lr * self.gamma.powi(self.step_t as i32)
+    }
+
+    fn step(&mut self) {
+        self.step_t += 1
+    }
+}
+
+fn cosine_anneal(start: f64, end: f64, pct: f64) -> f64 {
+    end + (start - end) / 2. * (1. + (std::f64::consts::PI * pct).cos())
+}
+
+/// Anneals the learning rate from `base_lr` to `eta_min` following a cosine curve over `t_max`
+/// steps, the learning rate stays at `eta_min` afterwards.
+#[derive(Clone, Debug)]
+pub struct CosineAnnealingLr {
+    pub base_lr: f64,
+    pub t_max: usize,
+    pub eta_min: f64,
+    step_t: usize,
+}
+
+impl CosineAnnealingLr {
+    pub fn new(base_lr: f64, t_max: usize, eta_min: f64) -> Result<Self> {
+        if t_max == 0 {
+            candle::bail!("t_max should be strictly positive")
+        }
+        Ok(Self {
+            base_lr,
+            t_max,
+            eta_min,
+            step_t: 0,
+        })
+    }
+}
+
+impl LrScheduler for CosineAnnealingLr {
+    fn get_lr(&self) -> f64 {
+        let pct = usize::min(self.step_t, self.t_max) as f64 / self.t_max as f64;
+        cosine_anneal(self.base_lr, self.eta_min, pct)
+    }
+
+    fn step(&mut self) {
+        self.step_t += 1
+    }
+}
+
+/// Cosine annealing with warm restarts, see "SGDR: Stochastic Gradient Descent with Warm Restarts"
+/// <https://arxiv.org/abs/1608.03983>.
+///
+/// The first cycle lasts `t_0` steps and each subsequent cycle is `t_mult` times longer than the
+/// previous one.
+#[derive(Clone, Debug)]
+pub struct CosineAnnealingWarmRestarts {
+    pub base_lr: f64,
+    pub t_0: usize,
+    pub t_mult: usize,
+    pub eta_min: f64,
+    step_t: usize,
+}
+
+impl CosineAnnealingWarmRestarts {
+    pub fn new(base_lr: f64, t_0: usize, t_mult: usize, eta_min: f64) -> Result<Self> {
+        if t_0 == 0 || t_mult == 0 {
+            candle::bail!("t_0 and t_mult should be strictly positive, got {t_0} and {t_mult}")
+        }
+        Ok(Self {
+            base_lr,
+            t_0,
+            t_mult,
+            eta_min,
+            step_t: 0,
+        })
+    }
+}
+
+impl LrScheduler for CosineAnnealingWarmRestarts {
+    fn get_lr(&self) -> f64 {
+        let mut t_cur = self.step_t;
+        let mut t_i = self.t_0;
+        while t_cur >= t_i {
+            t_cur -= t_i;
+            t_i *= self.t_mult;
+        }
+        cosine_anneal(self.base_lr, self.eta_min, t_cur as f64 / t_i as f64)
+    }
+
+    fn step(&mut self) {
+        self.step_t += 1
+    }
+}
+
+/// Linearly increases the learning rate from 0 to `base_lr` over `warmup_steps` steps, then
+/// linearly decreases it back to 0 at `total_steps`.
+#[derive(Clone, Debug)]
+pub struct LinearWarmupDecay {
+    pub base_lr: f64,
+    pub warmup_steps: usize,
+    pub total_steps: usize,
+    step_t: usize,
+}
+
+impl LinearWarmupDecay {
+    pub fn new(base_lr: f64, warmup_steps: usize, total_steps: usize) -> Result<Self> {
+        if warmup_steps >= total_steps {
+            candle::bail!(
+                "warmup_steps ({warmup_steps}) should be smaller than total_steps ({total_steps})"
+            )
+        }
+        Ok(Self {
+            base_lr,
+            warmup_steps,
+            total_steps,
+            step_t: 0,
+        })
+    }
+}
+
+impl LrScheduler for LinearWarmupDecay {
+    fn get_lr(&self) -> f64 {
+        let step_t = self.step_t as f64;
+        if self.step_t < self.warmup_steps {
+            self.base_lr * step_t / self.warmup_steps as f64
+        } else {
+            let remaining = (self.total_steps as f64 - step_t).max(0.);
+            self.base_lr * remaining / (self.total_steps - self.warmup_steps) as f64
+        }
+    }
+
+    fn step(&mut self) {
+        self.step_t += 1
+    }
+}
+
+/// The 1cycle policy, see "Super-Convergence: Very Fast Training of Neural Networks Using Large
+/// Learning Rates" <https://arxiv.org/abs/1708.07120>.
+///
+/// The learning rate starts at `max_lr / div_factor`, is annealed to `max_lr` over the first
+/// `pct_start` fraction of `total_steps`, and then annealed down to
+/// `max_lr / (div_factor * final_div_factor)`. Both phases use cosine annealing.
+#[derive(Clone, Debug)]
+pub struct OneCycleLr {
+    pub max_lr: f64,
+    pub total_steps: usize,
+    pub pct_start: f64,
+    pub div_factor: f64,
+    pub final_div_factor: f64,
+    step_t: usize,
+}
+
+impl OneCycleLr {
+    pub fn new(max_lr: f64, total_steps: usize) -> Result<Self> {
+        if total_steps < 2 {
+            candle::bail!("total_steps should be at least 2, got {total_steps}")
+        }
+        Ok(Self {
+            max_lr,
+            total_steps,
+            pct_start: 0.3,
+            div_factor: 25.,
+            final_div_factor: 1e4,
+            step_t: 0,
+        })
+    }
+}
+
+impl LrScheduler for OneCycleLr {
+    fn get_lr(&self) -> f64 {
+        let initial_lr = self.max_lr / self.div_factor;
+        let min_lr = initial_lr / self.final_div_factor;
+        let warmup_end = self.pct_start * self.total_steps as f64 - 1.;
+        let end = self.total_steps.saturating_sub(1) as f64;
+        let step_t = f64::min(self.step_t as f64, end);
+        if warmup_end > 0. && step_t <= warmup_end {
+            cosine_anneal(initial_lr, self.max_lr, step_t / warmup_end)
+        } else {
+            // When pct_start is too small for a single warmup step, the warmup is skipped and
+            // the annealing starts from max_lr.
+            let warmup_end = warmup_end.max(0.);
+            let pct = if end > warmup_end {
+                (step_t - warmup_end) / (end - warmup_end)
+            } else {
+                1.
+            };
+            cosine_anneal(self.max_lr, min_lr, pct)
+        }
+    }
+
+    fn step(&mut self) {
+        self.step_t += 1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlateauMode {
+    /// The metric is expected to decrease, e.g. a loss.
+    Min,
+    /// The metric is expected to increase, e.g. an accuracy.
+    Max,
+}
+
+/// Reduces the learning rate by `factor` when the metric has not improved for more than
+/// `patience` steps.
+///
+/// This scheduler relies on [`LrScheduler::step_with_metric`], calling [`LrScheduler::step`]
+/// leaves the learning rate unchanged.
+#[derive(Clone, Debug)]
+pub struct ReduceLrOnPlateau {
+    pub mode: PlateauMode,
+    pub factor: f64,
+    pub patience: usize,
+    /// Relative threshold for measuring an improvement over the best metric value.
+    pub threshold: f64,
+    /// Number of steps to wait after a reduction before resuming normal operation.
+    pub cooldown: usize,
+    pub min_lr: f64,
+    lr: f64,
+    best: f64,
+    num_bad_steps: usize,
+    cooldown_counter: usize,
+}
+
+impl ReduceLrOnPlateau {
+    pub fn new(base_lr: f64, mode: PlateauMode) -> Self {
+        let best = match mode {
+            PlateauMode::Min => f64::INFINITY,
+            PlateauMode::Max => f64::NEG_INFINITY,
+        };
+        Self {
+            mode,
+            factor: 0.1,
+            patience: 10,
+            threshold: 1e-4,
+            cooldown: 0,
+            min_lr: 0.,
+            lr: base_lr,
+            best,
+            num_bad_steps: 0,
+            cooldown_counter: 0,
+        }
+    }
+
+    fn is_better(&self, metric: f64) -> bool {
+        match self.mode {
+            PlateauMode::Min => metric < self.best * (1. - self.threshold),
+            PlateauMode::Max => metric > self.best * (1. + self.threshold),
+        }
+    }
+}
+
+impl LrScheduler for ReduceLrOnPlateau {
+    fn get_lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn step(&mut self) {}
+
+    fn step_with_metric(&mut self, metric: f64) {
+        if self.is_better(metric) {
+            self.best = metric;
+            self.num_bad_steps = 0;
+        } else {
+            self.num_bad_steps += 1;
+        }
+        if self.cooldown_counter > 0 {
+            self.cooldown_counter -= 1;
+            self.num_bad_steps = 0;
+        }
+        if self.num_bad_steps > self.patience {
+            let lr = f64::max(self.lr * self.factor, self.min_lr);
+            if self.lr - lr > 1e-8 {
+                self.lr = lr;
+            }
+            self.cooldown_counter = self.cooldown;
+            self.num_bad_steps = 0;
+        }
+    }
+}
+
+/// An optimizer together with a scheduler driving its learning rate.
+#[derive(Debug)]
+pub struct Scheduled<O, S> {
+    optimizer: O,
+    scheduler: S,
+}
+
+impl<O: Optimizer, S: LrScheduler> Scheduled<O, S> {
+    pub fn new(mut optimizer: O, scheduler: S) -> Self {
+        optimizer.set_learning_rate(scheduler.get_lr());
+        Self {
+            optimizer,
+            scheduler,
+        }
+    }
+
+    pub fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.optimizer.step(grads)
+    }
+
+    pub fn backward_step(&mut self, loss: &Tensor) -> Result<()> {
+        self.optimizer.backward_step(loss)
+    }
+
+    /// Advances the scheduler and updates the learning rate of the optimizer.
+    pub fn scheduler_step(&mut self) {
+        self.scheduler.step();
+        self.optimizer.set_learning_rate(self.scheduler.get_lr())
+    }
+
+    /// Advances the scheduler using a metric and updates the learning rate of the optimizer.
+    pub fn scheduler_step_with_metric(&mut self, metric: f64) {
+        self.scheduler.step_with_metric(metric);
+        self.optimizer.set_learning_rate(self.scheduler.get_lr())
+    }
+
+    pub fn learning_rate(&self) -> f64 {
+        self.optimizer.learning_rate()
+    }
+
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    pub fn scheduler(&self) -> &S {
+        &self.scheduler
+    }
+
+    pub fn into_inner(self) -> (O, S) {
+        (self.optimizer, self.scheduler)
+    }
+}
@@ -0,0 +1,235 @@
+//! Mixed precision training.
+//!
+//! The model variables are stored in a half precision dtype (F16 or BF16) and used as is for the
+//! forward and backward passes, whereas the optimizer updates some F32 copies of these variables,
+//! the master weights, which are cast back to half precision after each step.
+//!
+//! Small gradients tend to underflow in half precision so the loss is multiplied by a scale
+//! factor before running the backward pass, and the gradients are divided by the same factor
+//! before the optimizer step. [`GradScaler`] adjusts this factor dynamically: steps where some
+//! gradients overflow are skipped and the scale is reduced, and the scale grows again after a
+//! number of steps without overflow.
+use crate::optim::Optimizer;
+use candle::backprop::GradStore;
+use candle::{DType, Result, Tensor, Var};
+
+#[derive(Clone, Debug)]
+pub struct ParamsGradScaler {
+    /// The initial scale factor, this defaults to `2^10` as the F16 gradients of a larger scaled
+    /// loss tend to overflow and get the first steps skipped. The scale then grows every
+    /// `growth_interval` steps without overflow.
+    pub init_scale: f64,
+    pub growth_factor: f64,
+    pub backoff_factor: f64,
+    /// Number of consecutive steps without overflow after which the scale is increased.
+    pub growth_interval: usize,
+}
+
+impl Default for ParamsGradScaler {
+    fn default() -> Self {
+        Self {
+            init_scale: 1024.,
+            growth_factor: 2.,
+            backoff_factor: 0.5,
+            growth_interval: 2000,
+        }
+    }
+}
+
+/// Dynamic loss scaling.
+#[derive(Clone, Debug)]
+pub struct GradScaler {
+    params: ParamsGradScaler,
+    scale: f64,
+    growth_tracker: usize,
+}
+
+impl GradScaler {
+    pub fn new(params: ParamsGradScaler) -> Result<Self> {
+        if params.init_scale <= 0. {
+            candle::bail!("init_scale should be positive, got {}", params.init_scale)
+        }
+        if params.growth_factor <= 1. {
+            candle::bail!(
+                "growth_factor should be greater than 1, got {}",
+                params.growth_factor
+            )
+        }
+        if params.backoff_factor <= 0. || params.backoff_factor >= 1. {
+            candle::bail!(
+                "backoff_factor should be between 0 and 1, got {}",
+                params.backoff_factor
+            )
+        }
+        let scale = params.init_scale;
+        Ok(Self {
+            params,
+            scale,
+            growth_tracker: 0,
+        })
+    }
+
+    /// The current scale factor.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub fn params(&self) -> &ParamsGradScaler {
+        &self.params
+    }
+
+    /// Casts the loss to F32 and multiplies it by the current scale factor.
+    pub fn scale_loss(&self, loss: &Tensor) -> Result<Tensor> {
+        loss.to_dtype(DType::F32)? * self.scale
+    }
+
+    /// Updates the scale factor depending on whether some non-finite gradients have been found
+    /// for the last step.
+    pub fn update(&mut self, found_non_finite: bool) {
+        if found_non_finite {
+            self.scale *= self.params.backoff_factor;
+            self.growth_tracker = 0;
+        } else {
+            self.growth_tracker += 1;
+            if self.growth_tracker >= self.params.growth_interval {
+                self.scale *= self.params.growth_factor;
+                self.growth_tracker = 0;
+            }
+        }
+    }
+}
+
+/// Wraps an optimizer so that it updates F32 master copies of the model variables.
+///
+/// The gradients passed to [`Optimizer::step`] should be the gradients of the loss scaled with
+/// [`MixedPrecision::scale_loss`], [`Optimizer::backward_step`] takes care of the scaling.
+///
+/// ```ignore
+/// let varmap = VarMap::new();
+/// let vb = VarBuilder::from_varmap(&varmap, DType::F16, &device);
+/// let model = Model::new(vb)?;
+/// let mut opt = MixedPrecision::<AdamW>::new(varmap.all_vars(), ParamsAdamW::default())?;
+/// for batch in batches {
+///     let loss = model.loss(&batch)?;
+///     opt.backward_step(&loss)?;
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MixedPrecision<O> {
+    optimizer: O,
+    // The model variables together with the associated master variables. Variables that are
+    // already in F32 are used as their own master variable.
+    vars: Vec<(Var, Var)>,
+    scaler: GradScaler,
+    last_step_skipped: bool,
+    num_skipped_steps: usize,
+}
+
+impl<O: Optimizer> MixedPrecision<O> {
+    pub fn new_with_scaler(
+        vars: Vec<Var>,
+        config: O::Config,
+        scaler: ParamsGradScaler,
+    ) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let master = if var.dtype() == DType::F32 {
+                    var.clone()
+                } else {
+                    Var::from_tensor(&var.to_dtype(DType::F32)?)?
+                };
+                Ok((var, master))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let master_vars = vars.iter().map(|(_, master)| master.clone()).collect();
+        let optimizer = O::new(master_vars, config)?;
+        Ok(Self {
+            optimizer,
+            vars,
+            scaler: GradScaler::new(scaler)?,
+            last_step_skipped: false,
+            num_skipped_steps: 0,
+        })
+    }
+
+    pub fn scale_loss(&self, loss: &Tensor) -> Result<Tensor> {
+        self.scaler.scale_loss(loss)
+    }
+
+    pub fn master_vars(&self) -> Vec<Var> {
+        self.vars.iter().map(|(_, master)| master.clone()).collect()
+    }
+
+    pub fn scaler(&self) -> &GradScaler {
+        &self.scaler
+    }
+
+    /// Returns true if the last step was skipped because of non-finite gradients.
+    pub fn last_step_skipped(&self) -> bool {
+        self.last_step_skipped
+    }
+
+    pub fn num_skipped_steps(&self) -> usize {
+        self.num_skipped_steps
+    }
+
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    pub fn into_inner(self) -> O {
+        self.optimizer
+    }
+}
+
+impl<O: Optimizer> Optimizer for MixedPrecision<O> {
+    type Config = O::Config;
+
+    fn new(vars: Vec<Var>, config: O::Config) -> Result<Self> {
+        Self::new_with_scaler(vars, config, ParamsGradScaler::default())
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.optimizer.learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.optimizer.set_learning_rate(lr)
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<()> {
+        let inv_scale = 1. / self.scaler.scale();
+        let mut master_grads = GradStore::new();
+        for (var, master) in self.vars.iter() {
+            if let Some(grad) = grads.get(var) {
+                let grad = (grad.to_dtype(DType::F32)? * inv_scale)?;
+                master_grads.insert(master, grad);
+            }
+        }
+        let found_non_finite = crate::grad::has_non_finite(&master_grads, &self.master_vars())?;
+        self.scaler.update(found_non_finite);
+        self.last_step_skipped = found_non_finite;
+        if found_non_finite {
+            self.num_skipped_steps += 1;
+            return Ok(());
+        }
+        self.optimizer.step(&master_grads)?;
+        for (var, master) in self.vars.iter() {
+            if var.id() != master.id() {
+                var.set(&master.to_dtype(var.dtype())?)?
+            }
+        }
+        Ok(())
+    }
+
+    fn backward_step(&mut self, loss: &Tensor) -> Result<()> {
+        let grads = self.scale_loss(loss)?.backward()?;
+        self.step(&grads)
+    }
+}
@@ -1,5 +1,6 @@
 //! Various optimization algorithms.
 use candle::{Result, Tensor, Var};
+use std::collections::HashMap;
 
 /// The interface optimizers should implement.
 pub trait Optimizer: Sized {
@@ -28,54 +29,268 @@ pub trait Optimizer: Sized {
     }
 }
 
+/// Optimizers that can export and restore their internal state, e.g. to resume an interrupted
+/// training run.
+///
+/// The state is a flat map of tensors with the following key layout:
+/// - `step` is a `u32` scalar holding the number of steps performed so far, this is only present
+///   for optimizers that track it.
+/// - `{name}.{state}` holds some per-variable state, e.g. `{name}.first_moment` for [`AdamW`].
+///   `name` is the variable name as returned by the `var_name` closure.
+///
+/// See [`crate::VarMap::save_optimizer_state`] to save this state next to the variables.
+pub trait StatefulOptimizer: Optimizer {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>>;
+
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()>;
+}
+
+const STEP_KEY: &str = "step";
+
+fn get_state<'a>(state: &'a HashMap<String, Tensor>, key: &str) -> Result<&'a Tensor> {
+    match state.get(key) {
+        Some(t) => Ok(t),
+        None => Err(candle::Error::CannotFindTensor {
+            path: key.to_string(),
+        }
+        .bt()),
+    }
+}
+
+fn snapshot(var: &Var) -> Result<Tensor> {
+    var.as_tensor().detach().copy()
+}
+
+fn load_tensor(var: &Var, state: &HashMap<String, Tensor>, key: &str) -> Result<Tensor> {
+    let t = get_state(state, key)?;
+    if t.shape() != var.shape() {
+        candle::bail!(
+            "shape mismatch for optimizer state {key}, expected {:?}, got {:?}",
+            var.shape(),
+            t.shape()
+        )
+    }
+    t.to_device(var.device())?.to_dtype(var.dtype())
+}
+
+fn load_var(var: &Var, state: &HashMap<String, Tensor>, key: &str) -> Result<()> {
+    let t = load_tensor(var, state, key)?;
+    var.set(&t)
+}
+
+// Some state that the optimizer does not use with its current config, e.g. a momentum buffer when
+// the momentum is disabled, likely comes from a different training setup.
+fn check_unused(state: &HashMap<String, Tensor>, key: &str) -> Result<()> {
+    if state.contains_key(key) {
+        candle::bail!("unexpected optimizer state {key} for the optimizer config")
+    }
+    Ok(())
+}
+
+fn step_tensor(step_t: usize) -> Result<Tensor> {
+    Tensor::new(step_t as u32, &candle::Device::Cpu)
+}
+
+fn load_step(state: &HashMap<String, Tensor>) -> Result<usize> {
+    let step_t = get_state(state, STEP_KEY)?
+        .to_dtype(candle::DType::U32)?
+        .to_scalar::<u32>()?;
+    Ok(step_t as usize)
+}
+
+#[derive(Clone, Debug)]
+pub struct ParamsSGD {
+    pub lr: f64,
+    pub momentum: f64,
+    pub dampening: f64,
+    pub nesterov: bool,
+    /// Decoupled weight decay, the parameters are scaled by `1 - lr * weight_decay` on each step.
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsSGD {
+    fn default() -> Self {
+        Self {
+            lr: 0.01,
+            momentum: 0.,
+            dampening: 0.,
+            nesterov: false,
+            weight_decay: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarSGD {
+    var: Var,
+    // The momentum buffer is lazily created the first time a gradient is available for this
+    // variable, mirroring the PyTorch behavior where the initial buffer is the gradient itself.
+    momentum_buffer: Option<Var>,
+}
+
 /// Optimizer for Stochastic Gradient Descent.
 ///
-/// Contrary to the PyTorch implementation of SGD, this version does not support momentum.
+/// The optimizer config is the learning rate, use [`SGD::new_with_params`] to enable momentum,
+/// Nesterov momentum or weight decay.
 #[derive(Debug)]
 pub struct SGD {
-    vars: Vec<Var>,
-    learning_rate: f64,
+    vars: Vec<VarSGD>,
+    step_t: usize,
+    params: ParamsSGD,
 }
 
 impl Optimizer for SGD {
     type Config = f64;
 
     fn new(vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
-        let vars = vars
-            .into_iter()
-            .filter(|var| var.dtype().is_float())
-            .collect();
-        Ok(Self {
-            vars,
-            learning_rate,
-        })
+        let params = ParamsSGD {
+            lr: learning_rate,
+            ..ParamsSGD::default()
+        };
+        Self::new_with_params(vars, params)
     }
 
     fn learning_rate(&self) -> f64 {
-        self.learning_rate
+        self.params.lr
     }
 
     fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
-        for var in self.vars.iter() {
-            if let Some(grad) = grads.get(var) {
-                var.set(&var.sub(&(grad * self.learning_rate)?)?)?;
+        self.step_t += 1;
+        let lr = self.params.lr;
+        let momentum = self.params.momentum;
+        let dampening = self.params.dampening;
+        let weight_decay = self.params.weight_decay;
+        for var in self.vars.iter_mut() {
+            let theta = &var.var;
+            if let Some(grad) = grads.get(theta) {
+                let update = if momentum == 0. {
+                    grad.clone()
+                } else {
+                    let buf = match &var.momentum_buffer {
+                        None => {
+                            let buf = Var::from_tensor(grad)?;
+                            var.momentum_buffer = Some(buf.clone());
+                            buf.as_tensor().clone()
+                        }
+                        Some(buf) => {
+                            let next_buf =
+                                ((buf.as_tensor() * momentum)? + (grad * (1. - dampening))?)?;
+                            buf.set(&next_buf)?;
+                            next_buf
+                        }
+                    };
+                    if self.params.nesterov {
+                        (grad + (buf * momentum)?)?
+                    } else {
+                        buf
+                    }
+                };
+                let next_theta = if weight_decay == 0. {
+                    theta.as_tensor().clone()
+                } else {
+                    (theta.as_tensor() * (1. - lr * weight_decay))?
+                };
+                theta.set(&next_theta.sub(&(update * lr)?)?)?;
             }
         }
         Ok(())
     }
 
     fn set_learning_rate(&mut self, lr: f64) {
-        self.learning_rate = lr
+        self.params.lr = lr
     }
 }
 
 impl SGD {
+    fn check_params(params: &ParamsSGD) -> Result<()> {
+        if params.nesterov && (params.momentum <= 0. || params.dampening != 0.) {
+            candle::bail!("nesterov momentum requires a positive momentum and zero dampening")
+        }
+        Ok(())
+    }
+
+    pub fn new_with_params(vars: Vec<Var>, params: ParamsSGD) -> Result<Self> {
+        Self::check_params(&params)?;
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| VarSGD {
+                var,
+                momentum_buffer: None,
+            })
+            .collect();
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
     pub fn into_inner(self) -> Vec<Var> {
-        self.vars
+        self.vars.into_iter().map(|v| v.var).collect()
     }
 
     pub fn push(&mut self, var: &Var) {
-        self.vars.push(var.clone())
+        self.vars.push(VarSGD {
+            var: var.clone(),
+            momentum_buffer: None,
+        })
+    }
+
+    pub fn params(&self) -> &ParamsSGD {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsSGD) -> Result<()> {
+        Self::check_params(&params)?;
+        self.params = params;
+        Ok(())
+    }
+}
+
+impl StatefulOptimizer for SGD {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        state.insert(STEP_KEY.to_string(), step_tensor(self.step_t)?);
+        for var in self.vars.iter() {
+            if let Some(buf) = &var.momentum_buffer {
+                let name = var_name(&var.var)?;
+                state.insert(format!("{name}.momentum_buffer"), snapshot(buf)?);
+            }
+        }
+        Ok(state)
+    }
+
+    /// The momentum buffers are created on the first step, so they are required for all the
+    /// variables once the saved optimizer has been stepped with a non-zero momentum.
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()> {
+        let step_t = load_step(state)?;
+        for var in self.vars.iter_mut() {
+            let name = var_name(&var.var)?;
+            let key = format!("{name}.momentum_buffer");
+            var.momentum_buffer = if self.params.momentum == 0. || step_t == 0 {
+                check_unused(state, &key)?;
+                None
+            } else {
+                Some(Var::from_tensor(&load_tensor(&var.var, state, &key)?)?)
+            };
+        }
+        self.step_t = step_t;
+        Ok(())
     }
 }
 
@@ -199,3 +414,830 @@ impl AdamW {
         self.params = params;
     }
 }
+
+impl StatefulOptimizer for AdamW {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        state.insert(STEP_KEY.to_string(), step_tensor(self.step_t)?);
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            state.insert(format!("{name}.first_moment"), snapshot(&var.first_moment)?);
+            state.insert(
+                format!("{name}.second_moment"),
+                snapshot(&var.second_moment)?,
+            );
+        }
+        Ok(state)
+    }
+
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()> {
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            load_var(&var.first_moment, state, &format!("{name}.first_moment"))?;
+            load_var(&var.second_moment, state, &format!("{name}.second_moment"))?;
+        }
+        self.step_t = load_step(state)?;
+        Ok(())
+    }
+}
+
+/// Adam optimizer, contrary to [`AdamW`] the weight decay is applied as a L2 penalty added to the
+/// gradients as in the original paper.
+#[derive(Clone, Debug)]
+pub struct ParamsAdam {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsAdam {
+    fn default() -> Self {
+        Self {
+            lr: 0.001,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarAdam {
+    var: Var,
+    first_moment: Var,
+    second_moment: Var,
+}
+
+#[derive(Debug)]
+pub struct Adam {
+    vars: Vec<VarAdam>,
+    step_t: usize,
+    params: ParamsAdam,
+}
+
+impl Optimizer for Adam {
+    type Config = ParamsAdam;
+
+    fn new(vars: Vec<Var>, params: ParamsAdam) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let dtype = var.dtype();
+                let shape = var.shape();
+                let device = var.device();
+                let first_moment = Var::zeros(shape, dtype, device)?;
+                let second_moment = Var::zeros(shape, dtype, device)?;
+                Ok(VarAdam {
+                    var,
+                    first_moment,
+                    second_moment,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            params,
+            step_t: 0,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let lr = self.params.lr;
+        let beta1 = self.params.beta1;
+        let beta2 = self.params.beta2;
+        let scale_m = 1f64 / (1f64 - beta1.powi(self.step_t as i32));
+        let scale_v = 1f64 / (1f64 - beta2.powi(self.step_t as i32));
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = &var.first_moment;
+            let v = &var.second_moment;
+            if let Some(g) = grads.get(theta) {
+                let g = if self.params.weight_decay == 0. {
+                    g.clone()
+                } else {
+                    (g + (theta.as_tensor() * self.params.weight_decay)?)?
+                };
+                let next_m = ((m.as_tensor() * beta1)? + (&g * (1.0 - beta1))?)?;
+                let next_v = ((v.as_tensor() * beta2)? + (g.sqr()? * (1.0 - beta2))?)?;
+                let m_hat = (&next_m * scale_m)?;
+                let v_hat = (&next_v * scale_v)?;
+                let adjusted_grad = (m_hat / (v_hat.sqrt()? + self.params.eps)?)?;
+                let next_theta = (theta.as_tensor() - (adjusted_grad * lr)?)?;
+                m.set(&next_m)?;
+                v.set(&next_v)?;
+                theta.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Adam {
+    pub fn new_lr(vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
+        let params = ParamsAdam {
+            lr: learning_rate,
+            ..ParamsAdam::default()
+        };
+        Self::new(vars, params)
+    }
+
+    pub fn params(&self) -> &ParamsAdam {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsAdam) {
+        self.params = params;
+    }
+}
+
+impl StatefulOptimizer for Adam {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        state.insert(STEP_KEY.to_string(), step_tensor(self.step_t)?);
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            state.insert(format!("{name}.first_moment"), snapshot(&var.first_moment)?);
+            state.insert(
+                format!("{name}.second_moment"),
+                snapshot(&var.second_moment)?,
+            );
+        }
+        Ok(state)
+    }
+
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()> {
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            load_var(&var.first_moment, state, &format!("{name}.first_moment"))?;
+            load_var(&var.second_moment, state, &format!("{name}.second_moment"))?;
+        }
+        self.step_t = load_step(state)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParamsRMSprop {
+    pub lr: f64,
+    pub alpha: f64,
+    pub eps: f64,
+    pub weight_decay: f64,
+    pub momentum: f64,
+    /// When set, the gradients are normalized by an estimate of their variance rather than by
+    /// their uncentered second moment.
+    pub centered: bool,
+}
+
+impl Default for ParamsRMSprop {
+    fn default() -> Self {
+        Self {
+            lr: 0.01,
+            alpha: 0.99,
+            eps: 1e-8,
+            weight_decay: 0.,
+            momentum: 0.,
+            centered: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarRMSprop {
+    var: Var,
+    square_avg: Var,
+    grad_avg: Option<Var>,
+    momentum_buffer: Option<Var>,
+}
+
+#[derive(Debug)]
+pub struct RMSprop {
+    vars: Vec<VarRMSprop>,
+    params: ParamsRMSprop,
+}
+
+impl Optimizer for RMSprop {
+    type Config = ParamsRMSprop;
+
+    fn new(vars: Vec<Var>, params: ParamsRMSprop) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let dtype = var.dtype();
+                let shape = var.shape();
+                let device = var.device();
+                let square_avg = Var::zeros(shape, dtype, device)?;
+                let grad_avg = if params.centered {
+                    Some(Var::zeros(shape, dtype, device)?)
+                } else {
+                    None
+                };
+                let momentum_buffer = if params.momentum > 0. {
+                    Some(Var::zeros(shape, dtype, device)?)
+                } else {
+                    None
+                };
+                Ok(VarRMSprop {
+                    var,
+                    square_avg,
+                    grad_avg,
+                    momentum_buffer,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { vars, params })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        let lr = self.params.lr;
+        let alpha = self.params.alpha;
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            if let Some(g) = grads.get(theta) {
+                let g = if self.params.weight_decay == 0. {
+                    g.clone()
+                } else {
+                    (g + (theta.as_tensor() * self.params.weight_decay)?)?
+                };
+                let square_avg =
+                    ((var.square_avg.as_tensor() * alpha)? + (g.sqr()? * (1. - alpha))?)?;
+                var.square_avg.set(&square_avg)?;
+                let avg = match &var.grad_avg {
+                    None => square_avg.sqrt()?,
+                    Some(grad_avg) => {
+                        let next_grad_avg =
+                            ((grad_avg.as_tensor() * alpha)? + (&g * (1. - alpha))?)?;
+                        grad_avg.set(&next_grad_avg)?;
+                        (square_avg - next_grad_avg.sqr()?)?.sqrt()?
+                    }
+                };
+                let update = (g / (avg + self.params.eps)?)?;
+                let update = match &var.momentum_buffer {
+                    None => update,
+                    Some(buf) => {
+                        let next_buf = ((buf.as_tensor() * self.params.momentum)? + update)?;
+                        buf.set(&next_buf)?;
+                        next_buf
+                    }
+                };
+                theta.set(&(theta.as_tensor() - (update * lr)?)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RMSprop {
+    pub fn params(&self) -> &ParamsRMSprop {
+        &self.params
+    }
+
+    /// Updates the hyper-parameters, the gradient average and momentum buffers are created with
+    /// zeros or dropped when `centered` or `momentum` change.
+    pub fn set_params(&mut self, params: ParamsRMSprop) -> Result<()> {
+        for var in self.vars.iter_mut() {
+            let (shape, dtype, device) = (var.var.shape(), var.var.dtype(), var.var.device());
+            match (&var.grad_avg, params.centered) {
+                (None, true) => var.grad_avg = Some(Var::zeros(shape, dtype, device)?),
+                (Some(_), false) => var.grad_avg = None,
+                _ => {}
+            }
+            match (&var.momentum_buffer, params.momentum > 0.) {
+                (None, true) => var.momentum_buffer = Some(Var::zeros(shape, dtype, device)?),
+                (Some(_), false) => var.momentum_buffer = None,
+                _ => {}
+            }
+        }
+        self.params = params;
+        Ok(())
+    }
+}
+
+impl StatefulOptimizer for RMSprop {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            state.insert(format!("{name}.square_avg"), snapshot(&var.square_avg)?);
+            if let Some(grad_avg) = &var.grad_avg {
+                state.insert(format!("{name}.grad_avg"), snapshot(grad_avg)?);
+            }
+            if let Some(buf) = &var.momentum_buffer {
+                state.insert(format!("{name}.momentum_buffer"), snapshot(buf)?);
+            }
+        }
+        Ok(state)
+    }
+
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()> {
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            load_var(&var.square_avg, state, &format!("{name}.square_avg"))?;
+            let key = format!("{name}.grad_avg");
+            match &var.grad_avg {
+                Some(grad_avg) => load_var(grad_avg, state, &key)?,
+                None => check_unused(state, &key)?,
+            }
+            let key = format!("{name}.momentum_buffer");
+            match &var.momentum_buffer {
+                Some(buf) => load_var(buf, state, &key)?,
+                None => check_unused(state, &key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParamsAdagrad {
+    pub lr: f64,
+    pub lr_decay: f64,
+    pub weight_decay: f64,
+    pub initial_accumulator_value: f64,
+    pub eps: f64,
+}
+
+impl Default for ParamsAdagrad {
+    fn default() -> Self {
+        Self {
+            lr: 0.01,
+            lr_decay: 0.,
+            weight_decay: 0.,
+            initial_accumulator_value: 0.,
+            eps: 1e-10,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarAdagrad {
+    var: Var,
+    sum: Var,
+}
+
+#[derive(Debug)]
+pub struct Adagrad {
+    vars: Vec<VarAdagrad>,
+    step_t: usize,
+    params: ParamsAdagrad,
+}
+
+impl Optimizer for Adagrad {
+    type Config = ParamsAdagrad;
+
+    fn new(vars: Vec<Var>, params: ParamsAdagrad) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let sum = var
+                    .ones_like()?
+                    .affine(params.initial_accumulator_value, 0.)?;
+                let sum = Var::from_tensor(&sum)?;
+                Ok(VarAdagrad { var, sum })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            params,
+            step_t: 0,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let clr = self.params.lr / (1. + (self.step_t - 1) as f64 * self.params.lr_decay);
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            if let Some(g) = grads.get(theta) {
+                let g = if self.params.weight_decay == 0. {
+                    g.clone()
+                } else {
+                    (g + (theta.as_tensor() * self.params.weight_decay)?)?
+                };
+                let sum = (var.sum.as_tensor() + g.sqr()?)?;
+                var.sum.set(&sum)?;
+                let update = (g / (sum.sqrt()? + self.params.eps)?)?;
+                theta.set(&(theta.as_tensor() - (update * clr)?)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Adagrad {
+    pub fn params(&self) -> &ParamsAdagrad {
+        &self.params
+    }
+
+    /// Updates the hyper-parameters, `initial_accumulator_value` is only used when creating the
+    /// optimizer so changing it has no effect on the current accumulators.
+    pub fn set_params(&mut self, params: ParamsAdagrad) {
+        self.params = params;
+    }
+}
+
+impl StatefulOptimizer for Adagrad {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        state.insert(STEP_KEY.to_string(), step_tensor(self.step_t)?);
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            state.insert(format!("{name}.sum"), snapshot(&var.sum)?);
+        }
+        Ok(state)
+    }
+
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()> {
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            load_var(&var.sum, state, &format!("{name}.sum"))?;
+        }
+        self.step_t = load_step(state)?;
+        Ok(())
+    }
+}
+
+/// The Lion optimizer, see "Symbolic Discovery of Optimization Algorithms"
+/// <https://arxiv.org/abs/2302.06675>.
+#[derive(Clone, Debug)]
+pub struct ParamsLion {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsLion {
+    fn default() -> Self {
+        Self {
+            lr: 1e-4,
+            beta1: 0.9,
+            beta2: 0.99,
+            weight_decay: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarLion {
+    var: Var,
+    exp_avg: Var,
+}
+
+#[derive(Debug)]
+pub struct Lion {
+    vars: Vec<VarLion>,
+    params: ParamsLion,
+}
+
+impl Optimizer for Lion {
+    type Config = ParamsLion;
+
+    fn new(vars: Vec<Var>, params: ParamsLion) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let exp_avg = Var::zeros(var.shape(), var.dtype(), var.device())?;
+                Ok(VarLion { var, exp_avg })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { vars, params })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        let lr = self.params.lr;
+        let beta1 = self.params.beta1;
+        let beta2 = self.params.beta2;
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = &var.exp_avg;
+            if let Some(g) = grads.get(theta) {
+                let update = ((m.as_tensor() * beta1)? + (g * (1. - beta1))?)?.sign()?;
+                let next_theta = (theta.as_tensor() * (1. - lr * self.params.weight_decay))?;
+                let next_theta = (next_theta - (update * lr)?)?;
+                let next_m = ((m.as_tensor() * beta2)? + (g * (1. - beta2))?)?;
+                m.set(&next_m)?;
+                theta.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Lion {
+    pub fn params(&self) -> &ParamsLion {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsLion) {
+        self.params = params;
+    }
+}
+
+impl StatefulOptimizer for Lion {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            state.insert(format!("{name}.exp_avg"), snapshot(&var.exp_avg)?);
+        }
+        Ok(state)
+    }
+
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()> {
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            load_var(&var.exp_avg, state, &format!("{name}.exp_avg"))?;
+        }
+        Ok(())
+    }
+}
+
+/// The Adafactor optimizer, see "Adafactor: Adaptive Learning Rates with Sublinear Memory Cost"
+/// <https://arxiv.org/abs/1804.04235>.
+///
+/// For variables with at least two dimensions, the second moment is factored into row and column
+/// statistics over the last two dimensions so the memory cost for a `(n, m)` matrix is `n + m`
+/// rather than `n * m`. The implementation follows `torch.optim.Adafactor`.
+#[derive(Clone, Debug)]
+pub struct ParamsAdafactor {
+    pub lr: f64,
+    pub beta2_decay: f64,
+    /// Regularization constant for the squared gradients, defaults to the machine epsilon of the
+    /// variable dtype when `None`.
+    pub eps1: Option<f64>,
+    /// Regularization constant for the relative step size.
+    pub eps2: f64,
+    /// Clipping threshold for the root mean square of the update.
+    pub d: f64,
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsAdafactor {
+    fn default() -> Self {
+        Self {
+            lr: 0.01,
+            beta2_decay: -0.8,
+            eps1: None,
+            eps2: 1e-3,
+            d: 1.0,
+            weight_decay: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum AdafactorState {
+    Factored { row_var: Var, col_var: Var },
+    Full { variance: Var },
+}
+
+#[derive(Debug)]
+struct VarAdafactor {
+    var: Var,
+    state: AdafactorState,
+}
+
+#[derive(Debug)]
+pub struct Adafactor {
+    vars: Vec<VarAdafactor>,
+    step_t: usize,
+    params: ParamsAdafactor,
+}
+
+fn dtype_eps(dtype: candle::DType) -> f64 {
+    match dtype {
+        candle::DType::F64 => f64::EPSILON,
+        candle::DType::F16 => 0.0009765625,
+        candle::DType::BF16 => 0.0078125,
+        _ => f32::EPSILON as f64,
+    }
+}
+
+fn rms(t: &Tensor) -> Result<f64> {
+    t.to_dtype(candle::DType::F64)?
+        .sqr()?
+        .mean_all()?
+        .sqrt()?
+        .to_scalar::<f64>()
+}
+
+impl Optimizer for Adafactor {
+    type Config = ParamsAdafactor;
+
+    fn new(vars: Vec<Var>, params: ParamsAdafactor) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let dtype = var.dtype();
+                let device = var.device();
+                let dims = var.dims();
+                let state = if dims.len() >= 2 {
+                    let mut row_shape = dims.to_vec();
+                    row_shape[dims.len() - 1] = 1;
+                    let mut col_shape = dims.to_vec();
+                    col_shape[dims.len() - 2] = 1;
+                    AdafactorState::Factored {
+                        row_var: Var::zeros(row_shape, dtype, device)?,
+                        col_var: Var::zeros(col_shape, dtype, device)?,
+                    }
+                } else {
+                    AdafactorState::Full {
+                        variance: Var::zeros(var.shape(), dtype, device)?,
+                    }
+                };
+                Ok(VarAdafactor { var, state })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            params,
+            step_t: 0,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let step_t = self.step_t as f64;
+        let lr = self.params.lr;
+        let one_minus_beta2 = step_t.powf(self.params.beta2_decay);
+        let rho = f64::min(lr, 1. / step_t.sqrt());
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            if let Some(g) = grads.get(theta) {
+                let eps1 = self.params.eps1.unwrap_or_else(|| dtype_eps(theta.dtype()));
+                let alpha = f64::max(self.params.eps2, rms(theta.as_tensor())?) * rho;
+                let g_sqr = g.sqr()?;
+                let var_estimate = match &var.state {
+                    AdafactorState::Factored { row_var, col_var } => {
+                        let rank = g.rank();
+                        let row_mean = g_sqr.mean_keepdim(rank - 1)?;
+                        let col_mean = g_sqr.mean_keepdim(rank - 2)?;
+                        let row = row_var.as_tensor();
+                        let next_row = (row + ((row_mean - row)? * one_minus_beta2)?)?;
+                        let col = col_var.as_tensor();
+                        let next_col = (col + ((col_mean - col)? * one_minus_beta2)?)?;
+                        row_var.set(&next_row)?;
+                        col_var.set(&next_col)?;
+                        let row_mean = next_row.mean_keepdim(rank - 2)?.maximum(eps1)?;
+                        next_row
+                            .broadcast_mul(&next_col)?
+                            .broadcast_div(&row_mean)?
+                    }
+                    AdafactorState::Full { variance } => {
+                        let v = variance.as_tensor();
+                        let next_v = (v + ((g_sqr - v)? * one_minus_beta2)?)?;
+                        variance.set(&next_v)?;
+                        next_v
+                    }
+                };
+                let update = (var_estimate.maximum(eps1 * eps1)?.sqrt()?.recip()? * g)?;
+                let denom = f64::max(1., rms(&update)? / self.params.d);
+                let next_theta = if self.params.weight_decay == 0. {
+                    theta.as_tensor().clone()
+                } else {
+                    (theta.as_tensor() * (1. - lr * self.params.weight_decay))?
+                };
+                theta.set(&(next_theta - (update * (alpha / denom))?)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Adafactor {
+    pub fn params(&self) -> &ParamsAdafactor {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsAdafactor) {
+        self.params = params;
+    }
+}
+
+impl StatefulOptimizer for Adafactor {
+    fn state_dict<F: Fn(&Var) -> Result<String>>(
+        &self,
+        var_name: F,
+    ) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        state.insert(STEP_KEY.to_string(), step_tensor(self.step_t)?);
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            match &var.state {
+                AdafactorState::Factored { row_var, col_var } => {
+                    state.insert(format!("{name}.row_var"), snapshot(row_var)?);
+                    state.insert(format!("{name}.col_var"), snapshot(col_var)?);
+                }
+                AdafactorState::Full { variance } => {
+                    state.insert(format!("{name}.variance"), snapshot(variance)?);
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    fn load_state_dict<F: Fn(&Var) -> Result<String>>(
+        &mut self,
+        state: &HashMap<String, Tensor>,
+        var_name: F,
+    ) -> Result<()> {
+        for var in self.vars.iter() {
+            let name = var_name(&var.var)?;
+            match &var.state {
+                AdafactorState::Factored { row_var, col_var } => {
+                    load_var(row_var, state, &format!("{name}.row_var"))?;
+                    load_var(col_var, state, &format!("{name}.col_var"))?;
+                }
+                AdafactorState::Full { variance } => {
+                    load_var(variance, state, &format!("{name}.variance"))?;
+                }
+            }
+        }
+        self.step_t = load_step(state)?;
+        Ok(())
+    }
+}
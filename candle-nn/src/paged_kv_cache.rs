@@ -0,0 +1,369 @@
+//! Paged key-value cache for serving multiple sequences.
+//!
+//! The keys and values of all the sequences are stored in a shared pool of fixed-size blocks.
+//! Each sequence has a [`BlockTable`] listing the blocks holding its positions, blocks are
+//! allocated on demand by a [`BlockAllocator`] and can be shared between sequences, e.g. when
+//! forking a sequence to sample multiple continuations of a prompt. A shared block is copied
+//! before being written to.
+//!
+//! The allocator and block tables are shared by all the layers of a model whereas each layer
+//! has its own [`PagedKvCache`].
+//!
+//! ```ignore
+//! let mut allocator = BlockAllocator::new(num_blocks, block_size);
+//! let mut caches = (0..n_layers)
+//!     .map(|_| PagedKvCache::new(num_blocks, block_size, n_kv_heads, head_dim, dtype, &dev))
+//!     .collect::<Result<Vec<_>>>()?;
+//! let mut table = allocator.new_table();
+//!
+//! // For each step, reserve the slots once and then write to the cache of each layer.
+//! let slots = allocator.reserve(&mut table, seq_len)?;
+//! for cache in caches.iter_mut() {
+//!     // q: (seq_len, n_heads, head_dim), k & v: (seq_len, n_kv_heads, head_dim)
+//!     cache.append(&k, &v, &slots)?;
+//!     let ys = cache.attention(&q, &table, 1. / (head_dim as f32).sqrt())?;
+//! }
+//! allocator.free(table);
+//! ```
+use candle::{DType, Device, Result, Storage, Tensor, WithDType};
+use rayon::prelude::*;
+
+/// The blocks used by a sequence, in order, and the number of positions that they hold.
+#[derive(Debug)]
+pub struct BlockTable {
+    blocks: Vec<usize>,
+    num_tokens: usize,
+    block_size: usize,
+}
+
+impl BlockTable {
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_tokens == 0
+    }
+
+    /// The index in the pool of the slot holding position `pos`.
+    pub fn slot(&self, pos: usize) -> usize {
+        self.blocks[pos / self.block_size] * self.block_size + pos % self.block_size
+    }
+
+    /// The slots for all the positions of the sequence.
+    pub fn slots(&self) -> Vec<usize> {
+        (0..self.num_tokens).map(|pos| self.slot(pos)).collect()
+    }
+}
+
+/// The slots reserved for new positions of a sequence by [`BlockAllocator::reserve`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotMapping {
+    /// The slot for each new position.
+    pub slots: Vec<usize>,
+    /// The `(src, dst)` blocks to copy before writing, as the last block of the sequence was
+    /// shared with another sequence.
+    pub copies: Vec<(usize, usize)>,
+}
+
+/// Keeps track of the free blocks of the pool and of the number of sequences using each block.
+#[derive(Debug, Clone)]
+pub struct BlockAllocator {
+    block_size: usize,
+    ref_counts: Vec<usize>,
+    free_blocks: Vec<usize>,
+}
+
+impl BlockAllocator {
+    pub fn new(num_blocks: usize, block_size: usize) -> Self {
+        Self {
+            block_size,
+            ref_counts: vec![0; num_blocks],
+            // Reversed so that the blocks get allocated in increasing order.
+            free_blocks: (0..num_blocks).rev().collect(),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.ref_counts.len()
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    /// The number of sequences using `block`.
+    pub fn ref_count(&self, block: usize) -> usize {
+        self.ref_counts[block]
+    }
+
+    /// An empty block table, no block is allocated until positions get reserved.
+    pub fn new_table(&self) -> BlockTable {
+        BlockTable {
+            blocks: vec![],
+            num_tokens: 0,
+            block_size: self.block_size,
+        }
+    }
+
+    fn allocate(&mut self) -> usize {
+        // The callers check that there are enough free blocks beforehand.
+        let block = self.free_blocks.pop().expect("no free block left");
+        self.ref_counts[block] = 1;
+        block
+    }
+
+    fn release(&mut self, block: usize) {
+        self.ref_counts[block] -= 1;
+        if self.ref_counts[block] == 0 {
+            self.free_blocks.push(block)
+        }
+    }
+
+    /// The number of blocks to allocate for adding `n_tokens` positions to `table`.
+    pub fn blocks_needed(&self, table: &BlockTable, n_tokens: usize) -> usize {
+        let capacity = table.blocks.len() * self.block_size;
+        let new_blocks = (table.num_tokens + n_tokens).saturating_sub(capacity);
+        let cow = match table.blocks.last() {
+            Some(&last) => n_tokens > 0 && table.num_tokens < capacity && self.ref_counts[last] > 1,
+            None => false,
+        };
+        new_blocks.div_ceil(self.block_size) + usize::from(cow)
+    }
+
+    /// Reserves the slots for `n_tokens` new positions at the end of the sequence, allocating
+    /// the blocks as needed. This fails without modifying the table if there are not enough free
+    /// blocks.
+    pub fn reserve(&mut self, table: &mut BlockTable, n_tokens: usize) -> Result<SlotMapping> {
+        let needed = self.blocks_needed(table, n_tokens);
+        if needed > self.free_blocks.len() {
+            candle::bail!(
+                "paged kv-cache is full, {needed} blocks needed, {} free",
+                self.free_blocks.len()
+            )
+        }
+        let mut copies = vec![];
+        if let Some(&last) = table.blocks.last() {
+            let partially_filled = table.num_tokens < table.blocks.len() * self.block_size;
+            if n_tokens > 0 && partially_filled && self.ref_counts[last] > 1 {
+                let block = self.allocate();
+                self.release(last);
+                copies.push((last, block));
+                *table.blocks.last_mut().unwrap() = block;
+            }
+        }
+        let mut slots = Vec::with_capacity(n_tokens);
+        for pos in table.num_tokens..table.num_tokens + n_tokens {
+            if pos / self.block_size >= table.blocks.len() {
+                let block = self.allocate();
+                table.blocks.push(block)
+            }
+            slots.push(table.slot(pos))
+        }
+        table.num_tokens += n_tokens;
+        Ok(SlotMapping { slots, copies })
+    }
+
+    /// Returns a new table sharing the blocks of `table`.
+    pub fn fork(&mut self, table: &BlockTable) -> BlockTable {
+        for &block in table.blocks.iter() {
+            self.ref_counts[block] += 1
+        }
+        BlockTable {
+            blocks: table.blocks.clone(),
+            num_tokens: table.num_tokens,
+            block_size: table.block_size,
+        }
+    }
+
+    /// Releases the blocks of a sequence, the blocks not used by other sequences become free.
+    pub fn free(&mut self, table: BlockTable) {
+        for block in table.blocks {
+            self.release(block)
+        }
+    }
+}
+
+/// The keys and values of a layer, the pool of blocks is stored in two tensors of shape
+/// `(num_blocks * block_size, num_kv_heads, head_dim)`.
+#[derive(Debug, Clone)]
+pub struct PagedKvCache {
+    k: Tensor,
+    v: Tensor,
+    block_size: usize,
+}
+
+impl PagedKvCache {
+    pub fn new(
+        num_blocks: usize,
+        block_size: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let shape = (num_blocks * block_size, num_kv_heads, head_dim);
+        let k = Tensor::zeros(shape, dtype, device)?;
+        let v = Tensor::zeros(shape, dtype, device)?;
+        Ok(Self { k, v, block_size })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn k(&self) -> &Tensor {
+        &self.k
+    }
+
+    pub fn v(&self) -> &Tensor {
+        &self.v
+    }
+
+    /// Copies the `(src, dst)` blocks.
+    pub fn copy_blocks(&mut self, copies: &[(usize, usize)]) -> Result<()> {
+        let bs = self.block_size;
+        for &(src, dst) in copies.iter() {
+            for t in [&self.k, &self.v] {
+                let block = t.narrow(0, src * bs, bs)?.copy()?;
+                t.slice_set(&block, 0, dst * bs)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `k` and `v`, of shape `(n_tokens, num_kv_heads, head_dim)`, to the reserved slots
+    /// after performing the copy-on-write block copies.
+    pub fn append(&mut self, k: &Tensor, v: &Tensor, slots: &SlotMapping) -> Result<()> {
+        self.copy_blocks(&slots.copies)?;
+        if slots.slots.is_empty() {
+            return Ok(());
+        }
+        let (n_tokens, _, _) = k.dims3()?;
+        if n_tokens != slots.slots.len() {
+            candle::bail!(
+                "paged kv-cache: {n_tokens} tokens for {} slots",
+                slots.slots.len()
+            )
+        }
+        let indices = slots.slots.iter().map(|&s| s as u32).collect::<Vec<_>>();
+        let indices = Tensor::new(indices, self.k.device())?
+            .reshape((n_tokens, 1, 1))?
+            .broadcast_as(k.shape())?
+            .contiguous()?;
+        self.k.scatter_set(&indices, &k.contiguous()?, 0)?;
+        self.v.scatter_set(&indices, &v.contiguous()?, 0)?;
+        Ok(())
+    }
+
+    /// The keys and values of a sequence as contiguous tensors of shape
+    /// `(num_tokens, num_kv_heads, head_dim)`.
+    pub fn gather(&self, table: &BlockTable) -> Result<(Tensor, Tensor)> {
+        let slots = table.slots().iter().map(|&s| s as u32).collect::<Vec<_>>();
+        let slots = Tensor::new(slots, self.k.device())?;
+        let k = self.k.index_select(&slots, 0)?;
+        let v = self.v.index_select(&slots, 0)?;
+        Ok((k, v))
+    }
+
+    /// Causal attention for the last `q_len` positions of a sequence, reading the keys and values
+    /// through the block table. `q` has shape `(q_len, num_heads, head_dim)`, the number of heads
+    /// has to be a multiple of the number of kv heads. The output has the same shape as `q`.
+    ///
+    /// This is only implemented for the cpu, use [`PagedKvCache::gather`] on other devices.
+    pub fn attention(&self, q: &Tensor, table: &BlockTable, softmax_scale: f32) -> Result<Tensor> {
+        match q.dtype() {
+            DType::F32 => self.attention_::<f32>(q, table, softmax_scale),
+            DType::F64 => self.attention_::<f64>(q, table, softmax_scale),
+            DType::F16 => self.attention_::<half::f16>(q, table, softmax_scale),
+            DType::BF16 => self.attention_::<half::bf16>(q, table, softmax_scale),
+            dtype => candle::bail!("paged attention is not supported for {dtype:?}"),
+        }
+    }
+
+    fn attention_<T: WithDType>(
+        &self,
+        q: &Tensor,
+        table: &BlockTable,
+        softmax_scale: f32,
+    ) -> Result<Tensor> {
+        let (q_len, n_heads, head_dim) = q.dims3()?;
+        let (_, n_kv_heads, kv_head_dim) = self.k.dims3()?;
+        if head_dim != kv_head_dim || !n_heads.is_multiple_of(n_kv_heads) {
+            candle::bail!(
+                "paged attention: unexpected q shape {:?} for kv heads {n_kv_heads}x{kv_head_dim}",
+                q.shape()
+            )
+        }
+        if q_len > table.num_tokens {
+            candle::bail!(
+                "paged attention: {q_len} queries for {} positions",
+                table.num_tokens
+            )
+        }
+        let q = q.contiguous()?;
+        let (q_guard, q_layout) = q.storage_and_layout();
+        let (k_guard, k_layout) = self.k.storage_and_layout();
+        let (v_guard, v_layout) = self.v.storage_and_layout();
+        let (q_data, k_data, v_data) = match (&*q_guard, &*k_guard, &*v_guard) {
+            (Storage::Cpu(q_s), Storage::Cpu(k_s), Storage::Cpu(v_s)) => (
+                &q_s.as_slice::<T>()?[q_layout.start_offset()..],
+                &k_s.as_slice::<T>()?[k_layout.start_offset()..],
+                &v_s.as_slice::<T>()?[v_layout.start_offset()..],
+            ),
+            _ => candle::bail!("paged attention is only supported on cpu"),
+        };
+
+        let slots = table.slots();
+        let kv_group = n_heads / n_kv_heads;
+        let mut out = vec![T::zero(); q_len * n_heads * head_dim];
+        out.par_chunks_mut(head_dim)
+            .enumerate()
+            .for_each(|(idx, out)| {
+                let (i, h) = (idx / n_heads, idx % n_heads);
+                let q = &q_data[idx * head_dim..(idx + 1) * head_dim];
+                let kv_offset = (h / kv_group) * head_dim;
+                // The query at index i can attend to all the positions up to its own.
+                let n_ctx = table.num_tokens - q_len + i + 1;
+                let mut scores = slots[..n_ctx]
+                    .iter()
+                    .map(|&slot| {
+                        let start = slot * n_kv_heads * head_dim + kv_offset;
+                        let k = &k_data[start..start + head_dim];
+                        let dot = q
+                            .iter()
+                            .zip(k.iter())
+                            .map(|(q, k)| q.to_f64() as f32 * k.to_f64() as f32)
+                            .sum::<f32>();
+                        dot * softmax_scale
+                    })
+                    .collect::<Vec<_>>();
+                let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let mut sum = 0f32;
+                for s in scores.iter_mut() {
+                    *s = (*s - max).exp();
+                    sum += *s
+                }
+                let mut acc = vec![0f32; head_dim];
+                for (&slot, &p) in slots[..n_ctx].iter().zip(scores.iter()) {
+                    let start = slot * n_kv_heads * head_dim + kv_offset;
+                    let v = &v_data[start..start + head_dim];
+                    for (acc, v) in acc.iter_mut().zip(v.iter()) {
+                        *acc += p * v.to_f64() as f32
+                    }
+                }
+                for (out, acc) in out.iter_mut().zip(acc.iter()) {
+                    *out = T::from_f64((acc / sum) as f64)
+                }
+            });
+        Tensor::from_vec(out, (q_len, n_heads, head_dim), &Device::Cpu)
+    }
+}
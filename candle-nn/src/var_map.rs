@@ -1,5 +1,6 @@
 //! A `VarMap` is a store that holds named variables.
 //!
+use crate::optim::StatefulOptimizer;
 use candle::{DType, Device, Result, Shape, Tensor, Var};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
@@ -53,6 +54,49 @@ impl VarMap {
         Ok(())
     }
 
+    fn var_names(&self) -> HashMap<candle::TensorId, String> {
+        let tensor_data = self.data.lock().unwrap();
+        tensor_data
+            .iter()
+            .map(|(name, var)| (var.id(), name.clone()))
+            .collect()
+    }
+
+    fn name_of(names: &HashMap<candle::TensorId, String>, var: &Var) -> Result<String> {
+        match names.get(&var.id()) {
+            Some(name) => Ok(name.clone()),
+            None => candle::bail!(
+                "optimizer variable {:?} is not part of the VarMap",
+                var.id()
+            ),
+        }
+    }
+
+    /// Save the state of an optimizer in the safetensors format, the per-variable state uses the
+    /// variable names from this map so that it can be reloaded in a different process with
+    /// [`VarMap::load_optimizer_state`].
+    pub fn save_optimizer_state<O: StatefulOptimizer, P: AsRef<std::path::Path>>(
+        &self,
+        optimizer: &O,
+        path: P,
+    ) -> Result<()> {
+        let names = self.var_names();
+        let state = optimizer.state_dict(|var| Self::name_of(&names, var))?;
+        candle::safetensors::save(&state, path)
+    }
+
+    /// Restore the state of an optimizer from a safetensors file written by
+    /// [`VarMap::save_optimizer_state`].
+    pub fn load_optimizer_state<O: StatefulOptimizer, P: AsRef<std::path::Path>>(
+        &self,
+        optimizer: &mut O,
+        path: P,
+    ) -> Result<()> {
+        let names = self.var_names();
+        let state = candle::safetensors::load(path, &candle::Device::Cpu)?;
+        optimizer.load_state_dict(&state, |var| Self::name_of(&names, var))
+    }
+
     /// Set a named variable to some value.
     pub fn set_one<K: AsRef<str>, V: AsRef<Tensor>>(&mut self, name: K, value: V) -> Result<()> {
         let tensor_data = self.data.lock().unwrap();
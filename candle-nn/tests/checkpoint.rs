@@ -0,0 +1,86 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec2_round;
+use candle::{Device, Module, Tensor, Var};
+use candle_nn::{checkpoint, Checkpoint, Linear};
+
+#[test]
+fn checkpoint_grads() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w1 = Var::new(&[[1f32, -2.], [0.5, 0.3]], dev)?;
+    let b1 = Var::new(&[0.1f32, -0.2], dev)?;
+    let w2 = Var::new(&[[0.7f32, 0.2], [-0.4, 1.1]], dev)?;
+    let xs = Var::new(&[[1f32, 0.2], [-1., 0.5]], dev)?;
+    let l1 = Linear::new(w1.as_tensor().clone(), Some(b1.as_tensor().clone()));
+    let l2 = Linear::new(w2.as_tensor().clone(), None);
+    let block = move |xs: &Tensor| xs.apply(&l1)?.tanh()?.apply(&l2);
+
+    let loss = block(&xs)?.sqr()?.sum_all()?;
+    let expected = loss.backward()?;
+    let ys = checkpoint(block.clone(), &xs)?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&block(&xs)?, 4)?);
+    let grads = ys.sqr()?.sum_all()?.backward()?;
+    for var in [&w1, &w2, &xs] {
+        assert_eq!(
+            to_vec2_round(grads.get(var).unwrap(), 4)?,
+            to_vec2_round(expected.get(var).unwrap(), 4)?,
+        );
+    }
+    assert_eq!(
+        grads.get(&b1).unwrap().to_vec1::<f32>()?,
+        expected.get(&b1).unwrap().to_vec1::<f32>()?,
+    );
+
+    // Nested segments, the input of the model is not a variable.
+    let inner = Checkpoint::new(block.clone());
+    let outer = Checkpoint::new(move |xs: &Tensor| inner.forward(&xs.sqr()?));
+    let xs = xs.as_tensor().detach();
+    let loss = outer.forward(&xs)?.sqr()?.sum_all()?;
+    let grads = loss.backward()?;
+    let expected = block(&xs.sqr()?)?.sqr()?.sum_all()?.backward()?;
+    for var in [&w1, &w2] {
+        assert_eq!(
+            to_vec2_round(grads.get(var).unwrap(), 4)?,
+            to_vec2_round(expected.get(var).unwrap(), 4)?,
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn checkpoint_without_vars() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(&[[1f32, -2.], [0.5, 0.3]], dev)?;
+    let xs = Tensor::new(&[[1f32, 0.2], [-1., 0.5]], dev)?;
+    // At inference neither the input nor the weights are variables, so nothing is recorded and
+    // the later ops are not tracked either.
+    let ys = checkpoint(Linear::new(w.clone(), None), &xs)?;
+    assert!(!ys.track_op());
+    assert!(!ys.sqr()?.track_op());
+
+    // The segment is recorded when the module uses a variable, even with an untracked input.
+    let w = Var::from_tensor(&w)?;
+    let ys = checkpoint(Linear::new(w.as_tensor().clone(), None), &xs)?;
+    assert!(ys.track_op());
+    let grads = ys.sum_all()?.backward()?;
+    assert_eq!(
+        to_vec2_round(grads.get(&w).unwrap(), 4)?,
+        [[0., 0.7], [0., 0.7]]
+    );
+    Ok(())
+}
+
+#[test]
+fn no_grad() -> Result<()> {
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let y = candle::backprop::no_grad(|| x.sqr())?;
+    assert!(!y.track_op());
+    assert!(y.sum_all()?.backward()?.get(&x).is_none());
+    assert!(x.sqr()?.track_op());
+    Ok(())
+}
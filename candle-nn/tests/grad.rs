@@ -0,0 +1,86 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::{to_vec1_round, to_vec2_round};
+use candle::{Device, Tensor, Var};
+use candle_nn::grad::{
+    add_grads, clip_grad_norm, clip_grad_value, global_norm, has_non_finite, GradAccumulator,
+};
+
+#[test]
+fn clip_grads() -> Result<()> {
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let y = Var::new(&[[2f32, 0.], [0., 0.]], &Device::Cpu)?;
+    let vars = vec![x.clone(), y.clone()];
+    // The gradients are 2.x and 2.y.
+    let loss = (x.sqr()?.sum_all()? + y.sqr()?.sum_all()?)?;
+    let mut grads = loss.backward()?;
+    assert_eq!(global_norm(&grads, &vars)?, 6.);
+
+    let norm = clip_grad_norm(&mut grads, &vars, 3.)?;
+    assert_eq!(norm, 6.);
+    assert_eq!(to_vec1_round(grads.get(&x).unwrap(), 4)?, [1., 2.]);
+    assert_eq!(
+        to_vec2_round(grads.get(&y).unwrap(), 4)?,
+        [[2., 0.], [0., 0.]]
+    );
+    assert!((global_norm(&grads, &vars)? - 3.).abs() < 1e-5);
+
+    // Clipping has no effect when the norm is already below the threshold.
+    let norm = clip_grad_norm(&mut grads, &vars, 10.)?;
+    assert!((norm - 3.).abs() < 1e-5);
+    assert_eq!(to_vec1_round(grads.get(&x).unwrap(), 4)?, [1., 2.]);
+
+    clip_grad_value(&mut grads, &vars, 1.5)?;
+    assert_eq!(to_vec1_round(grads.get(&x).unwrap(), 4)?, [1., 1.5]);
+    assert_eq!(
+        to_vec2_round(grads.get(&y).unwrap(), 4)?,
+        [[1.5, 0.], [0., 0.]]
+    );
+
+    // The norm of F64 gradients is computed in F64.
+    let z = Var::new(&[1f64, 1.], &Device::Cpu)?;
+    let g = Tensor::new(&[3.0000001f64, 4.], &Device::Cpu)?;
+    let grads = (z.as_tensor() * &g)?.sum_all()?.backward()?;
+    let norm = global_norm(&grads, &[z])?;
+    assert!((norm - (3.0000001f64.powi(2) + 16.).sqrt()).abs() < 1e-12);
+    Ok(())
+}
+
+#[test]
+fn non_finite_grads() -> Result<()> {
+    let x = Var::new(&[1f32, 0.], &Device::Cpu)?;
+    let vars = vec![x.clone()];
+    let grads = x.sqr()?.sum_all()?.backward()?;
+    assert!(!has_non_finite(&grads, &vars)?);
+    // The derivative of sqrt is infinite in 0.
+    let grads = x.sqrt()?.sum_all()?.backward()?;
+    assert!(has_non_finite(&grads, &vars)?);
+    Ok(())
+}
+
+#[test]
+fn accumulate_grads() -> Result<()> {
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let vars = vec![x.clone()];
+    let mut acc = GradAccumulator::new(vars.clone());
+    assert!(acc.finish(true)?.is_none());
+    for scale in [1f64, 2., 3.] {
+        let loss = (x.as_tensor() * scale)?.sum_all()?;
+        acc.accumulate(loss.backward()?)?;
+    }
+    assert_eq!(acc.num_steps(), 3);
+    let grads = acc.finish(true)?.unwrap();
+    assert_eq!(grads.get(&x).unwrap().to_vec1::<f32>()?, [2., 2.]);
+    assert_eq!(acc.num_steps(), 0);
+
+    let mut grads = x.sum_all()?.backward()?;
+    let other = (x.as_tensor() * 4.)?.sum_all()?.backward()?;
+    add_grads(&mut grads, &other, &vars)?;
+    assert_eq!(grads.get(&x).unwrap().to_vec1::<f32>()?, [5., 5.]);
+    Ok(())
+}
@@ -121,3 +121,295 @@ fn rotating_kv_cache() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn quantized_kv_cache() -> Result<()> {
+    use candle::quantized::{GgmlDType, QTensor};
+    use candle_nn::kv_cache::{KvCacheDType, QuantizedKvCache};
+
+    let dev = &Device::Cpu;
+    let xs = (Tensor::arange(0f32, 320., dev)?.reshape((1, 2, 5, 32))? / 320.)?;
+    let max_err = |a: &Tensor, b: &Tensor| (a - b)?.abs()?.max_all()?.to_scalar::<f32>();
+    for (dtype, tol) in [
+        (KvCacheDType::Q8_0, 5e-3),
+        (KvCacheDType::Q4_0, 7e-2),
+        (KvCacheDType::F8E4M3, 5e-2),
+    ] {
+        let mut cache = QuantizedKvCache::new(2, 4, dtype);
+        for _ in [0, 1] {
+            assert_eq!(cache.current_seq_len(), 0);
+            assert!(cache.k()?.is_none());
+            let prefill = xs.narrow(2, 0, 3)?;
+            let (k1, v1) = cache.append(&prefill, &prefill.neg()?)?;
+            assert_eq!(k1.dims(), [1, 2, 3, 32]);
+            assert_eq!(v1.dims(), [1, 2, 3, 32]);
+            // Grow past the initial capacity.
+            let step = xs.narrow(2, 3, 2)?;
+            let (k, v) = cache.append(&step, &step.neg()?)?;
+            assert_eq!(k.dims(), [1, 2, 5, 32]);
+            assert_eq!(k.dtype(), candle::DType::F32);
+            assert_eq!(cache.current_seq_len(), 5);
+            assert!(max_err(&k, &xs)? < tol, "{dtype:?}");
+            assert!(max_err(&v, &xs.neg()?)? < tol, "{dtype:?}");
+            // The values already in the cache are not quantized again.
+            assert_eq!(max_err(&k.narrow(2, 0, 3)?, &k1)?, 0.);
+            assert_eq!(max_err(&cache.v()?.unwrap(), &v)?, 0.);
+            // The block formats match the ggml quantization.
+            let ggml_dtype = match dtype {
+                KvCacheDType::Q8_0 => Some(GgmlDType::Q8_0),
+                KvCacheDType::Q4_0 => Some(GgmlDType::Q4_0),
+                KvCacheDType::F8E4M3 => None,
+            };
+            if let Some(ggml_dtype) = ggml_dtype {
+                let expected = QTensor::quantize(&xs, ggml_dtype)?.dequantize(dev)?;
+                assert!(max_err(&k, &expected)? < 1e-6, "{dtype:?}");
+            }
+            cache.reset();
+        }
+    }
+
+    // The block formats require whole blocks for each position.
+    let mut cache = QuantizedKvCache::new(2, 4, KvCacheDType::Q4_0);
+    let xs = Tensor::zeros((1, 1, 2, 16), candle::DType::F32, dev)?;
+    assert!(cache.append(&xs, &xs).is_err());
+    let mut cache = QuantizedKvCache::new(2, 4, KvCacheDType::Q8_0);
+    cache.append(&xs.reshape((1, 1, 1, 32))?, &xs.reshape((1, 1, 1, 32))?)?;
+    assert_eq!(cache.k_cache().storage_size_in_bytes(), 34);
+    let mut cache = QuantizedKvCache::new(2, 4, KvCacheDType::F8E4M3);
+    cache.append(&xs, &xs)?;
+    assert_eq!(cache.k_cache().storage_size_in_bytes(), 32);
+    Ok(())
+}
+
+#[test]
+fn paged_kv_cache() -> Result<()> {
+    use candle::DType;
+    use candle_nn::paged_kv_cache::{BlockAllocator, PagedKvCache};
+
+    let dev = &Device::Cpu;
+    let (n_heads, n_kv_heads, head_dim) = (4, 2, 8);
+    let mut allocator = BlockAllocator::new(4, 4);
+    let mut cache = PagedKvCache::new(4, 4, n_kv_heads, head_dim, DType::F32, dev)?;
+    let mut table = allocator.new_table();
+    let k = Tensor::randn(0f32, 1., (6, n_kv_heads, head_dim), dev)?;
+    let v = Tensor::randn(0f32, 1., (6, n_kv_heads, head_dim), dev)?;
+    let slots = allocator.reserve(&mut table, 6)?;
+    assert_eq!(slots.slots, [0, 1, 2, 3, 4, 5]);
+    assert!(slots.copies.is_empty());
+    cache.append(&k, &v, &slots)?;
+    assert_eq!(table.blocks(), [0, 1]);
+    assert_eq!(allocator.num_free_blocks(), 2);
+
+    // The forked sequence shares the blocks until it writes to them.
+    let mut forked = allocator.fork(&table);
+    assert_eq!(allocator.ref_count(1), 2);
+    let k1 = Tensor::randn(0f32, 1., (1, n_kv_heads, head_dim), dev)?;
+    let v1 = Tensor::randn(0f32, 1., (1, n_kv_heads, head_dim), dev)?;
+    let slots = allocator.reserve(&mut forked, 1)?;
+    assert_eq!(slots.copies, [(1, 2)]);
+    assert_eq!(slots.slots, [10]);
+    cache.append(&k1, &v1, &slots)?;
+    assert_eq!(forked.blocks(), [0, 2]);
+    assert_eq!(allocator.ref_count(0), 2);
+    assert_eq!(allocator.ref_count(1), 1);
+    let (k_orig, v_orig) = cache.gather(&table)?;
+    assert_eq!(k_orig.to_vec3::<f32>()?, k.to_vec3::<f32>()?);
+    assert_eq!(v_orig.to_vec3::<f32>()?, v.to_vec3::<f32>()?);
+    let (k_fork, v_fork) = cache.gather(&forked)?;
+    let k_all = Tensor::cat(&[&k, &k1], 0)?;
+    assert_eq!(k_fork.to_vec3::<f32>()?, k_all.to_vec3::<f32>()?);
+
+    // Attention through the block table for the last 3 positions, compared to the attention on
+    // the gathered keys and values.
+    let q = Tensor::randn(0f32, 1., (3, n_heads, head_dim), dev)?;
+    let ys = cache.attention(&q, &forked, 0.5)?;
+    assert_eq!(ys.dims(), [3, n_heads, head_dim]);
+    let repeat_kv = |xs: &Tensor| {
+        xs.transpose(0, 1)?
+            .unsqueeze(1)?
+            .broadcast_as((n_kv_heads, 2, 7, head_dim))?
+            .reshape((n_heads, 7, head_dim))
+    };
+    let mask: Vec<f32> = (0..3)
+        .flat_map(|i| (0..7).map(move |j| if j > 4 + i { f32::NEG_INFINITY } else { 0. }))
+        .collect();
+    let mask = Tensor::from_vec(mask, (3, 7), dev)?;
+    let att = q
+        .transpose(0, 1)?
+        .contiguous()?
+        .matmul(&repeat_kv(&k_fork)?.t()?)?;
+    let att = (att * 0.5)?.broadcast_add(&mask)?;
+    let att = candle_nn::ops::softmax_last_dim(&att)?;
+    let expected = att.matmul(&repeat_kv(&v_fork)?)?.transpose(0, 1)?;
+    let diff = (ys - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+
+    allocator.free(table);
+    allocator.free(forked);
+    assert_eq!(allocator.num_free_blocks(), 4);
+    let mut table = allocator.new_table();
+    assert!(allocator.reserve(&mut table, 17).is_err());
+    assert!(table.is_empty());
+    assert_eq!(allocator.num_free_blocks(), 4);
+    Ok(())
+}
+
+#[test]
+fn kv_cache_truncate_restore() -> Result<()> {
+    use candle_nn::kv_cache::{ConcatKvCache, KvCache};
+
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 24., dev)?.reshape((2, 6, 2))?;
+    let mut cache = KvCache::new(1, 4);
+    cache.append(&xs, &xs.neg()?)?;
+    cache.truncate(4)?;
+    assert_eq!(cache.current_seq_len(), 4);
+    assert!(cache.truncate(5).is_err());
+    let last = xs.narrow(1, 5, 1)?.contiguous()?;
+    let (k, v) = cache.append(&last, &last)?;
+    assert_eq!(k.dims(), [2, 5, 2]);
+    let expected = Tensor::cat(&[&xs.narrow(1, 0, 4)?, &xs.narrow(1, 5, 1)?], 1)?;
+    assert_eq!(k.to_vec3::<f32>()?, expected.to_vec3::<f32>()?);
+    let (k_state, v_state) = cache.state()?.unwrap();
+    let mut restored = KvCache::new(1, 2);
+    restored.restore(&k_state, &v_state)?;
+    assert_eq!(restored.current_seq_len(), 5);
+    assert_eq!(
+        restored.k()?.unwrap().to_vec3::<f32>()?,
+        k.to_vec3::<f32>()?
+    );
+    assert_eq!(
+        restored.v()?.unwrap().to_vec3::<f32>()?,
+        v.to_vec3::<f32>()?
+    );
+
+    let mut cache = ConcatKvCache::new(1);
+    cache.append(&xs, &xs)?;
+    cache.truncate(3)?;
+    assert_eq!(cache.current_seq_len(), 3);
+    let (k, _) = cache.append(&last, &last)?;
+    let expected = Tensor::cat(&[&xs.narrow(1, 0, 3)?, &xs.narrow(1, 5, 1)?], 1)?;
+    assert_eq!(k.to_vec3::<f32>()?, expected.to_vec3::<f32>()?);
+    cache.truncate(0)?;
+    assert!(cache.is_empty());
+    cache.restore(&xs, &xs)?;
+    assert_eq!(cache.current_seq_len(), 6);
+    Ok(())
+}
+
+#[test]
+fn kv_state_and_prefix_cache() -> Result<()> {
+    use candle_nn::kv_cache::{KvState, PrefixCache};
+
+    let dev = &Device::Cpu;
+    let state = |seq_len: usize, offset: f32| -> Result<KvState> {
+        let layers = (0..2)
+            .map(|i| {
+                let k =
+                    Tensor::arange(0f32, (seq_len * 4) as f32, dev)?.reshape((1, 2, seq_len, 2))?;
+                let k = (k + (offset + i as f32) as f64)?;
+                Ok((k.clone(), k.neg()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        KvState::new(layers, 2)
+    };
+    let to_vec = |t: &Tensor| t.flatten_all()?.to_vec1::<f32>();
+
+    let s = state(5, 0.)?;
+    assert_eq!(s.seq_len(), 5);
+    let loaded = KvState::from_bytes(&s.to_bytes()?, dev)?;
+    assert_eq!(loaded.dim(), 2);
+    assert_eq!(loaded.layers().len(), 2);
+    for ((k1, v1), (k2, v2)) in s.layers().iter().zip(loaded.layers().iter()) {
+        assert_eq!(to_vec(k1)?, to_vec(k2)?);
+        assert_eq!(to_vec(v1)?, to_vec(v2)?);
+    }
+    let truncated = s.truncate(3)?;
+    assert_eq!(truncated.seq_len(), 3);
+    let tmp_file = std::env::temp_dir().join("candle-kv-state-test.safetensors");
+    truncated.save(&tmp_file)?;
+    let loaded = KvState::load(&tmp_file, dev)?;
+    std::fs::remove_file(&tmp_file)?;
+    assert_eq!(loaded.seq_len(), 3);
+    assert_eq!(
+        to_vec(&loaded.layers()[1].0)?,
+        to_vec(&s.layers()[1].0.narrow(2, 0, 3)?)?
+    );
+    let (k, v) = (
+        Tensor::zeros((1, 2, 3, 2), candle::DType::F32, dev)?,
+        Tensor::zeros((1, 2, 4, 2), candle::DType::F32, dev)?,
+    );
+    assert!(KvState::new(vec![(k, v)], 2).is_err());
+
+    let mut cache = PrefixCache::new(2);
+    assert!(cache.is_empty());
+    assert!(cache.insert(&[1, 2, 3], state(2, 0.)?).is_err());
+    cache.insert(&[1, 2, 3, 4, 5], state(5, 0.)?)?;
+    assert!(cache.get(&[2, 3])?.is_none());
+    // The snapshot for a longer sequence is truncated to the common prefix.
+    let s = cache.get(&[1, 2, 3, 7])?.unwrap();
+    assert_eq!(s.seq_len(), 3);
+    assert_eq!(
+        to_vec(&s.layers()[0].0)?,
+        to_vec(&state(5, 0.)?.truncate(3)?.layers()[0].0)?
+    );
+    let s = cache.get(&[1, 2, 3, 4, 5, 6])?.unwrap();
+    assert_eq!(s.seq_len(), 5);
+
+    cache.insert(&[1, 2], state(2, 10.)?)?;
+    assert_eq!(cache.len(), 2);
+    let s = cache.get(&[1, 2, 3])?.unwrap();
+    assert_eq!(s.seq_len(), 3);
+    let s = cache.get(&[1, 2])?.unwrap();
+    assert_eq!(
+        to_vec(&s.layers()[0].0)?,
+        to_vec(&state(2, 10.)?.layers()[0].0)?
+    );
+    // [1, 2, 3, 4, 5] is the least recently used entry and gets evicted.
+    cache.insert(&[8, 9], state(2, 20.)?)?;
+    assert_eq!(cache.len(), 2);
+    let s = cache.get(&[1, 2, 3])?.unwrap();
+    assert_eq!(s.seq_len(), 2);
+    assert!(cache.get(&[8, 9, 10])?.is_some());
+    cache.clear();
+    assert!(cache.get(&[8, 9])?.is_none());
+    Ok(())
+}
+
+#[test]
+fn prefix_cache_long_prompt() -> Result<()> {
+    use candle_nn::kv_cache::{KvState, PrefixCache};
+
+    // The cache is used on a thread with a small stack, the prompt length must not impact the
+    // stack depth of the lookups, evictions and drops.
+    let thread = std::thread::Builder::new().stack_size(256 * 1024);
+    let handle = thread.spawn(|| -> candle::Result<()> {
+        let dev = &Device::Cpu;
+        let state = |seq_len: usize| -> candle::Result<KvState> {
+            let k = Tensor::zeros((1, 1, seq_len, 1), candle::DType::F32, dev)?;
+            KvState::new(vec![(k.clone(), k)], 2)
+        };
+        let n = 100_000;
+        let tokens = (0..n as u32).collect::<Vec<_>>();
+        let mut other = tokens[..n / 2].to_vec();
+        other.push(u32::MAX);
+
+        let mut cache = PrefixCache::new(2);
+        cache.insert(&tokens, state(n)?)?;
+        cache.insert(&other, state(n / 2 + 1)?)?;
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&tokens[..n - 1])?.unwrap().seq_len(), n - 1);
+        assert_eq!(cache.get(&other)?.unwrap().seq_len(), n / 2 + 1);
+        // The full prompt is the least recently used entry, only the shared prefix remains.
+        cache.insert(&[7; 10], state(10)?)?;
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&tokens)?.unwrap().seq_len(), n / 2);
+        // Replacing an entry does not change the number of snapshots.
+        cache.insert(&other, state(n / 2 + 1)?)?;
+        assert_eq!(cache.len(), 2);
+        drop(cache);
+        Ok(())
+    })?;
+    handle.join().unwrap()?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec0_round;
+use candle::{Device, Var};
+use candle_nn::lr_scheduler::{
+    CosineAnnealingLr, CosineAnnealingWarmRestarts, ExponentialLr, LinearWarmupDecay, OneCycleLr,
+    PlateauMode, ReduceLrOnPlateau, StepLr,
+};
+use candle_nn::{LrScheduler, Optimizer, Scheduled, SGD};
+
+fn lrs<S: LrScheduler>(mut scheduler: S, steps: usize) -> Vec<f64> {
+    let mut lrs = Vec::with_capacity(steps);
+    for _step in 0..steps {
+        lrs.push((scheduler.get_lr() * 1e6).round() / 1e6);
+        scheduler.step()
+    }
+    lrs
+}
+
+#[test]
+fn step_and_exponential_lr() -> Result<()> {
+    let lr = lrs(StepLr::new(1., 2, 0.5)?, 6);
+    assert_eq!(lr, [1., 1., 0.5, 0.5, 0.25, 0.25]);
+    let lr = lrs(ExponentialLr::new(1., 0.5), 4);
+    assert_eq!(lr, [1., 0.5, 0.25, 0.125]);
+    assert!(StepLr::new(1., 0, 0.5).is_err());
+    Ok(())
+}
+
+#[test]
+fn cosine_annealing_lr() -> Result<()> {
+    let lr = lrs(CosineAnnealingLr::new(0.1, 4, 0.)?, 6);
+    assert_eq!(lr, [0.1, 0.085355, 0.05, 0.014645, 0., 0.]);
+    let lr = lrs(CosineAnnealingWarmRestarts::new(1., 2, 2, 0.)?, 8);
+    assert_eq!(lr, [1.0, 0.5, 1.0, 0.853553, 0.5, 0.146447, 1.0, 0.96194]);
+    Ok(())
+}
+
+#[test]
+fn linear_warmup_decay() -> Result<()> {
+    let lr = lrs(LinearWarmupDecay::new(1., 2, 6)?, 8);
+    assert_eq!(lr, [0., 0.5, 1., 0.75, 0.5, 0.25, 0., 0.]);
+    assert!(LinearWarmupDecay::new(1., 6, 6).is_err());
+    Ok(())
+}
+
+#[test]
+fn one_cycle_lr() -> Result<()> {
+    let lr = lrs(OneCycleLr::new(1., 10)?, 11);
+    assert_eq!(
+        lr,
+        [0.04, 0.52, 1.0, 0.950485, 0.811746, 0.611262, 0.388742, 0.188258, 0.049519, 4e-6, 4e-6]
+    );
+
+    // No warmup step with a small pct_start.
+    let mut scheduler = OneCycleLr::new(1., 10)?;
+    scheduler.pct_start = 0.1;
+    let lr = lrs(scheduler, 11);
+    assert!(lr.iter().all(|lr| lr.is_finite()));
+    assert_eq!((lr[0], lr[9], lr[10]), (1., 4e-6, 4e-6));
+    assert!(OneCycleLr::new(1., 0).is_err());
+    Ok(())
+}
+
+#[test]
+fn reduce_lr_on_plateau() -> Result<()> {
+    let mut scheduler = ReduceLrOnPlateau::new(1., PlateauMode::Min);
+    scheduler.patience = 1;
+    scheduler.cooldown = 1;
+    let mut lrs = vec![];
+    for metric in [5., 4., 4., 4., 4., 4., 4., 3., 3., 3.] {
+        scheduler.step_with_metric(metric);
+        lrs.push((scheduler.get_lr() * 1e6).round() / 1e6);
+    }
+    assert_eq!(lrs, [1., 1., 1., 0.1, 0.1, 0.1, 0.01, 0.01, 0.01, 0.001]);
+    Ok(())
+}
+
+#[test]
+fn scheduled_sgd() -> Result<()> {
+    let x = Var::new(0f32, &Device::Cpu)?;
+    let sgd = SGD::new(vec![x.clone()], 0.)?;
+    let mut opt = Scheduled::new(sgd, StepLr::new(0.1, 1, 0.5)?);
+    assert_eq!(opt.learning_rate(), 0.1);
+    let xt = x.as_tensor();
+    for _step in 0..3 {
+        let loss = ((xt - 4.)? * (xt - 4.)?)?;
+        opt.backward_step(&loss)?;
+        opt.scheduler_step();
+    }
+    assert_eq!(opt.learning_rate(), 0.0125);
+    assert_eq!(opt.optimizer().learning_rate(), 0.0125);
+    // x <- x - lr * 2 * (x - 4) with lr = 0.1, 0.05 and 0.025.
+    assert_eq!(to_vec0_round(&x, 4)?, 1.264);
+    Ok(())
+}
@@ -0,0 +1,68 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec1_round;
+use candle::{DType, Device, Var};
+use candle_nn::{GradScaler, MixedPrecision, Optimizer, ParamsGradScaler, SGD};
+
+#[test]
+fn grad_scaler() -> Result<()> {
+    let params = ParamsGradScaler {
+        init_scale: 8.,
+        growth_interval: 2,
+        ..Default::default()
+    };
+    let mut scaler = GradScaler::new(params)?;
+    scaler.update(false);
+    assert_eq!(scaler.scale(), 8.);
+    scaler.update(false);
+    assert_eq!(scaler.scale(), 16.);
+    scaler.update(false);
+    scaler.update(true);
+    assert_eq!(scaler.scale(), 8.);
+    scaler.update(false);
+    assert_eq!(scaler.scale(), 8.);
+    let params = ParamsGradScaler {
+        backoff_factor: 1.,
+        ..Default::default()
+    };
+    assert!(GradScaler::new(params).is_err());
+    Ok(())
+}
+
+#[test]
+fn mixed_precision_sgd() -> Result<()> {
+    let x = Var::zeros(2, DType::F16, &Device::Cpu)?;
+    let mut opt = MixedPrecision::<SGD>::new(vec![x.clone()], 0.1)?;
+    assert_eq!(opt.master_vars()[0].dtype(), DType::F32);
+    let xt = x.as_tensor();
+    // The gradient of the scaled loss is -8 * scale, this overflows in F16 until the scale goes
+    // down to 4096.
+    for _step in 0..4 {
+        let loss = (xt - 4.)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+        assert!(opt.last_step_skipped());
+    }
+    assert_eq!(opt.num_skipped_steps(), 4);
+    assert_eq!(opt.scaler().scale(), 4096.);
+    assert_eq!(x.to_dtype(DType::F32)?.to_vec1::<f32>()?, [0., 0.]);
+
+    let loss = (xt - 4.)?.sqr()?.sum_all()?;
+    opt.backward_step(&loss)?;
+    assert!(!opt.last_step_skipped());
+    assert_eq!(to_vec1_round(&opt.master_vars()[0], 4)?, [0.8, 0.8]);
+    assert_eq!(x.dtype(), DType::F16);
+    assert_eq!(to_vec1_round(&x.to_dtype(DType::F32)?, 3)?, [0.8, 0.8]);
+
+    for _step in 0..50 {
+        let loss = (xt - 4.)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+    }
+    assert_eq!(opt.num_skipped_steps(), 4);
+    assert_eq!(to_vec1_round(&x.to_dtype(DType::F32)?, 2)?, [4., 4.]);
+    Ok(())
+}
@@ -8,7 +8,10 @@ use candle::test_utils::{to_vec0_round, to_vec2_round};
 
 use anyhow::Result;
 use candle::{DType, Device, Tensor, Var};
-use candle_nn::{AdamW, Linear, Module, Optimizer, ParamsAdamW, SGD};
+use candle_nn::{
+    Adafactor, Adagrad, Adam, AdamW, Linear, Lion, Module, Optimizer, ParamsAdafactor,
+    ParamsAdagrad, ParamsAdam, ParamsAdamW, ParamsLion, ParamsRMSprop, ParamsSGD, RMSprop, SGD,
+};
 
 #[test]
 fn sgd_optim() -> Result<()> {
@@ -71,6 +74,104 @@ fn sgd_linear_regression() -> Result<()> {
     Ok(())
 }
 
+/* The results of this test have been checked against the following PyTorch code.
+    import torch
+    from torch import optim
+
+    w_gen = torch.tensor([[3., 1.]])
+    b_gen = torch.tensor([-2.])
+
+    sample_xs = torch.tensor([[2., 1.], [7., 4.], [-4., 12.], [5., 8.]])
+    sample_ys = sample_xs.matmul(w_gen.t()) + b_gen
+
+    m = torch.nn.Linear(2, 1)
+    with torch.no_grad():
+        m.weight.zero_()
+        m.bias.zero_()
+    optimizer = optim.SGD(m.parameters(), lr=0.001, momentum=0.9, nesterov=False)
+    for _step in range(100):
+        optimizer.zero_grad()
+        ys = m(sample_xs)
+        loss = ((ys - sample_ys)**2).sum()
+        loss.backward()
+        optimizer.step()
+    print(m.weight)
+    print(m.bias)
+*/
+#[test]
+fn sgd_momentum_linear_regression() -> Result<()> {
+    let w_gen = Tensor::new(&[[3f32, 1.]], &Device::Cpu)?;
+    let b_gen = Tensor::new(-2f32, &Device::Cpu)?;
+    let gen = Linear::new(w_gen, Some(b_gen));
+    let sample_xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], &Device::Cpu)?;
+    let sample_ys = gen.forward(&sample_xs)?;
+
+    let run = |nesterov: bool| -> Result<(Var, Var)> {
+        let w = Var::new(&[[0f32, 0.]], &Device::Cpu)?;
+        let b = Var::new(0f32, &Device::Cpu)?;
+        let params = ParamsSGD {
+            lr: 0.001,
+            momentum: 0.9,
+            nesterov,
+            ..Default::default()
+        };
+        let mut sgd = SGD::new_with_params(vec![w.clone(), b.clone()], params)?;
+        let lin = Linear::new(w.as_tensor().clone(), Some(b.as_tensor().clone()));
+        for _step in 0..100 {
+            let ys = lin.forward(&sample_xs)?;
+            let loss = ys.sub(&sample_ys)?.sqr()?.sum_all()?;
+            sgd.backward_step(&loss)?;
+        }
+        Ok((w, b))
+    };
+
+    let (w, b) = run(false)?;
+    assert_eq!(to_vec2_round(w.as_tensor(), 4)?, &[[2.9299, 0.9352]]);
+    assert_eq!(to_vec0_round(b.as_tensor(), 4)?, -1.3058);
+
+    let (w, b) = run(true)?;
+    assert_eq!(to_vec2_round(w.as_tensor(), 4)?, &[[2.9424, 0.9281]]);
+    assert_eq!(to_vec0_round(b.as_tensor(), 4)?, -1.304);
+    Ok(())
+}
+
+#[test]
+fn sgd_weight_decay_dampening() -> Result<()> {
+    let w_gen = Tensor::new(&[[3f32, 1.]], &Device::Cpu)?;
+    let b_gen = Tensor::new(-2f32, &Device::Cpu)?;
+    let gen = Linear::new(w_gen, Some(b_gen));
+    let sample_xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], &Device::Cpu)?;
+    let sample_ys = gen.forward(&sample_xs)?;
+
+    let w = Var::new(&[[0f32, 0.]], &Device::Cpu)?;
+    let b = Var::new(0f32, &Device::Cpu)?;
+    let params = ParamsSGD {
+        lr: 0.001,
+        momentum: 0.9,
+        dampening: 0.1,
+        weight_decay: 0.1,
+        ..Default::default()
+    };
+    let mut sgd = SGD::new_with_params(vec![w.clone(), b.clone()], params)?;
+    let lin = Linear::new(w.as_tensor().clone(), Some(b.as_tensor().clone()));
+    for _step in 0..100 {
+        let ys = lin.forward(&sample_xs)?;
+        let loss = ys.sub(&sample_ys)?.sqr()?.sum_all()?;
+        sgd.backward_step(&loss)?;
+    }
+    assert_eq!(to_vec2_round(w.as_tensor(), 4)?, &[[2.9434, 0.9147]]);
+    assert_eq!(to_vec0_round(b.as_tensor(), 4)?, -1.1999);
+
+    let params = ParamsSGD {
+        nesterov: true,
+        ..Default::default()
+    };
+    assert!(SGD::new_with_params(vec![], params.clone()).is_err());
+    assert!(sgd.set_params(params).is_err());
+    assert!(!sgd.params().nesterov);
+    Ok(())
+}
+
 /* The following test returns the same values as the PyTorch code below.
 import torch
 from torch import optim
@@ -158,3 +259,310 @@ fn adamw_linear_regression_varmap() -> Result<()> {
     assert_eq!(to_vec0_round(lin.bias().unwrap(), 4)?, 1.);
     Ok(())
 }
+
+// Runs the same linear regression as the tests above and returns the learned weight and bias.
+fn linear_regression<O: Optimizer>(
+    config: O::Config,
+    steps: usize,
+    init: f32,
+) -> Result<(Vec<Vec<f32>>, f32)> {
+    let w_gen = Tensor::new(&[[3f32, 1.]], &Device::Cpu)?;
+    let b_gen = Tensor::new(-2f32, &Device::Cpu)?;
+    let gen = Linear::new(w_gen, Some(b_gen));
+    let sample_xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], &Device::Cpu)?;
+    let sample_ys = gen.forward(&sample_xs)?;
+
+    let w = Var::new(&[[init, init]], &Device::Cpu)?;
+    let b = Var::new(init, &Device::Cpu)?;
+    let mut opt = O::new(vec![w.clone(), b.clone()], config)?;
+    let lin = Linear::new(w.as_tensor().clone(), Some(b.as_tensor().clone()));
+    for _step in 0..steps {
+        let ys = lin.forward(&sample_xs)?;
+        let loss = ys.sub(&sample_ys)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+    }
+    Ok((
+        to_vec2_round(w.as_tensor(), 4)?,
+        to_vec0_round(b.as_tensor(), 4)?,
+    ))
+}
+
+/* The expected values for the following tests match the torch.optim implementations (and the
+   reference implementation from the paper for Lion), e.g. for Adam:
+    m = torch.nn.Linear(2, 1)
+    with torch.no_grad():
+        m.weight.zero_()
+        m.bias.zero_()
+    optimizer = optim.Adam(m.parameters(), lr=0.1, weight_decay=0.1)
+    for _step in range(90):
+        optimizer.zero_grad()
+        ys = m(sample_xs)
+        loss = ((ys - sample_ys)**2).sum()
+        loss.backward()
+        optimizer.step()
+*/
+#[test]
+fn adam_linear_regression() -> Result<()> {
+    let params = ParamsAdam {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<Adam>(params, 90, 0.)?;
+    assert_eq!(w, &[[2.724, 0.7128]]);
+    assert_eq!(b, 0.8921);
+
+    let params = ParamsAdam {
+        lr: 0.1,
+        weight_decay: 0.1,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<Adam>(params, 90, 0.)?;
+    assert_eq!(w, &[[2.7244, 0.7152]]);
+    assert_eq!(b, 0.8682);
+    Ok(())
+}
+
+#[test]
+fn rmsprop_linear_regression() -> Result<()> {
+    let params = ParamsRMSprop {
+        lr: 0.01,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<RMSprop>(params, 200, 0.)?;
+    assert_eq!(w, &[[2.278, 0.6799]]);
+    assert_eq!(b, 1.5875);
+
+    let params = ParamsRMSprop {
+        lr: 0.01,
+        momentum: 0.5,
+        weight_decay: 0.01,
+        centered: true,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<RMSprop>(params, 90, 0.)?;
+    assert_eq!(w, &[[2.5247, 0.663]]);
+    assert_eq!(b, 1.4857);
+    Ok(())
+}
+
+#[test]
+fn optimizer_set_params() -> Result<()> {
+    use candle_nn::StatefulOptimizer;
+
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let name = |_: &Var| -> candle::Result<String> { Ok("x".to_string()) };
+    let mut rmsprop = RMSprop::new(vec![x.clone()], ParamsRMSprop::default())?;
+    assert_eq!(rmsprop.state_dict(name)?.len(), 1);
+    let params = ParamsRMSprop {
+        lr: 0.1,
+        momentum: 0.9,
+        centered: true,
+        ..Default::default()
+    };
+    rmsprop.set_params(params)?;
+    assert_eq!(rmsprop.learning_rate(), 0.1);
+    let mut keys = rmsprop.state_dict(name)?.into_keys().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, ["x.grad_avg", "x.momentum_buffer", "x.square_avg"]);
+    rmsprop.set_params(ParamsRMSprop::default())?;
+    assert_eq!(rmsprop.state_dict(name)?.len(), 1);
+
+    let mut adagrad = Adagrad::new(vec![x.clone()], ParamsAdagrad::default())?;
+    adagrad.set_params(ParamsAdagrad {
+        lr_decay: 0.5,
+        ..Default::default()
+    });
+    assert_eq!(adagrad.params().lr_decay, 0.5);
+    let mut lion = Lion::new(vec![x.clone()], ParamsLion::default())?;
+    lion.set_params(ParamsLion {
+        beta2: 0.5,
+        ..Default::default()
+    });
+    assert_eq!(lion.params().beta2, 0.5);
+    let mut adafactor = Adafactor::new(vec![x], ParamsAdafactor::default())?;
+    adafactor.set_params(ParamsAdafactor {
+        d: 2.,
+        ..Default::default()
+    });
+    assert_eq!(adafactor.params().d, 2.);
+    Ok(())
+}
+
+#[test]
+fn adagrad_linear_regression() -> Result<()> {
+    let params = ParamsAdagrad {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<Adagrad>(params, 100, 0.)?;
+    assert_eq!(w, &[[1.5401, 0.8028]]);
+    assert_eq!(b, 1.2208);
+
+    let params = ParamsAdagrad {
+        lr: 0.1,
+        lr_decay: 0.01,
+        weight_decay: 0.01,
+        initial_accumulator_value: 0.1,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<Adagrad>(params, 100, 0.)?;
+    assert_eq!(w, &[[1.2603, 0.8074]]);
+    assert_eq!(b, 1.0634);
+    Ok(())
+}
+
+#[test]
+fn lion_linear_regression() -> Result<()> {
+    let params = ParamsLion {
+        lr: 0.02,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<Lion>(params, 200, 0.)?;
+    assert_eq!(w, &[[3.68, 0.4]]);
+    assert_eq!(b, 2.84);
+
+    let params = ParamsLion {
+        lr: 0.01,
+        weight_decay: 0.5,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<Lion>(params, 90, 0.)?;
+    assert_eq!(w, &[[0.7262, 0.7262]]);
+    assert_eq!(b, 0.7262);
+    Ok(())
+}
+
+#[test]
+fn adafactor_linear_regression() -> Result<()> {
+    // The weight uses the factored second moment estimate whereas the bias, being a scalar, uses
+    // the full one.
+    let params = ParamsAdafactor {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let (w, b) = linear_regression::<Adafactor>(params.clone(), 80, 0.)?;
+    assert_eq!(w, &[[0.6051, 0.5047]]);
+    assert_eq!(b, 0.5456);
+
+    let (w, b) = linear_regression::<Adafactor>(params, 100, 1.)?;
+    assert_eq!(w, &[[2.8947, 0.881]]);
+    assert_eq!(b, 0.1809);
+    Ok(())
+}
+
+#[test]
+fn adamw_resume_from_checkpoint() -> Result<()> {
+    use candle_nn::Init::Const;
+
+    let w_gen = Tensor::new(&[[3f32, 1.]], &Device::Cpu)?;
+    let b_gen = Tensor::new(-2f32, &Device::Cpu)?;
+    let gen = Linear::new(w_gen, Some(b_gen));
+    let sample_xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], &Device::Cpu)?;
+    let sample_ys = gen.forward(&sample_xs)?;
+    let params = ParamsAdamW {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let tmp_dir = std::env::temp_dir();
+    let weights_file = tmp_dir.join(format!("candle-optim-{}-weights.st", std::process::id()));
+    let state_file = tmp_dir.join(format!("candle-optim-{}-state.st", std::process::id()));
+
+    // Run the first 50 steps and checkpoint both the weights and the optimizer state.
+    let var_map = candle_nn::VarMap::new();
+    let w = var_map.get((1, 2), "w", Const(0.), DType::F32, &Device::Cpu)?;
+    let b = var_map.get((), "b", Const(0.), DType::F32, &Device::Cpu)?;
+    let mut opt = AdamW::new(var_map.all_vars(), params.clone())?;
+    let lin = Linear::new(w, Some(b));
+    for _step in 0..50 {
+        let ys = lin.forward(&sample_xs)?;
+        let loss = ys.sub(&sample_ys)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+    }
+    var_map.save(&weights_file)?;
+    var_map.save_optimizer_state(&opt, &state_file)?;
+
+    // Resume in a fresh VarMap and optimizer for the remaining 50 steps.
+    let mut var_map = candle_nn::VarMap::new();
+    let w = var_map.get((1, 2), "w", Const(0.), DType::F32, &Device::Cpu)?;
+    let b = var_map.get((), "b", Const(0.), DType::F32, &Device::Cpu)?;
+    var_map.load(&weights_file)?;
+    let mut opt = AdamW::new(var_map.all_vars(), params)?;
+    var_map.load_optimizer_state(&mut opt, &state_file)?;
+    let lin = Linear::new(w, Some(b));
+    for _step in 0..50 {
+        let ys = lin.forward(&sample_xs)?;
+        let loss = ys.sub(&sample_ys)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+    }
+    std::fs::remove_file(weights_file)?;
+    std::fs::remove_file(state_file)?;
+    // These are the same values as in the uninterrupted 100 steps run above.
+    assert_eq!(to_vec2_round(lin.weight(), 4)?, &[[2.7257, 0.7097]]);
+    assert_eq!(to_vec0_round(lin.bias().unwrap(), 4)?, 0.7873);
+    Ok(())
+}
+
+#[test]
+fn optimizer_state_dict() -> Result<()> {
+    use candle_nn::StatefulOptimizer;
+
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let params = ParamsSGD {
+        lr: 0.1,
+        momentum: 0.9,
+        ..Default::default()
+    };
+    let mut sgd = SGD::new_with_params(vec![x.clone()], params.clone())?;
+    assert_eq!(sgd.state_dict(|_| Ok("x".to_string()))?.len(), 1);
+    sgd.backward_step(&x.sqr()?.sum_all()?)?;
+    let state = sgd.state_dict(|_| Ok("x".to_string()))?;
+    assert_eq!(state["x.momentum_buffer"].to_vec1::<f32>()?, [2., 4.]);
+
+    let mut resumed = SGD::new_with_params(vec![x.clone()], params.clone())?;
+    resumed.load_state_dict(&state, |_| Ok("x".to_string()))?;
+    assert_eq!(resumed.state_dict(|_| Ok("x".to_string()))?.len(), 2);
+
+    // A missing or badly shaped momentum buffer is an error rather than a zeroed state.
+    let mut bad_state = state.clone();
+    bad_state.remove("x.momentum_buffer");
+    assert!(resumed
+        .load_state_dict(&bad_state, |_| Ok("x".to_string()))
+        .is_err());
+    bad_state.insert(
+        "x.momentum_buffer".to_string(),
+        Tensor::zeros(3, DType::F32, &Device::Cpu)?,
+    );
+    assert!(resumed
+        .load_state_dict(&bad_state, |_| Ok("x".to_string()))
+        .is_err());
+    // The momentum buffer is not used without momentum.
+    let mut plain = SGD::new(vec![x.clone()], 0.1)?;
+    assert!(plain
+        .load_state_dict(&state, |_| Ok("x".to_string()))
+        .is_err());
+
+    let params = ParamsRMSprop {
+        centered: true,
+        ..Default::default()
+    };
+    let mut rmsprop = RMSprop::new(vec![x.clone()], params.clone())?;
+    rmsprop.backward_step(&x.sqr()?.sum_all()?)?;
+    let state = rmsprop.state_dict(|_| Ok("x".to_string()))?;
+    let mut resumed = RMSprop::new(vec![x.clone()], params)?;
+    resumed.load_state_dict(&state, |_| Ok("x".to_string()))?;
+    let mut bad_state = state.clone();
+    bad_state.remove("x.grad_avg");
+    assert!(resumed
+        .load_state_dict(&bad_state, |_| Ok("x".to_string()))
+        .is_err());
+    let mut not_centered = RMSprop::new(vec![x.clone()], ParamsRMSprop::default())?;
+    assert!(not_centered
+        .load_state_dict(&state, |_| Ok("x".to_string()))
+        .is_err());
+
+    let mut adam = Adam::new(vec![x.clone()], ParamsAdam::default())?;
+    assert!(adam
+        .load_state_dict(&state, |_| Ok("x".to_string()))
+        .is_err());
+    Ok(())
+}
@@ -6,12 +6,123 @@ use candle::{bail, DType, Device, IndexOp, Result, Tensor};
 use candle_nn::activation::PReLU;
 use std::collections::{HashMap, HashSet};
 
-pub type Value = Tensor;
+/// The values produced when evaluating a graph. Most operators work on tensors, sequences of
+/// tensors are created by the sequence operators and can be carried through `Loop` and `Scan`.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Tensor(Tensor),
+    Sequence(Vec<Tensor>),
+}
+
+impl Value {
+    pub fn as_tensor(&self) -> Result<&Tensor> {
+        match self {
+            Self::Tensor(t) => Ok(t),
+            Self::Sequence(_) => bail!("expected a tensor, got a sequence"),
+        }
+    }
+
+    pub fn as_sequence(&self) -> Result<&[Tensor]> {
+        match self {
+            Self::Tensor(_) => bail!("expected a sequence, got a tensor"),
+            Self::Sequence(s) => Ok(s),
+        }
+    }
+
+    pub fn into_tensor(self) -> Result<Tensor> {
+        match self {
+            Self::Tensor(t) => Ok(t),
+            Self::Sequence(_) => bail!("expected a tensor, got a sequence"),
+        }
+    }
+
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        match self {
+            Self::Tensor(t) => Ok(Self::Tensor(t.to_device(device)?)),
+            Self::Sequence(s) => {
+                let s = s
+                    .iter()
+                    .map(|t| t.to_device(device))
+                    .collect::<Result<_>>()?;
+                Ok(Self::Sequence(s))
+            }
+        }
+    }
+
+    /// The device of the value, `None` for an empty sequence.
+    pub fn device(&self) -> Option<&Device> {
+        match self {
+            Self::Tensor(t) => Some(t.device()),
+            Self::Sequence(s) => s.first().map(|t| t.device()),
+        }
+    }
+}
+
+impl From<Tensor> for Value {
+    fn from(t: Tensor) -> Self {
+        Self::Tensor(t)
+    }
+}
+
+impl From<Vec<Tensor>> for Value {
+    fn from(s: Vec<Tensor>) -> Self {
+        Self::Sequence(s)
+    }
+}
+
+/// The values visible when evaluating a graph. The sub-graphs of the `If`, `Loop` and `Scan`
+/// nodes are evaluated in their own scope on top of the enclosing one, so that they can read
+/// the outer values while their intermediate values are dropped with the scope.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Scope<'a> {
+    values: HashMap<String, Value>,
+    parent: Option<&'a Scope<'a>>,
+}
+
+impl<'a> Scope<'a> {
+    pub(crate) fn new(values: HashMap<String, Value>, parent: Option<&'a Scope<'a>>) -> Self {
+        Self { values, parent }
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Value> {
+        match self.values.get(name) {
+            Some(value) => Some(value),
+            None => self.parent.and_then(|p| p.get(name)),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub(crate) fn insert(&mut self, name: String, value: Value) {
+        self.values.insert(name, value);
+    }
+
+    /// Removes a value from this scope, the enclosing scopes are left unchanged.
+    pub(crate) fn remove(&mut self, name: &str) -> Option<Value> {
+        self.values.remove(name)
+    }
+
+    /// Removes a value from this scope or clones it from the enclosing scopes.
+    pub(crate) fn take(&mut self, name: &str) -> Option<Value> {
+        match self.values.remove(name) {
+            Some(value) => Some(value),
+            None => self.parent.and_then(|p| p.get(name)).cloned(),
+        }
+    }
+
+    pub(crate) fn into_values(self) -> HashMap<String, Value> {
+        self.values
+    }
+}
 
 pub fn dtype(dt: DataType) -> Option<DType> {
     match dt {
         DataType::Uint8 => Some(DType::U8),
         DataType::Uint32 => Some(DType::U32),
+        // There is no i8 dtype in candle, the int8 values are stored as i16.
+        DataType::Int8 => Some(DType::I16),
         DataType::Int64 => Some(DType::I64),
         DataType::Float16 => Some(DType::F16),
         DataType::Float => Some(DType::F32),
@@ -204,6 +315,17 @@ pub fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
                 Tensor::from_vec(data, t.int32_data.len(), &Device::Cpu)
             }
         }
+        Ok(DataType::Int8) => {
+            let data = if t.int32_data.is_empty() {
+                t.raw_data
+                    .iter()
+                    .map(|&v| v as i8 as i16)
+                    .collect::<Vec<_>>()
+            } else {
+                t.int32_data.iter().map(|&v| v as i16).collect::<Vec<_>>()
+            };
+            Tensor::from_vec(data, dims.as_slice(), &Device::Cpu)
+        }
         Ok(dt) => match dtype(dt) {
             Some(dt) => {
                 if dt == DType::F32 && !t.float_data.is_empty() {
@@ -212,6 +334,9 @@ pub fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
                     Tensor::from_slice(&t.double_data, dims.as_slice(), &Device::Cpu)
                 } else if dt == DType::I64 && !t.int64_data.is_empty() {
                     Tensor::from_slice(&t.int64_data, dims.as_slice(), &Device::Cpu)
+                } else if dt == DType::U8 && !t.int32_data.is_empty() {
+                    let data = t.int32_data.iter().map(|&v| v as u8).collect::<Vec<_>>();
+                    Tensor::from_vec(data, dims.as_slice(), &Device::Cpu)
                 } else {
                     Tensor::from_raw_buffer(
                         t.raw_data.as_slice(),
@@ -231,30 +356,89 @@ pub fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
     }
 }
 
-// This function provides a direct evaluation of the proto.
-// Longer-term, we should first convert the proto to an intermediate representation of the compute
-// graph so as to make multiple evaluations more efficient.
-// An example upside of this would be to remove intermediary values when they are not needed
-// anymore.
+// This function provides a direct evaluation of the proto, use `crate::Model` to evaluate the
+// same model multiple times.
 pub fn simple_eval(
     model: &onnx::ModelProto,
-    mut inputs: HashMap<String, Value>,
+    inputs: HashMap<String, Tensor>,
+) -> Result<HashMap<String, Tensor>> {
+    let inputs = inputs.into_iter().map(|(k, v)| (k, v.into())).collect();
+    into_tensors(simple_eval_values(model, inputs)?)
+}
+
+/// Same as [`simple_eval`] for the graphs that have sequence inputs or outputs.
+pub fn simple_eval_values(
+    model: &onnx::ModelProto,
+    inputs: HashMap<String, Value>,
 ) -> Result<HashMap<String, Value>> {
     let graph = match &model.graph {
         None => bail!("no graph defined in proto"),
         Some(graph) => graph,
     };
-    simple_eval_(graph, &mut inputs)
+    let mut values = Scope::new(inputs, None);
+    for (name, value) in initializers(graph)? {
+        values.insert(name, value);
+    }
+    check_inputs(graph, &values)?;
+    let outputs = eval_graph(graph, &mut values, opset_version(model))?;
+    Ok(graph
+        .output
+        .iter()
+        .map(|o| o.name.clone())
+        .zip(outputs)
+        .collect())
 }
 
-fn simple_eval_(
-    graph: &onnx::GraphProto,
-    values: &mut HashMap<String, Value>,
-) -> Result<HashMap<String, Value>> {
-    for t in graph.initializer.iter() {
-        let tensor = get_tensor(t, t.name.as_str())?;
-        values.insert(t.name.to_string(), tensor);
+// The version used when the model does not import the default operator set, the latest
+// version of each operator is supported in this case.
+const DEFAULT_OPSET: i64 = 23;
+
+// The version of the default operator set imported by the model.
+pub(crate) fn opset_version(model: &onnx::ModelProto) -> i64 {
+    model
+        .opset_import
+        .iter()
+        .find(|o| o.domain.is_empty() || o.domain == "ai.onnx")
+        .map_or(DEFAULT_OPSET, |o| o.version)
+}
+
+pub(crate) fn into_tensors(values: HashMap<String, Value>) -> Result<HashMap<String, Tensor>> {
+    values
+        .into_iter()
+        .map(|(name, value)| match value {
+            Value::Tensor(t) => Ok((name, t)),
+            Value::Sequence(_) => bail!("{name} is a sequence, use simple_eval_values"),
+        })
+        .collect()
+}
+
+// Decodes the initializers of a graph.
+fn initializers(graph: &GraphProto) -> Result<HashMap<String, Value>> {
+    graph
+        .initializer
+        .iter()
+        .map(|t| Ok((t.name.to_string(), get_tensor(t, t.name.as_str())?.into())))
+        .collect()
+}
+
+// Evaluates the nodes of a graph in `values` and returns the graph outputs in order.
+fn eval_graph(graph: &GraphProto, values: &mut Scope<'_>, opset: i64) -> Result<Vec<Value>> {
+    // The nodes are topologically sorted so we can just process them in order.
+    for node in graph.node.iter() {
+        eval_node(node, values, opset)?
     }
+    graph
+        .output
+        .iter()
+        .map(|output| match values.take(&output.name) {
+            None => bail!("cannot find output {}", output.name),
+            Some(value) => Ok(value),
+        })
+        .collect()
+}
+
+// Checks that the graph inputs are present in `values` with the expected dtypes and shapes.
+pub(crate) fn check_inputs(graph: &onnx::GraphProto, values: &Scope<'_>) -> Result<()> {
     for input in graph.input.iter() {
         let input_type = match &input.r#type {
             Some(input_type) => input_type,
@@ -271,7 +455,8 @@ fn simple_eval_(
 
         let tensor = match values.get(&input.name) {
             None => bail!("missing input {}", input.name),
-            Some(tensor) => tensor,
+            Some(Value::Sequence(_)) => bail!("expected a tensor for input {}", input.name),
+            Some(Value::Tensor(tensor)) => tensor,
         };
         let dt = match DataType::try_from(tensor_type.elem_type) {
             Ok(dt) => match dtype(dt) {
@@ -319,2243 +504,3942 @@ fn simple_eval_(
             )
         }
     }
-    // The nodes are topologically sorted so we can just process them in order.
-    for node in graph.node.iter() {
-        let get = |input_name: &str| match values.get(input_name) {
-            Some(value) => Ok(value),
-            None => bail!("cannot find {input_name} for op '{}'", node.name),
-        };
-        let get_opt = |i: usize| {
-            node.input
-                .get(i)
-                .filter(|s: &&String| !s.is_empty())
-                .map(|s| get(s))
-        };
+    Ok(())
+}
 
-        // TODO: Validate node.input for each operator.
-        match node.op_type.as_str() {
-            "Add" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_add(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Sub" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_sub(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Mul" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_mul(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Div" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_div(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Pow" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                // HACK: current implementation of broadcast_pow cannot handle negative base,
-                // so we use powf where we can, which *does* correctly handle negative base.
-                if let Ok(exp) = to_scalar_flexible::<f64>(&input1.to_dtype(DType::F64)?) {
-                    let output = input0.powf(exp)?;
-                    values.insert(node.output[0].clone(), output);
-                } else {
-                    let output = input0.broadcast_pow(input1)?;
-                    values.insert(node.output[0].clone(), output);
-                }
-            }
-            "Exp" => {
-                let xs = get(&node.input[0])?;
-                let output = xs.exp()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Equal" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_eq(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Not" => {
-                let xs = get(&node.input[0])?;
-                let xs = xs.eq(&xs.zeros_like()?)?;
-                values.insert(node.output[0].clone(), xs);
-            }
-            "MatMul" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_matmul(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Reshape" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?.to_vec1::<i64>()?;
-                // TODO: Check that there is at most a single -1 or 0, handle other neg values.
-                let mut other_than_minus1 = 1usize;
-                for &v in input1.iter() {
-                    if v != -1 && v != 0 {
-                        other_than_minus1 *= v as usize
+/// An operator with its attributes parsed. The nodes of a [`crate::Model`] are lowered once when
+/// loading the model so that running it does not go through the node attributes again, the
+/// operators without a variant here are evaluated from the node proto.
+#[derive(Debug, Clone)]
+pub(crate) enum Op {
+    /// An element-wise operator with a single input.
+    Unary(fn(&Tensor) -> Result<Tensor>),
+    /// An operator with two inputs that are broadcasted together.
+    Binary(fn(&Tensor, &Tensor) -> Result<Tensor>),
+    Identity,
+    Cast(DType),
+    Softmax {
+        axis: Option<i64>,
+    },
+    Transpose {
+        perm: Option<Vec<usize>>,
+    },
+    Concat {
+        axis: i64,
+    },
+    Gather {
+        axis: i64,
+    },
+    /// The axes are read from the second input when not set as an attribute.
+    Unsqueeze {
+        axes: Option<Vec<i64>>,
+    },
+    Gemm {
+        alpha: f32,
+        beta: f32,
+        trans_a: bool,
+        trans_b: bool,
+    },
+    ConstantOfShape(Option<Tensor>),
+    Reshape,
+    Conv(ConvAttrs),
+    LayerNorm {
+        eps: f32,
+        axis: i64,
+    },
+    /// Any other operator.
+    Node,
+}
+
+// Resolves the operator of a node and parses its attributes.
+pub(crate) fn lower_node(node: &onnx::NodeProto) -> Result<Op> {
+    let op = match node.op_type.as_str() {
+        "Add" => Op::Binary(Tensor::broadcast_add),
+        "Sub" => Op::Binary(Tensor::broadcast_sub),
+        "Mul" => Op::Binary(Tensor::broadcast_mul),
+        "Div" => Op::Binary(Tensor::broadcast_div),
+        "MatMul" => Op::Binary(Tensor::broadcast_matmul),
+        "Equal" => Op::Binary(Tensor::broadcast_eq),
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Greater
+        "Greater" => Op::Binary(Tensor::broadcast_gt),
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Less
+        "Less" => Op::Binary(Tensor::broadcast_lt),
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#LessOrEqual
+        "LessOrEqual" => Op::Binary(Tensor::broadcast_le),
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GreaterOrEqual
+        "GreaterOrEqual" => Op::Binary(Tensor::broadcast_ge),
+        "Exp" => Op::Unary(Tensor::exp),
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Sqrt
+        "Sqrt" => Op::Unary(Tensor::sqrt),
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Log
+        "Log" => Op::Unary(Tensor::log),
+        "Abs" => Op::Unary(Tensor::abs),
+        "Cos" => Op::Unary(Tensor::cos),
+        "Sin" => Op::Unary(Tensor::sin),
+        "Erf" => Op::Unary(Tensor::erf),
+        "Tanh" => Op::Unary(Tensor::tanh),
+        "Sigmoid" => Op::Unary(candle_nn::ops::sigmoid),
+        "Gelu" => Op::Unary(Tensor::gelu_erf),
+        "Relu" => Op::Unary(Tensor::relu),
+        "Ceil" => Op::Unary(Tensor::ceil),
+        "Floor" => Op::Unary(Tensor::floor),
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#identity
+        "Identity" => Op::Identity,
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Cast
+        "Cast" => {
+            let dt: i64 = *get_attr(node, "to")?;
+            let dtype = match DataType::try_from(dt as i32) {
+                Ok(DataType::Int32) => DType::I64,
+                Ok(dt) => match dtype(dt) {
+                    Some(dt) => dt,
+                    None => {
+                        bail!("unsupported 'to' value {dt:?} for cast {}", node.name)
                     }
+                },
+                Err(_) => {
+                    bail!("unsupported 'to' value {dt:?} for cast {}", node.name)
                 }
-                let input1 = input1
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, &v)| match v {
-                        -1 => Ok(input0.elem_count() / other_than_minus1),
-                        0 => input0.dim(idx),
-                        _ => Ok(v as usize),
-                    })
-                    .collect::<Result<Vec<usize>>>()?;
-                let output = input0.reshape(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "LogSoftmax" => {
-                let input = get(&node.input[0])?;
-                let output = match get_attr_opt::<i64>(node, "axis")? {
-                    None => candle_nn::ops::softmax_last_dim(input)?,
-                    Some(&axis) => {
-                        let axis = input.normalize_axis(axis)?;
-                        candle_nn::ops::log_softmax(input, axis)?
-                    }
-                };
-                values.insert(node.output[0].clone(), output);
-            }
-            "Softmax" => {
-                let input = get(&node.input[0])?;
-                let output = match get_attr_opt::<i64>(node, "axis")? {
-                    None => candle_nn::ops::softmax_last_dim(input)?,
-                    Some(&axis) => {
-                        let axis = input.normalize_axis(axis)?;
-                        candle_nn::ops::softmax(input, axis)?
-                    }
-                };
-                values.insert(node.output[0].clone(), output);
-            }
-            "Transpose" => {
-                let input = get(&node.input[0])?;
-                let output = match get_attr_opt::<[i64]>(node, "perm")? {
-                    None => input.t()?,
-                    Some(perm) => {
-                        let perm = perm.iter().map(|&v| v as usize).collect::<Vec<_>>();
-                        input.permute(perm)?.contiguous()?
-                    }
-                };
-                values.insert(node.output[0].clone(), output);
-            }
-            "Dropout" => {
-                let input = get(&node.input[0])?;
-                // Do not apply dropout at the moment, consider that we're only doing inference.
-                values.insert(node.output[0].clone(), input.clone());
-            }
-            "MaxPool" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#MaxPool
-                let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
-                let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
-                let pads = get_attr_opt::<[i64]>(node, "pads")?;
-                let strides = get_attr_opt::<[i64]>(node, "strides")?;
-                let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
-                match auto_pad {
-                    None | Some("NOTSET") => (),
-                    Some(s) => bail!("unsupported auto_pad {s}"),
-                };
-                if let Some(d) = dilations {
-                    if d.iter().any(|&v| v != 1) {
-                        bail!("MaxPool with dilation != 1, {dilations:?}")
-                    }
+            };
+            Op::Cast(dtype)
+        }
+        "Softmax" => Op::Softmax {
+            axis: get_attr_opt::<i64>(node, "axis")?.copied(),
+        },
+        "Transpose" => Op::Transpose {
+            perm: get_attr_opt::<[i64]>(node, "perm")?
+                .map(|perm| perm.iter().map(|&v| v as usize).collect()),
+        },
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Concat
+        "Concat" => Op::Concat {
+            axis: *get_attr(node, "axis")?,
+        },
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Gather
+        "Gather" => Op::Gather {
+            axis: get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0),
+        },
+        "Unsqueeze" => Op::Unsqueeze {
+            axes: get_attr_opt::<[i64]>(node, "axes")?.map(|axes| axes.to_vec()),
+        },
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Gemm
+        "Gemm" => Op::Gemm {
+            alpha: get_attr_opt::<f32>(node, "alpha")?.copied().unwrap_or(1.0),
+            beta: get_attr_opt::<f32>(node, "beta")?.copied().unwrap_or(1.0),
+            trans_a: get_attr_opt::<i64>(node, "transA")?.is_some_and(|&v| v != 0),
+            trans_b: get_attr_opt::<i64>(node, "transB")?.is_some_and(|&v| v != 0),
+        },
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ConstantOfShape
+        "ConstantOfShape" => Op::ConstantOfShape(get_attr_opt_owned::<Tensor>(node, "value")?),
+        "Reshape" => Op::Reshape,
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Conv
+        "Conv" => Op::Conv(ConvAttrs::new(node)?),
+        // https://onnx.ai/onnx/operators/onnx__LayerNormalization.html
+        "LayerNormalization" => Op::LayerNorm {
+            eps: get_attr_opt::<f32>(node, "epsilon")?
+                .copied()
+                .unwrap_or(1e-5),
+            axis: get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(-1),
+        },
+        _ => Op::Node,
+    };
+    Ok(op)
+}
+
+// `opset` is the version of the default operator set imported by the model.
+pub(crate) fn eval_node(node: &onnx::NodeProto, values: &mut Scope<'_>, opset: i64) -> Result<()> {
+    let op = lower_node(node)?;
+    eval_op(&op, node, values, opset)
+}
+
+// Evaluates a node lowered with `lower_node`.
+pub(crate) fn eval_op(
+    op: &Op,
+    node: &onnx::NodeProto,
+    values: &mut Scope<'_>,
+    opset: i64,
+) -> Result<()> {
+    let get = |input_name: &str| match values.get(input_name) {
+        Some(Value::Tensor(t)) => Ok(t),
+        Some(Value::Sequence(_)) => {
+            bail!("expected a tensor for {input_name} in op '{}'", node.name)
+        }
+        None => bail!("cannot find {input_name} for op '{}'", node.name),
+    };
+    let get_opt = |i: usize| {
+        node.input
+            .get(i)
+            .filter(|s: &&String| !s.is_empty())
+            .map(|s| get(s))
+    };
+    let output = match op {
+        Op::Node => return eval_node_(node, values, opset),
+        Op::Unary(f) => f(get(&node.input[0])?)?.into(),
+        Op::Binary(f) => f(get(&node.input[0])?, get(&node.input[1])?)?.into(),
+        Op::Identity => match values.get(&node.input[0]) {
+            Some(value) => value.clone(),
+            None => bail!("cannot find {} for op '{}'", node.input[0], node.name),
+        },
+        Op::Cast(dtype) => get(&node.input[0])?.to_dtype(*dtype)?.into(),
+        Op::Softmax { axis } => {
+            let input = get(&node.input[0])?;
+            let output = match axis {
+                None => candle_nn::ops::softmax_last_dim(input)?,
+                Some(axis) => {
+                    let axis = input.normalize_axis(*axis)?;
+                    candle_nn::ops::softmax(input, axis)?
                 }
-                if let Some(d) = pads {
-                    if d.iter().any(|&v| v != 0) {
-                        bail!("MaxPool with pads != 0, {pads:?}")
+            };
+            output.into()
+        }
+        Op::Transpose { perm } => {
+            let input = get(&node.input[0])?;
+            let output = match perm {
+                None => input.t()?,
+                Some(perm) => input.permute(perm.as_slice())?.contiguous()?,
+            };
+            output.into()
+        }
+        Op::Concat { axis } => {
+            let inputs = node
+                .input
+                .iter()
+                .map(|n| Ok(get(n.as_str())?.clone()))
+                .collect::<Result<Vec<Tensor>>>()?;
+            if inputs.is_empty() {
+                bail!("empty concat")
+            };
+            // Find minimum rank among inputs and squeeze trailing singleton dims to match
+            let min_rank = inputs.iter().map(|t| t.rank()).min().unwrap();
+            let inputs: Vec<_> = inputs
+                .into_iter()
+                .map(|t| {
+                    let mut t = t;
+                    while t.rank() > min_rank {
+                        let last_dim = t.rank() - 1;
+                        if t.dims()[last_dim] == 1 {
+                            t = t.squeeze(last_dim).unwrap_or(t);
+                        } else {
+                            break;
+                        }
                     }
+                    t
+                })
+                .collect();
+            let axis = inputs[0].normalize_axis(*axis)?;
+            let output = Tensor::cat(&inputs, axis).map_err(|e| {
+                let shapes: Vec<_> = inputs.iter().map(|t| format!("{:?}", t.dims())).collect();
+                candle::Error::Msg(format!(
+                    "Concat failed for node '{}': {} (input shapes: {:?})",
+                    node.name, e, shapes
+                ))
+            })?;
+            output.into()
+        }
+        Op::Gather { axis } => {
+            let xs = get(&node.input[0])?;
+            let indices = get(&node.input[1])?;
+            let axis = xs.normalize_axis(*axis)?;
+
+            // index_select does not support negative indices, so normalize them
+            // to positive indices.
+            let indices = &{
+                let zeros = Tensor::zeros(indices.shape(), indices.dtype(), indices.device())?;
+                let max = Tensor::new(xs.dims()[axis] as i64, indices.device())?
+                    .to_dtype(indices.dtype())?;
+                let mask = indices.lt(&zeros)?;
+                mask.to_dtype(indices.dtype())?
+                    .broadcast_mul(&max)?
+                    .add(indices)?
+            };
+
+            // In Pytorch or Numpy this can be done by indexing the xs tensor using the indices
+            // tensor directly, but candle does not support tensor indexing at the moment, so
+            // some workarounds must be done.
+            let xs = match indices.dims() {
+                [] => {
+                    let index = indices.to_vec0::<i64>()? as usize;
+                    xs.narrow(axis, index, 1)?.squeeze(axis)?
                 }
-                let xs = get(&node.input[0])?;
-                let (k1, k2) = match kernel_shape {
-                    [k1, k2] => (*k1 as usize, *k2 as usize),
-                    _ => bail!("only 2d MaxPool is supported, kernel shape {kernel_shape:?}"),
-                };
-                let ys = match strides {
-                    None => xs.max_pool2d((k1, k2))?,
-                    Some([s1, s2]) => {
-                        xs.max_pool2d_with_stride((k1, k2), (*s1 as usize, *s2 as usize))?
-                    }
-                    Some(strides) => bail!("only 2d MaxPool is supported, strides {strides:?}"),
-                };
-                values.insert(node.output[0].clone(), ys);
-            }
-            "AveragePool" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#AveragePool
-                let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
-                let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
-                let pads = get_attr_opt::<[i64]>(node, "pads")?;
-                let strides = get_attr_opt::<[i64]>(node, "strides")?;
-                let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
-                match auto_pad {
-                    None | Some("NOTSET") => (),
-                    Some(s) => bail!("unsupported auto_pad {s}"),
-                };
-                if let Some(d) = dilations {
-                    if d.iter().any(|&v| v != 1) {
-                        bail!("AvgPool with dilation != 1, {dilations:?}")
-                    }
+                [_] => xs.index_select(indices, axis)?,
+                _ => {
+                    // Select along the flattened indices, then split the selected axis back to
+                    // the indices shape.
+                    let mut dims = xs.dims()[..axis].to_vec();
+                    dims.extend_from_slice(indices.dims());
+                    dims.extend_from_slice(&xs.dims()[axis + 1..]);
+                    xs.index_select(&indices.flatten_all()?, axis)?
+                        .reshape(dims)?
                 }
-                if let Some(d) = pads {
-                    if d.iter().any(|&v| v != 0) {
-                        bail!("AvgPool with pads != 0, {pads:?}")
+            };
+            xs.into()
+        }
+        Op::Unsqueeze { axes } => {
+            let xs = get(&node.input[0])?;
+            let axes = match axes {
+                Some(axes) => axes.clone(),
+                None => get(&node.input[1])?.to_vec1::<i64>()?,
+            };
+            let mut axes = axes
+                .iter()
+                .map(|&i| {
+                    if i == xs.rank() as i64 {
+                        Ok(xs.rank())
+                    } else if i < 0 {
+                        // normalize_axis doesn't work correctly here
+                        // because we actually want normalized with respect
+                        // to the final size, not the current (off by one)
+                        Ok(xs.rank() - (-i as usize) + 1)
+                    } else {
+                        xs.normalize_axis(i)
                     }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            axes.sort();
+            let mut xs = xs.clone();
+            for &axis in axes.iter().rev() {
+                xs = xs.unsqueeze(axis)?
+            }
+            xs.into()
+        }
+        Op::Gemm {
+            alpha,
+            beta,
+            trans_a,
+            trans_b,
+        } => {
+            let a = get(&node.input[0])?;
+            let b = get(&node.input[1])?;
+            let c = get(&node.input[2])?;
+
+            let a = if *trans_a { a.t()? } else { a.clone() };
+            let b = if *trans_b { b.t()? } else { b.clone() };
+
+            let output = a
+                .affine(*alpha as f64, 0.)?
+                .broadcast_matmul(&b)?
+                .broadcast_add(&c.affine(*beta as f64, 0.)?)?;
+            output.into()
+        }
+        Op::ConstantOfShape(value) => {
+            let input = get(&node.input[0])?;
+            let shape_vec: Vec<usize> = input
+                .to_vec1::<i64>()?
+                .iter()
+                .map(|&x| x as usize)
+                .collect();
+
+            // The value defaults to a f32 zero.
+            let value = match value {
+                Some(value) => value.to_device(input.device())?,
+                None => Tensor::zeros((), DType::F32, input.device())?,
+            };
+            let xs =
+                Tensor::ones(shape_vec, value.dtype(), input.device())?.broadcast_mul(&value)?;
+            xs.into()
+        }
+        Op::Reshape => {
+            let input0 = get(&node.input[0])?;
+            let input1 = get(&node.input[1])?.to_vec1::<i64>()?;
+            // TODO: Check that there is at most a single -1 or 0, handle other neg values.
+            let mut other_than_minus1 = 1usize;
+            for &v in input1.iter() {
+                if v != -1 && v != 0 {
+                    other_than_minus1 *= v as usize
                 }
-                let xs = get(&node.input[0])?;
-                let (k1, k2) = match kernel_shape {
-                    [k1, k2] => (*k1 as usize, *k2 as usize),
-                    _ => bail!("only 2d AvgPool is supported, kernel shape {kernel_shape:?}"),
-                };
-                let ys = match strides {
-                    None => xs.avg_pool2d((k1, k2))?,
-                    Some([s1, s2]) => {
-                        xs.avg_pool2d_with_stride((k1, k2), (*s1 as usize, *s2 as usize))?
-                    }
-                    Some(strides) => bail!("only 2d AvgPool is supported, strides {strides:?}"),
-                };
-                values.insert(node.output[0].clone(), ys);
             }
-            "BatchNormalization" => {
-                let training_mode = get_attr_opt::<i64>(node, "training_mode")?;
-                if training_mode.copied().unwrap_or(0) != 0 {
-                    bail!("training mode is not supported for BatchNorm")
+            let input1 = input1
+                .iter()
+                .enumerate()
+                .map(|(idx, &v)| match v {
+                    -1 => Ok(input0.elem_count() / other_than_minus1),
+                    0 => input0.dim(idx),
+                    _ => Ok(v as usize),
+                })
+                .collect::<Result<Vec<usize>>>()?;
+            input0.reshape(input1)?.into()
+        }
+        Op::Conv(attrs) => {
+            let xs = get(&node.input[0])?;
+            let ws = get(&node.input[1])?;
+            let ys = conv(node, attrs, xs, ws)?;
+            let ys = if node.input.len() > 2 {
+                let bs = get(&node.input[2])?;
+                let mut bs_shape = vec![1; ys.rank()];
+                bs_shape[1] = bs.elem_count();
+                ys.broadcast_add(&bs.reshape(bs_shape)?)?
+            } else {
+                ys
+            };
+            ys.into()
+        }
+        Op::LayerNorm { eps, axis } => {
+            let eps = *eps;
+            let xs = get(&node.input[0])?;
+            let scale = get(&node.input[1])?;
+            let bias = get_opt(2).transpose()?;
+            let axis = xs.normalize_axis(*axis)?;
+            let with_stats = node.output.iter().skip(1).any(|o| !o.is_empty());
+            match bias {
+                Some(bias)
+                    if axis + 1 == xs.rank()
+                        && scale.rank() == 1
+                        && bias.rank() == 1
+                        && !with_stats =>
+                {
+                    candle_nn::ops::layer_norm(&xs.contiguous()?, scale, bias, eps)?.into()
                 }
-                let eps = get_attr_opt::<f32>(node, "epsilon")?
-                    .copied()
-                    .unwrap_or(1e-5);
-                let xs = get(&node.input[0])?;
-                let weight = get(&node.input[1])?;
-                let bias = get(&node.input[2])?;
-                let running_mean = get(&node.input[3])?;
-                let running_var = get(&node.input[4])?;
-                let target_shape: Vec<usize> = xs
-                    .dims()
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, v)| if idx == 1 { *v } else { 1 })
-                    .collect();
-                let target_shape = target_shape.as_slice();
-                let xs = xs
-                    .broadcast_sub(&running_mean.reshape(target_shape)?)?
-                    .broadcast_div(&(running_var.reshape(target_shape)? + eps as f64)?.sqrt()?)?;
-                let weight = weight.reshape(target_shape)?;
-                let bias = bias.reshape(target_shape)?;
-                let xs = xs.broadcast_mul(&weight)?.broadcast_add(&bias)?;
-                values.insert(node.output[0].clone(), xs);
-            }
-            "Squeeze" => {
-                let xs = get(&node.input[0])?;
-                let mut axes = if node.input.len() <= 1 {
-                    // contract all the dimensions with size 1 except the batch dim.
-                    xs.dims()
+                _ => {
+                    let n_rows = xs.dims()[..axis].iter().product();
+                    let (ys, mean, inv_std_dev) = normalize_rows(xs, n_rows, eps as f64)?;
+                    let ys = ys.broadcast_mul(scale)?;
+                    let ys = match bias {
+                        None => ys,
+                        Some(bias) => ys.broadcast_add(bias)?,
+                    };
+                    let stats_shape = xs
+                        .dims()
                         .iter()
                         .enumerate()
-                        .flat_map(|(idx, &s)| if s == 1 && idx > 0 { Some(idx) } else { None })
-                        .collect()
-                } else {
-                    get(&node.input[1])?
-                        .to_vec1::<i64>()?
-                        .iter()
-                        .map(|&i| xs.normalize_axis(i))
-                        .collect::<Result<Vec<_>>>()?
-                };
-                axes.sort();
-                let mut xs = xs.clone();
-                for &axis in axes.iter().rev() {
-                    xs = xs.squeeze(axis)?
+                        .map(|(idx, &d)| if idx < axis { d } else { 1 })
+                        .collect::<Vec<_>>();
+                    let mean = mean.reshape(stats_shape.as_slice())?;
+                    let inv_std_dev = inv_std_dev.reshape(stats_shape.as_slice())?;
+                    for (output, stat) in node.output.iter().skip(1).zip([mean, inv_std_dev]) {
+                        if !output.is_empty() {
+                            values.insert(output.clone(), stat.into());
+                        }
+                    }
+                    ys.into()
                 }
-                values.insert(node.output[0].clone(), xs);
             }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ConstantOfShape
-            "ConstantOfShape" => {
-                let input = get(&node.input[0])?;
-                let value = get_attr_opt_owned::<Tensor>(node, "value")?.unwrap_or(Tensor::zeros(
-                    (),
-                    DType::F32,
-                    &Device::Cpu,
-                )?);
+        }
+    };
+    values.insert(node.output[0].clone(), output);
+    Ok(())
+}
 
-                let shape_vec: Vec<usize> = input
-                    .to_vec1::<i64>()?
-                    .iter()
-                    .map(|&x| x as usize)
-                    .collect();
-
-                let xs = Tensor::ones(shape_vec, value.dtype(), input.device())?
-                    .broadcast_mul(&value)?;
-                values.insert(node.output[0].clone(), xs);
-            }
-            "Unsqueeze" => {
-                let xs = get(&node.input[0])?;
-                let axes = match get_attr_opt::<[i64]>(node, "axes")? {
-                    Some(axis) => axis.to_vec(),
-                    None => get(&node.input[1])?.to_vec1::<i64>()?,
-                };
-                let mut axes = axes
-                    .iter()
-                    .map(|&i| {
-                        if i == xs.rank() as i64 {
-                            Ok(xs.rank())
-                        } else if i < 0 {
-                            // normalize_axis doesn't work correctly here
-                            // because we actually want normalized with respect
-                            // to the final size, not the current (off by one)
-                            Ok(xs.rank() - (-i as usize) + 1)
-                        } else {
-                            xs.normalize_axis(i)
-                        }
-                    })
-                    .collect::<Result<Vec<_>>>()?;
-                axes.sort();
-                let mut xs = xs.clone();
-                for &axis in axes.iter().rev() {
-                    xs = xs.unsqueeze(axis)?
+// Evaluates the operators that are not lowered to a specific `Op`.
+fn eval_node_(node: &onnx::NodeProto, values: &mut Scope<'_>, opset: i64) -> Result<()> {
+    let get = |input_name: &str| match values.get(input_name) {
+        Some(Value::Tensor(t)) => Ok(t),
+        Some(Value::Sequence(_)) => {
+            bail!("expected a tensor for {input_name} in op '{}'", node.name)
+        }
+        None => bail!("cannot find {input_name} for op '{}'", node.name),
+    };
+    let get_sequence = |input_name: &str| match values.get(input_name) {
+        Some(Value::Sequence(s)) => Ok(s),
+        Some(Value::Tensor(_)) => {
+            bail!("expected a sequence for {input_name} in op '{}'", node.name)
+        }
+        None => bail!("cannot find {input_name} for op '{}'", node.name),
+    };
+    let get_opt = |i: usize| {
+        node.input
+            .get(i)
+            .filter(|s: &&String| !s.is_empty())
+            .map(|s| get(s))
+    };
+    let check_opset = |since: i64| -> Result<()> {
+        if opset < since {
+            bail!(
+                "{} requires opset {since}, the model uses opset {opset}",
+                node.op_type
+            )
+        }
+        Ok(())
+    };
+    // The axes of the reduce ops are an attribute before opset `since` and an optional input
+    // after.
+    let reduce_axes_opt = |since: i64| -> Result<Option<Vec<i64>>> {
+        if opset < since {
+            Ok(get_attr_opt::<[i64]>(node, "axes")?.map(|axes| axes.to_vec()))
+        } else {
+            get_opt(1)
+                .and_then(Result::ok)
+                .map(|axes| axes.to_vec1::<i64>())
+                .transpose()
+        }
+    };
+
+    // TODO: Validate node.input for each operator.
+    match node.op_type.as_str() {
+        "Pow" => {
+            let input0 = get(&node.input[0])?;
+            let input1 = get(&node.input[1])?;
+            // HACK: current implementation of broadcast_pow cannot handle negative base,
+            // so we use powf where we can, which *does* correctly handle negative base.
+            if let Ok(exp) = to_scalar_flexible::<f64>(&input1.to_dtype(DType::F64)?) {
+                let output = input0.powf(exp)?;
+                values.insert(node.output[0].clone(), output.into());
+            } else {
+                let output = input0.broadcast_pow(input1)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+        }
+        "Not" => {
+            let xs = get(&node.input[0])?;
+            let xs = xs.eq(&xs.zeros_like()?)?;
+            values.insert(node.output[0].clone(), xs.into());
+        }
+        "LogSoftmax" => {
+            let input = get(&node.input[0])?;
+            let output = match get_attr_opt::<i64>(node, "axis")? {
+                None => candle_nn::ops::softmax_last_dim(input)?,
+                Some(&axis) => {
+                    let axis = input.normalize_axis(axis)?;
+                    candle_nn::ops::log_softmax(input, axis)?
+                }
+            };
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "Dropout" => {
+            let input = get(&node.input[0])?;
+            // Do not apply dropout at the moment, consider that we're only doing inference.
+            values.insert(node.output[0].clone(), input.clone().into());
+        }
+        "MaxPool" => {
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#MaxPool
+            let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
+            let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
+            let pads = get_attr_opt::<[i64]>(node, "pads")?;
+            let strides = get_attr_opt::<[i64]>(node, "strides")?;
+            let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
+            match auto_pad {
+                None | Some("NOTSET") => (),
+                Some(s) => bail!("unsupported auto_pad {s}"),
+            };
+            if let Some(d) = dilations {
+                if d.iter().any(|&v| v != 1) {
+                    bail!("MaxPool with dilation != 1, {dilations:?}")
                 }
-                values.insert(node.output[0].clone(), xs);
             }
-            "Clip" => {
-                let xs = get(&node.input[0])?;
-                let xs = if let Some(mins) = get_opt(1) {
-                    xs.broadcast_maximum(mins?)?
-                } else {
-                    xs.clone()
-                };
-                let xs = if let Some(maxs) = get_opt(2) {
-                    xs.broadcast_minimum(maxs?)?
-                } else {
-                    xs.clone()
-                };
-                values.insert(node.output[0].clone(), xs);
-            }
-            "Gather" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Gather
-                let xs = get(&node.input[0])?;
-                let indices = get(&node.input[1])?;
-                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
-                let axis = xs.normalize_axis(axis)?;
-
-                // index_select does not support negative indices, so normalize them
-                // to positive indices.
-                let indices = &{
-                    let zeros = Tensor::zeros(indices.shape(), indices.dtype(), indices.device())?;
-                    let max = Tensor::new(xs.dims()[axis] as i64, indices.device())?
-                        .to_dtype(indices.dtype())?;
-                    let mask = indices.lt(&zeros)?;
-                    mask.to_dtype(indices.dtype())?
-                        .broadcast_mul(&max)?
-                        .add(indices)?
-                };
-
-                // In Pytorch or Numpy this can be done by indexing the xs tensor using the indices
-                // tensor directly, but candle does not support tensor indexing at the moment, so
-                // some workarounds must be done.
-                let xs = match indices.dims() {
-                    [] => {
-                        let index = indices.to_vec0::<i64>()? as usize;
-                        xs.narrow(axis, index, 1)?.squeeze(axis)?
-                    }
-                    [_] => xs.index_select(indices, axis)?,
-                    [first, _] => {
-                        let mut v = Vec::with_capacity(*first);
-                        for i in 0..*first {
-                            v.push(xs.index_select(&indices.get(i)?, axis)?)
-                        }
-                        Tensor::stack(&v, axis)?
-                    }
-                    _ => {
-                        // TODO: Provide an op to handle the ONNX generalized gather op ideally in a
-                        // differentiable way.
-                        todo!("implement gather for {xs:?} {indices:?} axis {axis}")
-                    }
-                };
-                values.insert(node.output[0].clone(), xs);
+            if let Some(d) = pads {
+                if d.iter().any(|&v| v != 0) {
+                    bail!("MaxPool with pads != 0, {pads:?}")
+                }
             }
-            // https://onnx.ai/onnx/operators/onnx__GatherElements.html#gatherelements
-            // A Note to fellow lurkers:
-            // The numpy based `gather_elements` implementation in `onnx` tests [here](https://github.com/onnx/onnx/blob/main/onnx/backend/test/case/node/gatherelements.py)
-            // and examples is incorrect.
-            // Use `torch.gather` for the validating/ verifying against the proper behaviour
-            "GatherElements" => {
-                let data = get(&node.input[0])?;
-                let indices = get(&node.input[1])?;
-
-                let rank = data.rank();
-                if rank != indices.rank() {
-                    bail!("indices must have same rank as input data. Data rank [{}] != indices rank [{}]", data.rank(), indices.rank());
+            let xs = get(&node.input[0])?;
+            let (k1, k2) = match kernel_shape {
+                [k1, k2] => (*k1 as usize, *k2 as usize),
+                _ => bail!("only 2d MaxPool is supported, kernel shape {kernel_shape:?}"),
+            };
+            let ys = match strides {
+                None => xs.max_pool2d((k1, k2))?,
+                Some([s1, s2]) => {
+                    xs.max_pool2d_with_stride((k1, k2), (*s1 as usize, *s2 as usize))?
                 }
+                Some(strides) => bail!("only 2d MaxPool is supported, strides {strides:?}"),
+            };
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "AveragePool" => {
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#AveragePool
+            let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
+            let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
+            let pads = get_attr_opt::<[i64]>(node, "pads")?;
+            let strides = get_attr_opt::<[i64]>(node, "strides")?;
+            let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
+            match auto_pad {
+                None | Some("NOTSET") => (),
+                Some(s) => bail!("unsupported auto_pad {s}"),
+            };
+            if let Some(d) = dilations {
+                if d.iter().any(|&v| v != 1) {
+                    bail!("AvgPool with dilation != 1, {dilations:?}")
+                }
+            }
+            if let Some(d) = pads {
+                if d.iter().any(|&v| v != 0) {
+                    bail!("AvgPool with pads != 0, {pads:?}")
+                }
+            }
+            let xs = get(&node.input[0])?;
+            let (k1, k2) = match kernel_shape {
+                [k1, k2] => (*k1 as usize, *k2 as usize),
+                _ => bail!("only 2d AvgPool is supported, kernel shape {kernel_shape:?}"),
+            };
+            let ys = match strides {
+                None => xs.avg_pool2d((k1, k2))?,
+                Some([s1, s2]) => {
+                    xs.avg_pool2d_with_stride((k1, k2), (*s1 as usize, *s2 as usize))?
+                }
+                Some(strides) => bail!("only 2d AvgPool is supported, strides {strides:?}"),
+            };
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "GlobalAveragePool" => {
+            // https://onnx.ai/onnx/operators/onnx__GlobalAveragePool.html
+            let xs = get(&node.input[0])?;
+            let ys = xs.mean_keepdim((2..xs.rank()).collect::<Vec<_>>())?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "GlobalMaxPool" => {
+            // https://onnx.ai/onnx/operators/onnx__GlobalMaxPool.html
+            let xs = get(&node.input[0])?;
+            let mut ys = xs.clone();
+            for dim in 2..xs.rank() {
+                ys = ys.max_keepdim(dim)?
+            }
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "BatchNormalization" => {
+            let training_mode = get_attr_opt::<i64>(node, "training_mode")?;
+            if training_mode.copied().unwrap_or(0) != 0 {
+                bail!("training mode is not supported for BatchNorm")
+            }
+            let eps = get_attr_opt::<f32>(node, "epsilon")?
+                .copied()
+                .unwrap_or(1e-5);
+            let xs = get(&node.input[0])?;
+            let weight = get(&node.input[1])?;
+            let bias = get(&node.input[2])?;
+            let running_mean = get(&node.input[3])?;
+            let running_var = get(&node.input[4])?;
+            let target_shape: Vec<usize> = xs
+                .dims()
+                .iter()
+                .enumerate()
+                .map(|(idx, v)| if idx == 1 { *v } else { 1 })
+                .collect();
+            let target_shape = target_shape.as_slice();
+            let xs = xs
+                .broadcast_sub(&running_mean.reshape(target_shape)?)?
+                .broadcast_div(&(running_var.reshape(target_shape)? + eps as f64)?.sqrt()?)?;
+            let weight = weight.reshape(target_shape)?;
+            let bias = bias.reshape(target_shape)?;
+            let xs = xs.broadcast_mul(&weight)?.broadcast_add(&bias)?;
+            values.insert(node.output[0].clone(), xs.into());
+        }
+        "InstanceNormalization" => {
+            // https://onnx.ai/onnx/operators/onnx__InstanceNormalization.html
+            let eps = get_attr_opt::<f32>(node, "epsilon")?
+                .copied()
+                .unwrap_or(1e-5);
+            let xs = get(&node.input[0])?;
+            let scale = get(&node.input[1])?;
+            let bias = get(&node.input[2])?;
+            let (b_size, c) = (xs.dim(0)?, xs.dim(1)?);
+            let (ys, _, _) = normalize_rows(xs, b_size * c, eps as f64)?;
+            let mut target_shape = vec![1; xs.rank()];
+            target_shape[1] = c;
+            let ys = ys
+                .broadcast_mul(&scale.reshape(target_shape.as_slice())?)?
+                .broadcast_add(&bias.reshape(target_shape.as_slice())?)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "GroupNormalization" => {
+            // https://onnx.ai/onnx/operators/onnx__GroupNormalization.html
+            let eps = get_attr_opt::<f32>(node, "epsilon")?
+                .copied()
+                .unwrap_or(1e-5);
+            let num_groups = *get_attr::<i64>(node, "num_groups")? as usize;
+            let xs = get(&node.input[0])?;
+            let scale = get(&node.input[1])?;
+            let bias = get(&node.input[2])?;
+            let (b_size, c) = (xs.dim(0)?, xs.dim(1)?);
+            if num_groups == 0 || !c.is_multiple_of(num_groups) {
+                bail!("{c} channels cannot be split in {num_groups} groups")
+            }
+            let (ys, _, _) = normalize_rows(xs, b_size * num_groups, eps as f64)?;
+            let mut target_shape = vec![1; xs.rank()];
+            target_shape[1] = c;
+            // Before opset 21, scale and bias have one value per group rather than per channel.
+            let per_channel = |t: &Tensor| -> Result<Tensor> {
+                if t.elem_count() == c {
+                    t.reshape(target_shape.as_slice())
+                } else if t.elem_count() == num_groups {
+                    t.reshape((num_groups, 1))?
+                        .broadcast_as((num_groups, c / num_groups))?
+                        .reshape(target_shape.as_slice())
+                } else {
+                    bail!("unexpected shape {:?} in GroupNormalization", t.shape())
+                }
+            };
+            let ys = ys
+                .broadcast_mul(&per_channel(scale)?)?
+                .broadcast_add(&per_channel(bias)?)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "LpNormalization" => {
+            // https://onnx.ai/onnx/operators/onnx__LpNormalization.html
+            let xs = get(&node.input[0])?;
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(-1);
+            let axis = xs.normalize_axis(axis)?;
+            let norm = match get_attr_opt::<i64>(node, "p")?.copied().unwrap_or(2) {
+                1 => xs.abs()?.sum_keepdim(axis)?,
+                2 => xs.sqr()?.sum_keepdim(axis)?.sqrt()?,
+                p => bail!("unsupported p {p} for LpNormalization"),
+            };
+            let ys = xs.broadcast_div(&norm)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "Squeeze" => {
+            let xs = get(&node.input[0])?;
+            let mut axes = if node.input.len() <= 1 {
+                // contract all the dimensions with size 1 except the batch dim.
+                xs.dims()
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(idx, &s)| if s == 1 && idx > 0 { Some(idx) } else { None })
+                    .collect()
+            } else {
+                get(&node.input[1])?
+                    .to_vec1::<i64>()?
+                    .iter()
+                    .map(|&i| xs.normalize_axis(i))
+                    .collect::<Result<Vec<_>>>()?
+            };
+            axes.sort();
+            let mut xs = xs.clone();
+            for &axis in axes.iter().rev() {
+                xs = xs.squeeze(axis)?
+            }
+            values.insert(node.output[0].clone(), xs.into());
+        }
+        "Clip" => {
+            let xs = get(&node.input[0])?;
+            let xs = if let Some(mins) = get_opt(1) {
+                xs.broadcast_maximum(mins?)?
+            } else {
+                xs.clone()
+            };
+            let xs = if let Some(maxs) = get_opt(2) {
+                xs.broadcast_minimum(maxs?)?
+            } else {
+                xs.clone()
+            };
+            values.insert(node.output[0].clone(), xs.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__GatherElements.html#gatherelements
+        // A Note to fellow lurkers:
+        // The numpy based `gather_elements` implementation in `onnx` tests [here](https://github.com/onnx/onnx/blob/main/onnx/backend/test/case/node/gatherelements.py)
+        // and examples is incorrect.
+        // Use `torch.gather` for the validating/ verifying against the proper behaviour
+        "GatherElements" => {
+            let data = get(&node.input[0])?;
+            let indices = get(&node.input[1])?;
+
+            let rank = data.rank();
+            if rank != indices.rank() {
+                bail!("indices must have same rank as input data. Data rank [{}] != indices rank [{}]", data.rank(), indices.rank());
+            }
 
-                let axis = {
-                    let axis_i64 = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
-                    let axis = data.normalize_axis(axis_i64)?;
-
-                    if axis >= rank {
-                        bail!(
-                            "axis ({}) out of accepted range [-rank, rank-1] which was [-{rank}, {}]",
-                            axis_i64,
-                            rank - 1
-                        )
-                    }
+            let axis = {
+                let axis_i64 = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+                let axis = data.normalize_axis(axis_i64)?;
 
-                    axis
-                };
+                if axis >= rank {
+                    bail!(
+                        "axis ({}) out of accepted range [-rank, rank-1] which was [-{rank}, {}]",
+                        axis_i64,
+                        rank - 1
+                    )
+                }
 
-                // index_select does not support negative indices, so normalize them
-                // to positive indices.
-                let indices = &{
-                    let zeros = Tensor::zeros(indices.shape(), indices.dtype(), indices.device())?;
-                    let max = Tensor::new(data.dims()[axis] as i64, indices.device())?
-                        .to_dtype(indices.dtype())?;
-                    let mask = indices.lt(&zeros)?;
-                    mask.to_dtype(indices.dtype())?
-                        .broadcast_mul(&max)?
-                        .add(indices)?
+                axis
+            };
+
+            // index_select does not support negative indices, so normalize them
+            // to positive indices.
+            let indices = &{
+                let zeros = Tensor::zeros(indices.shape(), indices.dtype(), indices.device())?;
+                let max = Tensor::new(data.dims()[axis] as i64, indices.device())?
+                    .to_dtype(indices.dtype())?;
+                let mask = indices.lt(&zeros)?;
+                mask.to_dtype(indices.dtype())?
+                    .broadcast_mul(&max)?
+                    .add(indices)?
+            };
+
+            values.insert(node.output[0].clone(), data.gather(indices, axis)?.into());
+        }
+        "Shape" => {
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Shape
+            let xs = get(&node.input[0])?;
+            let start = get_attr_opt::<i64>(node, "start")?.copied().unwrap_or(0);
+            let end = get_attr_opt::<i64>(node, "end")?.copied().unwrap_or(-1);
+            let start = xs.normalize_axis(start)?;
+            let end = xs.normalize_axis(end)?;
+            let mut dims = vec![];
+            for idx in start..=end {
+                dims.push(xs.dim(idx)? as i64)
+            }
+            let dims = Tensor::from_vec(dims, xs.rank(), xs.device())?;
+            values.insert(node.output[0].clone(), dims.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Size
+        "Size" => {
+            let data = get(&node.input[0])?;
+            let size: usize = data.dims().iter().product();
+            let output = Tensor::from_slice(&[size as i64], (), data.device())?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Range
+        "Range" => {
+            let start = get(&node.input[0])?;
+            let limit = get(&node.input[1])?;
+            let delta = get(&node.input[2])?;
+
+            macro_rules! arange_step {
+                ($t: ty) => {
+                    Tensor::arange_step(
+                        to_vec0_flexible::<$t>(start)?,
+                        to_vec0_flexible::<$t>(limit)?,
+                        to_vec0_flexible::<$t>(delta)?,
+                        &Device::Cpu,
+                    )?
                 };
+            }
 
-                values.insert(node.output[0].clone(), data.gather(indices, axis)?);
-            }
-            "Shape" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Shape
-                let xs = get(&node.input[0])?;
-                let start = get_attr_opt::<i64>(node, "start")?.copied().unwrap_or(0);
-                let end = get_attr_opt::<i64>(node, "end")?.copied().unwrap_or(-1);
-                let start = xs.normalize_axis(start)?;
-                let end = xs.normalize_axis(end)?;
-                let mut dims = vec![];
-                for idx in start..=end {
-                    dims.push(xs.dim(idx)? as i64)
-                }
-                let dims = Tensor::from_vec(dims, xs.rank(), xs.device())?;
-                values.insert(node.output[0].clone(), dims);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Size
-            "Size" => {
-                let data = get(&node.input[0])?;
-                let size: usize = data.dims().iter().product();
-                let output = Tensor::from_slice(&[size as i64], (), data.device())?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Sqrt
-            "Sqrt" => {
-                let xs = get(&node.input[0])?;
-                let output = xs.sqrt()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Range
-            "Range" => {
-                let start = get(&node.input[0])?;
-                let limit = get(&node.input[1])?;
-                let delta = get(&node.input[2])?;
-
-                macro_rules! arange_step {
-                    ($t: ty) => {
-                        Tensor::arange_step(
-                            to_vec0_flexible::<$t>(start)?,
-                            to_vec0_flexible::<$t>(limit)?,
-                            to_vec0_flexible::<$t>(delta)?,
-                            &Device::Cpu,
-                        )?
-                    };
+            let output = match start.dtype() {
+                DType::U8 => arange_step!(u8),
+                DType::U32 => arange_step!(u32),
+                DType::I64 => arange_step!(i64),
+                DType::BF16 => arange_step!(f32),
+                DType::F16 => arange_step!(f32),
+                DType::F32 => arange_step!(f32),
+                DType::F64 => arange_step!(f64),
+                DType::F8E4M3 => arange_step!(f32),
+                DType::I32
+                | DType::I16
+                | DType::F6E2M3
+                | DType::F6E3M2
+                | DType::F4
+                | DType::F8E8M0 => {
+                    bail!("unsupported Range type i32/i16/f6e2m3/f6e3m2/f4/f8e8m0")
                 }
+            };
 
-                let output = match start.dtype() {
-                    DType::U8 => arange_step!(u8),
-                    DType::U32 => arange_step!(u32),
-                    DType::I64 => arange_step!(i64),
-                    DType::BF16 => arange_step!(f32),
-                    DType::F16 => arange_step!(f32),
-                    DType::F32 => arange_step!(f32),
-                    DType::F64 => arange_step!(f64),
-                    DType::F8E4M3 => arange_step!(f32),
-                    DType::I32
-                    | DType::I16
-                    | DType::F6E2M3
-                    | DType::F6E3M2
-                    | DType::F4
-                    | DType::F8E8M0 => {
-                        bail!("unsupported Range type i32/i16/f6e2m3/f6e3m2/f4/f8e8m0")
-                    }
-                };
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Min
+        "Min" => {
+            let mut output = get(&node.input[0])?.clone();
+            for input in node.input.iter() {
+                let input = get(input)?;
+                output = output.broadcast_minimum(input)?
+            }
 
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Greater
-            "Greater" => {
-                let a = get(&node.input[0])?;
-                let b = get(&node.input[1])?;
-
-                let output = a.broadcast_gt(b)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Less
-            "Less" => {
-                let a = get(&node.input[0])?;
-                let b = get(&node.input[1])?;
-
-                let output = a.broadcast_lt(b)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#LessOrEqual
-            "LessOrEqual" => {
-                let a = get(&node.input[0])?;
-                let b = get(&node.input[1])?;
-
-                let output = a.broadcast_le(b)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GreaterOrEqual
-            "GreaterOrEqual" => {
-                let a = get(&node.input[0])?;
-                let b = get(&node.input[1])?;
-
-                let output = a.broadcast_ge(b)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Log
-            "Log" => {
-                let a = get(&node.input[0])?;
-
-                let output = a.log()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Min
-            "Min" => {
-                let mut output = get(&node.input[0])?.clone();
-                for input in node.input.iter() {
-                    let input = get(input)?;
-                    output = output.broadcast_minimum(input)?
-                }
-
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Where
-            "Where" => {
-                let cond = get(&node.input[0])?;
-                let a = get(&node.input[1])?;
-                let b = get(&node.input[2])?;
-
-                // where_cond requires that all inputs are the same shape.
-                // In contrast, the Where op in ONNX only requires that they are broadcastable.
-                let shape = broadcast_shape_from_many(&[cond.dims(), a.dims(), b.dims()])?;
-                let cond = cond.broadcast_as(shape.clone())?;
-                let a = a.broadcast_as(shape.clone())?;
-                let b = b.broadcast_as(shape)?;
-                let output = cond.where_cond(&a, &b)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Conv" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Conv
-                let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
-                let groups = get_attr_opt::<i64>(node, "group")?.copied().unwrap_or(1);
-                let _kernel_shape = get_attr_opt::<[i64]>(node, "kernel_shape")?;
-                let pads = get_attr_opt::<[i64]>(node, "pads")?;
-                let strides = get_attr_opt::<[i64]>(node, "strides")?;
-                let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
-                match auto_pad {
-                    None | Some("NOTSET") => (),
-                    Some(s) => bail!("unsupported auto_pad {s}"),
-                };
-                let xs = get(&node.input[0])?;
-                let ws = get(&node.input[1])?;
-                let ys = match ws.rank() {
-                    3 => {
-                        let (pads, xs) = match pads {
-                            None => (0, xs.clone()),
-                            Some([p]) => (*p as usize, xs.clone()),
-                            Some([p1, p2]) => {
-                                if p1 != p2 {
-                                    (0usize, xs.pad_with_zeros(2, *p1 as usize, *p2 as usize)?)
-                                } else {
-                                    (*p1 as usize, xs.clone())
-                                }
-                            }
-                            Some(pads) => {
-                                bail!("more pads than expected in conv1d {pads:?} {}", node.name)
-                            }
-                        };
-                        let strides = match strides {
-                            None => 1,
-                            Some([p]) => *p as usize,
-                            Some(s) => {
-                                bail!("more strides than expected in conv1d {s:?} {}", node.name)
-                            }
-                        };
-                        let dilations = match dilations {
-                            None => 1,
-                            Some([p]) => *p as usize,
-                            Some(s) => {
-                                bail!("more dilations than expected in conv1d {s:?} {}", node.name)
-                            }
-                        };
-                        xs.conv1d(ws, pads, strides, dilations, groups as usize)?
-                    }
-                    4 => {
-                        let (pads, xs) = match pads {
-                            None => (0, xs.clone()),
-                            Some([p]) => (*p as usize, xs.clone()),
-                            Some(&[p1, p2, p3, p4]) => {
-                                let p1 = p1 as usize;
-                                let p2 = p2 as usize;
-                                let p3 = p3 as usize;
-                                let p4 = p4 as usize;
-                                if p1 != p2 || p1 != p3 || p1 != p4 {
-                                    (0, xs.pad_with_zeros(2, p1, p3)?.pad_with_zeros(3, p2, p4)?)
-                                } else {
-                                    (p1, xs.clone())
-                                }
-                            }
-                            Some(pads) => {
-                                bail!("more pads than expected in conv2d {pads:?} {}", node.name)
-                            }
-                        };
-                        let strides = match strides {
-                            None => 1,
-                            Some([p]) => *p as usize,
-                            Some([p1, p2]) => {
-                                if p1 != p2 {
-                                    bail!(
-                                        "strides have to be the same on both axis {pads:?} {}",
-                                        node.name
-                                    )
-                                }
-                                *p1 as usize
-                            }
-                            Some(s) => {
-                                bail!("more strides than expected in conv2d {s:?} {}", node.name)
-                            }
-                        };
-                        let dilations = match dilations {
-                            None => 1,
-                            Some([p]) => *p as usize,
-                            Some([p1, p2]) => {
-                                if p1 != p2 {
-                                    bail!(
-                                        "dilations have to be the same on both axis {pads:?} {}",
-                                        node.name
-                                    )
-                                }
-                                *p1 as usize
-                            }
-                            Some(s) => {
-                                bail!("more dilations than expected in conv2d {s:?} {}", node.name)
-                            }
-                        };
-                        xs.conv2d(ws, pads, strides, dilations, groups as usize)?
-                    }
-                    rank => bail!(
-                        "unsupported rank for weight matrix {rank} in conv {}",
-                        node.name
-                    ),
-                };
-                let ys = if node.input.len() > 2 {
-                    let bs = get(&node.input[2])?;
-                    let mut bs_shape = vec![1; ys.rank()];
-                    bs_shape[1] = bs.elem_count();
-                    ys.broadcast_add(&bs.reshape(bs_shape)?)?
-                } else {
-                    ys
-                };
-                values.insert(node.output[0].clone(), ys);
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Where
+        "Where" => {
+            let cond = get(&node.input[0])?;
+            let a = get(&node.input[1])?;
+            let b = get(&node.input[2])?;
+
+            // where_cond requires that all inputs are the same shape.
+            // In contrast, the Where op in ONNX only requires that they are broadcastable.
+            let shape = broadcast_shape_from_many(&[cond.dims(), a.dims(), b.dims()])?;
+            let cond = cond.broadcast_as(shape.clone())?;
+            let a = a.broadcast_as(shape.clone())?;
+            let b = b.broadcast_as(shape)?;
+            let output = cond.where_cond(&a, &b)?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "ConvTranspose" => {
+            // https://onnx.ai/onnx/operators/onnx__ConvTranspose.html
+            let xs = get(&node.input[0])?;
+            let ws = get(&node.input[1])?;
+            let groups = get_attr_opt::<i64>(node, "group")?.copied().unwrap_or(1) as usize;
+            let n_dims = ws.rank().saturating_sub(2);
+            if !(1..=3).contains(&n_dims) || xs.rank() != ws.rank() {
+                bail!(
+                    "unsupported shapes {:?} {:?} in ConvTranspose {}",
+                    xs.shape(),
+                    ws.shape(),
+                    node.name
+                )
             }
-            "Concat" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Concat
-                let inputs = node
-                    .input
-                    .iter()
-                    .map(|n| Ok(get(n.as_str())?.clone()))
-                    .collect::<Result<Vec<Value>>>()?;
-                let axis: i64 = *get_attr(node, "axis")?;
-                if inputs.is_empty() {
-                    bail!("empty concat")
-                };
-                // Find minimum rank among inputs and squeeze trailing singleton dims to match
-                let min_rank = inputs.iter().map(|t| t.rank()).min().unwrap();
-                let inputs: Vec<_> = inputs
-                    .into_iter()
-                    .map(|t| {
-                        let mut t = t;
-                        while t.rank() > min_rank {
-                            let last_dim = t.rank() - 1;
-                            if t.dims()[last_dim] == 1 {
-                                t = t.squeeze(last_dim).unwrap_or(t);
-                            } else {
-                                break;
-                            }
+            if groups == 0 || !xs.dim(1)?.is_multiple_of(groups) {
+                bail!("unexpected group {groups} in ConvTranspose {}", node.name)
+            }
+            let attr = |name: &str, default: usize| -> Result<Vec<usize>> {
+                match get_attr_opt::<[i64]>(node, name)? {
+                    None => Ok(vec![default; n_dims]),
+                    Some(v) if v.len() == n_dims => Ok(v.iter().map(|&v| v as usize).collect()),
+                    Some(v) => bail!("unexpected {name} {v:?} in ConvTranspose {}", node.name),
+                }
+            };
+            let same_on_all_axis = |v: Vec<usize>, name: &str| -> Result<usize> {
+                if v.iter().any(|&x| x != v[0]) {
+                    bail!("{name} have to be the same on all axis {v:?} {}", node.name)
+                }
+                Ok(v[0])
+            };
+            let stride = same_on_all_axis(attr("strides", 1)?, "strides")?;
+            let dilation = same_on_all_axis(attr("dilations", 1)?, "dilations")?;
+            let output_padding = attr("output_padding", 0)?;
+            let in_dims = &xs.dims()[2..];
+            // The size of each spatial dim when there is no padding.
+            let full_dims = in_dims
+                .iter()
+                .zip(ws.dims()[2..].iter())
+                .map(|(&i, &k)| i.saturating_sub(1) * stride + dilation * (k - 1) + 1)
+                .collect::<Vec<_>>();
+            let auto_pad = get_attr_opt::<str>(node, "auto_pad")?.unwrap_or("NOTSET");
+            let output_shape = match (get_attr_opt::<[i64]>(node, "output_shape")?, auto_pad) {
+                (Some(s), _) if s.len() >= n_dims => Some(
+                    s[s.len() - n_dims..]
+                        .iter()
+                        .map(|&v| v as usize)
+                        .collect::<Vec<_>>(),
+                ),
+                (Some(s), _) => bail!("unexpected output_shape {s:?} in ConvTranspose"),
+                (None, "SAME_UPPER" | "SAME_LOWER") => {
+                    Some(in_dims.iter().map(|&i| i * stride).collect())
+                }
+                (None, "NOTSET" | "VALID") => None,
+                (None, s) => bail!("unsupported auto_pad {s}"),
+            };
+            // The padding at the beginning and at the end of each spatial dim.
+            let pads = match output_shape {
+                Some(output_shape) => (0..n_dims)
+                    .map(|i| {
+                        let total = full_dims[i] + output_padding[i];
+                        if output_shape[i] > total {
+                            bail!("output_shape {output_shape:?} is too large in ConvTranspose")
+                        }
+                        let total = total - output_shape[i];
+                        if auto_pad == "SAME_UPPER" {
+                            Ok((total / 2, total - total / 2))
+                        } else {
+                            Ok((total - total / 2, total / 2))
                         }
-                        t
                     })
-                    .collect();
-                let axis = inputs[0].normalize_axis(axis)?;
-                let output = Tensor::cat(&inputs, axis).map_err(|e| {
-                    let shapes: Vec<_> = inputs.iter().map(|t| format!("{:?}", t.dims())).collect();
-                    candle::Error::Msg(format!(
-                        "Concat failed for node '{}': {} (input shapes: {:?})",
-                        node.name, e, shapes
-                    ))
-                })?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Abs" => {
-                let input = get(&node.input[0])?;
-                let output = input.abs()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Cos" => {
-                let input = get(&node.input[0])?;
-                let output = input.cos()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Sin" => {
-                let input = get(&node.input[0])?;
-                let output = input.sin()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Neg" => {
-                let input = get(&node.input[0])?;
-                // neg() not implemented for i64, work around with multiply by -1
-                let output = if input.dtype() == DType::I64 {
-                    let minus_one =
-                        Tensor::new(&[-1i64], input.device())?.broadcast_as(input.shape())?;
-                    input.mul(&minus_one)?
-                } else {
-                    input.neg()?
-                };
-                values.insert(node.output[0].clone(), output);
-            }
-            "Erf" => {
-                let input = get(&node.input[0])?;
-                let output = input.erf()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Tanh" => {
-                let input = get(&node.input[0])?;
-                let output = input.tanh()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Sigmoid" => {
-                let input = get(&node.input[0])?;
-                let output = candle_nn::ops::sigmoid(input)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Gelu" => {
-                let input = get(&node.input[0])?;
-                let output = input.gelu_erf()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Relu" => {
-                let input = get(&node.input[0])?;
-                let output = input.relu()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "PRelu" => {
-                // https://onnx.ai/onnx/operators/onnx__PRelu.html
-                let input = get(&node.input[0])?;
-                let slope = get(&node.input[1])?;
-
-                let output = PReLU::new(slope.clone(), false).forward(input)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Ceil" => {
-                let input = get(&node.input[0])?;
-                let output = input.ceil()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Floor" => {
-                let input = get(&node.input[0])?;
-                let output = input.floor()?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Constant
-            "Constant" => {
-                let value = match node.attribute.iter().find(|attr| attr.name == "value") {
-                    None => {
-                        // TODO: support sparse_value etc.
-                        bail!("cannot find 'value' attr in 'Constant' for {}", node.name)
-                    }
-                    Some(value) => value,
-                };
-                let output = match value.r#type() {
-                    AttributeType::Tensor => {
-                        let t = value.t.as_ref().unwrap();
-                        get_tensor(t, &node.name)?
-                    }
-                    rtype => bail!("unsupported 'value' type {rtype:?} for {}", node.name),
-                };
+                    .collect::<Result<Vec<_>>>()?,
+                None => match get_attr_opt::<[i64]>(node, "pads")? {
+                    None => vec![(0, 0); n_dims],
+                    Some(_) if auto_pad == "VALID" => vec![(0, 0); n_dims],
+                    Some(p) if p.len() == 2 * n_dims => (0..n_dims)
+                        .map(|i| (p[i] as usize, p[i + n_dims] as usize))
+                        .collect(),
+                    Some(p) => bail!("unexpected pads {p:?} in ConvTranspose {}", node.name),
+                },
+            };
+            // The transposed convolution is computed without padding, the result is then cropped
+            // and extended with zeros according to the pads and output_padding.
+            let ys = match n_dims {
+                1 => xs.conv_transpose1d(ws, 0, 0, stride, dilation, groups)?,
+                2 if groups == 1 => xs.conv_transpose2d(ws, 0, 0, stride, dilation)?,
+                2 => {
+                    // conv_transpose2d does not support groups, each group is processed separately.
+                    let ys = xs
+                        .chunk(groups, 1)?
+                        .iter()
+                        .zip(ws.chunk(groups, 0)?.iter())
+                        .map(|(xs, ws)| xs.conv_transpose2d(ws, 0, 0, stride, dilation))
+                        .collect::<Result<Vec<_>>>()?;
+                    Tensor::cat(&ys, 1)?
+                }
+                _ => xs.conv_transpose3d(ws, 0, 0, stride, dilation, groups)?,
+            };
+            let mut ys = ys;
+            for (i, &(pad_begin, pad_end)) in pads.iter().enumerate() {
+                let len = (full_dims[i] + output_padding[i]).saturating_sub(pad_begin + pad_end);
+                let start = pad_begin.min(full_dims[i]);
+                let available = (full_dims[i] - start).min(len);
+                let dim = i + 2;
+                ys = ys
+                    .narrow(dim, start, available)?
+                    .pad_with_zeros(dim, 0, len - available)?;
+            }
+            let ys = match get_opt(2) {
+                Some(bs) => {
+                    let bs = bs?;
+                    let mut bs_shape = vec![1; ys.rank()];
+                    bs_shape[1] = bs.elem_count();
+                    ys.broadcast_add(&bs.reshape(bs_shape)?)?
+                }
+                None => ys,
+            };
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "Neg" => {
+            let input = get(&node.input[0])?;
+            // neg() not implemented for i64, work around with multiply by -1
+            let output = if input.dtype() == DType::I64 {
+                let minus_one =
+                    Tensor::new(&[-1i64], input.device())?.broadcast_as(input.shape())?;
+                input.mul(&minus_one)?
+            } else {
+                input.neg()?
+            };
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "PRelu" => {
+            // https://onnx.ai/onnx/operators/onnx__PRelu.html
+            let input = get(&node.input[0])?;
+            let slope = get(&node.input[1])?;
 
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Cast
-            "Cast" => {
-                let input = get(&node.input[0])?;
-                let dt: i64 = *get_attr(node, "to")?;
-                let dtype = match DataType::try_from(dt as i32) {
-                    Ok(DataType::Int32) => DType::I64,
-                    Ok(dt) => match dtype(dt) {
-                        Some(dt) => dt,
-                        None => {
-                            bail!("unsupported 'to' value {dt:?} for cast {}", node.name)
-                        }
-                    },
-                    Err(_) => {
-                        bail!("unsupported 'to' value {dt:?} for cast {}", node.name)
+            let output = PReLU::new(slope.clone(), false).forward(input)?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Constant
+        "Constant" => {
+            let value = match node.attribute.iter().find(|attr| attr.name == "value") {
+                None => {
+                    // TODO: support sparse_value etc.
+                    bail!("cannot find 'value' attr in 'Constant' for {}", node.name)
+                }
+                Some(value) => value,
+            };
+            let output = match value.r#type() {
+                AttributeType::Tensor => {
+                    let t = value.t.as_ref().unwrap();
+                    get_tensor(t, &node.name)?
+                }
+                rtype => bail!("unsupported 'value' type {rtype:?} for {}", node.name),
+            };
+
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "QuantizeLinear" => {
+            // https://onnx.ai/onnx/operators/onnx__QuantizeLinear.html
+            let xs = get(&node.input[0])?;
+            let scale = get(&node.input[1])?;
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(1);
+            let block_size = get_attr_opt::<i64>(node, "block_size")?
+                .copied()
+                .unwrap_or(0) as usize;
+            let zero_point = match get_opt(2) {
+                Some(zero_point) => zero_point?.clone(),
+                None => {
+                    let dtype = match get_attr_opt::<i64>(node, "output_dtype")?.copied() {
+                        None | Some(0) => DType::U8,
+                        Some(dt) if dt == DataType::Uint8 as i64 => DType::U8,
+                        Some(dt) if dt == DataType::Int8 as i64 => DType::I16,
+                        Some(dt) => bail!("unsupported output_dtype {dt} for {}", node.name),
+                    };
+                    Tensor::zeros((), dtype, xs.device())?
+                }
+            };
+            let ys = quantize_linear(xs, scale, &zero_point, axis, block_size)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "DequantizeLinear" => {
+            // https://onnx.ai/onnx/operators/onnx__DequantizeLinear.html
+            let xs = get(&node.input[0])?;
+            let scale = get(&node.input[1])?;
+            let zero_point = get_opt(2).transpose()?;
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(1);
+            let block_size = get_attr_opt::<i64>(node, "block_size")?
+                .copied()
+                .unwrap_or(0) as usize;
+            let ys = dequantize_linear(xs, scale, zero_point, axis, block_size)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "DynamicQuantizeLinear" => {
+            // https://onnx.ai/onnx/operators/onnx__DynamicQuantizeLinear.html
+            let xs = get(&node.input[0])?.to_dtype(DType::F32)?;
+            let flat_xs = xs.flatten_all()?;
+            // The quantization range has to include 0.
+            let x_min = flat_xs.min(0)?.to_scalar::<f32>()?.min(0.);
+            let x_max = flat_xs.max(0)?.to_scalar::<f32>()?.max(0.);
+            let scale = (x_max - x_min) / 255.;
+            let (ys, zero_point) = if scale == 0. {
+                // All the values are zeros.
+                (xs.zeros_like()?.to_dtype(DType::U8)?, 0u8)
+            } else {
+                let zero_point = (-x_min / scale).round_ties_even().clamp(0., 255.) as u8;
+                let zp = Tensor::new(zero_point, xs.device())?;
+                let ys = quantize_linear(&xs, &Tensor::new(scale, xs.device())?, &zp, 1, 0)?;
+                (ys, zero_point)
+            };
+            let scale = Tensor::new(scale, xs.device())?;
+            let zero_point = Tensor::new(zero_point, xs.device())?;
+            values.insert(node.output[0].clone(), ys.into());
+            values.insert(node.output[1].clone(), scale.into());
+            values.insert(node.output[2].clone(), zero_point.into());
+        }
+        "MatMulInteger" => {
+            // https://onnx.ai/onnx/operators/onnx__MatMulInteger.html
+            // The products are accumulated with f64 values, this is exact for 8 bits inputs.
+            let a = get(&node.input[0])?.to_dtype(DType::F64)?;
+            let b = get(&node.input[1])?.to_dtype(DType::F64)?;
+            let a = match get_opt(2) {
+                None => a,
+                Some(zero_point) => {
+                    // The zero point of a is either a scalar or has one value per row.
+                    let zero_point = zero_point?.to_dtype(DType::F64)?;
+                    let zero_point = match zero_point.rank() {
+                        1 => zero_point.reshape((zero_point.elem_count(), 1))?,
+                        _ => zero_point,
+                    };
+                    a.broadcast_sub(&zero_point)?
+                }
+            };
+            let b = match get_opt(3) {
+                None => b,
+                Some(zero_point) => b.broadcast_sub(&zero_point?.to_dtype(DType::F64)?)?,
+            };
+            let ys = a.broadcast_matmul(&b)?.to_dtype(DType::I64)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "QLinearMatMul" => {
+            // https://onnx.ai/onnx/operators/onnx__QLinearMatMul.html
+            // a is quantized per row and b per column, the same code handles the per-tensor case.
+            let a = dequantize_linear(
+                get(&node.input[0])?,
+                get(&node.input[1])?,
+                Some(get(&node.input[2])?),
+                -2,
+                0,
+            )?;
+            let b = dequantize_linear(
+                get(&node.input[3])?,
+                get(&node.input[4])?,
+                Some(get(&node.input[5])?),
+                -1,
+                0,
+            )?;
+            let ys = a.broadcast_matmul(&b)?;
+            let y_scale = get(&node.input[6])?;
+            let y_zero_point = get(&node.input[7])?;
+            let ys = quantize_linear(&ys, y_scale, y_zero_point, -1, 0)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "ConvInteger" => {
+            // https://onnx.ai/onnx/operators/onnx__ConvInteger.html
+            let xs = get(&node.input[0])?;
+            let ws = get(&node.input[1])?;
+            let one = Tensor::new(1f32, xs.device())?;
+            let xs = dequantize_linear(xs, &one, get_opt(2).transpose()?, 1, 0)?;
+            let ws = dequantize_linear(ws, &one, get_opt(3).transpose()?, 0, 0)?;
+            let ys = conv(node, &ConvAttrs::new(node)?, &xs, &ws)?.to_dtype(DType::I64)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "QLinearConv" => {
+            // https://onnx.ai/onnx/operators/onnx__QLinearConv.html
+            let x_scale = get(&node.input[1])?;
+            let w_scale = get(&node.input[4])?;
+            let xs = dequantize_linear(
+                get(&node.input[0])?,
+                x_scale,
+                Some(get(&node.input[2])?),
+                1,
+                0,
+            )?;
+            // The weights are quantized per tensor or per output channel.
+            let ws = dequantize_linear(
+                get(&node.input[3])?,
+                w_scale,
+                Some(get(&node.input[5])?),
+                0,
+                0,
+            )?;
+            let ys = conv(node, &ConvAttrs::new(node)?, &xs, &ws)?;
+            let ys = match get_opt(8) {
+                None => ys,
+                Some(bs) => {
+                    // The int32 bias has a zero point of 0 and a scale of x_scale * w_scale.
+                    let bs = bs?
+                        .to_dtype(ys.dtype())?
+                        .broadcast_mul(&x_scale.to_dtype(ys.dtype())?)?
+                        .broadcast_mul(&w_scale.to_dtype(ys.dtype())?)?;
+                    let mut bs_shape = vec![1; ys.rank()];
+                    bs_shape[1] = bs.elem_count();
+                    ys.broadcast_add(&bs.reshape(bs_shape)?)?
+                }
+            };
+            let y_scale = get(&node.input[6])?;
+            let y_zero_point = get(&node.input[7])?;
+            let ys = quantize_linear(&ys, y_scale, y_zero_point, 1, 0)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#CumSum
+        "CumSum" => {
+            let exclusive = get_attr_opt::<i64>(node, "exclusive")?
+                .copied()
+                .unwrap_or(0);
+            let reverse = get_attr_opt::<i64>(node, "reverse")?.copied().unwrap_or(0);
+            if exclusive != 0 {
+                bail!("only exclusive == 0 is supported in CumSum")
+            }
+            if reverse != 0 {
+                bail!("only reverse == 0 is supported in CumSum")
+            }
+            let input = get(&node.input[0])?;
+            let axis = to_vec0_flexible::<u32>(&get(&node.input[1])?.to_dtype(DType::U32)?)?;
+            let output = input.cumsum(axis as usize)?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        //  https://github.com/onnx/onnx/blob/main/docs/Operators.md#flatten
+        "Flatten" => {
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(1) as usize;
+            let input = get(&node.input[0])?;
+            let first_part: usize = input.shape().dims().iter().take(axis).product();
+            let end_index = input.shape().dims().iter().product::<usize>();
+            let new_shape = (first_part, end_index / first_part);
+            let output = input.reshape(new_shape)?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#if
+        "If" => {
+            // protobuf encodes boolean false as 0 and true as 1
+            let cond = to_scalar_flexible::<u8>(&get(&node.input[0])?.get(0)?)?;
+            let attr_name = if cond != 0 {
+                "then_branch"
+            } else {
+                "else_branch"
+            };
+            let sub_graph = get_attr::<GraphProto>(node, attr_name)?;
+            if sub_graph.output.len() != node.output.len() {
+                bail!(
+                    "If node {:?} is malformed: branch outputs ({}) don't match node outputs ({})",
+                    node.name,
+                    sub_graph.output.len(),
+                    node.output.len()
+                );
+            }
+            let mut scope = Scope::new(initializers(sub_graph)?, Some(&*values));
+            let branch_out = eval_graph(sub_graph, &mut scope, opset)?;
+            for (out, value) in node.output.iter().zip(branch_out) {
+                values.insert(out.clone(), value);
+            }
+        }
+        // https://onnx.ai/onnx/operators/onnx__Loop.html
+        "Loop" => {
+            let body = get_attr::<GraphProto>(node, "body")?;
+            let max_trip_count = match get_opt(0) {
+                None => None,
+                Some(m) => Some(to_scalar_flexible::<i64>(&m?.to_dtype(DType::I64)?)?),
+            };
+            // The condition computed by the body is ignored when there is no cond input.
+            let use_cond = node.input.get(1).is_some_and(|s| !s.is_empty());
+            let mut cond = match get_opt(1) {
+                None => true,
+                Some(cond) => to_scalar_flexible::<u8>(&cond?.to_dtype(DType::U8)?)? != 0,
+            };
+            let n_carried = node.input.len().saturating_sub(2);
+            if body.input.len() != n_carried + 2 || body.output.len() < n_carried + 1 {
+                bail!(
+                    "Loop node {:?} is malformed: {} inputs, body with {} inputs and {} outputs",
+                    node.name,
+                    node.input.len(),
+                    body.input.len(),
+                    body.output.len()
+                )
+            }
+            let mut carried = node.input[2..]
+                .iter()
+                .map(|name| match values.get(name) {
+                    Some(value) => Ok(value.clone()),
+                    None => bail!("cannot find {name} for op '{}'", node.name),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut scan_outputs = vec![vec![]; body.output.len() - n_carried - 1];
+            // The body initializers are only decoded once, each iteration is evaluated in its
+            // own scope on top of them.
+            let body_values = Scope::new(initializers(body)?, Some(&*values));
+            // The iteration number and condition are created on the device of the loop inputs.
+            let device = node
+                .input
+                .iter()
+                .filter_map(|name| values.get(name))
+                .find_map(Value::device)
+                .cloned()
+                .unwrap_or(Device::Cpu);
+            let mut iter = 0i64;
+            while cond && !max_trip_count.is_some_and(|m| iter >= m) {
+                let mut scope = Scope::new(HashMap::new(), Some(&body_values));
+                scope.insert(
+                    body.input[0].name.clone(),
+                    Tensor::new(iter, &device)?.into(),
+                );
+                scope.insert(
+                    body.input[1].name.clone(),
+                    Tensor::new(cond as u8, &device)?.into(),
+                );
+                for (input, value) in body.input[2..].iter().zip(carried) {
+                    scope.insert(input.name.clone(), value);
+                }
+                if iter == 0 {
+                    check_inputs(body, &scope)?
+                }
+                let mut outputs = eval_graph(body, &mut scope, opset)?.into_iter();
+                let body_cond = outputs.next();
+                if use_cond {
+                    if let Some(body_cond) = body_cond {
+                        let body_cond = body_cond.into_tensor()?.to_dtype(DType::U8)?;
+                        cond = to_scalar_flexible::<u8>(&body_cond)? != 0;
                     }
-                };
-                let output = input.to_dtype(dtype)?;
-                values.insert(node.output[0].clone(), output);
+                }
+                carried = outputs.by_ref().take(n_carried).collect();
+                for (scan_output, value) in scan_outputs.iter_mut().zip(outputs) {
+                    scan_output.push(value.into_tensor()?)
+                }
+                iter += 1;
             }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#CumSum
-            "CumSum" => {
-                let exclusive = get_attr_opt::<i64>(node, "exclusive")?
-                    .copied()
-                    .unwrap_or(0);
-                let reverse = get_attr_opt::<i64>(node, "reverse")?.copied().unwrap_or(0);
-                if exclusive != 0 {
-                    bail!("only exclusive == 0 is supported in CumSum")
-                }
-                if reverse != 0 {
-                    bail!("only reverse == 0 is supported in CumSum")
-                }
-                let input = get(&node.input[0])?;
-                let axis = to_vec0_flexible::<u32>(&get(&node.input[1])?.to_dtype(DType::U32)?)?;
-                let output = input.cumsum(axis as usize)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            //  https://github.com/onnx/onnx/blob/main/docs/Operators.md#flatten
-            "Flatten" => {
-                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(1) as usize;
-                let input = get(&node.input[0])?;
-                let first_part: usize = input.shape().dims().iter().take(axis).product();
-                let end_index = input.shape().dims().iter().product::<usize>();
-                let new_shape = (first_part, end_index / first_part);
-                let output = input.reshape(new_shape)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#identity
-            "Identity" => {
-                let input = get(&node.input[0])?;
-                values.insert(node.output[0].clone(), input.clone());
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#if
-            "If" => {
-                // protobuf encodes boolean false as 0 and true as 1
-                let cond = to_scalar_flexible::<u8>(&get(&node.input[0])?.get(0)?)?;
-                let attr_name = if cond != 0 {
-                    "then_branch"
-                } else {
-                    "else_branch"
-                };
-                let sub_graph = get_attr::<GraphProto>(node, attr_name)?;
-                if sub_graph.output.len() != node.output.len() {
-                    bail!(
-                        "If node {:?} is malformed: branch outputs ({}) don't match node outputs ({})",
-                        node.name,
-                        sub_graph.output.len(),
-                        node.output.len()
-                    );
-                }
-                let branch_out = simple_eval_(sub_graph, values)?;
-                for (i, out) in node.output.iter().enumerate() {
-                    values.insert(
-                        out.clone(),
-                        branch_out.get(&sub_graph.output[i].name).unwrap().clone(),
-                    );
-                }
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#pad
-            "Pad" => {
-                let mode = get_attr_opt(node, "mode")?.unwrap_or("constant");
-                let data = get(&node.input[0])?;
-                let pads = get(&node.input[1])?;
-                if node.input.len() > 2 {
-                    bail!(
-                        "unsupported number of inputs {} for Pad node {:?}, expected 2",
-                        node.input.len(),
-                        node.name
-                    );
+            let scan_outputs = scan_outputs
+                .into_iter()
+                .map(|ys| stack_scan_output(ys, 0, node))
+                .collect::<Result<Vec<_>>>()?;
+            let outputs = carried
+                .into_iter()
+                .chain(scan_outputs.into_iter().map(Value::from));
+            for (name, value) in node.output.iter().zip(outputs) {
+                if !name.is_empty() {
+                    values.insert(name.clone(), value);
+                }
+            }
+        }
+        // https://onnx.ai/onnx/operators/onnx__Scan.html
+        "Scan" => {
+            let body = get_attr::<GraphProto>(node, "body")?;
+            let n_scan_inputs = *get_attr::<i64>(node, "num_scan_inputs")? as usize;
+            if n_scan_inputs == 0
+                || n_scan_inputs > node.input.len()
+                || body.input.len() != node.input.len()
+                || body.output.len() != node.output.len()
+            {
+                bail!(
+                    "Scan node {:?} is malformed: {} inputs including {n_scan_inputs} scan inputs",
+                    node.name,
+                    node.input.len()
+                )
+            }
+            let n_states = node.input.len() - n_scan_inputs;
+            let n_scan_outputs = node.output.len().saturating_sub(n_states);
+            let ints_or_zeros = |name: &str, len: usize| -> Result<Vec<i64>> {
+                match get_attr_opt::<[i64]>(node, name)? {
+                    None => Ok(vec![0; len]),
+                    Some(v) if v.len() == len => Ok(v.to_vec()),
+                    Some(v) => bail!("unexpected {name} {v:?} for Scan {}", node.name),
+                }
+            };
+            let input_directions = ints_or_zeros("scan_input_directions", n_scan_inputs)?;
+            let input_axes = ints_or_zeros("scan_input_axes", n_scan_inputs)?;
+            let output_directions = ints_or_zeros("scan_output_directions", n_scan_outputs)?;
+            let output_axes = ints_or_zeros("scan_output_axes", n_scan_outputs)?;
+            let mut states = node.input[..n_states]
+                .iter()
+                .map(|name| match values.get(name) {
+                    Some(value) => Ok(value.clone()),
+                    None => bail!("cannot find {name} for op '{}'", node.name),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let scan_inputs = node.input[n_states..]
+                .iter()
+                .zip(input_axes.iter())
+                .map(|(name, &axis)| {
+                    let xs = get(name)?;
+                    Ok((xs.clone(), xs.normalize_axis(axis)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let seq_len = scan_inputs[0].0.dim(scan_inputs[0].1)?;
+            for (xs, axis) in scan_inputs.iter() {
+                if xs.dim(*axis)? != seq_len {
+                    bail!("Scan inputs with different lengths in {}", node.name)
+                }
+            }
+            let mut scan_outputs = vec![vec![]; n_scan_outputs];
+            // The body initializers are only decoded once, each step is evaluated in its own
+            // scope on top of them.
+            let body_values = Scope::new(initializers(body)?, Some(&*values));
+            for step in 0..seq_len {
+                let mut scope = Scope::new(HashMap::new(), Some(&body_values));
+                for (input, value) in body.input.iter().zip(states) {
+                    scope.insert(input.name.clone(), value);
+                }
+                let scan_slices = body.input[n_states..]
+                    .iter()
+                    .zip(scan_inputs.iter())
+                    .zip(input_directions.iter());
+                for ((input, (xs, axis)), &direction) in scan_slices {
+                    let idx = if direction == 1 {
+                        seq_len - 1 - step
+                    } else {
+                        step
+                    };
+                    let xs = xs.narrow(*axis, idx, 1)?.squeeze(*axis)?;
+                    scope.insert(input.name.clone(), xs.into());
+                }
+                if step == 0 {
+                    check_inputs(body, &scope)?
+                }
+                let mut outputs = eval_graph(body, &mut scope, opset)?.into_iter();
+                states = outputs.by_ref().take(n_states).collect();
+                for (scan_output, value) in scan_outputs.iter_mut().zip(outputs) {
+                    scan_output.push(value.into_tensor()?)
                 }
-                if pads.rank() != 1 {
-                    bail!("Pad expects 'pads' input to be 1D vector: {pads:?}");
+            }
+            let scan_outputs = scan_outputs
+                .into_iter()
+                .zip(output_directions.iter().zip(output_axes.iter()))
+                .map(|(mut ys, (&direction, &axis))| {
+                    // With the reverse direction, each output is prepended.
+                    if direction == 1 {
+                        ys.reverse()
+                    }
+                    stack_scan_output(ys, axis, node)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let outputs = states
+                .into_iter()
+                .chain(scan_outputs.into_iter().map(Value::from));
+            for (name, value) in node.output.iter().zip(outputs) {
+                if !name.is_empty() {
+                    values.insert(name.clone(), value);
                 }
-                if pads.dim(0).unwrap() != 2 * data.rank() {
-                    bail!("Pad expects 'pads' input len to be 2 * rank of 'data' input: pads: {}, data rank: {}", pads, data.rank());
+            }
+        }
+        // https://onnx.ai/onnx/operators/onnx__SequenceEmpty.html
+        "SequenceEmpty" => {
+            values.insert(node.output[0].clone(), Value::Sequence(vec![]));
+        }
+        // https://onnx.ai/onnx/operators/onnx__SequenceConstruct.html
+        "SequenceConstruct" => {
+            let seq = node
+                .input
+                .iter()
+                .map(|name| Ok(get(name)?.clone()))
+                .collect::<Result<Vec<_>>>()?;
+            values.insert(node.output[0].clone(), seq.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__SequenceLength.html
+        "SequenceLength" => {
+            let seq = get_sequence(&node.input[0])?;
+            let device = seq.first().map_or(&Device::Cpu, |t| t.device());
+            let output = Tensor::new(seq.len() as i64, device)?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__SequenceAt.html
+        "SequenceAt" => {
+            let seq = get_sequence(&node.input[0])?;
+            let position = to_scalar_flexible::<i64>(&get(&node.input[1])?.to_dtype(DType::I64)?)?;
+            let output = seq[sequence_position(position, seq.len(), false)?].clone();
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__SequenceInsert.html
+        "SequenceInsert" => {
+            let mut seq = get_sequence(&node.input[0])?.to_vec();
+            let xs = get(&node.input[1])?.clone();
+            let position = match get_opt(2) {
+                None => seq.len(),
+                Some(p) => {
+                    let p = to_scalar_flexible::<i64>(&p?.to_dtype(DType::I64)?)?;
+                    sequence_position(p, seq.len(), true)?
                 }
+            };
+            seq.insert(position, xs);
+            values.insert(node.output[0].clone(), seq.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__SequenceErase.html
+        "SequenceErase" => {
+            let mut seq = get_sequence(&node.input[0])?.to_vec();
+            let position = match get_opt(1) {
+                None => -1,
+                Some(p) => to_scalar_flexible::<i64>(&p?.to_dtype(DType::I64)?)?,
+            };
+            seq.remove(sequence_position(position, seq.len(), false)?);
+            values.insert(node.output[0].clone(), seq.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__ConcatFromSequence.html
+        "ConcatFromSequence" => {
+            let seq = get_sequence(&node.input[0])?;
+            let axis = *get_attr::<i64>(node, "axis")?;
+            let new_axis = get_attr_opt::<i64>(node, "new_axis")?.copied().unwrap_or(0);
+            if seq.is_empty() {
+                bail!("ConcatFromSequence with an empty sequence {}", node.name)
+            }
+            let output = if new_axis == 1 {
+                stack_scan_output(seq.to_vec(), axis, node)?
+            } else {
+                Tensor::cat(seq, seq[0].normalize_axis(axis)?)?
+            };
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#pad
+        "Pad" => {
+            let mode = get_attr_opt(node, "mode")?.unwrap_or("constant");
+            let data = get(&node.input[0])?;
+            let pads = get(&node.input[1])?;
+            if node.input.len() > 2 {
+                bail!(
+                    "unsupported number of inputs {} for Pad node {:?}, expected 2",
+                    node.input.len(),
+                    node.name
+                );
+            }
+            if pads.rank() != 1 {
+                bail!("Pad expects 'pads' input to be 1D vector: {pads:?}");
+            }
+            if pads.dim(0).unwrap() != 2 * data.rank() {
+                bail!("Pad expects 'pads' input len to be 2 * rank of 'data' input: pads: {}, data rank: {}", pads, data.rank());
+            }
 
-                let pads = pads.to_vec1::<i64>()?;
-                let (pads_pre, pads_post) = pads.split_at(pads.len() / 2);
+            let pads = pads.to_vec1::<i64>()?;
+            let (pads_pre, pads_post) = pads.split_at(pads.len() / 2);
 
-                match mode {
-                    "reflect" => {
-                        let mut out = data.clone();
-                        for (i, &dim) in data.dims().iter().enumerate().rev() {
-                            if pads_pre[i] == 0 && pads_post[i] == 0 {
-                                continue;
-                            }
-                            fn zigzag(min: i64, max: i64) -> impl Iterator<Item = i64> {
-                                std::iter::repeat((min..max).chain((min + 1..=max).rev())).flatten()
-                            }
-                            let idx = if dim > 1 {
-                                let cycle_len = dim * 2 - 2;
-                                let skip = cycle_len - ((pads_pre[i] as usize) % cycle_len);
-                                let idx = zigzag(0, (dim - 1) as i64)
-                                    .skip(skip)
-                                    .take((pads_pre[i] as usize) + dim + (pads_post[i] as usize));
-                                Tensor::from_iter(idx, out.device())?
-                            } else {
-                                Tensor::full(0i64, (dim,), out.device())?
-                            };
-
-                            out = out.index_select(&idx, i)?;
+            match mode {
+                "reflect" => {
+                    let mut out = data.clone();
+                    for (i, &dim) in data.dims().iter().enumerate().rev() {
+                        if pads_pre[i] == 0 && pads_post[i] == 0 {
+                            continue;
                         }
+                        fn zigzag(min: i64, max: i64) -> impl Iterator<Item = i64> {
+                            std::iter::repeat((min..max).chain((min + 1..=max).rev())).flatten()
+                        }
+                        let idx = if dim > 1 {
+                            let cycle_len = dim * 2 - 2;
+                            let skip = cycle_len - ((pads_pre[i] as usize) % cycle_len);
+                            let idx = zigzag(0, (dim - 1) as i64)
+                                .skip(skip)
+                                .take((pads_pre[i] as usize) + dim + (pads_post[i] as usize));
+                            Tensor::from_iter(idx, out.device())?
+                        } else {
+                            Tensor::full(0i64, (dim,), out.device())?
+                        };
 
-                        values.insert(node.output[0].clone(), out);
-                    }
-                    _ => bail!(
-                        "unsupported 'mode' value {mode:?} for Pad node {:?}",
-                        node.name
-                    ),
-                }
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#slice
-            "Slice" => {
-                let data = get(&node.input[0])?;
-                let starts = get(&node.input[1])?;
-                let ends = get(&node.input[2])?;
-                let default_axes;
-                let default_steps;
-                let axes: &Tensor;
-                let steps: &Tensor;
-                // If axes are omitted, they are set to [0, ..., r-1]. If steps are omitted,
-                // they are set to [1, ..., 1] of length len(starts)
-                match node.input.len() {
-                    3 => {
-                        let len = starts.dims()[0];
-                        default_axes = Some(Tensor::arange(0, len as i64, starts.device())?);
-                        axes = default_axes.as_ref().unwrap();
-                        default_steps = Some(Tensor::ones((len,), DType::I64, starts.device())?);
-                        steps = default_steps.as_ref().unwrap();
-                    }
-                    4 => {
-                        let len = starts.dims()[0];
-                        axes = get(&node.input[3])?;
-                        default_steps = Some(Tensor::ones((len,), DType::I64, starts.device())?);
-                        steps = default_steps.as_ref().unwrap();
-                    }
-                    5 => {
-                        steps = get(&node.input[4])?;
-                        axes = get(&node.input[3])?;
-                    }
-                    _ => bail!(
-                        "Slice node is invalid, expected 3-5 inputs, got {}: {:?}",
-                        node.input.len(),
-                        node
-                    ),
-                }
-
-                let mut out = data.clone();
-                for (i, axis) in axes.to_vec1::<i64>()?.into_iter().enumerate() {
-                    // All negative elements of axes are made non-negative by
-                    // adding r to them, where r = rank(input).
-                    let axis = if axis < 0 {
-                        axis + data.rank() as i64
-                    } else {
-                        axis
-                    } as usize;
-
-                    let data_dim = data.dims()[axis] as i64;
-                    let mut s = to_scalar_flexible::<i64>(&starts.get(i)?)?;
-                    let mut e = to_scalar_flexible::<i64>(&ends.get(i)?)?;
-                    // All negative values in starts[i] and ends[i] have
-                    // dims[axes[i]] added to them, where dims are the
-                    // dimensions of input.
-                    if s < 0 {
-                        s += data_dim;
-                    }
-                    if e < 0 {
-                        e += data_dim;
-                    }
-
-                    let p = to_scalar_flexible::<i64>(&steps.get(i)?)?;
-                    // starts[i] is clamped into the range [0, dims[axes[i]]]
-                    // for positive stepping and [0, dims[axes[i]]-1] for
-                    // negative stepping.
-                    // for positive stepping ends[axes[i]] is clamped to
-                    // [0, dims[axes[i]]], while for negative stepping it is
-                    // clamped to [-1, dims[axes[i]]-1].
-                    if p >= 0 {
-                        s = s.clamp(0, data_dim);
-                        e = e.clamp(0, data_dim);
-                    } else {
-                        s = s.clamp(0, data_dim - 1);
-                        e = e.clamp(-1, data_dim - 1);
+                        out = out.index_select(&idx, i)?;
                     }
 
-                    let indexes = Tensor::arange_step(s, e, p, data.device())?;
-                    out = out.contiguous()?.index_select(&indexes, axis)?
+                    values.insert(node.output[0].clone(), out.into());
+                }
+                _ => bail!(
+                    "unsupported 'mode' value {mode:?} for Pad node {:?}",
+                    node.name
+                ),
+            }
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#slice
+        "Slice" => {
+            let data = get(&node.input[0])?;
+            let starts = get(&node.input[1])?;
+            let ends = get(&node.input[2])?;
+            let default_axes;
+            let default_steps;
+            let axes: &Tensor;
+            let steps: &Tensor;
+            // If axes are omitted, they are set to [0, ..., r-1]. If steps are omitted,
+            // they are set to [1, ..., 1] of length len(starts)
+            match node.input.len() {
+                3 => {
+                    let len = starts.dims()[0];
+                    default_axes = Some(Tensor::arange(0, len as i64, starts.device())?);
+                    axes = default_axes.as_ref().unwrap();
+                    default_steps = Some(Tensor::ones((len,), DType::I64, starts.device())?);
+                    steps = default_steps.as_ref().unwrap();
+                }
+                4 => {
+                    let len = starts.dims()[0];
+                    axes = get(&node.input[3])?;
+                    default_steps = Some(Tensor::ones((len,), DType::I64, starts.device())?);
+                    steps = default_steps.as_ref().unwrap();
                 }
-                values.insert(node.output[0].clone(), out);
+                5 => {
+                    steps = get(&node.input[4])?;
+                    axes = get(&node.input[3])?;
+                }
+                _ => bail!(
+                    "Slice node is invalid, expected 3-5 inputs, got {}: {:?}",
+                    node.input.len(),
+                    node
+                ),
             }
-            // https://onnx.ai/onnx/operators/onnx__ReduceMax.html#reducemax
-            "ReduceMax" => {
-                let input = get(&node.input[0])?;
-                let axes = get_opt(1);
-                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1) == 1;
 
-                let axes = if let Some(Ok(axes)) = axes {
-                    // Satisfies version 18+
-                    axes.to_vec1::<i64>().ok()
-                } else if let Ok(Some(axes)) = get_attr_opt::<[i64]>(node, "axes") {
-                    // Backward compatibility with version 13 and below
-                    Some(axes.to_vec())
+            let mut out = data.clone();
+            for (i, axis) in axes.to_vec1::<i64>()?.into_iter().enumerate() {
+                // All negative elements of axes are made non-negative by
+                // adding r to them, where r = rank(input).
+                let axis = if axis < 0 {
+                    axis + data.rank() as i64
                 } else {
-                    None
-                };
+                    axis
+                } as usize;
+
+                let data_dim = data.dims()[axis] as i64;
+                let mut s = to_scalar_flexible::<i64>(&starts.get(i)?)?;
+                let mut e = to_scalar_flexible::<i64>(&ends.get(i)?)?;
+                // All negative values in starts[i] and ends[i] have
+                // dims[axes[i]] added to them, where dims are the
+                // dimensions of input.
+                if s < 0 {
+                    s += data_dim;
+                }
+                if e < 0 {
+                    e += data_dim;
+                }
 
-                let axes = if let Some(axes) = axes {
-                    let rank = input.rank();
-                    let mut axes_set = HashSet::new();
+                let p = to_scalar_flexible::<i64>(&steps.get(i)?)?;
+                // starts[i] is clamped into the range [0, dims[axes[i]]]
+                // for positive stepping and [0, dims[axes[i]]-1] for
+                // negative stepping.
+                // for positive stepping ends[axes[i]] is clamped to
+                // [0, dims[axes[i]]], while for negative stepping it is
+                // clamped to [-1, dims[axes[i]]-1].
+                if p >= 0 {
+                    s = s.clamp(0, data_dim);
+                    e = e.clamp(0, data_dim);
+                } else {
+                    s = s.clamp(0, data_dim - 1);
+                    e = e.clamp(-1, data_dim - 1);
+                }
 
-                    let mut axes = axes
-                        .iter()
-                        .map(|a| {
-                            let axis = if *a < 0 {
-                                (rank as i64 + *a) as usize
-                            } else {
-                                *a as usize
-                            };
-
-                            axes_set.insert(axis);
-                            axis
-                        })
-                        .collect::<Vec<_>>();
+                let indexes = Tensor::arange_step(s, e, p, data.device())?;
+                out = out.contiguous()?.index_select(&indexes, axis)?
+            }
+            values.insert(node.output[0].clone(), out.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__ReduceMax.html#reducemax
+        "ReduceMax" => {
+            let input = get(&node.input[0])?;
+            let axes = get_opt(1);
+            let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1) == 1;
+
+            let axes = if let Some(Ok(axes)) = axes {
+                // Satisfies version 18+
+                axes.to_vec1::<i64>().ok()
+            } else if let Ok(Some(axes)) = get_attr_opt::<[i64]>(node, "axes") {
+                // Backward compatibility with version 13 and below
+                Some(axes.to_vec())
+            } else {
+                None
+            };
 
-                    if axes_set.len() < axes.len() {
-                        bail!("Duplicate value in 'axes'");
-                    }
+            let axes = if let Some(axes) = axes {
+                let rank = input.rank();
+                let mut axes_set = HashSet::new();
 
-                    if axes.len() > 1 {
-                        axes.sort();
-                    }
+                let mut axes = axes
+                    .iter()
+                    .map(|a| {
+                        let axis = if *a < 0 {
+                            (rank as i64 + *a) as usize
+                        } else {
+                            *a as usize
+                        };
 
-                    Some(axes)
-                } else {
-                    None
-                };
+                        axes_set.insert(axis);
+                        axis
+                    })
+                    .collect::<Vec<_>>();
 
-                // TODO: Handle empty set
-                // Definition:
-                // "Reduction over an empty set of values yields minus infinity (if supported by the datatype) or the minimum value of the data type otherwise"
-                // For now, this will throw an error
-                if input.elem_count() == 0 {
-                    bail!("reduction over zero-size tensor not supported");
+                if axes_set.len() < axes.len() {
+                    bail!("Duplicate value in 'axes'");
                 }
 
-                let output = if let Some(axes) = axes {
-                    let mut result = input.clone();
-                    for &axis in axes.iter().rev() {
-                        result = if keepdims {
-                            result.max_keepdim(axis)?
-                        } else {
-                            result.max(axis)?
-                        }
-                    }
-
-                    result
-                } else {
-                    // If `axes` is empty and `noop_with_empty_axes` is set to `true (1)`
-                    // ""input tensor will not be reduced,and the output tensor would be equivalent to input tensor.""
-                    if get_attr_opt::<i64>(node, "noop_with_empty_axes")?.copied() == Some(1) {
-                        input.clone()
-                    } else {
-                        let mut result = input.flatten_all()?;
-                        if keepdims {
-                            result = result.max_keepdim(0)?;
-                            // If keepdims is true, reshape to match input dimensions
-                            let shape = vec![1; input.rank()];
-                            result.reshape(shape)?
-                        } else {
-                            result.max(0)?
-                        }
-                    }
-                };
+                if axes.len() > 1 {
+                    axes.sort();
+                }
 
-                values.insert(node.output[0].clone(), output);
+                Some(axes)
+            } else {
+                None
+            };
+
+            // TODO: Handle empty set
+            // Definition:
+            // "Reduction over an empty set of values yields minus infinity (if supported by the datatype) or the minimum value of the data type otherwise"
+            // For now, this will throw an error
+            if input.elem_count() == 0 {
+                bail!("reduction over zero-size tensor not supported");
             }
-            // https://onnx.ai/onnx/operators/onnx__ReduceMean.html#reducemean-13
-            // TODO: This version is only compatible with ReduceMean V13 and below.
-            "ReduceMean" => {
-                let input = get(&node.input[0])?;
-                let axes = get_attr_opt::<[i64]>(node, "axes")?;
-                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
 
-                let n_dims = input.dims().len();
+            let output = if let Some(axes) = axes {
+                let mut result = input.clone();
+                for &axis in axes.iter().rev() {
+                    result = if keepdims {
+                        result.max_keepdim(axis)?
+                    } else {
+                        result.max(axis)?
+                    }
+                }
 
-                let axes: Vec<usize> = if let Some(axes) = axes {
-                    axes.iter()
-                        .map(|e| (if e < &0 { (n_dims as i64) + *e } else { *e }) as usize)
-                        .collect()
-                } else {
-                    (0..n_dims).collect()
-                };
-                let output = if keepdims == 1 {
-                    input.mean_keepdim(axes)?
-                } else {
-                    input.mean(axes)?
-                };
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://onnx.ai/onnx/operators/onnx__ReduceMin.html#reducemin
-            "ReduceMin" => {
-                let input = get(&node.input[0])?;
-                let axes = get_opt(1);
-                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1) == 1;
-
-                let axes = if let Some(Ok(axes)) = axes {
-                    // Satisfies version 18+
-                    axes.to_vec1::<i64>().ok()
-                } else if let Ok(Some(axes)) = get_attr_opt::<[i64]>(node, "axes") {
-                    // Backward compatibility with version 13 and below
-                    Some(axes.to_vec())
+                result
+            } else {
+                // If `axes` is empty and `noop_with_empty_axes` is set to `true (1)`
+                // ""input tensor will not be reduced,and the output tensor would be equivalent to input tensor.""
+                if get_attr_opt::<i64>(node, "noop_with_empty_axes")?.copied() == Some(1) {
+                    input.clone()
                 } else {
-                    None
-                };
+                    let mut result = input.flatten_all()?;
+                    if keepdims {
+                        result = result.max_keepdim(0)?;
+                        // If keepdims is true, reshape to match input dimensions
+                        let shape = vec![1; input.rank()];
+                        result.reshape(shape)?
+                    } else {
+                        result.max(0)?
+                    }
+                }
+            };
 
-                let axes = if let Some(axes) = axes {
-                    let rank = input.rank();
-                    let mut axes_set = HashSet::new();
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__ReduceMean.html#reducemean-13
+        // TODO: This version is only compatible with ReduceMean V13 and below.
+        "ReduceMean" => {
+            let input = get(&node.input[0])?;
+            let axes = get_attr_opt::<[i64]>(node, "axes")?;
+            let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
+
+            let n_dims = input.dims().len();
+
+            let axes: Vec<usize> = if let Some(axes) = axes {
+                axes.iter()
+                    .map(|e| (if e < &0 { (n_dims as i64) + *e } else { *e }) as usize)
+                    .collect()
+            } else {
+                (0..n_dims).collect()
+            };
+            let output = if keepdims == 1 {
+                input.mean_keepdim(axes)?
+            } else {
+                input.mean(axes)?
+            };
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__ReduceMin.html#reducemin
+        "ReduceMin" => {
+            let input = get(&node.input[0])?;
+            let axes = get_opt(1);
+            let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1) == 1;
+
+            let axes = if let Some(Ok(axes)) = axes {
+                // Satisfies version 18+
+                axes.to_vec1::<i64>().ok()
+            } else if let Ok(Some(axes)) = get_attr_opt::<[i64]>(node, "axes") {
+                // Backward compatibility with version 13 and below
+                Some(axes.to_vec())
+            } else {
+                None
+            };
 
-                    let mut axes = axes
-                        .iter()
-                        .map(|a| {
-                            let axis = if *a < 0 {
-                                (rank as i64 + *a) as usize
-                            } else {
-                                *a as usize
-                            };
-
-                            axes_set.insert(axis);
-                            axis
-                        })
-                        .collect::<Vec<_>>();
+            let axes = if let Some(axes) = axes {
+                let rank = input.rank();
+                let mut axes_set = HashSet::new();
 
-                    if axes_set.len() < axes.len() {
-                        bail!("Duplicate value in 'axes'");
-                    }
+                let mut axes = axes
+                    .iter()
+                    .map(|a| {
+                        let axis = if *a < 0 {
+                            (rank as i64 + *a) as usize
+                        } else {
+                            *a as usize
+                        };
 
-                    if axes.len() > 1 {
-                        axes.sort();
-                    }
+                        axes_set.insert(axis);
+                        axis
+                    })
+                    .collect::<Vec<_>>();
 
-                    Some(axes)
-                } else {
-                    None
-                };
+                if axes_set.len() < axes.len() {
+                    bail!("Duplicate value in 'axes'");
+                }
 
-                // TODO: Handle empty set
-                // Definition:
-                // "Reduction over an empty set of values yields positive infinity (if supported by the datatype) or the max value of the data type otherwise"
-                // For now, this will throw an error
-                if input.elem_count() == 0 {
-                    bail!("reduction over zero-size tensor not supported");
+                if axes.len() > 1 {
+                    axes.sort();
                 }
 
-                let output = if let Some(axes) = axes {
-                    let mut result = input.clone();
-                    for &axis in axes.iter().rev() {
-                        result = if keepdims {
-                            result.min_keepdim(axis)?
-                        } else {
-                            result.min(axis)?
-                        }
+                Some(axes)
+            } else {
+                None
+            };
+
+            // TODO: Handle empty set
+            // Definition:
+            // "Reduction over an empty set of values yields positive infinity (if supported by the datatype) or the max value of the data type otherwise"
+            // For now, this will throw an error
+            if input.elem_count() == 0 {
+                bail!("reduction over zero-size tensor not supported");
+            }
+
+            let output = if let Some(axes) = axes {
+                let mut result = input.clone();
+                for &axis in axes.iter().rev() {
+                    result = if keepdims {
+                        result.min_keepdim(axis)?
+                    } else {
+                        result.min(axis)?
                     }
+                }
 
-                    result
+                result
+            } else {
+                // If `axes` is empty and `noop_with_empty_axes` is set to `true (1)`
+                // ""input tensor will not be reduced,and the output tensor would be equivalent to input tensor.""
+                if get_attr_opt::<i64>(node, "noop_with_empty_axes")?.copied() == Some(1) {
+                    input.clone()
                 } else {
-                    // If `axes` is empty and `noop_with_empty_axes` is set to `true (1)`
-                    // ""input tensor will not be reduced,and the output tensor would be equivalent to input tensor.""
-                    if get_attr_opt::<i64>(node, "noop_with_empty_axes")?.copied() == Some(1) {
-                        input.clone()
+                    let mut result = input.flatten_all()?;
+                    if keepdims {
+                        result = result.min_keepdim(0)?;
+                        // If keepdims is true, reshape to match input dimensions
+                        let shape = vec![1; input.rank()];
+                        result.reshape(shape)?
                     } else {
-                        let mut result = input.flatten_all()?;
-                        if keepdims {
-                            result = result.min_keepdim(0)?;
-                            // If keepdims is true, reshape to match input dimensions
-                            let shape = vec![1; input.rank()];
-                            result.reshape(shape)?
-                        } else {
-                            result.min(0)?
-                        }
+                        result.min(0)?
                     }
-                };
-
-                values.insert(node.output[0].clone(), output);
-            }
-            //https://github.com/onnx/onnx/blob/main/docs/Operators.md#Split
-            // Version 18 impl
-            "Split" => {
-                let input_tensor = get(&node.input[0])?;
-                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
-                let axis = input_tensor.normalize_axis(axis)?;
+                }
+            };
 
-                // Determine split sizes
-                let splits = if node.input.len() > 1 {
-                    // If the split tensor is provided, use it to determine sizes
-                    let split_tensor = get(&node.input[1])?.to_vec1::<i64>()?;
-                    split_tensor.iter().map(|&x| x as usize).collect::<Vec<_>>()
-                } else {
-                    let num_outputs = if let Some(&num_outputs_attrib) =
-                        get_attr_opt::<i64>(node, "num_outputs")?
-                    {
+            values.insert(node.output[0].clone(), output.into());
+        }
+        //https://github.com/onnx/onnx/blob/main/docs/Operators.md#Split
+        // Version 18 impl
+        "Split" => {
+            let input_tensor = get(&node.input[0])?;
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+            let axis = input_tensor.normalize_axis(axis)?;
+
+            // Determine split sizes
+            let splits = if node.input.len() > 1 {
+                // If the split tensor is provided, use it to determine sizes
+                let split_tensor = get(&node.input[1])?.to_vec1::<i64>()?;
+                split_tensor.iter().map(|&x| x as usize).collect::<Vec<_>>()
+            } else {
+                let num_outputs =
+                    if let Some(&num_outputs_attrib) = get_attr_opt::<i64>(node, "num_outputs")? {
                         num_outputs_attrib as usize
                     } else {
                         node.output.len()
                     };
 
-                    let input_dim = input_tensor.dim(axis)?;
-
-                    let mut split_sizes =
-                        vec![input_dim / num_outputs as usize; num_outputs as usize];
-                    let remainder = input_dim % num_outputs as usize;
-                    if remainder > 0 {
-                        // If there's a remainder, add it to the last split size
-                        split_sizes[num_outputs as usize - 1] += remainder;
-                    }
-
-                    split_sizes
-                };
+                let input_dim = input_tensor.dim(axis)?;
 
-                // Perform the split operation
-                let mut outputs = vec![];
-                let mut start = 0;
-                for &size in &splits {
-                    let end = start + size;
-                    let slice = input_tensor.narrow(axis, start, size)?;
-                    outputs.push(slice);
-                    start = end;
+                let mut split_sizes = vec![input_dim / num_outputs as usize; num_outputs as usize];
+                let remainder = input_dim % num_outputs as usize;
+                if remainder > 0 {
+                    // If there's a remainder, add it to the last split size
+                    split_sizes[num_outputs as usize - 1] += remainder;
                 }
 
-                // Insert the split outputs into the values map
-                for (output, slice) in node.output.iter().zip(outputs.into_iter()) {
-                    values.insert(output.clone(), slice);
-                }
+                split_sizes
+            };
+
+            // Perform the split operation
+            let mut outputs = vec![];
+            let mut start = 0;
+            for &size in &splits {
+                let end = start + size;
+                let slice = input_tensor.narrow(axis, start, size)?;
+                outputs.push(slice);
+                start = end;
             }
-            //https://github.com/onnx/onnx/blob/main/docs/Operators.md#Expand
-            // Version 13 impl
-            "Expand" => {
-                // unlike broadcast_to, expand allows for the output shape to
-                // be different from the specified shape.
-                let input_tensor = get(&node.input[0])?;
-                let input_shape = get(&node.input[1])?;
 
-                // Check that the shape tensor is 1D
-                if input_shape.rank() != 1 {
-                    bail!(
-                        "Expand expects 'shape' input to be 1D tensor: {:?}",
-                        input_shape
-                    );
-                }
-                let input_tensor_dims = input_tensor.dims();
-                let input_shape_dims = input_shape
-                    .to_vec1::<i64>()?
-                    .into_iter()
-                    .map(|x| x as usize)
-                    .collect::<Vec<_>>();
-
-                let target_shape = broadcast_shape(input_tensor_dims, input_shape_dims.as_slice())?;
-
-                let expanded_tensor = input_tensor.broadcast_as(target_shape)?;
-
-                values.insert(node.output[0].clone(), expanded_tensor);
+            // Insert the split outputs into the values map
+            for (output, slice) in node.output.iter().zip(outputs.into_iter()) {
+                values.insert(output.clone(), slice.into());
             }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Tile
-            "Tile" => {
-                let input = get(&node.input[0])?;
-                let repeats = get(&node.input[1])?.to_vec1::<i64>()?;
-
-                let mut result = input.clone();
-                for (dim, &repeat) in repeats.iter().enumerate() {
-                    if repeat > 1 {
-                        let repeat = repeat as usize;
-                        let tensors: Vec<_> = (0..repeat).map(|_| result.clone()).collect();
-                        result = Tensor::cat(&tensors, dim)?;
-                    }
-                }
-                values.insert(node.output[0].clone(), result);
+        }
+        //https://github.com/onnx/onnx/blob/main/docs/Operators.md#Expand
+        // Version 13 impl
+        "Expand" => {
+            // unlike broadcast_to, expand allows for the output shape to
+            // be different from the specified shape.
+            let input_tensor = get(&node.input[0])?;
+            let input_shape = get(&node.input[1])?;
+
+            // Check that the shape tensor is 1D
+            if input_shape.rank() != 1 {
+                bail!(
+                    "Expand expects 'shape' input to be 1D tensor: {:?}",
+                    input_shape
+                );
             }
-            //https://github.com/onnx/onnx/blob/main/docs/Operators.md#ReduceSum
-            // Version 13 impl
-            "ReduceSum" => {
-                let input = get(&node.input[0])?;
-                let axes = get_opt(1);
-                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
-                let noop_with_empty_axes = get_attr_opt::<i64>(node, "noop_with_empty_axes")?
-                    .copied()
-                    .unwrap_or(0);
+            let input_tensor_dims = input_tensor.dims();
+            let input_shape_dims = input_shape
+                .to_vec1::<i64>()?
+                .into_iter()
+                .map(|x| x as usize)
+                .collect::<Vec<_>>();
 
-                let axes = match axes {
-                    Some(Ok(axes)) => axes
-                        .to_vec1::<i64>()?
-                        .into_iter()
-                        .map(|x| x as usize)
-                        .collect::<Vec<_>>(),
-                    Some(Err(_)) | None => {
-                        if noop_with_empty_axes == 1 {
-                            vec![]
-                        } else {
-                            (0..input.rank()).collect()
-                        }
-                    }
-                };
+            let target_shape = broadcast_shape(input_tensor_dims, input_shape_dims.as_slice())?;
 
-                let output = if keepdims == 1 {
-                    input.sum_keepdim(axes)?
-                } else {
-                    input.sum(axes)?
-                };
+            let expanded_tensor = input_tensor.broadcast_as(target_shape)?;
 
-                values.insert(node.output[0].clone(), output);
+            values.insert(node.output[0].clone(), expanded_tensor.into());
+        }
+        // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Tile
+        "Tile" => {
+            let input = get(&node.input[0])?;
+            let repeats = get(&node.input[1])?.to_vec1::<i64>()?;
+
+            let mut result = input.clone();
+            for (dim, &repeat) in repeats.iter().enumerate() {
+                if repeat > 1 {
+                    let repeat = repeat as usize;
+                    let tensors: Vec<_> = (0..repeat).map(|_| result.clone()).collect();
+                    result = Tensor::cat(&tensors, dim)?;
+                }
             }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ReduceL2
-            // Version 18 impl
-            "ReduceL2" => {
-                let input = get(&node.input[0])?;
-                let axes = get_opt(1);
-                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
-                let noop_with_empty_axes = get_attr_opt::<i64>(node, "noop_with_empty_axes")?
-                    .copied()
-                    .unwrap_or(0);
+            values.insert(node.output[0].clone(), result.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__ReduceSum.html
+        "ReduceSum" => {
+            let input = get(&node.input[0])?;
+            let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
+            let axes = reduce_axes(node, input, reduce_axes_opt(13)?)?;
+
+            let output = if keepdims == 1 {
+                input.sum_keepdim(axes)?
+            } else {
+                input.sum(axes)?
+            };
 
-                let input_sq = input.sqr()?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__ReduceL2.html
+        "ReduceL2" => {
+            let input = get(&node.input[0])?;
+            let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
+            let axes = reduce_axes(node, input, reduce_axes_opt(18)?)?;
 
-                let axes = match axes {
-                    Some(axes) => axes?
-                        .to_vec1::<i64>()?
-                        .into_iter()
-                        .map(|x| x as usize)
-                        .collect::<Vec<_>>(),
-                    None => {
-                        if noop_with_empty_axes == 1 {
-                            vec![]
-                        } else {
-                            (0..input_sq.rank()).collect()
-                        }
-                    }
-                };
+            let input_sq = input.sqr()?;
 
-                let output = if keepdims == 1 {
-                    input_sq.sum_keepdim(axes)?.sqrt()?
-                } else {
-                    input_sq.sum(axes)?.sqrt()?
-                };
+            let output = if keepdims == 1 {
+                input_sq.sum_keepdim(axes)?.sqrt()?
+            } else {
+                input_sq.sum(axes)?.sqrt()?
+            };
 
-                values.insert(node.output[0].clone(), output);
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__ReduceProd.html
+        "ReduceProd" => {
+            let input = get(&node.input[0])?;
+            let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
+            let axes = reduce_axes(node, input, reduce_axes_opt(18)?)?;
+
+            let mut output = input.clone();
+            for &axis in axes.iter() {
+                let mut dims = output.dims().to_vec();
+                dims[axis] = 1;
+                let mut prod = Tensor::ones(dims, output.dtype(), output.device())?;
+                for idx in 0..output.dim(axis)? {
+                    prod = prod.mul(&output.narrow(axis, idx, 1)?)?
+                }
+                output = prod
             }
-            random_type @ ("RandomUniform" | "RandomNormal") => {
-                let dt: i64 = get_attr_opt(node, "dtype")?.copied().unwrap_or(1); // 1 is float
-                                                                                  // type by
-                                                                                  // default
-                let dtype = match DataType::try_from(dt as i32) {
-                    Ok(dt) => match dtype(dt) {
-                        Some(DType::U8 | DType::U32 | DType::I64) => {
-                            bail!(
-                                "unsupported 'dtype' value {dt:?}, only floats are allowed, for {random_type} {}",
-                                node.name
-                            )
-                        }
-                        Some(dt) => dt,
-                        None => {
-                            bail!(
-                                "unsupported 'dtype' value {dt:?} for {random_type} {}",
-                                node.name
-                            )
-                        }
-                    },
-                    Err(_) => {
+            if keepdims != 1 {
+                for &axis in axes.iter().rev() {
+                    output = output.squeeze(axis)?
+                }
+            }
+
+            values.insert(node.output[0].clone(), output.into());
+        }
+        random_type @ ("RandomUniform" | "RandomNormal") => {
+            let dt: i64 = get_attr_opt(node, "dtype")?.copied().unwrap_or(1); // 1 is float
+                                                                              // type by
+                                                                              // default
+            let dtype = match DataType::try_from(dt as i32) {
+                Ok(dt) => match dtype(dt) {
+                    Some(DType::U8 | DType::U32 | DType::I64) => {
+                        bail!(
+                            "unsupported 'dtype' value {dt:?}, only floats are allowed, for {random_type} {}",
+                            node.name
+                        )
+                    }
+                    Some(dt) => dt,
+                    None => {
                         bail!(
                             "unsupported 'dtype' value {dt:?} for {random_type} {}",
                             node.name
                         )
                     }
-                };
-                let seed: Option<f32> = get_attr_opt(node, "seed")?.copied();
-                if seed.is_some() {
-                    bail!("seed for {random_type} is currently not supported")
-                };
-                let shape: Vec<usize> = get_attr::<[i64]>(node, "shape")?
-                    .iter()
-                    .map(|x| *x as usize)
-                    .collect();
-                let output = if random_type == "RandomUniform" {
-                    let low: f32 = get_attr_opt(node, "low")?.copied().unwrap_or(0.0);
-                    let high: f32 = get_attr_opt(node, "high")?.copied().unwrap_or(1.0);
-                    Tensor::rand(low, high, shape, &Device::Cpu)?.to_dtype(dtype)?
-                } else {
-                    let mean: f32 = get_attr_opt(node, "mean")?.copied().unwrap_or(0.0);
-                    let scale: f32 = get_attr_opt(node, "scale")?.copied().unwrap_or(1.0);
-                    Tensor::randn(mean, scale, shape, &Device::Cpu)?.to_dtype(dtype)?
-                };
-                values.insert(node.output[0].clone(), output);
-            }
-            "ArgMin" => {
-                let input = get(&node.input[0])?;
-                let axis_i64: i64 = get_attr_opt(node, "axis")?.copied().unwrap_or(0);
-                let rank_i64: i64 = input.rank().try_into().unwrap();
-                if axis_i64 < -rank_i64 || axis_i64 >= rank_i64 {
+                },
+                Err(_) => {
                     bail!(
-                        "axis ({}) out of accepted range [-rank, rank-1] which was [{}, {}]",
-                        axis_i64,
-                        -rank_i64,
-                        rank_i64 - 1
+                        "unsupported 'dtype' value {dt:?} for {random_type} {}",
+                        node.name
                     )
                 }
-                let axis = input.normalize_axis(axis_i64)?;
-                let keepdims: i64 = get_attr_opt(node, "keepdims")?.copied().unwrap_or(1);
-                let select_last_index: i64 = get_attr_opt(node, "select_last_index")?
-                    .copied()
-                    .unwrap_or(0);
-                if select_last_index == 1 {
-                    bail!("select_last_index for ArgMin is currently not supported")
-                }
-                let output = if keepdims == 1 {
-                    input.argmin_keepdim(axis)?
-                } else {
-                    input.argmin(axis)?
-                }
-                .to_dtype(DType::I64)?;
-                values.insert(node.output[0].clone(), output);
+            };
+            let seed: Option<f32> = get_attr_opt(node, "seed")?.copied();
+            if seed.is_some() {
+                bail!("seed for {random_type} is currently not supported")
+            };
+            let shape: Vec<usize> = get_attr::<[i64]>(node, "shape")?
+                .iter()
+                .map(|x| *x as usize)
+                .collect();
+            let output = if random_type == "RandomUniform" {
+                let low: f32 = get_attr_opt(node, "low")?.copied().unwrap_or(0.0);
+                let high: f32 = get_attr_opt(node, "high")?.copied().unwrap_or(1.0);
+                Tensor::rand(low, high, shape, &Device::Cpu)?.to_dtype(dtype)?
+            } else {
+                let mean: f32 = get_attr_opt(node, "mean")?.copied().unwrap_or(0.0);
+                let scale: f32 = get_attr_opt(node, "scale")?.copied().unwrap_or(1.0);
+                Tensor::randn(mean, scale, shape, &Device::Cpu)?.to_dtype(dtype)?
+            };
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "ArgMin" => {
+            let input = get(&node.input[0])?;
+            let axis_i64: i64 = get_attr_opt(node, "axis")?.copied().unwrap_or(0);
+            let rank_i64: i64 = input.rank().try_into().unwrap();
+            if axis_i64 < -rank_i64 || axis_i64 >= rank_i64 {
+                bail!(
+                    "axis ({}) out of accepted range [-rank, rank-1] which was [{}, {}]",
+                    axis_i64,
+                    -rank_i64,
+                    rank_i64 - 1
+                )
+            }
+            let axis = input.normalize_axis(axis_i64)?;
+            let keepdims: i64 = get_attr_opt(node, "keepdims")?.copied().unwrap_or(1);
+            let select_last_index: i64 = get_attr_opt(node, "select_last_index")?
+                .copied()
+                .unwrap_or(0);
+            if select_last_index == 1 {
+                bail!("select_last_index for ArgMin is currently not supported")
+            }
+            let output = if keepdims == 1 {
+                input.argmin_keepdim(axis)?
+            } else {
+                input.argmin(axis)?
+            }
+            .to_dtype(DType::I64)?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "ArgMax" => {
+            let input = get(&node.input[0])?;
+            let axis_i64: i64 = get_attr_opt(node, "axis")?.copied().unwrap_or(0);
+            let rank_i64: i64 = input.rank().try_into().unwrap();
+            if axis_i64 < -rank_i64 || axis_i64 >= rank_i64 {
+                bail!(
+                    "axis ({}) out of accepted range [-rank, rank-1] which was [{}, {}]",
+                    axis_i64,
+                    -rank_i64,
+                    rank_i64 - 1
+                )
+            }
+            let axis = input.normalize_axis(axis_i64)?;
+            let keepdims: i64 = get_attr_opt(node, "keepdims")?.copied().unwrap_or(1);
+            let select_last_index: i64 = get_attr_opt(node, "select_last_index")?
+                .copied()
+                .unwrap_or(0);
+            if select_last_index == 1 {
+                bail!("select_last_index for ArgMin is currently not supported")
+            }
+            let output = if keepdims == 1 {
+                input.argmax_keepdim(axis)?
+            } else {
+                input.argmax(axis)?
             }
-            "ArgMax" => {
-                let input = get(&node.input[0])?;
-                let axis_i64: i64 = get_attr_opt(node, "axis")?.copied().unwrap_or(0);
-                let rank_i64: i64 = input.rank().try_into().unwrap();
-                if axis_i64 < -rank_i64 || axis_i64 >= rank_i64 {
+            .to_dtype(DType::I64)?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "LeakyRelu" => {
+            let input = get(&node.input[0])?;
+            let dt = input.dtype();
+            match dt {
+                DType::U8
+                | DType::U32
+                | DType::I64
+                | DType::I32
+                | DType::I16
+                | DType::F6E2M3
+                | DType::F6E3M2
+                | DType::F4
+                | DType::F8E8M0 => {
                     bail!(
-                        "axis ({}) out of accepted range [-rank, rank-1] which was [{}, {}]",
-                        axis_i64,
-                        -rank_i64,
-                        rank_i64 - 1
+                        "unsupported dtype {}, only float types are allowed for LeakyRelu",
+                        dt.as_str()
                     )
                 }
-                let axis = input.normalize_axis(axis_i64)?;
-                let keepdims: i64 = get_attr_opt(node, "keepdims")?.copied().unwrap_or(1);
-                let select_last_index: i64 = get_attr_opt(node, "select_last_index")?
-                    .copied()
-                    .unwrap_or(0);
-                if select_last_index == 1 {
-                    bail!("select_last_index for ArgMin is currently not supported")
+                DType::BF16 | DType::F16 | DType::F32 | DType::F64 | DType::F8E4M3 => {}
+            }
+            let alpha = get_attr_opt::<f32>(node, "alpha")?.copied().unwrap_or(0.01);
+            let output = candle_nn::ops::leaky_relu(input, alpha.into())?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "LSTM" => {
+            let direction = get_attr_opt(node, "direction")?.unwrap_or("forward");
+            if direction != "forward" {
+                bail!("LSTM currently only supports direction == \"forward\"");
+            }
+            let num_directions = if direction == "bidirectional" { 2 } else { 1 };
+            let hidden_size: i64 = get_attr(node, "hidden_size").copied()?;
+            let input_forget = get_attr_opt(node, "input_forget")?.copied().unwrap_or(0);
+            if input_forget != 0 {
+                bail!("LSTM currently only supports input_forget == 0");
+            }
+            let activations_default = vec![
+                "Sigmoid".to_string(),
+                "Tanh".to_string(),
+                "Tanh".to_string(),
+            ];
+            let activations = get_attr_opt_owned::<Vec<String>>(node, "activations")?
+                .unwrap_or(activations_default.clone());
+            if activations != activations_default {
+                bail!("LSTM currently only supports default activations ({activations_default:?})");
+            }
+            // activation_alpha and activation_beta don't apply to (Sigmoid, Tanh, Tanh) so ignoring them is okay
+            if get_attr_opt::<f32>(node, "clip")?.is_some() {
+                bail!("LSTM does not currently support clip attribute");
+            }
+
+            // The shape format of inputs X, initial_h and outputs Y, Y_h.
+            // If 0, the following shapes are expected:
+            //     X.shape = [seq_length, batch_size, input_size],
+            //     Y.shape = [seq_length, num_directions, batch_size, hidden_size],
+            //     initial_h.shape = Y_h.shape = [num_directions, batch_size, hidden_size].
+            // If 1, the following shapes are expected:
+            //     X.shape = [batch_size, seq_length, input_size],
+            //     Y.shape = [batch_size, seq_length, num_directions, hidden_size],
+            //     initial_h.shape = Y_h.shape = [batch_size, num_directions, hidden_size].
+            let layout = get_attr_opt(node, "layout")?.copied().unwrap_or(0);
+            if layout != 0 {
+                bail!("LSTM currently only supports layout == 0");
+            }
+
+            // The input sequences packed (and potentially padded) into one 3-D tensor
+            // with the shape of `[seq_length, batch_size, input_size]`.
+            let x = get(&node.input[0])?;
+            // XXX: depends on layout
+            let (seq_length, batch_size, input_size) = x.dims3()?;
+            // The weight tensor for the gates.
+            // Concatenation of `W[iofc]` and `WB[iofc]` (if bidirectional) along dimension 0.
+            // The tensor has shape `[num_directions, 4*hidden_size, input_size]`.
+            let w = get(&node.input[1])?;
+            // The recurrence weight tensor.
+            // Concatenation of `R[iofc]` and `RB[iofc]` (if bidirectional) along dimension 0.
+            // This tensor has shape `[num_directions, 4*hidden_size, hidden_size]`.
+            let r = get(&node.input[2])?;
+
+            // The bias tensor for input gate.
+            // Concatenation of `[Wb[iofc], Rb[iofc]]`, and `[WBb[iofc], RBb[iofc]]` (if bidirectional) along dimension 0.
+            // This tensor has shape `[num_directions, 8*hidden_size]`.
+            // Optional: If not specified - assumed to be 0.
+            let b_default: Tensor;
+            let b = match get_opt(3) {
+                Some(n) => n?,
+                None => {
+                    b_default = Tensor::zeros(
+                        (num_directions, 8 * hidden_size as usize),
+                        DType::F32,
+                        x.device(),
+                    )?;
+                    &b_default
                 }
-                let output = if keepdims == 1 {
-                    input.argmax_keepdim(axis)?
-                } else {
-                    input.argmax(axis)?
-                }
-                .to_dtype(DType::I64)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "LeakyRelu" => {
-                let input = get(&node.input[0])?;
-                let dt = input.dtype();
-                match dt {
-                    DType::U8
-                    | DType::U32
-                    | DType::I64
-                    | DType::I32
-                    | DType::I16
-                    | DType::F6E2M3
-                    | DType::F6E3M2
-                    | DType::F4
-                    | DType::F8E8M0 => {
-                        bail!(
-                            "unsupported dtype {}, only float types are allowed for LeakyRelu",
-                            dt.as_str()
-                        )
-                    }
-                    DType::BF16 | DType::F16 | DType::F32 | DType::F64 | DType::F8E4M3 => {}
-                }
-                let alpha = get_attr_opt::<f32>(node, "alpha")?.copied().unwrap_or(0.01);
-                let output = candle_nn::ops::leaky_relu(input, alpha.into())?;
-                values.insert(node.output[0].clone(), output);
-            }
-            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Gemm
-            "Gemm" => {
-                let a = get(&node.input[0])?;
-                let b = get(&node.input[1])?;
-                let c = get(&node.input[2])?;
-
-                let alpha = get_attr_opt::<f32>(node, "alpha")?.copied().unwrap_or(1.0);
-                let beta = get_attr_opt::<f32>(node, "beta")?.copied().unwrap_or(1.0);
-
-                let alpha = Tensor::full(alpha, a.shape(), &Device::Cpu)?;
-                let beta = Tensor::full(beta, c.shape(), &Device::Cpu)?;
-
-                let trans_a = get_attr_opt::<i64>(node, "transA")?.copied().unwrap_or(0);
-                let trans_b = get_attr_opt::<i64>(node, "transB")?.copied().unwrap_or(0);
-
-                let a = if trans_a == 0 { a.clone() } else { a.t()? };
-                let b = if trans_b == 0 { b.clone() } else { b.t()? };
-
-                let output = a
-                    .broadcast_mul(&alpha)?
-                    .broadcast_matmul(&b)?
-                    .broadcast_add(&c.broadcast_mul(&beta)?)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "LSTM" => {
-                let direction = get_attr_opt(node, "direction")?.unwrap_or("forward");
-                if direction != "forward" {
-                    bail!("LSTM currently only supports direction == \"forward\"");
-                }
-                let num_directions = if direction == "bidirectional" { 2 } else { 1 };
-                let hidden_size: i64 = get_attr(node, "hidden_size").copied()?;
-                let input_forget = get_attr_opt(node, "input_forget")?.copied().unwrap_or(0);
-                if input_forget != 0 {
-                    bail!("LSTM currently only supports input_forget == 0");
-                }
-                let activations_default = vec![
-                    "Sigmoid".to_string(),
-                    "Tanh".to_string(),
-                    "Tanh".to_string(),
-                ];
-                let activations = get_attr_opt_owned::<Vec<String>>(node, "activations")?
-                    .unwrap_or(activations_default.clone());
-                if activations != activations_default {
-                    bail!("LSTM currently only supports default activations ({activations_default:?})");
-                }
-                // activation_alpha and activation_beta don't apply to (Sigmoid, Tanh, Tanh) so ignoring them is okay
-                if get_attr_opt::<f32>(node, "clip")?.is_some() {
-                    bail!("LSTM does not currently support clip attribute");
-                }
-
-                // The shape format of inputs X, initial_h and outputs Y, Y_h.
-                // If 0, the following shapes are expected:
-                //     X.shape = [seq_length, batch_size, input_size],
-                //     Y.shape = [seq_length, num_directions, batch_size, hidden_size],
-                //     initial_h.shape = Y_h.shape = [num_directions, batch_size, hidden_size].
-                // If 1, the following shapes are expected:
-                //     X.shape = [batch_size, seq_length, input_size],
-                //     Y.shape = [batch_size, seq_length, num_directions, hidden_size],
-                //     initial_h.shape = Y_h.shape = [batch_size, num_directions, hidden_size].
-                let layout = get_attr_opt(node, "layout")?.copied().unwrap_or(0);
-                if layout != 0 {
-                    bail!("LSTM currently only supports layout == 0");
-                }
-
-                // The input sequences packed (and potentially padded) into one 3-D tensor
-                // with the shape of `[seq_length, batch_size, input_size]`.
-                let x = get(&node.input[0])?;
-                // XXX: depends on layout
-                let (seq_length, batch_size, input_size) = x.dims3()?;
-                // The weight tensor for the gates.
-                // Concatenation of `W[iofc]` and `WB[iofc]` (if bidirectional) along dimension 0.
-                // The tensor has shape `[num_directions, 4*hidden_size, input_size]`.
-                let w = get(&node.input[1])?;
-                // The recurrence weight tensor.
-                // Concatenation of `R[iofc]` and `RB[iofc]` (if bidirectional) along dimension 0.
-                // This tensor has shape `[num_directions, 4*hidden_size, hidden_size]`.
-                let r = get(&node.input[2])?;
-
-                // The bias tensor for input gate.
-                // Concatenation of `[Wb[iofc], Rb[iofc]]`, and `[WBb[iofc], RBb[iofc]]` (if bidirectional) along dimension 0.
-                // This tensor has shape `[num_directions, 8*hidden_size]`.
-                // Optional: If not specified - assumed to be 0.
-                let b_default: Tensor;
-                let b = match get_opt(3) {
-                    Some(n) => n?,
-                    None => {
-                        b_default = Tensor::zeros(
-                            (num_directions, 8 * hidden_size as usize),
-                            DType::F32,
-                            x.device(),
-                        )?;
-                        &b_default
-                    }
-                };
+            };
+
+            // Optional tensor specifying lengths of the sequences in a batch.
+            // If not specified - assumed all sequences in the batch to have length `seq_length`.
+            // It has shape `[batch_size]`.
+            let seq_lens_default: Tensor;
+            let seq_lens = match get_opt(4) {
+                Some(n) => n?,
+                None => {
+                    seq_lens_default = Tensor::full(seq_length as i64, (batch_size,), x.device())?;
+                    &seq_lens_default
+                }
+            };
+            let seq_lens_is_default =
+                (seq_lens.to_vec1::<i64>()?.iter()).all(|e| *e as usize == seq_length);
+            if !seq_lens_is_default {
+                bail!("LSTM currently only supports default value of seq_lens");
+            }
 
-                // Optional tensor specifying lengths of the sequences in a batch.
-                // If not specified - assumed all sequences in the batch to have length `seq_length`.
-                // It has shape `[batch_size]`.
-                let seq_lens_default: Tensor;
-                let seq_lens = match get_opt(4) {
-                    Some(n) => n?,
-                    None => {
-                        seq_lens_default =
-                            Tensor::full(seq_length as i64, (batch_size,), x.device())?;
-                        &seq_lens_default
-                    }
-                };
-                let seq_lens_is_default =
-                    (seq_lens.to_vec1::<i64>()?.iter()).all(|e| *e as usize == seq_length);
-                if !seq_lens_is_default {
-                    bail!("LSTM currently only supports default value of seq_lens");
-                }
-
-                // Optional initial value of the hidden. If not specified - assumed to be 0.
-                // It has shape `[num_directions, batch_size, hidden_size]`.
-                let initial_h_default: Tensor;
-                let initial_h = match get_opt(5) {
-                    Some(n) => n?,
-                    _ => {
-                        initial_h_default = Tensor::zeros(
-                            (num_directions, batch_size, hidden_size as usize),
-                            DType::F32,
-                            x.device(),
-                        )?;
-                        &initial_h_default
-                    }
-                };
+            // Optional initial value of the hidden. If not specified - assumed to be 0.
+            // It has shape `[num_directions, batch_size, hidden_size]`.
+            let initial_h_default: Tensor;
+            let initial_h = match get_opt(5) {
+                Some(n) => n?,
+                _ => {
+                    initial_h_default = Tensor::zeros(
+                        (num_directions, batch_size, hidden_size as usize),
+                        DType::F32,
+                        x.device(),
+                    )?;
+                    &initial_h_default
+                }
+            };
+
+            // Optional initial value of the cell.
+            // If not specified - assumed to be 0.
+            // It has shape `[num_directions, batch_size, hidden_size]`.
+            let initial_c_default: Tensor;
+            let initial_c = match node.input.get(6) {
+                Some(n) if !n.is_empty() => get(n)?,
+                _ => {
+                    initial_c_default = Tensor::zeros(
+                        (num_directions, batch_size, hidden_size as usize),
+                        DType::F32,
+                        x.device(),
+                    )?;
+                    &initial_c_default
+                }
+            };
+
+            // The weight tensor for peepholes.
+            // Concatenation of `P[iof]` and `PB[iof]` (if bidirectional) along dimension 0.
+            // It has shape `[num_directions, 3*hidde_size]`. Optional: If not specified - assumed to be 0.
+            let p_default = Tensor::zeros(
+                (num_directions, 3 * hidden_size as usize),
+                DType::F32,
+                x.device(),
+            )?;
+            let p = get_opt(7).unwrap_or(Ok(&p_default))?;
+            let p_is_zeros = (p.to_vec2::<f32>()?.iter()).all(|v| v.iter().all(|e| *e == 0.0));
+            if !p_is_zeros {
+                bail!("LSTM currently only supports default value of p (a Tensor of all zeroes)");
+            }
 
-                // Optional initial value of the cell.
-                // If not specified - assumed to be 0.
-                // It has shape `[num_directions, batch_size, hidden_size]`.
-                let initial_c_default: Tensor;
-                let initial_c = match node.input.get(6) {
-                    Some(n) if !n.is_empty() => get(n)?,
-                    _ => {
-                        initial_c_default = Tensor::zeros(
-                            (num_directions, batch_size, hidden_size as usize),
-                            DType::F32,
-                            x.device(),
-                        )?;
-                        &initial_c_default
-                    }
-                };
+            // these all have [num_directions, ...] shapes
+            let w = w.get(0)?; // w[iofc] has shape [4*hidden_size, input_size]
+            let r = r.get(0)?; // r[iofc] has shape [4*hidden_size, hidden_size]
+            let b = b.get(0)?; // concat of [wb[iofc],rb[iofc]] has shape [8*hidden_size]
+            let idx_wb = Tensor::arange(0, 4 * hidden_size, x.device())?;
+            let idx_rb = Tensor::arange(4 * hidden_size, 8 * hidden_size, x.device())?;
+            let wb = b.index_select(&idx_wb, 0)?;
+            let rb = b.index_select(&idx_rb, 0)?;
+            let c = initial_c.get(0)?;
+            let h = initial_h.get(0)?;
+
+            // w, r, wb, rb are all iofc but lstm expects ifco
+            // so we need to move some stuff around
+            let idx_i = Tensor::arange(0, hidden_size, x.device())?;
+            let idx_o = Tensor::arange(hidden_size, 2 * hidden_size, x.device())?;
+            let idx_f = Tensor::arange(2 * hidden_size, 3 * hidden_size, x.device())?;
+            let idx_c = Tensor::arange(3 * hidden_size, 4 * hidden_size, x.device())?;
+            let idx_ifco = Tensor::cat(&[&idx_i, &idx_f, &idx_c, &idx_o], 0)?;
+            let w = w.index_select(&idx_ifco, 0)?;
+            let r = r.index_select(&idx_ifco, 0)?;
+            let wb = wb.index_select(&idx_ifco, 0)?;
+            let rb = rb.index_select(&idx_ifco, 0)?;
+            let vmap = candle_nn::VarMap::new();
+            vmap.data().lock().unwrap().extend([
+                ("weight_ih_l0".to_string(), candle::Var::from_tensor(&w)?),
+                ("weight_hh_l0".to_string(), candle::Var::from_tensor(&r)?),
+                ("bias_ih_l0".to_string(), candle::Var::from_tensor(&wb)?),
+                ("bias_hh_l0".to_string(), candle::Var::from_tensor(&rb)?),
+            ]);
+            use candle_nn::rnn::RNN as _;
+            let lstm = candle_nn::rnn::lstm(
+                input_size,
+                hidden_size as usize,
+                candle_nn::rnn::LSTMConfig::default(),
+                candle_nn::VarBuilder::from_varmap(&vmap, w.dtype(), w.device()),
+            )?;
+
+            let mut lstm_state = candle_nn::rnn::LSTMState::new(h, c);
+            let mut h_acc = if node.output.first().map(String::as_str).unwrap_or("") != "" {
+                Some(vec![])
+            } else {
+                None
+            };
+            for t in 0..seq_length {
+                let x = x.get(t)?;
+                lstm_state = lstm.step(&x, &lstm_state)?;
+                if let Some(h_acc) = &mut h_acc {
+                    h_acc.push(lstm_state.clone());
+                }
+            }
 
-                // The weight tensor for peepholes.
-                // Concatenation of `P[iof]` and `PB[iof]` (if bidirectional) along dimension 0.
-                // It has shape `[num_directions, 3*hidde_size]`. Optional: If not specified - assumed to be 0.
-                let p_default = Tensor::zeros(
-                    (num_directions, 3 * hidden_size as usize),
-                    DType::F32,
-                    x.device(),
-                )?;
-                let p = get_opt(7).unwrap_or(Ok(&p_default))?;
-                let p_is_zeros = (p.to_vec2::<f32>()?.iter()).all(|v| v.iter().all(|e| *e == 0.0));
-                if !p_is_zeros {
-                    bail!(
-                        "LSTM currently only supports default value of p (a Tensor of all zeroes)"
-                    );
-                }
-
-                // these all have [num_directions, ...] shapes
-                let w = w.get(0)?; // w[iofc] has shape [4*hidden_size, input_size]
-                let r = r.get(0)?; // r[iofc] has shape [4*hidden_size, hidden_size]
-                let b = b.get(0)?; // concat of [wb[iofc],rb[iofc]] has shape [8*hidden_size]
-                let idx_wb = Tensor::arange(0, 4 * hidden_size, x.device())?;
-                let idx_rb = Tensor::arange(4 * hidden_size, 8 * hidden_size, x.device())?;
-                let wb = b.index_select(&idx_wb, 0)?;
-                let rb = b.index_select(&idx_rb, 0)?;
-                let c = initial_c.get(0)?;
-                let h = initial_h.get(0)?;
-
-                // w, r, wb, rb are all iofc but lstm expects ifco
-                // so we need to move some stuff around
-                let idx_i = Tensor::arange(0, hidden_size, x.device())?;
-                let idx_o = Tensor::arange(hidden_size, 2 * hidden_size, x.device())?;
-                let idx_f = Tensor::arange(2 * hidden_size, 3 * hidden_size, x.device())?;
-                let idx_c = Tensor::arange(3 * hidden_size, 4 * hidden_size, x.device())?;
-                let idx_ifco = Tensor::cat(&[&idx_i, &idx_f, &idx_c, &idx_o], 0)?;
-                let w = w.index_select(&idx_ifco, 0)?;
-                let r = r.index_select(&idx_ifco, 0)?;
-                let wb = wb.index_select(&idx_ifco, 0)?;
-                let rb = rb.index_select(&idx_ifco, 0)?;
-                let vmap = candle_nn::VarMap::new();
-                vmap.data().lock().unwrap().extend([
-                    ("weight_ih_l0".to_string(), candle::Var::from_tensor(&w)?),
-                    ("weight_hh_l0".to_string(), candle::Var::from_tensor(&r)?),
-                    ("bias_ih_l0".to_string(), candle::Var::from_tensor(&wb)?),
-                    ("bias_hh_l0".to_string(), candle::Var::from_tensor(&rb)?),
-                ]);
-                use candle_nn::rnn::RNN as _;
-                let lstm = candle_nn::rnn::lstm(
-                    input_size,
+            assert_eq!(num_directions, 1, "if support for bidirectional is ever added, outputs will have to be concatenated, not simply reshaped");
+            if let Some(name) = node.output.first() {
+                let h_acc = h_acc.as_ref().unwrap();
+                let h_acc = lstm.states_to_tensor(h_acc)?;
+                let h_acc = h_acc.reshape((
+                    seq_length,
+                    num_directions,
+                    batch_size,
                     hidden_size as usize,
-                    candle_nn::rnn::LSTMConfig::default(),
-                    candle_nn::VarBuilder::from_varmap(&vmap, w.dtype(), w.device()),
-                )?;
+                ))?;
+                values.insert(name.clone(), h_acc.into());
+            }
+            if let Some(name) = node.output.get(1) {
+                values.insert(
+                    name.clone(),
+                    lstm_state
+                        .h()
+                        .reshape((num_directions, batch_size, hidden_size as usize))?
+                        .into(),
+                );
+            }
+            if let Some(name) = node.output.get(2) {
+                values.insert(
+                    name.clone(),
+                    lstm_state
+                        .c()
+                        .reshape((num_directions, batch_size, hidden_size as usize))?
+                        .into(),
+                );
+            }
+        }
+        "RNN" => {
+            // activation_alpha and activation_beta don't apply to (Tanh, Tanh) so ignoring them is okay
+            let activations_default = vec!["Tanh".to_string(), "Tanh".to_string()];
+            let activations = get_attr_opt_owned::<Vec<String>>(node, "activations")?
+                .unwrap_or(activations_default.clone());
+            let clip = get_attr_opt::<f32>(node, "clip")?.copied();
+            if clip.is_some() {
+                bail!("RNN does not currently support clip attribute");
+            }
+            let direction = get_attr_opt(node, "direction")?.unwrap_or("forward");
+            if direction != "forward" {
+                bail!("RNN currently only supports direction == \"forward\"");
+            }
+            let num_directions = if direction == "bidirectional" { 2 } else { 1 };
+            let hidden_size: i64 = get_attr(node, "hidden_size").copied()?;
+
+            // The shape format of inputs X, initial_h and outputs Y, Y_h.
+            // If 0, the following shapes are expected:
+            //    X.shape = [seq_length, batch_size, input_size],
+            //    Y.shape = [seq_length, num_directions, batch_size, hidden_size],
+            //    initial_h.shape = Y_h.shape = [num_directions, batch_size, hidden_size].
+            // If 1, the following shapes are expected:
+            //    X.shape = [batch_size, seq_length, input_size],
+            //    Y.shape = [batch_size, seq_length, num_directions, hidden_size],
+            //    initial_h.shape = Y_h.shape = [batch_size, num_directions, hidden_size].
+            let layout = get_attr_opt(node, "layout")?.copied().unwrap_or(0);
+            if layout != 0 {
+                bail!("RNN currently only supports layout == 0");
+            }
 
-                let mut lstm_state = candle_nn::rnn::LSTMState::new(h, c);
-                let mut h_acc = if node.output.first().map(String::as_str).unwrap_or("") != "" {
-                    Some(vec![])
-                } else {
-                    None
-                };
-                for t in 0..seq_length {
-                    let x = x.get(t)?;
-                    lstm_state = lstm.step(&x, &lstm_state)?;
-                    if let Some(h_acc) = &mut h_acc {
-                        h_acc.push(lstm_state.clone());
-                    }
+            // The input sequences packed (and potentially padded) into one 3-D tensor
+            // with the shape of `[seq_length, batch_size, input_size]`.
+            let x = get(&node.input[0])?;
+            // XXX: depends on layout
+            let (seq_length, batch_size, _) = x.dims3()?;
+            // The weight tensor for the input gate.
+            // Concatenation of `Wi` and `WBi` (if bidirectional).
+            // The tensor has shape `[num_directions, hidden_size, input_size]`.
+            let w = get(&node.input[1])?;
+            // The recurrence weight tensor.
+            // Concatenation of `Ri` and `RBi` (if bidirectional).
+            // This tensor has shape `[num_directions, hidden_size, hidden_size]`.
+            let r = get(&node.input[2])?;
+
+            // The bias tensor for input gate.
+            // Concatenation of `[Wbi, Rbi]` and `[WBbi, RBbi]` (if bidirectional).
+            // This tensor has shape `[num_directions, 2*hidden_size]`.
+            // Optional: If not specified - assumed to be 0.
+            let b_default: Tensor;
+            let b = match get_opt(3) {
+                Some(n) => n?,
+                None => {
+                    b_default = Tensor::zeros(
+                        (num_directions, 2 * hidden_size as usize),
+                        DType::F32,
+                        x.device(),
+                    )?;
+                    &b_default
                 }
+            };
+
+            // Optional tensor specifying lengths of the sequences in a batch.
+            // If not specified - assumed all sequences in the batch to have length `seq_length`.
+            // It has shape `[batch_size]`.
+            let seq_lens_default: Tensor;
+            let seq_lens = match get_opt(4) {
+                Some(n) => n?,
+                None => {
+                    seq_lens_default = Tensor::full(seq_length as i64, (batch_size,), x.device())?;
+                    &seq_lens_default
+                }
+            };
+            let seq_lens_is_default =
+                (seq_lens.to_vec1::<i64>()?.iter()).all(|e| *e as usize == seq_length);
+            if !seq_lens_is_default {
+                bail!("RNN currently does not support variable-length sequences. All sequences must use the full sequence length of {}", seq_length);
+            }
 
-                assert_eq!(num_directions, 1, "if support for bidirectional is ever added, outputs will have to be concatenated, not simply reshaped");
-                if let Some(name) = node.output.first() {
-                    let h_acc = h_acc.as_ref().unwrap();
-                    let h_acc = lstm.states_to_tensor(h_acc)?;
-                    let h_acc = h_acc.reshape((
-                        seq_length,
-                        num_directions,
-                        batch_size,
-                        hidden_size as usize,
-                    ))?;
-                    values.insert(name.clone(), h_acc);
-                }
-                if let Some(name) = node.output.get(1) {
-                    values.insert(
-                        name.clone(),
-                        lstm_state.h().reshape((
-                            num_directions,
-                            batch_size,
-                            hidden_size as usize,
-                        ))?,
-                    );
-                }
-                if let Some(name) = node.output.get(2) {
-                    values.insert(
-                        name.clone(),
-                        lstm_state.c().reshape((
-                            num_directions,
-                            batch_size,
-                            hidden_size as usize,
-                        ))?,
-                    );
-                }
-            }
-            "RNN" => {
-                // activation_alpha and activation_beta don't apply to (Tanh, Tanh) so ignoring them is okay
-                let activations_default = vec!["Tanh".to_string(), "Tanh".to_string()];
-                let activations = get_attr_opt_owned::<Vec<String>>(node, "activations")?
-                    .unwrap_or(activations_default.clone());
-                let clip = get_attr_opt::<f32>(node, "clip")?.copied();
-                if clip.is_some() {
-                    bail!("RNN does not currently support clip attribute");
-                }
-                let direction = get_attr_opt(node, "direction")?.unwrap_or("forward");
-                if direction != "forward" {
-                    bail!("RNN currently only supports direction == \"forward\"");
-                }
-                let num_directions = if direction == "bidirectional" { 2 } else { 1 };
-                let hidden_size: i64 = get_attr(node, "hidden_size").copied()?;
-
-                // The shape format of inputs X, initial_h and outputs Y, Y_h.
-                // If 0, the following shapes are expected:
-                //    X.shape = [seq_length, batch_size, input_size],
-                //    Y.shape = [seq_length, num_directions, batch_size, hidden_size],
-                //    initial_h.shape = Y_h.shape = [num_directions, batch_size, hidden_size].
-                // If 1, the following shapes are expected:
-                //    X.shape = [batch_size, seq_length, input_size],
-                //    Y.shape = [batch_size, seq_length, num_directions, hidden_size],
-                //    initial_h.shape = Y_h.shape = [batch_size, num_directions, hidden_size].
-                let layout = get_attr_opt(node, "layout")?.copied().unwrap_or(0);
-                if layout != 0 {
-                    bail!("RNN currently only supports layout == 0");
-                }
-
-                // The input sequences packed (and potentially padded) into one 3-D tensor
-                // with the shape of `[seq_length, batch_size, input_size]`.
-                let x = get(&node.input[0])?;
-                // XXX: depends on layout
-                let (seq_length, batch_size, _) = x.dims3()?;
-                // The weight tensor for the input gate.
-                // Concatenation of `Wi` and `WBi` (if bidirectional).
-                // The tensor has shape `[num_directions, hidden_size, input_size]`.
-                let w = get(&node.input[1])?;
-                // The recurrence weight tensor.
-                // Concatenation of `Ri` and `RBi` (if bidirectional).
-                // This tensor has shape `[num_directions, hidden_size, hidden_size]`.
-                let r = get(&node.input[2])?;
-
-                // The bias tensor for input gate.
-                // Concatenation of `[Wbi, Rbi]` and `[WBbi, RBbi]` (if bidirectional).
-                // This tensor has shape `[num_directions, 2*hidden_size]`.
-                // Optional: If not specified - assumed to be 0.
-                let b_default: Tensor;
-                let b = match get_opt(3) {
-                    Some(n) => n?,
-                    None => {
-                        b_default = Tensor::zeros(
-                            (num_directions, 2 * hidden_size as usize),
-                            DType::F32,
-                            x.device(),
-                        )?;
-                        &b_default
-                    }
-                };
-
-                // Optional tensor specifying lengths of the sequences in a batch.
-                // If not specified - assumed all sequences in the batch to have length `seq_length`.
-                // It has shape `[batch_size]`.
-                let seq_lens_default: Tensor;
-                let seq_lens = match get_opt(4) {
-                    Some(n) => n?,
-                    None => {
-                        seq_lens_default =
-                            Tensor::full(seq_length as i64, (batch_size,), x.device())?;
-                        &seq_lens_default
-                    }
-                };
-                let seq_lens_is_default =
-                    (seq_lens.to_vec1::<i64>()?.iter()).all(|e| *e as usize == seq_length);
-                if !seq_lens_is_default {
-                    bail!("RNN currently does not support variable-length sequences. All sequences must use the full sequence length of {}", seq_length);
-                }
-
-                // Optional initial value of the hidden. If not specified - assumed to be 0.
-                // It has shape `[num_directions, batch_size, hidden_size]`.
-                let initial_h_default: Tensor;
-                let initial_h = match get_opt(5) {
-                    Some(n) => n?,
-                    _ => {
-                        initial_h_default = Tensor::zeros(
-                            (num_directions, batch_size, hidden_size as usize),
-                            DType::F32,
-                            x.device(),
-                        )?;
-                        &initial_h_default
-                    }
-                };
+            // Optional initial value of the hidden. If not specified - assumed to be 0.
+            // It has shape `[num_directions, batch_size, hidden_size]`.
+            let initial_h_default: Tensor;
+            let initial_h = match get_opt(5) {
+                Some(n) => n?,
+                _ => {
+                    initial_h_default = Tensor::zeros(
+                        (num_directions, batch_size, hidden_size as usize),
+                        DType::F32,
+                        x.device(),
+                    )?;
+                    &initial_h_default
+                }
+            };
 
-                fn choose_activation(activation: &str, x: &Tensor) -> Result<Tensor> {
-                    match activation {
-                        "Tanh" => x.tanh(),
-                        _ => bail!("unsupported activation {activation}"),
-                    }
+            fn choose_activation(activation: &str, x: &Tensor) -> Result<Tensor> {
+                match activation {
+                    "Tanh" => x.tanh(),
+                    _ => bail!("unsupported activation {activation}"),
                 }
+            }
 
-                // these all have [num_directions, ...] shapes
-                let w = w.get(0)?;
-                let r = r.get(0)?;
-                let b = b.get(0)?;
-                let idx_wb = Tensor::arange(0, hidden_size, x.device())?;
-                let idx_rb = Tensor::arange(hidden_size, 2 * hidden_size, x.device())?;
-                let wb = b.index_select(&idx_wb, 0)?;
-                let rb = b.index_select(&idx_rb, 0)?;
-                let mut h_t = initial_h.get(0)?;
-                let mut h_list: Vec<Tensor> = vec![];
-                for i in 0..seq_length {
-                    let xs = x.get(i)?;
-                    let h = xs
-                        .matmul(&w.t()?)?
-                        .add(&h_t.matmul(&r.t()?)?)?
-                        .add(&wb.unsqueeze(0)?)?
-                        .add(&rb.unsqueeze(0)?)?;
-                    let h = choose_activation(&activations[0], &h)?;
-                    h_list.push(h.to_owned());
-                    h_t = h;
-                }
-                let h = Tensor::stack(&h_list, 0)?;
-                let h =
-                    h.reshape((seq_length, num_directions, batch_size, hidden_size as usize))?;
-                values.insert(node.output[0].clone(), h);
-                values.insert(
-                    node.output[1].clone(),
-                    h_t.reshape((num_directions, batch_size, hidden_size as usize))?,
-                );
+            // these all have [num_directions, ...] shapes
+            let w = w.get(0)?;
+            let r = r.get(0)?;
+            let b = b.get(0)?;
+            let idx_wb = Tensor::arange(0, hidden_size, x.device())?;
+            let idx_rb = Tensor::arange(hidden_size, 2 * hidden_size, x.device())?;
+            let wb = b.index_select(&idx_wb, 0)?;
+            let rb = b.index_select(&idx_rb, 0)?;
+            let mut h_t = initial_h.get(0)?;
+            let mut h_list: Vec<Tensor> = vec![];
+            for i in 0..seq_length {
+                let xs = x.get(i)?;
+                let h = xs
+                    .matmul(&w.t()?)?
+                    .add(&h_t.matmul(&r.t()?)?)?
+                    .add(&wb.unsqueeze(0)?)?
+                    .add(&rb.unsqueeze(0)?)?;
+                let h = choose_activation(&activations[0], &h)?;
+                h_list.push(h.to_owned());
+                h_t = h;
             }
-            // https://onnx.ai/onnx/operators/onnx__Xor.html
-            "Xor" => {
-                // Since we don't have a `DType::Bool` yet, this ensures that we are working with `0`(False) & `1`(True)
-                let a = get(&node.input[0])?.gt(0_u8)?;
-                let b = get(&node.input[1])?.gt(0_u8)?;
+            let h = Tensor::stack(&h_list, 0)?;
+            let h = h.reshape((seq_length, num_directions, batch_size, hidden_size as usize))?;
+            values.insert(node.output[0].clone(), h.into());
+            values.insert(
+                node.output[1].clone(),
+                h_t.reshape((num_directions, batch_size, hidden_size as usize))?
+                    .into(),
+            );
+        }
+        // https://onnx.ai/onnx/operators/onnx__Xor.html
+        "Xor" => {
+            // Since we don't have a `DType::Bool` yet, this ensures that we are working with `0`(False) & `1`(True)
+            let a = get(&node.input[0])?.gt(0_u8)?;
+            let b = get(&node.input[1])?.gt(0_u8)?;
 
-                let out = a.broadcast_add(&b)?.eq(1_u8)?;
+            let out = a.broadcast_add(&b)?.eq(1_u8)?;
 
-                values.insert(node.output[0].clone(), out);
-            }
-            // https://onnx.ai/onnx/operators/onnx__And.html
-            "And" => {
-                let a = get(&node.input[0])?.gt(0_u8)?;
-                let b = get(&node.input[1])?.gt(0_u8)?;
+            values.insert(node.output[0].clone(), out.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__And.html
+        "And" => {
+            let a = get(&node.input[0])?.gt(0_u8)?;
+            let b = get(&node.input[1])?.gt(0_u8)?;
 
-                let out = a.broadcast_mul(&b)?;
+            let out = a.broadcast_mul(&b)?;
 
-                values.insert(node.output[0].clone(), out);
-            }
-            // https://onnx.ai/onnx/operators/onnx__Or.html
-            "Or" => {
-                let a = get(&node.input[0])?.gt(0_u8)?;
-                let b = get(&node.input[1])?.gt(0_u8)?;
+            values.insert(node.output[0].clone(), out.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__Or.html
+        "Or" => {
+            let a = get(&node.input[0])?.gt(0_u8)?;
+            let b = get(&node.input[1])?.gt(0_u8)?;
+
+            let out = a.broadcast_add(&b)?.gt(0_u8)?;
 
-                let out = a.broadcast_add(&b)?.gt(0_u8)?;
+            values.insert(node.output[0].clone(), out.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__Sign.html
+        "Sign" => {
+            let input = get(&node.input[0])?;
+            let output = input.sign()?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        // https://onnx.ai/onnx/operators/onnx__Selu.html
+        "Selu" => {
+            let input = get(&node.input[0])?;
+            let alpha = get_attr_opt::<f32>(node, "alpha")?
+                .copied()
+                .unwrap_or(1.6732632);
+            let gamma = get_attr_opt::<f32>(node, "gamma")?
+                .copied()
+                .unwrap_or(1.050701);
+            let out = candle_nn::ops::selu(input, alpha as f32, gamma as f32)?;
+            values.insert(node.output[0].clone(), out.into());
+        }
 
-                values.insert(node.output[0].clone(), out);
+        // https://onnx.ai/onnx/operators/onnx__OneHot.html
+        "OneHot" => {
+            let indices = get(&node.input[0])?;
+            let orig_shape = get(&node.input[0])?.dims().to_vec();
+            let depth_tensor = get(&node.input[1])?;
+            let values_tensor = get(&node.input[2])?;
+
+            let depth = to_scalar_flexible::<i64>(depth_tensor)? as usize;
+            let values_vec = values_tensor.to_vec1::<f32>()?;
+            if values_vec.len() != 2 {
+                return Err(candle::Error::Msg(
+                    "OneHot: expected 2-element values tensor".to_string(),
+                ));
             }
-            // https://onnx.ai/onnx/operators/onnx__Sign.html
-            "Sign" => {
-                let input = get(&node.input[0])?;
-                let output = input.sign()?;
-                values.insert(node.output[0].clone(), output);
+            let off_value = values_vec[0];
+            let on_value = values_vec[1];
+
+            let mut axis = node
+                .attribute
+                .iter()
+                .find(|attr| attr.name == "axis")
+                .map(|attr| attr.i)
+                .unwrap_or(-1);
+
+            let rank = indices.rank();
+            if axis < -((rank as i64) + 1) || axis > (rank as i64) {
+                return Err(candle::Error::Msg(format!(
+                    "OneHot: invalid axis {axis} for rank {rank}"
+                )));
+            }
+            if axis < 0 {
+                axis += rank as i64 + 1;
             }
-            // https://onnx.ai/onnx/operators/onnx__Selu.html
-            "Selu" => {
-                let input = get(&node.input[0])?;
-                let alpha = get_attr_opt::<f32>(node, "alpha")?
-                    .copied()
-                    .unwrap_or(1.6732632);
-                let gamma = get_attr_opt::<f32>(node, "gamma")?
-                    .copied()
-                    .unwrap_or(1.050701);
-                let out = candle_nn::ops::selu(input, alpha as f32, gamma as f32)?;
-                values.insert(node.output[0].clone(), out);
-            }
-
-            // https://onnx.ai/onnx/operators/onnx__OneHot.html
-            "OneHot" => {
-                let indices = get(&node.input[0])?;
-                let orig_shape = get(&node.input[0])?.dims().to_vec();
-                let depth_tensor = get(&node.input[1])?;
-                let values_tensor = get(&node.input[2])?;
-
-                let depth = to_scalar_flexible::<i64>(depth_tensor)? as usize;
-                let values_vec = values_tensor.to_vec1::<f32>()?;
-                if values_vec.len() != 2 {
-                    return Err(candle::Error::Msg(
-                        "OneHot: expected 2-element values tensor".to_string(),
-                    ));
-                }
-                let off_value = values_vec[0];
-                let on_value = values_vec[1];
-
-                let mut axis = node
-                    .attribute
-                    .iter()
-                    .find(|attr| attr.name == "axis")
-                    .map(|attr| attr.i)
-                    .unwrap_or(-1);
-
-                let rank = indices.rank();
-                if axis < -((rank as i64) + 1) || axis > (rank as i64) {
-                    return Err(candle::Error::Msg(format!(
-                        "OneHot: invalid axis {axis} for rank {rank}"
-                    )));
-                }
-                if axis < 0 {
-                    axis += rank as i64 + 1;
-                }
-
-                let indices = indices.flatten_all()?;
-                let indices_vec = indices.to_vec1::<i64>()?;
-                let mut out = vec![off_value; depth * indices.elem_count()];
-                for (i, &index) in indices_vec.iter().enumerate() {
-                    let idx = if index < 0 {
-                        (index + depth as i64) as usize
-                    } else {
-                        index as usize
-                    };
-                    if idx >= depth {
-                        continue;
-                    }
-                    out[i * depth + idx] = on_value;
-                }
-
-                let mut target_shape = orig_shape;
-                target_shape.push(depth);
-                let output = Tensor::from_vec(out, target_shape, indices.device())?;
 
-                let final_output = if axis as usize == output.rank() - 1 {
-                    output
+            let indices = indices.flatten_all()?;
+            let indices_vec = indices.to_vec1::<i64>()?;
+            let mut out = vec![off_value; depth * indices.elem_count()];
+            for (i, &index) in indices_vec.iter().enumerate() {
+                let idx = if index < 0 {
+                    (index + depth as i64) as usize
                 } else {
-                    fn move_axis_to(rank: usize, from: usize, to: usize) -> Vec<usize> {
-                        let mut dims: Vec<usize> = (0..rank).collect();
-                        let axis = dims.remove(from);
-                        dims.insert(to, axis);
-                        dims
-                    }
-
-                    let perm = move_axis_to(output.rank(), output.rank() - 1, axis as usize);
-                    output.permute(&*perm)?
+                    index as usize
                 };
-                values.insert(node.output[0].clone(), final_output);
-            }
-            "HardSwish" => {
-                let input = get(&node.input[0])?;
-                let hard_sigmoid = candle_nn::ops::hard_sigmoid(&input)?;
-                let output = input * hard_sigmoid;
-                values.insert(node.output[0].clone(), output?);
+                if idx >= depth {
+                    continue;
+                }
+                out[i * depth + idx] = on_value;
             }
-            "Resize" => {
-                let input = get(&node.input[0])?;
 
-                if input.rank() != 4 {
-                    bail!("Unsupported rank for nearest resize: {}", input.rank());
-                }
+            let mut target_shape = orig_shape;
+            target_shape.push(depth);
+            let output = Tensor::from_vec(out, target_shape, indices.device())?;
 
-                let scales = if node.input.len() > 2 && !node.input[2].is_empty() {
-                    Some(get(&node.input[2])?)
-                } else {
-                    None
-                };
+            let final_output = if axis as usize == output.rank() - 1 {
+                output
+            } else {
+                fn move_axis_to(rank: usize, from: usize, to: usize) -> Vec<usize> {
+                    let mut dims: Vec<usize> = (0..rank).collect();
+                    let axis = dims.remove(from);
+                    dims.insert(to, axis);
+                    dims
+                }
 
-                let sizes = if node.input.len() > 3 && !node.input[3].is_empty() {
-                    Some(get(&node.input[3])?)
-                } else {
-                    None
-                };
+                let perm = move_axis_to(output.rank(), output.rank() - 1, axis as usize);
+                output.permute(&*perm)?
+            };
+            values.insert(node.output[0].clone(), final_output.into());
+        }
+        "HardSwish" => {
+            let input = get(&node.input[0])?;
+            let hard_sigmoid = candle_nn::ops::hard_sigmoid(&input)?;
+            let output = input * hard_sigmoid;
+            values.insert(node.output[0].clone(), output?.into());
+        }
+        "Resize" => {
+            let input = get(&node.input[0])?;
 
-                let output_dims = match (scales, sizes) {
-                    (Some(_), Some(_)) => {
-                        bail!("Scales and sizes cannot both be set for Resize operation")
-                    }
-                    (Some(scales_tensor), None) => {
-                        let scale_values = scales_tensor.to_vec1::<f32>()?;
-                        input
-                            .dims()
-                            .iter()
-                            .enumerate()
-                            .map(|(i, &d)| (d as f32 * scale_values[i]) as usize)
-                            .collect::<Vec<_>>()
-                    }
-                    (None, Some(sizes_tensor)) => sizes_tensor
-                        .to_vec1::<i64>()?
-                        .iter()
-                        .map(|&d| d as usize)
-                        .collect::<Vec<_>>(),
-                    (None, None) => bail!("Either scales or sizes should be present"),
-                };
+            if input.rank() != 4 {
+                bail!("Unsupported rank for nearest resize: {}", input.rank());
+            }
 
-                let coordinate_transformation_mode =
-                    get_attr_opt::<str>(node, "coordinate_transformation_mode")?
-                        .unwrap_or("half_pixel");
-                // Interpolation mode: nearest, linear, or cubic.
-                let mode = get_attr_opt::<str>(node, "mode")?.unwrap_or("nearest");
-                // How to determine the "nearest" pixel in nearest interpolation mode.
-                let nearest_mode =
-                    get_attr_opt::<str>(node, "nearest_mode")?.unwrap_or("round_prefer_floor");
+            let scales = if node.input.len() > 2 && !node.input[2].is_empty() {
+                Some(get(&node.input[2])?)
+            } else {
+                None
+            };
 
-                if mode != "nearest" {
-                    bail!("Unsupported resize mode: {}", mode);
-                }
+            let sizes = if node.input.len() > 3 && !node.input[3].is_empty() {
+                Some(get(&node.input[3])?)
+            } else {
+                None
+            };
 
-                if nearest_mode != "floor" {
-                    bail!("Unsupported nearest_mode for resize: {}", nearest_mode);
+            let output_dims = match (scales, sizes) {
+                (Some(_), Some(_)) => {
+                    bail!("Scales and sizes cannot both be set for Resize operation")
                 }
-
-                if coordinate_transformation_mode != "asymmetric" {
-                    bail!(
-                        "Unsupported coordinate_transformation_mode for resize: {}",
-                        coordinate_transformation_mode
-                    );
+                (Some(scales_tensor), None) => {
+                    let scale_values = scales_tensor.to_vec1::<f32>()?;
+                    input
+                        .dims()
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &d)| (d as f32 * scale_values[i]) as usize)
+                        .collect::<Vec<_>>()
                 }
+                (None, Some(sizes_tensor)) => sizes_tensor
+                    .to_vec1::<i64>()?
+                    .iter()
+                    .map(|&d| d as usize)
+                    .collect::<Vec<_>>(),
+                (None, None) => bail!("Either scales or sizes should be present"),
+            };
+
+            let coordinate_transformation_mode =
+                get_attr_opt::<str>(node, "coordinate_transformation_mode")?
+                    .unwrap_or("half_pixel");
+            // Interpolation mode: nearest, linear, or cubic.
+            let mode = get_attr_opt::<str>(node, "mode")?.unwrap_or("nearest");
+            // How to determine the "nearest" pixel in nearest interpolation mode.
+            let nearest_mode =
+                get_attr_opt::<str>(node, "nearest_mode")?.unwrap_or("round_prefer_floor");
+
+            if mode != "nearest" {
+                bail!("Unsupported resize mode: {}", mode);
+            }
 
-                let h = output_dims[2];
-                let w = output_dims[3];
-                let output = input.upsample_nearest2d(h, w)?;
+            if nearest_mode != "floor" {
+                bail!("Unsupported nearest_mode for resize: {}", nearest_mode);
+            }
 
-                values.insert(node.output[0].clone(), output);
+            if coordinate_transformation_mode != "asymmetric" {
+                bail!(
+                    "Unsupported coordinate_transformation_mode for resize: {}",
+                    coordinate_transformation_mode
+                );
             }
-            "Trilu" => {
-                let input = get(&node.input[0])?;
 
-                // Get the diagonal offset 'k' from the second input if provided
-                let k = if node.input.len() > 1 && !node.input[1].is_empty() {
-                    to_vec0_flexible::<i64>(get(&node.input[1])?)?
-                } else {
-                    0
-                };
+            let h = output_dims[2];
+            let w = output_dims[3];
+            let output = input.upsample_nearest2d(h, w)?;
 
-                // Get the 'upper' attribute
-                let upper = get_attr_opt::<i64>(node, "upper")?.copied().unwrap_or(1);
-
-                // For batched inputs, we need to handle each matrix separately
-                let dims = input.dims();
-                if dims.len() < 2 {
-                    bail!("Trilu expects input with at least 2 dimensions: {:?}", dims);
-                }
-
-                // Get the last two dimensions which represent the matrix
-                let n = dims[dims.len() - 2];
-                let m = dims[dims.len() - 1];
-                let max_dim = std::cmp::max(n, m);
-
-                // Handle the diagonal offset k
-                let mask = if k != 0 {
-                    let mut data = vec![0u32; n * m];
-                    for i in 0..n {
-                        for j in 0..m {
-                            if (upper != 0 && (j as i64) >= (i as i64) + k)
-                                || (upper == 0 && (j as i64) <= (i as i64) + k)
-                            {
-                                data[i * m + j] = 1u32;
-                            }
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "Trilu" => {
+            let input = get(&node.input[0])?;
+
+            // Get the diagonal offset 'k' from the second input if provided
+            let k = if node.input.len() > 1 && !node.input[1].is_empty() {
+                to_vec0_flexible::<i64>(get(&node.input[1])?)?
+            } else {
+                0
+            };
+
+            // Get the 'upper' attribute
+            let upper = get_attr_opt::<i64>(node, "upper")?.copied().unwrap_or(1);
+
+            // For batched inputs, we need to handle each matrix separately
+            let dims = input.dims();
+            if dims.len() < 2 {
+                bail!("Trilu expects input with at least 2 dimensions: {:?}", dims);
+            }
+
+            // Get the last two dimensions which represent the matrix
+            let n = dims[dims.len() - 2];
+            let m = dims[dims.len() - 1];
+            let max_dim = std::cmp::max(n, m);
+
+            // Handle the diagonal offset k
+            let mask = if k != 0 {
+                let mut data = vec![0u32; n * m];
+                for i in 0..n {
+                    for j in 0..m {
+                        if (upper != 0 && (j as i64) >= (i as i64) + k)
+                            || (upper == 0 && (j as i64) <= (i as i64) + k)
+                        {
+                            data[i * m + j] = 1u32;
                         }
                     }
-                    Tensor::from_vec(data, (n, m), input.device())?.to_dtype(input.dtype())?
-                } else if upper == 0 {
-                    Tensor::tril2(max_dim, input.dtype(), input.device())?
-                } else {
-                    Tensor::triu2(max_dim, input.dtype(), input.device())?
-                };
+                }
+                Tensor::from_vec(data, (n, m), input.device())?.to_dtype(input.dtype())?
+            } else if upper == 0 {
+                Tensor::tril2(max_dim, input.dtype(), input.device())?
+            } else {
+                Tensor::triu2(max_dim, input.dtype(), input.device())?
+            };
 
-                let final_mask = if n != m {
-                    mask.narrow(0, 0, n)?.narrow(1, 0, m)?
-                } else {
-                    mask
-                };
+            let final_mask = if n != m {
+                mask.narrow(0, 0, n)?.narrow(1, 0, m)?
+            } else {
+                mask
+            };
 
-                let output = (input * &final_mask)?;
+            let output = (input * &final_mask)?;
 
-                values.insert(node.output[0].clone(), output);
-            }
-            "ScatterND" => {
-                let data = get(&node.input[0])?;
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "ScatterND" => {
+            let data = get(&node.input[0])?;
 
-                let indices = get(&node.input[1])?;
-                let indices = indices.to_dtype(DType::I64)?;
+            let indices = get(&node.input[1])?;
+            let indices = indices.to_dtype(DType::I64)?;
 
-                let updates = get(&node.input[2])?;
+            let updates = get(&node.input[2])?;
 
-                let reduction = get_attr_opt::<str>(node, "reduction")?.unwrap_or("none");
+            let reduction = get_attr_opt::<str>(node, "reduction")?.unwrap_or("none");
 
-                let indices_shape = indices.dims();
-                let data_shape = data.dims();
-                let _updates_shape = updates.dims();
+            let indices_shape = indices.dims();
+            let data_shape = data.dims();
+            let _updates_shape = updates.dims();
 
-                // Last dimension of indices represents the depth of indexing
-                let k = indices_shape.last().unwrap().clone();
+            // Last dimension of indices represents the depth of indexing
+            let k = indices_shape.last().unwrap().clone();
 
-                if k > data.rank() {
-                    bail!("ScatterND expects k (indices.shape[-1]) to be at most the rank of data");
-                }
+            if k > data.rank() {
+                bail!("ScatterND expects k (indices.shape[-1]) to be at most the rank of data");
+            }
 
-                let num_updates = indices_shape[..indices_shape.len() - 1]
-                    .iter()
-                    .product::<usize>();
+            let num_updates = indices_shape[..indices_shape.len() - 1]
+                .iter()
+                .product::<usize>();
 
-                let flat_indices = if indices.rank() == 1 && k == 1 {
-                    indices.unsqueeze(0)?
-                } else {
-                    indices.reshape((num_updates, k))?
-                };
+            let flat_indices = if indices.rank() == 1 && k == 1 {
+                indices.unsqueeze(0)?
+            } else {
+                indices.reshape((num_updates, k))?
+            };
 
-                // Calculate the shape of each update element
-                let update_element_shape = if k < data_shape.len() {
-                    data_shape[k..].to_vec()
+            // Calculate the shape of each update element
+            let update_element_shape = if k < data_shape.len() {
+                data_shape[k..].to_vec()
+            } else {
+                vec![]
+            };
+
+            // Expected shape for updates based on indices and target tensor
+            let expected_updates_shape = {
+                let mut shape = indices_shape[..indices_shape.len() - 1].to_vec();
+                shape.extend(&update_element_shape);
+                shape
+            };
+
+            // Validate or reshape updates to expected shape
+            let updates = if updates.dims() != expected_updates_shape {
+                if updates.rank() == 0 {
+                    // Handle scalar updates
+                    let mut target_shape = vec![num_updates];
+                    target_shape.extend(&update_element_shape);
+                    updates.broadcast_as(target_shape)?
                 } else {
-                    vec![]
-                };
+                    // Try to broadcast or reshape updates to expected shape
+                    let flat_shape =
+                        vec![num_updates, update_element_shape.iter().product::<usize>()];
+                    let flattened = updates.reshape(flat_shape)?;
+                    flattened.reshape(expected_updates_shape)?
+                }
+            } else {
+                updates.clone()
+            };
 
-                // Expected shape for updates based on indices and target tensor
-                let expected_updates_shape = {
-                    let mut shape = indices_shape[..indices_shape.len() - 1].to_vec();
-                    shape.extend(&update_element_shape);
-                    shape
-                };
+            let mut output = data.clone();
 
-                // Validate or reshape updates to expected shape
-                let updates = if updates.dims() != expected_updates_shape {
-                    if updates.rank() == 0 {
-                        // Handle scalar updates
-                        let mut target_shape = vec![num_updates];
-                        target_shape.extend(&update_element_shape);
-                        updates.broadcast_as(target_shape)?
-                    } else {
-                        // Try to broadcast or reshape updates to expected shape
-                        let flat_shape =
-                            vec![num_updates, update_element_shape.iter().product::<usize>()];
-                        let flattened = updates.reshape(flat_shape)?;
-                        flattened.reshape(expected_updates_shape)?
+            // convert indices to flat indices
+            let mut flat_output = output.flatten_all()?;
+            let flat_updates = if update_element_shape.is_empty() {
+                updates.reshape(num_updates)?
+            } else {
+                let product = update_element_shape.iter().product::<usize>();
+                updates.reshape((num_updates, product))?
+            };
+
+            // Calculate strides for the output tensor
+            let mut strides: Vec<usize> = vec![1];
+            for i in (0..data_shape.len() - 1).rev() {
+                strides.push(strides.last().unwrap() * data_shape[i + 1]);
+            }
+            strides.reverse();
+
+            // Process each update
+            for i in 0..num_updates {
+                let index_slice = flat_indices.narrow(0, i, 1)?;
+                let indices_vec = index_slice.squeeze(0)?.to_vec1::<i64>()?;
+
+                // Convert multi-dimensional indices to flat index
+                let mut flat_idx: usize = 0;
+                for (dim, &idx) in indices_vec.iter().enumerate() {
+                    let dim_size = data_shape[dim] as i64;
+                    let norm_idx = if idx < 0 { dim_size + idx } else { idx };
+
+                    if norm_idx < 0 || norm_idx >= dim_size {
+                        bail!(
+                            "Index {} out of bounds for dimension {} with size {}",
+                            idx,
+                            dim,
+                            dim_size
+                        );
                     }
-                } else {
-                    updates.clone()
-                };
 
-                let mut output = data.clone();
+                    flat_idx += (norm_idx as usize) * strides[dim];
+                }
 
-                // convert indices to flat indices
-                let mut flat_output = output.flatten_all()?;
-                let flat_updates = if update_element_shape.is_empty() {
-                    updates.reshape(num_updates)?
+                // Extract current update
+                let update_slice = if update_element_shape.is_empty() {
+                    flat_updates.narrow(0, i, 1)?.squeeze(0)?
                 } else {
-                    let product = update_element_shape.iter().product::<usize>();
-                    updates.reshape((num_updates, product))?
+                    flat_updates.narrow(0, i, 1)?
                 };
 
-                // Calculate strides for the output tensor
-                let mut strides: Vec<usize> = vec![1];
-                for i in (0..data_shape.len() - 1).rev() {
-                    strides.push(strides.last().unwrap() * data_shape[i + 1]);
+                match reduction {
+                    "add" => {
+                        if update_element_shape.is_empty() {
+                            let existing = flat_output.narrow(0, flat_idx, 1)?;
+                            let new_value = existing.add(&update_slice.unsqueeze(0)?)?;
+                            flat_output = flat_output.slice_scatter(&new_value, 0, flat_idx)?;
+                        } else {
+                            let slice_size = update_element_shape.iter().product::<usize>();
+                            let existing = flat_output.narrow(0, flat_idx, slice_size)?;
+                            let new_value = existing.add(&update_slice)?;
+                            flat_output = flat_output.slice_scatter(&new_value, 0, flat_idx)?;
+                        }
+                    }
+                    "none" | _ => {
+                        if update_element_shape.is_empty() {
+                            flat_output = flat_output.slice_scatter(
+                                &update_slice.unsqueeze(0)?,
+                                0,
+                                flat_idx,
+                            )?;
+                        } else {
+                            flat_output = flat_output.slice_scatter(&update_slice, 0, flat_idx)?;
+                        }
+                    }
                 }
-                strides.reverse();
-
-                // Process each update
-                for i in 0..num_updates {
-                    let index_slice = flat_indices.narrow(0, i, 1)?;
-                    let indices_vec = index_slice.squeeze(0)?.to_vec1::<i64>()?;
+            }
 
-                    // Convert multi-dimensional indices to flat index
-                    let mut flat_idx: usize = 0;
-                    for (dim, &idx) in indices_vec.iter().enumerate() {
-                        let dim_size = data_shape[dim] as i64;
-                        let norm_idx = if idx < 0 { dim_size + idx } else { idx };
+            // Reshape flat output back to original shape
+            output = flat_output.reshape(data_shape.to_vec())?;
 
-                        if norm_idx < 0 || norm_idx >= dim_size {
+            values.insert(node.output[0].clone(), output.into());
+        }
+        "Einsum" => {
+            // https://onnx.ai/onnx/operators/onnx__Einsum.html
+            let equation = get_attr::<str>(node, "equation")?;
+            let operands = node
+                .input
+                .iter()
+                .map(|name| get(name))
+                .collect::<Result<Vec<_>>>()?;
+            let ys = einsum(equation, &operands)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "TopK" => {
+            // https://onnx.ai/onnx/operators/onnx__TopK.html
+            let xs = get(&node.input[0])?;
+            // Before opset 10, k is an attribute rather than an input.
+            let k = if opset < 10 {
+                *get_attr::<i64>(node, "k")?
+            } else {
+                to_scalar_flexible::<i64>(get(&node.input[1])?)?
+            };
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(-1);
+            let axis = xs.normalize_axis(axis)?;
+            let largest = get_attr_opt::<i64>(node, "largest")?.copied().unwrap_or(1) == 1;
+            if k < 0 || k as usize > xs.dim(axis)? {
+                bail!("invalid k {k} for TopK on {:?}, axis {axis}", xs.shape())
+            }
+            // The values are always sorted, the order is unspecified when sorted is 0.
+            let last_dim = xs.rank() - 1;
+            let xs = xs.transpose(axis, last_dim)?.contiguous()?;
+            let indices = xs
+                .arg_sort_last_dim(!largest)?
+                .narrow(last_dim, 0, k as usize)?
+                .contiguous()?;
+            let ys = xs.gather(&indices, last_dim)?.transpose(axis, last_dim)?;
+            let indices = indices.to_dtype(DType::I64)?.transpose(axis, last_dim)?;
+            values.insert(node.output[0].clone(), ys.into());
+            values.insert(node.output[1].clone(), indices.into());
+        }
+        "NonZero" => {
+            // https://onnx.ai/onnx/operators/onnx__NonZero.html
+            let xs = get(&node.input[0])?;
+            let dims = xs.dims().to_vec();
+            let mask = xs.ne(0.)?.flatten_all()?.to_vec1::<u8>()?;
+            let positions = mask
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, &m)| (m != 0).then_some(idx))
+                .collect::<Vec<_>>();
+            // The output has one row per dimension, with the coordinates of the non-zero values.
+            let mut indices = vec![0i64; dims.len() * positions.len()];
+            for (i, &position) in positions.iter().enumerate() {
+                let mut position = position;
+                for (d, &dim) in dims.iter().enumerate().rev() {
+                    indices[d * positions.len() + i] = (position % dim) as i64;
+                    position /= dim;
+                }
+            }
+            let indices = Tensor::from_vec(indices, (dims.len(), positions.len()), xs.device())?;
+            values.insert(node.output[0].clone(), indices.into());
+        }
+        "Mod" => {
+            // https://onnx.ai/onnx/operators/onnx__Mod.html
+            let a = get(&node.input[0])?;
+            let b = get(&node.input[1])?;
+            let fmod = get_attr_opt::<i64>(node, "fmod")?.copied().unwrap_or(0);
+            let dtype = a.dtype();
+            let a = a.to_dtype(DType::F64)?;
+            let b = b.to_dtype(DType::F64)?;
+            let quotient = a.broadcast_div(&b)?;
+            // With fmod the quotient is truncated and the result has the sign of the dividend,
+            // otherwise it is floored and the result has the sign of the divisor.
+            let quotient = if fmod == 1 {
+                (quotient.abs()?.floor()? * quotient.sign()?)?
+            } else {
+                quotient.floor()?
+            };
+            let ys = a.broadcast_sub(&quotient.broadcast_mul(&b)?)?;
+            values.insert(node.output[0].clone(), ys.to_dtype(dtype)?.into());
+        }
+        "Softplus" => {
+            // https://onnx.ai/onnx/operators/onnx__Softplus.html
+            let xs = get(&node.input[0])?;
+            values.insert(node.output[0].clone(), softplus(xs)?.into());
+        }
+        "Mish" => {
+            // https://onnx.ai/onnx/operators/onnx__Mish.html
+            let xs = get(&node.input[0])?;
+            let ys = (xs * softplus(xs)?.tanh()?)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "GatherND" => {
+            // https://onnx.ai/onnx/operators/onnx__GatherND.html
+            let data = get(&node.input[0])?;
+            let indices = get(&node.input[1])?;
+            let batch_dims = get_attr_opt::<i64>(node, "batch_dims")?
+                .copied()
+                .unwrap_or(0) as usize;
+            let (data_dims, indices_dims) = (data.dims(), indices.dims());
+            let m = match indices_dims.last() {
+                Some(&m) if indices_dims.len() > batch_dims && batch_dims + m <= data.rank() => m,
+                _ => bail!(
+                    "unexpected indices shape {:?} for GatherND on {:?}, batch_dims {batch_dims}",
+                    indices.shape(),
+                    data.shape()
+                ),
+            };
+            let n_batches = data_dims[..batch_dims].iter().product::<usize>();
+            let indexed_dims = &data_dims[batch_dims..batch_dims + m];
+            let slice_dims = &data_dims[batch_dims + m..];
+            let n_slices = indices_dims[batch_dims..indices_dims.len() - 1]
+                .iter()
+                .product::<usize>();
+            // The position of each gathered slice when viewing data as a (-1, slice_size) matrix.
+            let indices_vec = indices
+                .to_dtype(DType::I64)?
+                .flatten_all()?
+                .to_vec1::<i64>()?;
+            let mut positions = Vec::with_capacity(n_batches * n_slices);
+            for batch_idx in 0..n_batches {
+                for slice_idx in 0..n_slices {
+                    let offset = (batch_idx * n_slices + slice_idx) * m;
+                    let mut position = batch_idx;
+                    for (&index, &dim) in indices_vec[offset..offset + m].iter().zip(indexed_dims) {
+                        let index = if index < 0 { index + dim as i64 } else { index };
+                        if index < 0 || index >= dim as i64 {
                             bail!(
-                                "Index {} out of bounds for dimension {} with size {}",
-                                idx,
-                                dim,
-                                dim_size
-                            );
+                                "index {index} out of bounds for GatherND on {:?}",
+                                data.shape()
+                            )
                         }
-
-                        flat_idx += (norm_idx as usize) * strides[dim];
+                        position = position * dim + index as usize
                     }
-
-                    // Extract current update
-                    let update_slice = if update_element_shape.is_empty() {
-                        flat_updates.narrow(0, i, 1)?.squeeze(0)?
-                    } else {
-                        flat_updates.narrow(0, i, 1)?
+                    positions.push(position as i64)
+                }
+            }
+            let positions = Tensor::from_vec(positions, n_batches * n_slices, data.device())?;
+            let slice_size = slice_dims.iter().product::<usize>();
+            let mut out_dims = indices_dims[..indices_dims.len() - 1].to_vec();
+            out_dims.extend_from_slice(slice_dims);
+            let ys = data
+                .reshape(((), slice_size))?
+                .index_select(&positions, 0)?
+                .reshape(out_dims)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "ScatterElements" => {
+            // https://onnx.ai/onnx/operators/onnx__ScatterElements.html
+            let data = get(&node.input[0])?;
+            let indices = get(&node.input[1])?;
+            let updates = get(&node.input[2])?;
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+            let axis = data.normalize_axis(axis)?;
+            let reduction = get_attr_opt::<str>(node, "reduction")?.unwrap_or("none");
+            let dim = data.dim(axis)? as i64;
+            let indices_vec = indices
+                .to_dtype(DType::I64)?
+                .flatten_all()?
+                .to_vec1::<i64>()?
+                .into_iter()
+                .map(|index| {
+                    let index = if index < 0 { index + dim } else { index };
+                    if index < 0 || index >= dim {
+                        bail!(
+                            "index {index} out of bounds for ScatterElements on {:?}",
+                            data.shape()
+                        )
+                    }
+                    Ok(index)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let ys = match reduction {
+                "none" => {
+                    let indices = Tensor::from_vec(indices_vec, indices.shape(), data.device())?;
+                    data.scatter(&indices, updates, axis)?
+                }
+                "add" => {
+                    let indices = Tensor::from_vec(indices_vec, indices.shape(), data.device())?;
+                    data.scatter_add(&indices, updates, axis)?
+                }
+                "mul" | "max" | "min" => scatter_elements_reduce(
+                    data,
+                    indices.dims(),
+                    &indices_vec,
+                    updates,
+                    axis,
+                    reduction,
+                )?,
+                reduction => bail!("unsupported reduction {reduction} for ScatterElements"),
+            };
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "DepthToSpace" => {
+            // https://onnx.ai/onnx/operators/onnx__DepthToSpace.html
+            let xs = get(&node.input[0])?;
+            let b = *get_attr::<i64>(node, "blocksize")? as usize;
+            let mode = get_attr_opt::<str>(node, "mode")?.unwrap_or("DCR");
+            let (b_size, c, h, w) = xs.dims4()?;
+            if b == 0 || !c.is_multiple_of(b * b) {
+                bail!("{c} channels cannot be moved to blocks of size {b}")
+            }
+            let c_out = c / (b * b);
+            let ys = match mode {
+                "DCR" => xs
+                    .reshape((b_size, b, b, c_out, h, w))?
+                    .permute((0, 3, 4, 1, 5, 2))?,
+                "CRD" => xs
+                    .reshape((b_size, c_out, b, b, h, w))?
+                    .permute((0, 1, 4, 2, 5, 3))?,
+                mode => bail!("unsupported mode {mode} for DepthToSpace"),
+            };
+            let ys = ys.reshape((b_size, c_out, h * b, w * b))?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "SpaceToDepth" => {
+            // https://onnx.ai/onnx/operators/onnx__SpaceToDepth.html
+            let xs = get(&node.input[0])?;
+            let b = *get_attr::<i64>(node, "blocksize")? as usize;
+            let (b_size, c, h, w) = xs.dims4()?;
+            if b == 0 || !h.is_multiple_of(b) || !w.is_multiple_of(b) {
+                bail!("{h}x{w} cannot be split in blocks of size {b}")
+            }
+            let ys = xs
+                .reshape((b_size, c, h / b, b, w / b, b))?
+                .permute((0, 3, 5, 1, 2, 4))?
+                .reshape((b_size, c * b * b, h / b, w / b))?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "RMSNormalization" | "SimplifiedLayerNormalization" => {
+            // https://onnx.ai/onnx/operators/onnx__RMSNormalization.html
+            // SimplifiedLayerNormalization is the onnxruntime version of the op, it can also
+            // output the inverse of the root mean square.
+            if node.op_type == "RMSNormalization" {
+                check_opset(23)?
+            }
+            let eps = get_attr_opt::<f32>(node, "epsilon")?
+                .copied()
+                .unwrap_or(1e-5);
+            let xs = get(&node.input[0])?;
+            let scale = get(&node.input[1])?;
+            let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(-1);
+            let axis = xs.normalize_axis(axis)?;
+            let with_stats = node.output.iter().skip(1).any(|o| !o.is_empty());
+            if axis + 1 == xs.rank() && scale.rank() == 1 && !with_stats {
+                let ys = candle_nn::ops::rms_norm(&xs.contiguous()?, scale, eps)?;
+                values.insert(node.output[0].clone(), ys.into());
+            } else {
+                let n_rows = xs.dims()[..axis].iter().product();
+                let (ys, inv_rms) = rms_normalize_rows(xs, n_rows, eps as f64)?;
+                let ys = ys.broadcast_mul(scale)?;
+                let stats_shape = xs
+                    .dims()
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, &d)| if idx < axis { d } else { 1 })
+                    .collect::<Vec<_>>();
+                let inv_rms = inv_rms.reshape(stats_shape)?;
+                values.insert(node.output[0].clone(), ys.into());
+                if let Some(output) = node.output.get(1).filter(|o| !o.is_empty()) {
+                    values.insert(output.clone(), inv_rms.into());
+                }
+            }
+        }
+        "RotaryEmbedding" => {
+            // https://onnx.ai/onnx/operators/onnx__RotaryEmbedding.html
+            // The com.microsoft version of the op takes the position ids as second input, these
+            // can also be a single offset for the whole sequence.
+            let xs = get(&node.input[0])?;
+            let seq_len = match xs.rank() {
+                3 => xs.dim(1)?,
+                _ => xs.dim(2)?,
+            };
+            let (cos_cache, sin_cache, position_ids) = if node.domain == "com.microsoft" {
+                let position_ids = get(&node.input[1])?;
+                let position_ids = if position_ids.elem_count() == 1 {
+                    let offset = to_vec0_flexible::<i64>(&position_ids.to_dtype(DType::I64)?)?;
+                    Tensor::arange(offset, offset + seq_len as i64, xs.device())?
+                        .unsqueeze(0)?
+                        .broadcast_as((xs.dim(0)?, seq_len))?
+                } else {
+                    position_ids.clone()
+                };
+                (
+                    get(&node.input[2])?,
+                    get(&node.input[3])?,
+                    Some(position_ids),
+                )
+            } else {
+                check_opset(23)?;
+                let position_ids = get_opt(3).transpose()?.cloned();
+                (get(&node.input[1])?, get(&node.input[2])?, position_ids)
+            };
+            let interleaved = get_attr_opt::<i64>(node, "interleaved")?
+                .copied()
+                .unwrap_or(0)
+                == 1;
+            let rotary_dim = get_attr_opt::<i64>(node, "rotary_embedding_dim")?
+                .copied()
+                .unwrap_or(0) as usize;
+            let rotary = |xs: &Tensor| {
+                rotary_embedding(
+                    xs,
+                    cos_cache,
+                    sin_cache,
+                    position_ids.as_ref(),
+                    interleaved,
+                    rotary_dim,
+                )
+            };
+            let ys = match xs.rank() {
+                4 => rotary(xs)?,
+                3 => {
+                    // Without the num_heads attribute, the head size is deduced from the caches.
+                    let num_heads = match get_attr_opt::<i64>(node, "num_heads")? {
+                        Some(&num_heads) if num_heads > 0 => num_heads as usize,
+                        _ => xs.dim(2)? / (2 * cos_cache.dim(cos_cache.rank() - 1)?),
                     };
+                    merge_heads(&rotary(&split_heads(xs, num_heads)?)?)?
+                }
+                rank => bail!("unsupported rank {rank} for RotaryEmbedding"),
+            };
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        // The com.microsoft Attention op takes packed qkv weights and a bias, it is not the same
+        // op as the ai.onnx one.
+        "Attention" if node.domain == "com.microsoft" => {
+            bail!("unsupported com.microsoft::Attention for op {}", node.name)
+        }
+        "Attention" => {
+            // https://onnx.ai/onnx/operators/onnx__Attention.html
+            check_opset(23)?;
+            let q = get(&node.input[0])?;
+            let k = get(&node.input[1])?;
+            let v = get(&node.input[2])?;
+            let attn_mask = get_opt(3).transpose()?;
+            let past_key = get_opt(4).transpose()?;
+            let past_value = get_opt(5).transpose()?;
+            let is_causal = get_attr_opt::<i64>(node, "is_causal")?
+                .copied()
+                .unwrap_or(0)
+                == 1;
+            let softcap = get_attr_opt::<f32>(node, "softcap")?.copied().unwrap_or(0.);
+            let qk_output_mode = get_attr_opt::<i64>(node, "qk_matmul_output_mode")?
+                .copied()
+                .unwrap_or(0);
+            // The 3d inputs have the heads merged in the last dimension.
+            let is_3d = q.rank() == 3;
+            let to_4d = |xs: &Tensor, num_heads: &str| -> Result<Tensor> {
+                match xs.rank() {
+                    3 => split_heads(xs, *get_attr::<i64>(node, num_heads)? as usize),
+                    4 => Ok(xs.clone()),
+                    rank => bail!("unsupported rank {rank} for Attention"),
+                }
+            };
+            let q = to_4d(q, "q_num_heads")?;
+            let k = to_4d(k, "kv_num_heads")?;
+            let v = to_4d(v, "kv_num_heads")?;
+            let k = match past_key {
+                None => k,
+                Some(past_key) => Tensor::cat(&[past_key, &k], 2)?,
+            };
+            let v = match past_value {
+                None => v,
+                Some(past_value) => Tensor::cat(&[past_value, &v], 2)?,
+            };
+            let scale = match get_attr_opt::<f32>(node, "scale")? {
+                Some(&scale) => scale as f64,
+                None => 1. / (q.dim(3)? as f64).sqrt(),
+            };
+            let (ys, qk) = attention(&q, &k, &v, attn_mask, scale, softcap as f64, is_causal)?;
+            let ys = if is_3d { merge_heads(&ys)? } else { ys };
+            let qk = match qk_output_mode {
+                0..=3 => qk[qk_output_mode as usize].clone(),
+                mode => bail!("unsupported qk_matmul_output_mode {mode} for Attention"),
+            };
+            for (output, value) in node.output.iter().zip([ys, k, v, qk]) {
+                if !output.is_empty() {
+                    values.insert(output.clone(), value.into());
+                }
+            }
+        }
+        // The com.microsoft contrib ops, as documented in
+        // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md
+        "FastGelu" => {
+            let xs = get(&node.input[0])?;
+            let xs = match get_opt(1) {
+                None => xs.clone(),
+                Some(bias) => xs.broadcast_add(bias?)?,
+            };
+            values.insert(node.output[0].clone(), xs.gelu()?.into());
+        }
+        "BiasGelu" => {
+            let xs = get(&node.input[0])?;
+            let bias = get(&node.input[1])?;
+            let ys = xs.broadcast_add(bias)?.gelu_erf()?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "QuickGelu" => {
+            let xs = get(&node.input[0])?;
+            let alpha = get_attr_opt::<f32>(node, "alpha")?
+                .copied()
+                .unwrap_or(1.702);
+            let ys = (xs * candle_nn::ops::sigmoid(&(xs * alpha as f64)?)?)?;
+            values.insert(node.output[0].clone(), ys.into());
+        }
+        "SkipLayerNormalization" | "SkipSimplifiedLayerNormalization" => {
+            // The outputs are the normalized values, the mean, the inverse standard deviation and
+            // the sum of the input, skip and bias. The simplified version has no beta input and
+            // does not compute the mean.
+            let simplified = node.op_type == "SkipSimplifiedLayerNormalization";
+            let eps = get_attr_opt::<f32>(node, "epsilon")?
+                .copied()
+                .unwrap_or(1e-12);
+            let xs = get(&node.input[0])?;
+            let skip = get(&node.input[1])?;
+            let gamma = get(&node.input[2])?;
+            let (beta, bias) = if simplified {
+                (None, get_opt(3).transpose()?)
+            } else {
+                (get_opt(3).transpose()?, get_opt(4).transpose()?)
+            };
+            let xs = xs.broadcast_add(skip)?;
+            let xs = match bias {
+                None => xs,
+                Some(bias) => xs.broadcast_add(bias)?,
+            };
+            let n_rows = xs.dims()[..xs.rank() - 1].iter().product();
+            let (ys, mean, inv_std_dev) = if simplified {
+                let (ys, inv_rms) = rms_normalize_rows(&xs, n_rows, eps as f64)?;
+                (ys, None, inv_rms)
+            } else {
+                let (ys, mean, inv_std_dev) = normalize_rows(&xs, n_rows, eps as f64)?;
+                (ys, Some(mean), inv_std_dev)
+            };
+            let ys = ys.broadcast_mul(gamma)?;
+            let ys = match beta {
+                None => ys,
+                Some(beta) => ys.broadcast_add(beta)?,
+            };
+            let mut stats_shape = xs.dims().to_vec();
+            stats_shape[xs.rank() - 1] = 1;
+            let mean = mean
+                .map(|mean| mean.reshape(stats_shape.as_slice()))
+                .transpose()?;
+            let inv_std_dev = inv_std_dev.reshape(stats_shape.as_slice())?;
+            let outputs = [Some(ys), mean, Some(inv_std_dev), Some(xs)];
+            for (output, value) in node.output.iter().zip(outputs) {
+                if let Some(value) = value.filter(|_| !output.is_empty()) {
+                    values.insert(output.clone(), value.into());
+                }
+            }
+        }
+        "MultiHeadAttention" => {
+            let q = get(&node.input[0])?;
+            let k = get(&node.input[1])?;
+            let v = get(&node.input[2])?;
+            let bias = get_opt(3).transpose()?;
+            let attention_bias = get_opt(5).transpose()?;
+            if [4, 6, 7].iter().any(|&i| get_opt(i).is_some()) {
+                bail!("key_padding_mask and past inputs are not supported for MultiHeadAttention")
+            }
+            let num_heads = *get_attr::<i64>(node, "num_heads")? as usize;
+            let unidirectional = get_attr_opt::<i64>(node, "unidirectional")?
+                .copied()
+                .unwrap_or(0)
+                == 1;
+            // The bias is the concatenation of the q, k and v biases.
+            let (q, k, v) = match bias {
+                None => (q.clone(), k.clone(), v.clone()),
+                Some(bias) => {
+                    let (d, d_v) = (q.dim(2)?, v.dim(2)?);
+                    (
+                        q.broadcast_add(&bias.narrow(0, 0, d)?)?,
+                        k.broadcast_add(&bias.narrow(0, d, d)?)?,
+                        v.broadcast_add(&bias.narrow(0, 2 * d, d_v)?)?,
+                    )
+                }
+            };
+            let q = split_heads(&q, num_heads)?;
+            let k = split_heads(&k, num_heads)?;
+            let v = split_heads(&v, num_heads)?;
+            let scale = match get_attr_opt::<f32>(node, "scale")? {
+                Some(&scale) if scale != 0. => scale as f64,
+                _ => 1. / (q.dim(3)? as f64).sqrt(),
+            };
+            let (ys, _) = attention(&q, &k, &v, attention_bias, scale, 0., unidirectional)?;
+            for (output, value) in node.output.iter().zip([merge_heads(&ys)?, k, v]) {
+                if !output.is_empty() {
+                    values.insert(output.clone(), value.into());
+                }
+            }
+        }
+        op_type => bail!("unsupported op_type {op_type} for op {node:?}"),
+    }
+    Ok(())
+}
 
-                    match reduction {
-                        "add" => {
-                            if update_element_shape.is_empty() {
-                                let existing = flat_output.narrow(0, flat_idx, 1)?;
-                                let new_value = existing.add(&update_slice.unsqueeze(0)?)?;
-                                flat_output = flat_output.slice_scatter(&new_value, 0, flat_idx)?;
-                            } else {
-                                let slice_size = update_element_shape.iter().product::<usize>();
-                                let existing = flat_output.narrow(0, flat_idx, slice_size)?;
-                                let new_value = existing.add(&update_slice)?;
-                                flat_output = flat_output.slice_scatter(&new_value, 0, flat_idx)?;
-                            }
-                        }
-                        "none" | _ => {
-                            if update_element_shape.is_empty() {
-                                flat_output = flat_output.slice_scatter(
-                                    &update_slice.unsqueeze(0)?,
-                                    0,
-                                    flat_idx,
-                                )?;
-                            } else {
-                                flat_output =
-                                    flat_output.slice_scatter(&update_slice, 0, flat_idx)?;
-                            }
-                        }
+// The convolution part of the Conv node, without the bias.
+/// The attributes of the `Conv`, `ConvInteger` and `QLinearConv` ops.
+#[derive(Debug, Clone)]
+pub(crate) struct ConvAttrs {
+    dilations: Option<Vec<i64>>,
+    groups: i64,
+    pads: Option<Vec<i64>>,
+    strides: Option<Vec<i64>>,
+}
+
+impl ConvAttrs {
+    fn new(node: &onnx::NodeProto) -> Result<Self> {
+        let to_vec = |name: &str| -> Result<Option<Vec<i64>>> {
+            Ok(get_attr_opt::<[i64]>(node, name)?.map(|v| v.to_vec()))
+        };
+        let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
+        match auto_pad {
+            None | Some("NOTSET") => (),
+            Some(s) => bail!("unsupported auto_pad {s}"),
+        };
+        Ok(Self {
+            dilations: to_vec("dilations")?,
+            groups: get_attr_opt::<i64>(node, "group")?.copied().unwrap_or(1),
+            pads: to_vec("pads")?,
+            strides: to_vec("strides")?,
+        })
+    }
+}
+
+fn conv(node: &onnx::NodeProto, attrs: &ConvAttrs, xs: &Tensor, ws: &Tensor) -> Result<Tensor> {
+    let dilations = attrs.dilations.as_deref();
+    let groups = attrs.groups;
+    let pads = attrs.pads.as_deref();
+    let strides = attrs.strides.as_deref();
+    match ws.rank() {
+        3 => {
+            let (pads, xs) = match pads {
+                None => (0, xs.clone()),
+                Some([p]) => (*p as usize, xs.clone()),
+                Some([p1, p2]) => {
+                    if p1 != p2 {
+                        (0usize, xs.pad_with_zeros(2, *p1 as usize, *p2 as usize)?)
+                    } else {
+                        (*p1 as usize, xs.clone())
                     }
                 }
+                Some(pads) => {
+                    bail!("more pads than expected in conv1d {pads:?} {}", node.name)
+                }
+            };
+            let strides = match strides {
+                None => 1,
+                Some([p]) => *p as usize,
+                Some(s) => {
+                    bail!("more strides than expected in conv1d {s:?} {}", node.name)
+                }
+            };
+            let dilations = match dilations {
+                None => 1,
+                Some([p]) => *p as usize,
+                Some(s) => {
+                    bail!("more dilations than expected in conv1d {s:?} {}", node.name)
+                }
+            };
+            xs.conv1d(ws, pads, strides, dilations, groups as usize)
+        }
+        4 => {
+            let (pads, xs) = match pads {
+                None => (0, xs.clone()),
+                Some([p]) => (*p as usize, xs.clone()),
+                Some(&[p1, p2, p3, p4]) => {
+                    let p1 = p1 as usize;
+                    let p2 = p2 as usize;
+                    let p3 = p3 as usize;
+                    let p4 = p4 as usize;
+                    if p1 != p2 || p1 != p3 || p1 != p4 {
+                        (0, xs.pad_with_zeros(2, p1, p3)?.pad_with_zeros(3, p2, p4)?)
+                    } else {
+                        (p1, xs.clone())
+                    }
+                }
+                Some(pads) => {
+                    bail!("more pads than expected in conv2d {pads:?} {}", node.name)
+                }
+            };
+            let strides = match strides {
+                None => 1,
+                Some([p]) => *p as usize,
+                Some([p1, p2]) => {
+                    if p1 != p2 {
+                        bail!(
+                            "strides have to be the same on both axis {pads:?} {}",
+                            node.name
+                        )
+                    }
+                    *p1 as usize
+                }
+                Some(s) => {
+                    bail!("more strides than expected in conv2d {s:?} {}", node.name)
+                }
+            };
+            let dilations = match dilations {
+                None => 1,
+                Some([p]) => *p as usize,
+                Some([p1, p2]) => {
+                    if p1 != p2 {
+                        bail!(
+                            "dilations have to be the same on both axis {pads:?} {}",
+                            node.name
+                        )
+                    }
+                    *p1 as usize
+                }
+                Some(s) => {
+                    bail!("more dilations than expected in conv2d {s:?} {}", node.name)
+                }
+            };
+            xs.conv2d(ws, pads, strides, dilations, groups as usize)
+        }
+        rank => bail!(
+            "unsupported rank for weight matrix {rank} in conv {}",
+            node.name
+        ),
+    }
+}
 
-                // Reshape flat output back to original shape
-                output = flat_output.reshape(data_shape.to_vec())?;
+// Stacks the per-iteration outputs of Loop and Scan along a new axis, `axis` can be negative
+// and is relative to the output rank.
+fn stack_scan_output(ys: Vec<Tensor>, axis: i64, node: &onnx::NodeProto) -> Result<Tensor> {
+    if ys.is_empty() {
+        bail!(
+            "no iteration in {}, cannot infer the output shape",
+            node.name
+        )
+    }
+    let rank = ys[0].rank() as i64 + 1;
+    let stack_axis = if axis < 0 { axis + rank } else { axis };
+    if !(0..rank).contains(&stack_axis) {
+        bail!("axis {axis} out of range for rank {rank} in {}", node.name)
+    }
+    Tensor::stack(&ys, stack_axis as usize)
+}
+
+// Converts a possibly negative position in a sequence of length `len`, `len` is only valid
+// when `allow_end` is set, e.g. when inserting a tensor at the end of the sequence.
+fn sequence_position(position: i64, len: usize, allow_end: bool) -> Result<usize> {
+    let signed_len = len as i64;
+    let pos = if position < 0 {
+        position + signed_len
+    } else {
+        position
+    };
+    let max_pos = if allow_end {
+        signed_len
+    } else {
+        signed_len - 1
+    };
+    if pos < 0 || pos > max_pos {
+        bail!("position {position} out of range for a sequence of length {len}")
+    }
+    Ok(pos as usize)
+}
+
+// Reshapes the scale or zero point of the quantization ops so that it broadcasts to `xs`. The
+// parameter is a scalar for per-tensor quantization, a 1d tensor along `axis` for per-axis
+// quantization, or has the same rank as `xs` for blocked quantization.
+fn quantization_param(p: &Tensor, xs: &Tensor, axis: i64, block_size: usize) -> Result<Tensor> {
+    if block_size == 0 && p.rank() <= 1 && p.elem_count() == 1 {
+        return p.reshape(());
+    }
+    let axis = xs.normalize_axis(axis)?;
+    if block_size > 0 && p.rank() == xs.rank() {
+        // Each value applies to block_size consecutive elements along the axis.
+        let mut dims = p.dims().to_vec();
+        dims.insert(axis + 1, block_size);
+        p.unsqueeze(axis + 1)?
+            .broadcast_as(dims)?
+            .flatten(axis, axis + 1)?
+            .narrow(axis, 0, xs.dim(axis)?)
+    } else if block_size == 0 && p.rank() == 1 {
+        let mut dims = vec![1; xs.rank()];
+        dims[axis] = p.elem_count();
+        p.reshape(dims)
+    } else {
+        bail!(
+            "unexpected quantization parameter shape {:?} for {:?}, block size {block_size}",
+            p.shape(),
+            xs.shape()
+        )
+    }
+}
+
+// Rounds to the nearest integer with ties to even as used by the onnx quantization ops,
+// `Tensor::round` rounds the ties away from zero.
+fn round_half_to_even(xs: &Tensor) -> Result<Tensor> {
+    let rounded = xs.round()?;
+    let is_tie = (xs - &rounded)?.abs()?.eq(0.5)?;
+    let is_odd = (&rounded - ((&rounded * 0.5)?.floor()? * 2.)?)?.ne(0.)?;
+    let even = (&rounded - xs.sign()?)?;
+    (is_tie * is_odd)?.where_cond(&even, &rounded)
+}
+
+// Computes `saturate(round(xs / scale) + zero_point)`. The result uses the dtype of the zero
+// point: u8 for uint8 and i16 for int8 as candle has no i8 dtype.
+fn quantize_linear(
+    xs: &Tensor,
+    scale: &Tensor,
+    zero_point: &Tensor,
+    axis: i64,
+    block_size: usize,
+) -> Result<Tensor> {
+    let (min, max) = match zero_point.dtype() {
+        DType::U8 => (0., 255.),
+        DType::I16 => (-128., 127.),
+        dtype => bail!("unsupported dtype {dtype:?} for quantization"),
+    };
+    let scale = quantization_param(scale, xs, axis, block_size)?.to_dtype(DType::F32)?;
+    let zero_point = quantization_param(zero_point, xs, axis, block_size)?;
+    let ys = xs.to_dtype(DType::F32)?.broadcast_div(&scale)?;
+    round_half_to_even(&ys)?
+        .broadcast_add(&zero_point.to_dtype(DType::F32)?)?
+        .clamp(min, max)?
+        .to_dtype(zero_point.dtype())
+}
+
+// Computes `(xs - zero_point) * scale` using the dtype of the scale.
+fn dequantize_linear(
+    xs: &Tensor,
+    scale: &Tensor,
+    zero_point: Option<&Tensor>,
+    axis: i64,
+    block_size: usize,
+) -> Result<Tensor> {
+    let dtype = scale.dtype();
+    let xs = xs.to_dtype(dtype)?;
+    let xs = match zero_point {
+        None => xs,
+        Some(zero_point) => {
+            let zero_point = quantization_param(zero_point, &xs, axis, block_size)?;
+            xs.broadcast_sub(&zero_point.to_dtype(dtype)?)?
+        }
+    };
+    xs.broadcast_mul(&quantization_param(scale, &xs, axis, block_size)?)
+}
+
+// The sorted axes reduced by the reduce ops, a missing or empty list of axes reduces all the
+// dimensions unless noop_with_empty_axes is set.
+fn reduce_axes(node: &onnx::NodeProto, xs: &Tensor, axes: Option<Vec<i64>>) -> Result<Vec<usize>> {
+    let axes = axes.unwrap_or_default();
+    if axes.is_empty() {
+        let noop_with_empty_axes = get_attr_opt::<i64>(node, "noop_with_empty_axes")?
+            .copied()
+            .unwrap_or(0);
+        return Ok(if noop_with_empty_axes == 1 {
+            vec![]
+        } else {
+            (0..xs.rank()).collect()
+        });
+    }
+    let mut axes = axes
+        .iter()
+        .map(|&axis| xs.normalize_axis(axis))
+        .collect::<Result<Vec<_>>>()?;
+    axes.sort();
+    axes.dedup();
+    Ok(axes)
+}
 
-                values.insert(node.output[0].clone(), output);
+// The labels of the dimensions covered by an ellipsis, these cannot collide with the letters.
+const EINSUM_ELLIPSIS: u32 = 0x110000;
+
+// Evaluates an einsum equation by contracting the operands pairwise with batched matmuls, the
+// dimensions of size 1 are broadcasted. A label can only appear once per operand, i.e. the
+// diagonals are not supported.
+fn einsum(equation: &str, operands: &[&Tensor]) -> Result<Tensor> {
+    let equation = equation.replace(' ', "");
+    let (inputs, output) = match equation.split_once("->") {
+        Some((inputs, output)) => (inputs, Some(output)),
+        None => (equation.as_str(), None),
+    };
+    let inputs = inputs.split(',').collect::<Vec<_>>();
+    if inputs.len() != operands.len() {
+        bail!(
+            "einsum equation {equation} expects {} operands, got {}",
+            inputs.len(),
+            operands.len()
+        )
+    }
+    let mut n_ellipsis_dims = Vec::with_capacity(inputs.len());
+    for (term, xs) in inputs.iter().zip(operands.iter()) {
+        let n_letters = term.replace("...", "").chars().count();
+        match xs.rank().checked_sub(n_letters) {
+            Some(n) if n == 0 || term.contains("...") => n_ellipsis_dims.push(n),
+            _ => bail!(
+                "einsum term {term} does not match the shape {:?}",
+                xs.shape()
+            ),
+        }
+    }
+    let max_ellipsis_dims = n_ellipsis_dims.iter().copied().max().unwrap_or(0);
+    // The ellipsis dimensions are aligned on the right as for broadcasting.
+    let parse = |term: &str, n_ellipsis_dims: usize| -> Result<Vec<u32>> {
+        let (head, tail) = term.split_once("...").unwrap_or((term, ""));
+        let mut labels = vec![];
+        for c in head.chars() {
+            if !c.is_ascii_alphabetic() {
+                bail!("unexpected character {c} in einsum equation {equation}")
             }
-            op_type => bail!("unsupported op_type {op_type} for op {node:?}"),
+            labels.push(c as u32)
         }
+        if term.contains("...") {
+            let ellipsis = max_ellipsis_dims - n_ellipsis_dims..max_ellipsis_dims;
+            labels.extend(ellipsis.map(|i| EINSUM_ELLIPSIS + i as u32))
+        }
+        for c in tail.chars() {
+            if !c.is_ascii_alphabetic() {
+                bail!("unexpected character {c} in einsum equation {equation}")
+            }
+            labels.push(c as u32)
+        }
+        if labels.iter().collect::<HashSet<_>>().len() != labels.len() {
+            bail!("repeated labels are not supported in einsum equation {equation}")
+        }
+        Ok(labels)
+    };
+    let labels = inputs
+        .iter()
+        .zip(n_ellipsis_dims.iter())
+        .map(|(term, &n)| parse(term, n))
+        .collect::<Result<Vec<_>>>()?;
+    let output = match output {
+        Some(output) => parse(output, max_ellipsis_dims)?,
+        None => {
+            // The implicit output has the ellipsis dimensions followed by the labels that
+            // appear only once, in alphabetical order.
+            let mut counts = HashMap::new();
+            for &label in labels.iter().flatten() {
+                *counts.entry(label).or_insert(0) += 1
+            }
+            let mut letters = counts
+                .into_iter()
+                .filter(|&(label, count)| count == 1 && label < EINSUM_ELLIPSIS)
+                .map(|(label, _)| label)
+                .collect::<Vec<_>>();
+            letters.sort();
+            (0..max_ellipsis_dims as u32)
+                .map(|i| EINSUM_ELLIPSIS + i)
+                .chain(letters)
+                .collect()
+        }
+    };
+
+    // Sums over the dimensions which labels are not used anymore.
+    let sum_out =
+        |xs: Tensor, labels: Vec<u32>, used: &HashSet<u32>| -> Result<(Tensor, Vec<u32>)> {
+            let dims = (0..labels.len())
+                .filter(|&i| !used.contains(&labels[i]))
+                .collect::<Vec<_>>();
+            let xs = if dims.is_empty() { xs } else { xs.sum(dims)? };
+            let labels = labels.into_iter().filter(|l| used.contains(l)).collect();
+            Ok((xs, labels))
+        };
+    let mut acc: Option<(Tensor, Vec<u32>)> = None;
+    for (idx, (&xs, xs_labels)) in operands.iter().zip(labels.iter()).enumerate() {
+        let used = labels[idx + 1..]
+            .iter()
+            .flatten()
+            .chain(output.iter())
+            .copied()
+            .collect::<HashSet<_>>();
+        let (xs, xs_labels) = (xs.clone(), xs_labels.clone());
+        acc = Some(match acc {
+            None => sum_out(xs, xs_labels, &used)?,
+            Some((acc, acc_labels)) => {
+                let used_by = |labels: &[u32]| -> HashSet<u32> {
+                    used.iter().chain(labels).copied().collect()
+                };
+                let (a, a_labels) = sum_out(acc, acc_labels, &used_by(&xs_labels))?;
+                let (b, b_labels) = sum_out(xs, xs_labels, &used_by(&a_labels))?;
+                let size = |label: u32| {
+                    let size = |xs: &Tensor, labels: &[u32]| {
+                        let idx = labels.iter().position(|&l| l == label)?;
+                        Some(xs.dims()[idx])
+                    };
+                    size(&a, &a_labels).max(size(&b, &b_labels)).unwrap_or(1)
+                };
+                let (mut batch, mut contracted, mut a_only) = (vec![], vec![], vec![]);
+                for &label in a_labels.iter() {
+                    if !b_labels.contains(&label) {
+                        a_only.push(label)
+                    } else if used.contains(&label) {
+                        batch.push(label)
+                    } else {
+                        contracted.push(label)
+                    }
+                }
+                let b_only = b_labels
+                    .iter()
+                    .copied()
+                    .filter(|l| !a_labels.contains(l))
+                    .collect::<Vec<_>>();
+                // Arranges the operands as (batch, m, k) and (batch, k, n) matrixes.
+                let to_matrix =
+                    |xs: &Tensor, labels: &[u32], groups: [&[u32]; 3]| -> Result<Tensor> {
+                        let order = groups.concat();
+                        let perm = order
+                            .iter()
+                            .map(|l| labels.iter().position(|x| x == l).unwrap_or(0))
+                            .collect::<Vec<_>>();
+                        let dims = order.iter().map(|&l| size(l)).collect::<Vec<_>>();
+                        let [g0, g1, g2] =
+                            groups.map(|g| g.iter().map(|&l| size(l)).product::<usize>());
+                        xs.permute(perm)?.broadcast_as(dims)?.reshape((g0, g1, g2))
+                    };
+                let a = to_matrix(&a, &a_labels, [&batch, &a_only, &contracted])?;
+                let b = to_matrix(&b, &b_labels, [&batch, &contracted, &b_only])?;
+                let ys_labels = [batch, a_only, b_only].concat();
+                let dims = ys_labels.iter().map(|&l| size(l)).collect::<Vec<_>>();
+                (a.matmul(&b)?.reshape(dims)?, ys_labels)
+            }
+        });
     }
-    graph
-        .output
+    let (ys, labels) = match acc {
+        None => bail!("no operand for einsum equation {equation}"),
+        Some(acc) => acc,
+    };
+    let perm = output
         .iter()
-        .map(|output| match values.remove(&output.name) {
-            None => bail!("cannot find output {}", output.name),
-            Some(value) => Ok((output.name.clone(), value)),
+        .map(|label| match labels.iter().position(|l| l == label) {
+            None => bail!("einsum output labels do not appear in the inputs of {equation}"),
+            Some(i) => Ok(i),
         })
-        .collect()
+        .collect::<Result<Vec<_>>>()?;
+    if perm.len() != labels.len() {
+        bail!("unexpected einsum equation {equation}")
+    }
+    if perm.iter().enumerate().all(|(i, &p)| i == p) {
+        return Ok(ys);
+    }
+    ys.permute(perm)
+}
+
+// Computes `log(1 + exp(xs))` without overflowing for the large values.
+fn softplus(xs: &Tensor) -> Result<Tensor> {
+    xs.relu()? + (xs.abs()?.neg()?.exp()? + 1.)?.log()?
+}
+
+// The mul, max and min reductions of ScatterElements, these are evaluated on the host. The
+// indices are given as a flat vector and have already been normalized.
+fn scatter_elements_reduce(
+    data: &Tensor,
+    indices_dims: &[usize],
+    indices: &[i64],
+    updates: &Tensor,
+    axis: usize,
+    reduction: &str,
+) -> Result<Tensor> {
+    let dims = data.dims();
+    let mut strides = vec![1; dims.len()];
+    for i in (0..dims.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * dims[i + 1]
+    }
+    let mut ys = data.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+    let updates = updates
+        .to_dtype(DType::F64)?
+        .flatten_all()?
+        .to_vec1::<f64>()?;
+    for (idx, (&index, &update)) in indices.iter().zip(updates.iter()).enumerate() {
+        // The target has the coordinates of the update, except along the axis.
+        let mut rem = idx;
+        let mut offset = 0;
+        for d in (0..indices_dims.len()).rev() {
+            let coord = if d == axis {
+                index as usize
+            } else {
+                rem % indices_dims[d]
+            };
+            rem /= indices_dims[d];
+            offset += coord * strides[d]
+        }
+        let y = &mut ys[offset];
+        *y = match reduction {
+            "mul" => *y * update,
+            "max" => y.max(update),
+            _ => y.min(update),
+        }
+    }
+    Tensor::from_vec(ys, dims, data.device())?.to_dtype(data.dtype())
+}
+
+// Reshapes a (batch, seq_len, num_heads * head_size) tensor to
+// (batch, num_heads, seq_len, head_size).
+fn split_heads(xs: &Tensor, num_heads: usize) -> Result<Tensor> {
+    let (b_size, seq_len, hidden_size) = xs.dims3()?;
+    if num_heads == 0 || !hidden_size.is_multiple_of(num_heads) {
+        bail!("hidden size {hidden_size} cannot be split in {num_heads} heads")
+    }
+    xs.reshape((b_size, seq_len, num_heads, hidden_size / num_heads))?
+        .transpose(1, 2)
+}
+
+// The inverse of `split_heads`.
+fn merge_heads(xs: &Tensor) -> Result<Tensor> {
+    let (b_size, num_heads, seq_len, head_size) = xs.dims4()?;
+    xs.transpose(1, 2)?
+        .reshape((b_size, seq_len, num_heads * head_size))
+}
+
+// Rotates the first `rotary_dim` values of each head of `xs`, a (batch, num_heads, seq_len,
+// head_size) tensor, `rotary_dim` defaults to the head size when 0. The caches have shape
+// (max_position, rotary_dim / 2) when the position ids are set and
+// (batch, seq_len, rotary_dim / 2) otherwise.
+fn rotary_embedding(
+    xs: &Tensor,
+    cos_cache: &Tensor,
+    sin_cache: &Tensor,
+    position_ids: Option<&Tensor>,
+    interleaved: bool,
+    rotary_dim: usize,
+) -> Result<Tensor> {
+    let (b_size, num_heads, seq_len, head_size) = xs.dims4()?;
+    let rotary_dim = if rotary_dim == 0 {
+        head_size
+    } else {
+        rotary_dim
+    };
+    if rotary_dim > head_size || !rotary_dim.is_multiple_of(2) {
+        bail!("invalid rotary dim {rotary_dim} for head size {head_size}")
+    }
+    let half = rotary_dim / 2;
+    let cos_sin = |cache: &Tensor| -> Result<Tensor> {
+        match position_ids {
+            None => cache.narrow(2, 0, half)?.unsqueeze(1),
+            Some(position_ids) => cache
+                .narrow(1, 0, half)?
+                .index_select(&position_ids.flatten_all()?.to_dtype(DType::I64)?, 0)?
+                .reshape((b_size, 1, seq_len, half)),
+        }
+    };
+    let cos = cos_sin(cos_cache)?.to_dtype(xs.dtype())?;
+    let sin = cos_sin(sin_cache)?.to_dtype(xs.dtype())?;
+    let xs_rot = xs.narrow(3, 0, rotary_dim)?;
+    let (x1, x2) = if interleaved {
+        let xs_rot = xs_rot.reshape((b_size, num_heads, seq_len, half, 2))?;
+        (
+            xs_rot.narrow(4, 0, 1)?.squeeze(4)?,
+            xs_rot.narrow(4, 1, 1)?.squeeze(4)?,
+        )
+    } else {
+        (xs_rot.narrow(3, 0, half)?, xs_rot.narrow(3, half, half)?)
+    };
+    let y1 = (x1.broadcast_mul(&cos)? - x2.broadcast_mul(&sin)?)?;
+    let y2 = (x2.broadcast_mul(&cos)? + x1.broadcast_mul(&sin)?)?;
+    let ys = if interleaved {
+        Tensor::stack(&[y1, y2], 4)?.reshape((b_size, num_heads, seq_len, rotary_dim))?
+    } else {
+        Tensor::cat(&[y1, y2], 3)?
+    };
+    if rotary_dim == head_size {
+        Ok(ys)
+    } else {
+        Tensor::cat(&[ys, xs.narrow(3, rotary_dim, head_size - rotary_dim)?], 3)
+    }
+}
+
+// Scaled dot product attention on (batch, num_heads, seq_len, head_size) tensors, the key and
+// value heads are repeated when there are less of them than query heads. The mask is either a
+// boolean mask, with 1 for the positions that can be attended, or a float mask added to the
+// attention scores. The causal mask is aligned on the upper left corner.
+// Returns the output and the attention scores before and after adding the mask, after applying
+// the softcap and after the softmax.
+fn attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    scale: f64,
+    softcap: f64,
+    is_causal: bool,
+) -> Result<(Tensor, [Tensor; 4])> {
+    let (q_heads, kv_heads) = (q.dim(1)?, k.dim(1)?);
+    if kv_heads == 0 || !q_heads.is_multiple_of(kv_heads) {
+        bail!("{q_heads} query heads cannot be grouped by {kv_heads} key-value heads")
+    }
+    let repeat_kv = |xs: &Tensor| -> Result<Tensor> {
+        let n_rep = q_heads / kv_heads;
+        if n_rep == 1 {
+            return Ok(xs.clone());
+        }
+        let (b_size, n_heads, seq_len, head_size) = xs.dims4()?;
+        xs.unsqueeze(2)?
+            .broadcast_as((b_size, n_heads, n_rep, seq_len, head_size))?
+            .reshape((b_size, n_heads * n_rep, seq_len, head_size))
+    };
+    let k = repeat_kv(k)?;
+    let v = repeat_kv(v)?;
+    let qk = (q.contiguous()?.matmul(&k.t()?.contiguous()?)? * scale)?;
+    let (q_len, kv_len) = (q.dim(2)?, k.dim(2)?);
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, q.device())?.to_dtype(qk.dtype())?;
+    let zero = Tensor::zeros((), qk.dtype(), q.device())?;
+    let mut masked = qk.clone();
+    if let Some(mask) = mask {
+        let bias = if mask.dtype() == DType::U8 {
+            mask.where_cond(
+                &zero.broadcast_as(mask.shape())?,
+                &neg_inf.broadcast_as(mask.shape())?,
+            )?
+        } else {
+            mask.to_dtype(qk.dtype())?
+        };
+        masked = masked.broadcast_add(&bias)?
+    }
+    if is_causal {
+        let rows = Tensor::arange(0u32, q_len as u32, q.device())?.reshape((q_len, 1))?;
+        let cols = Tensor::arange(0u32, kv_len as u32, q.device())?.reshape((1, kv_len))?;
+        let causal = cols.broadcast_le(&rows)?.where_cond(
+            &zero.broadcast_as((q_len, kv_len))?,
+            &neg_inf.broadcast_as((q_len, kv_len))?,
+        )?;
+        masked = masked.broadcast_add(&causal)?
+    }
+    let capped = if softcap > 0. {
+        ((&masked / softcap)?.tanh()? * softcap)?
+    } else {
+        masked.clone()
+    };
+    let probs = candle_nn::ops::softmax_last_dim(&capped.contiguous()?)?;
+    let ys = probs.matmul(&v.contiguous()?)?;
+    Ok((ys, [qk, masked, capped, probs]))
+}
+
+// Normalizes each row of `xs` viewed as a `(n_rows, -1)` matrix, the half precision dtypes use
+// f32 for the intermediary values. The mean and inverse standard deviation of each row are also
+// returned, with shape `(n_rows, 1)`.
+fn normalize_rows(xs: &Tensor, n_rows: usize, eps: f64) -> Result<(Tensor, Tensor, Tensor)> {
+    let internal_dtype = match xs.dtype() {
+        DType::F64 => DType::F64,
+        _ => DType::F32,
+    };
+    let rows = xs.reshape((n_rows, ()))?.to_dtype(internal_dtype)?;
+    let mean = rows.mean_keepdim(1)?;
+    let centered = rows.broadcast_sub(&mean)?;
+    let inv_std_dev = (centered.sqr()?.mean_keepdim(1)? + eps)?.sqrt()?.recip()?;
+    let ys = centered
+        .broadcast_mul(&inv_std_dev)?
+        .reshape(xs.shape())?
+        .to_dtype(xs.dtype())?;
+    Ok((ys, mean, inv_std_dev))
+}
+
+// Same as `normalize_rows` without centering the values, the inverse of the root mean square of
+// each row is returned with shape `(n_rows, 1)`.
+fn rms_normalize_rows(xs: &Tensor, n_rows: usize, eps: f64) -> Result<(Tensor, Tensor)> {
+    let internal_dtype = match xs.dtype() {
+        DType::F64 => DType::F64,
+        _ => DType::F32,
+    };
+    let rows = xs.reshape((n_rows, ()))?.to_dtype(internal_dtype)?;
+    let inv_rms = (rows.sqr()?.mean_keepdim(1)? + eps)?.sqrt()?.recip()?;
+    let ys = rows
+        .broadcast_mul(&inv_rms)?
+        .reshape(xs.shape())?
+        .to_dtype(xs.dtype())?;
+    Ok((ys, inv_rms))
 }
 
 fn broadcast_shape(shape_a: &[usize], shape_b: &[usize]) -> Result<Vec<usize>> {
@@ -6,7 +6,9 @@ pub mod onnx {
 }
 
 pub mod eval;
-pub use eval::{dtype, simple_eval};
+pub mod model;
+pub use eval::{dtype, simple_eval, simple_eval_values, Value};
+pub use model::Model;
 
 pub fn read_file<P: AsRef<std::path::Path>>(p: P) -> Result<onnx::ModelProto> {
     let buf = std::fs::read(p)?;
@@ -0,0 +1,257 @@
+//! Compiled ONNX models.
+//!
+//! `simple_eval` walks the proto on each call, a [`Model`] analyzes the graph once so that it can
+//! be evaluated multiple times efficiently.
+//!
+//! ```no_run
+//! # fn main() -> candle::Result<()> {
+//! let model = candle_onnx::Model::load("model.onnx", &candle::Device::Cpu)?;
+//! let xs = candle::Tensor::zeros((1, 3, 224, 224), candle::DType::F32, &candle::Device::Cpu)?;
+//! let inputs = std::collections::HashMap::from([("input".to_string(), xs)]);
+//! let outputs = model.run(inputs)?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::eval::{
+    check_inputs, eval_node, eval_op, get_tensor, into_tensors, lower_node, opset_version, Op,
+    Scope, Value,
+};
+use crate::onnx;
+use candle::{bail, Device, Result, Tensor};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// The names of the values read by a node, including the values from the outer scope used by its
+// sub-graphs.
+fn node_reads(node: &onnx::NodeProto, reads: &mut HashSet<String>) {
+    for input in node.input.iter().filter(|i| !i.is_empty()) {
+        reads.insert(input.clone());
+    }
+    for attr in node.attribute.iter() {
+        for graph in attr.g.iter().chain(attr.graphs.iter()) {
+            for node in graph.node.iter() {
+                node_reads(node, reads)
+            }
+            // A sub-graph output can directly be a value from the outer scope.
+            for output in graph.output.iter() {
+                reads.insert(output.name.clone());
+            }
+        }
+    }
+}
+
+// Sorts the nodes so that each node comes after the nodes producing its inputs, the original
+// order is kept for nodes that do not depend on each other.
+fn topological_sort(nodes: Vec<onnx::NodeProto>) -> Result<Vec<onnx::NodeProto>> {
+    let mut producers = HashMap::new();
+    for (idx, node) in nodes.iter().enumerate() {
+        for output in node.output.iter().filter(|o| !o.is_empty()) {
+            if producers.insert(output.as_str(), idx).is_some() {
+                bail!("{output} is produced by multiple nodes")
+            }
+        }
+    }
+    let mut users = vec![vec![]; nodes.len()];
+    let mut n_deps = vec![0usize; nodes.len()];
+    for (idx, node) in nodes.iter().enumerate() {
+        let mut reads = HashSet::new();
+        node_reads(node, &mut reads);
+        let deps = reads
+            .iter()
+            .filter_map(|name| producers.get(name.as_str()).copied())
+            .filter(|&p| p != idx)
+            .collect::<HashSet<_>>();
+        for &p in deps.iter() {
+            users[p].push(idx)
+        }
+        n_deps[idx] = deps.len();
+    }
+    let mut ready = (0..nodes.len())
+        .filter(|&idx| n_deps[idx] == 0)
+        .map(Reverse)
+        .collect::<BinaryHeap<_>>();
+    let mut order = Vec::with_capacity(nodes.len());
+    while let Some(Reverse(idx)) = ready.pop() {
+        order.push(idx);
+        for &user in users[idx].iter() {
+            n_deps[user] -= 1;
+            if n_deps[user] == 0 {
+                ready.push(Reverse(user))
+            }
+        }
+    }
+    if order.len() != nodes.len() {
+        bail!("the onnx graph contains a cycle")
+    }
+    let mut nodes = nodes.into_iter().map(Some).collect::<Vec<_>>();
+    Ok(order.iter().filter_map(|&idx| nodes[idx].take()).collect())
+}
+
+// Moves the initializers of the sub-graphs of a node to the weights so that they are not decoded
+// again on each run. An initializer with the same name as an existing weight stays in its
+// sub-graph as it shadows the outer value there.
+fn hoist_initializers(
+    node: &mut onnx::NodeProto,
+    weights: &mut HashMap<String, Value>,
+    device: &Device,
+) -> Result<()> {
+    for attr in node.attribute.iter_mut() {
+        for graph in attr.g.iter_mut().chain(attr.graphs.iter_mut()) {
+            let mut shadowed = vec![];
+            for t in std::mem::take(&mut graph.initializer) {
+                if weights.contains_key(&t.name) {
+                    shadowed.push(t);
+                    continue;
+                }
+                let tensor = get_tensor(&t, t.name.as_str())?.to_device(device)?;
+                weights.insert(t.name, tensor.into());
+            }
+            graph.initializer = shadowed;
+            for node in graph.node.iter_mut() {
+                hoist_initializers(node, weights, device)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An ONNX model ready to be evaluated.
+///
+/// When creating the model, the nodes are topologically sorted and the initializers as well as
+/// the `Constant` nodes are loaded on the target device, this includes the initializers of the
+/// `If`, `Loop` and `Scan` sub-graphs. The nodes are lowered once to an operator with its attributes
+/// parsed, and each call to [`Model::run`] drops the intermediate values after their last use.
+///
+/// Only the most common operators are lowered: the element-wise ones, `MatMul`, `Gemm`, `Conv`,
+/// `Reshape`, `Concat`, `Gather`, `LayerNormalization` and a few others. The remaining ones, e.g.
+/// the reductions, `Slice` or the pooling ops, still read their attributes on each run.
+#[derive(Debug, Clone)]
+pub struct Model {
+    // The main graph, without its initializers and constant nodes.
+    graph: onnx::GraphProto,
+    // The scope in which each run is evaluated.
+    weights: Scope<'static>,
+    // The lowered operator for each node of the graph.
+    ops: Vec<Op>,
+    // The values that are not needed anymore after evaluating each node.
+    drops: Vec<Vec<String>>,
+    opset: i64,
+    device: Device,
+}
+
+impl Model {
+    pub fn new(model: &onnx::ModelProto, device: &Device) -> Result<Self> {
+        let mut graph = match &model.graph {
+            None => bail!("no graph defined in proto"),
+            Some(graph) => graph.clone(),
+        };
+        let opset = opset_version(model);
+        let mut weights = HashMap::new();
+        for t in std::mem::take(&mut graph.initializer) {
+            let tensor = get_tensor(&t, t.name.as_str())?.to_device(device)?;
+            weights.insert(t.name, tensor.into());
+        }
+        let mut nodes = vec![];
+        for mut node in topological_sort(std::mem::take(&mut graph.node))? {
+            if node.op_type == "Constant" && node.domain.is_empty() {
+                let mut values = Scope::default();
+                eval_node(&node, &mut values, opset)?;
+                for (name, value) in values.into_values() {
+                    weights.insert(name, value.to_device(device)?);
+                }
+            } else {
+                hoist_initializers(&mut node, &mut weights, device)?;
+                nodes.push(node)
+            }
+        }
+        let ops = nodes.iter().map(lower_node).collect::<Result<Vec<_>>>()?;
+
+        let outputs = graph
+            .output
+            .iter()
+            .map(|o| o.name.as_str())
+            .collect::<HashSet<_>>();
+        let mut last_uses = HashMap::new();
+        for (idx, node) in nodes.iter().enumerate() {
+            let mut reads = HashSet::new();
+            node_reads(node, &mut reads);
+            for name in reads {
+                last_uses.insert(name, idx);
+            }
+            // The values that are never read are dropped right away.
+            for output in node.output.iter().filter(|o| !o.is_empty()) {
+                last_uses.entry(output.clone()).or_insert(idx);
+            }
+        }
+        let mut drops = vec![vec![]; nodes.len()];
+        for (name, idx) in last_uses {
+            if !outputs.contains(name.as_str()) {
+                drops[idx].push(name)
+            }
+        }
+        graph.node = nodes;
+        Ok(Self {
+            graph,
+            weights: Scope::new(weights, None),
+            ops,
+            drops,
+            opset,
+            device: device.clone(),
+        })
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(p: P, device: &Device) -> Result<Self> {
+        let model = crate::read_file(p)?;
+        Self::new(&model, device)
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// The names of the inputs that have to be provided when running the model, the graph inputs
+    /// with a default value from the initializers are not included.
+    pub fn inputs(&self) -> Vec<&str> {
+        self.graph
+            .input
+            .iter()
+            .filter(|i| !self.weights.contains_key(&i.name))
+            .map(|i| i.name.as_str())
+            .collect()
+    }
+
+    pub fn outputs(&self) -> Vec<&str> {
+        self.graph.output.iter().map(|o| o.name.as_str()).collect()
+    }
+
+    /// Evaluates the model, the inputs are moved to the model device if needed.
+    pub fn run(&self, inputs: HashMap<String, Tensor>) -> Result<HashMap<String, Tensor>> {
+        let inputs = inputs.into_iter().map(|(k, v)| (k, v.into())).collect();
+        into_tensors(self.run_values(inputs)?)
+    }
+
+    /// Same as [`Model::run`] for the models that have sequence inputs or outputs.
+    pub fn run_values(&self, inputs: HashMap<String, Value>) -> Result<HashMap<String, Value>> {
+        // The weights are not copied, the run values live in a scope on top of them.
+        let mut values = Scope::new(HashMap::new(), Some(&self.weights));
+        for (name, value) in inputs {
+            values.insert(name, value.to_device(&self.device)?);
+        }
+        check_inputs(&self.graph, &values)?;
+        let nodes = self.graph.node.iter().zip(self.ops.iter());
+        for ((node, op), drops) in nodes.zip(self.drops.iter()) {
+            eval_op(op, node, &mut values, self.opset)?;
+            for name in drops.iter() {
+                values.remove(name);
+            }
+        }
+        self.graph
+            .output
+            .iter()
+            .map(|output| match values.take(&output.name) {
+                None => bail!("cannot find output {}", output.name),
+                Some(value) => Ok((output.name.clone(), value)),
+            })
+            .collect()
+    }
+}
@@ -1,4 +1,4 @@
-use candle::test_utils::to_vec2_round;
+use candle::test_utils::{to_vec2_round, to_vec3_round};
 use candle::{DType, Device, NdArray, Result, Tensor};
 use candle_onnx::onnx::attribute_proto::AttributeType;
 use candle_onnx::onnx::tensor_proto::DataType;
@@ -1057,6 +1057,14 @@ fn test_gather_operation() -> Result<()> {
         &[[[1.0, 1.9]], [[2.3, 3.9]], [[4.5, 5.9]]],
     )?;
 
+    // Indices with more than two dimensions.
+    test(
+        &[[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]],
+        &[[[0i64, 1]], [[2, -3]]],
+        0,
+        &[[[[1.0, 2.0], [3.0, 4.0]]], [[[5.0, 6.0], [1.0, 2.0]]]],
+    )?;
+
     // all the tests below are generated from numpy.take, which works like
     // onnx's Gather operation.
     test(&[1.0, 2.0, 3.0, 4.0], 3i64, 0, 4.0)?;
@@ -1160,7 +1168,13 @@ fn test_gather_operation() -> Result<()> {
             1 => assert_eq!(z.to_vec1::<f64>()?, expected.to_vec1::<f64>()?),
             2 => assert_eq!(z.to_vec2::<f64>()?, expected.to_vec2::<f64>()?),
             3 => assert_eq!(z.to_vec3::<f64>()?, expected.to_vec3::<f64>()?),
-            _ => unreachable!(),
+            _ => {
+                assert_eq!(z.dims(), expected.dims());
+                assert_eq!(
+                    z.flatten_all()?.to_vec1::<f64>()?,
+                    expected.flatten_all()?.to_vec1::<f64>()?
+                )
+            }
         };
 
         Ok(())
@@ -7226,3 +7240,1304 @@ fn test_one_hot() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_compiled_model() -> Result<()> {
+    let node = |op_type: &str, input: &[&str], output: &str| NodeProto {
+        op_type: op_type.to_string(),
+        input: input.iter().map(|s| s.to_string()).collect(),
+        output: vec![output.to_string()],
+        ..Default::default()
+    };
+    let constant = NodeProto {
+        attribute: vec![AttributeProto {
+            name: "value".into(),
+            r#type: AttributeType::Tensor as i32,
+            t: Some(TensorProto {
+                data_type: DataType::Float as i32,
+                float_data: vec![3.],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..node("Constant", &[], "c")
+    };
+    // The nodes are not topologically sorted.
+    let proto = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![
+            node("Mul", &["t", "c"], OUTPUT_Z),
+            node("Add", &[INPUT_X, "w"], "t"),
+            constant,
+        ],
+        initializer: vec![TensorProto {
+            name: "w".into(),
+            dims: vec![2],
+            data_type: DataType::Float as i32,
+            float_data: vec![1., 2.],
+            ..Default::default()
+        }],
+        input: vec![ValueInfoProto {
+            name: INPUT_X.to_string(),
+            ..Default::default()
+        }],
+        output: vec![ValueInfoProto {
+            name: OUTPUT_Z.to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }));
+    let inputs = |x: &[f32]| -> Result<HashMap<String, Tensor>> {
+        let x = Tensor::new(x, &Device::Cpu)?;
+        Ok(HashMap::from([(INPUT_X.to_string(), x)]))
+    };
+    assert!(simple_eval(&proto, inputs(&[1., 1.])?).is_err());
+
+    let model = candle_onnx::Model::new(&proto, &Device::Cpu)?;
+    assert_eq!(model.inputs(), [INPUT_X]);
+    assert_eq!(model.outputs(), [OUTPUT_Z]);
+    for (x, z) in [([1f32, 1.], [6f32, 9.]), ([0., -1.], [3., 3.])] {
+        let outputs = model.run(inputs(&x)?)?;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[OUTPUT_Z].to_vec1::<f32>()?, z);
+    }
+    assert!(model.run(HashMap::new()).is_err());
+
+    let proto = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![
+            node("Add", &["q", INPUT_X], "p"),
+            node("Add", &["p", INPUT_X], "q"),
+        ],
+        output: vec![ValueInfoProto {
+            name: "q".to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }));
+    assert!(candle_onnx::Model::new(&proto, &Device::Cpu).is_err());
+
+    // The branch initializers are loaded with the weights, the one of the else branch shadows the
+    // then branch one and stays in its sub-graph.
+    let branch = |op_type: &str, b: &[f32]| GraphProto {
+        initializer: vec![TensorProto {
+            name: "b".into(),
+            dims: vec![2],
+            data_type: DataType::Float as i32,
+            float_data: b.to_vec(),
+            ..Default::default()
+        }],
+        ..make_graph(vec![node(op_type, &[INPUT_X, "b"], "out")], &[], &["out"])
+    };
+    let if_node = NodeProto {
+        attribute: vec![
+            graph_attr("then_branch", branch("Add", &[10., 20.])),
+            graph_attr("else_branch", branch("Sub", &[1., 1.])),
+        ],
+        ..node("If", &[INPUT_Y], OUTPUT_Z)
+    };
+    let proto = create_model_proto_with_graph(Some(make_graph(
+        vec![if_node],
+        &[INPUT_X, INPUT_Y],
+        &[OUTPUT_Z],
+    )));
+    let model = candle_onnx::Model::new(&proto, &Device::Cpu)?;
+    for (cond, z) in [(1u8, [11f32, 22.]), (0, [0., 1.]), (1, [11., 22.])] {
+        let inputs = HashMap::from([
+            (INPUT_X.to_string(), Tensor::new(&[1f32, 2.], &Device::Cpu)?),
+            (INPUT_Y.to_string(), Tensor::new(&[cond], &Device::Cpu)?),
+        ]);
+        let outputs = model.run(inputs.clone())?;
+        assert_eq!(outputs[OUTPUT_Z].to_vec1::<f32>()?, z);
+        let outputs = simple_eval(&proto, inputs)?;
+        assert_eq!(outputs[OUTPUT_Z].to_vec1::<f32>()?, z);
+    }
+    Ok(())
+}
+
+fn int_attr(name: &str, i: i64) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Int.into(),
+        i,
+        ..AttributeProto::default()
+    }
+}
+
+fn ints_attr(name: &str, ints: &[i64]) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Ints.into(),
+        ints: ints.to_vec(),
+        ..AttributeProto::default()
+    }
+}
+
+#[test]
+fn test_layer_normalization() -> Result<()> {
+    let graph = make_graph_helper(
+        "LayerNormalization",
+        &["x", "scale", "bias"],
+        &["y"],
+        vec![],
+    );
+    let x = Tensor::new(&[[1f32, 2., 3.], [2., 4., 6.]], &Device::Cpu)?;
+    let inputs = HashMap::from([
+        ("x".to_string(), x),
+        (
+            "scale".to_string(),
+            Tensor::new(&[2f32, 2., 2.], &Device::Cpu)?,
+        ),
+        (
+            "bias".to_string(),
+            Tensor::new(&[1f32, 1., 1.], &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        to_vec2_round(&eval["y"], 4)?,
+        [[-1.4495, 1., 3.4495], [-1.4495, 1., 3.4495]]
+    );
+
+    // Normalize over all the dims, without bias and with the mean and inverse std-dev outputs.
+    let graph = make_graph_helper(
+        "LayerNormalization",
+        &["x", "scale"],
+        &["y", "mean", "inv_std_dev"],
+        vec![int_attr("axis", 0)],
+    );
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?,
+        ),
+        (
+            "scale".to_string(),
+            Tensor::ones((2, 2), DType::F32, &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        to_vec2_round(&eval["y"], 4)?,
+        [[-1.3416, -0.4472], [0.4472, 1.3416]]
+    );
+    assert_eq!(to_vec2_round(&eval["mean"], 4)?, [[2.5]]);
+    assert_eq!(to_vec2_round(&eval["inv_std_dev"], 4)?, [[0.8944]]);
+    Ok(())
+}
+
+#[test]
+fn test_instance_normalization() -> Result<()> {
+    let graph = make_graph_helper(
+        "InstanceNormalization",
+        &["x", "scale", "bias"],
+        &["y"],
+        vec![],
+    );
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[[[1f32, 3.], [2., 2.]]], &Device::Cpu)?,
+        ),
+        ("scale".to_string(), Tensor::new(&[1f32, 2.], &Device::Cpu)?),
+        ("bias".to_string(), Tensor::new(&[0f32, 1.], &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(to_vec3_round(&eval["y"], 4)?, [[[-1., 1.], [1., 1.]]]);
+    Ok(())
+}
+
+#[test]
+fn test_group_normalization() -> Result<()> {
+    let graph = make_graph_helper(
+        "GroupNormalization",
+        &["x", "scale", "bias"],
+        &["y"],
+        vec![int_attr("num_groups", 2)],
+    );
+    let x = Tensor::new(&[[[1f32], [3.], [5.], [5.]]], &Device::Cpu)?;
+    // Scale and bias per channel, as in opset 21.
+    let inputs = HashMap::from([
+        ("x".to_string(), x.clone()),
+        (
+            "scale".to_string(),
+            Tensor::ones(4, DType::F32, &Device::Cpu)?,
+        ),
+        (
+            "bias".to_string(),
+            Tensor::zeros(4, DType::F32, &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(to_vec3_round(&eval["y"], 4)?, [[[-1.], [1.], [0.], [0.]]]);
+
+    // Scale and bias per group, as in opset 18.
+    let inputs = HashMap::from([
+        ("x".to_string(), x),
+        ("scale".to_string(), Tensor::new(&[2f32, 3.], &Device::Cpu)?),
+        ("bias".to_string(), Tensor::new(&[0f32, 1.], &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(to_vec3_round(&eval["y"], 4)?, [[[-2.], [2.], [1.], [1.]]]);
+    Ok(())
+}
+
+#[test]
+fn test_global_pools() -> Result<()> {
+    let x = Tensor::arange(1f32, 9., &Device::Cpu)?.reshape((1, 2, 2, 2))?;
+    for (op_type, expected) in [
+        ("GlobalAveragePool", [2.5f32, 6.5]),
+        ("GlobalMaxPool", [4., 8.]),
+    ] {
+        let graph = make_graph_helper(op_type, &["x"], &["y"], vec![]);
+        let eval = simple_eval(&graph, HashMap::from([("x".to_string(), x.clone())]))?;
+        let y = &eval["y"];
+        assert_eq!(y.dims(), [1, 2, 1, 1]);
+        assert_eq!(y.flatten_all()?.to_vec1::<f32>()?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_lp_normalization() -> Result<()> {
+    let x = Tensor::new(&[[3f32, 4.], [0., 5.]], &Device::Cpu)?;
+    for (p, expected) in [
+        (1, [[0.4286f32, 0.5714], [0., 1.]]),
+        (2, [[0.6, 0.8], [0., 1.]]),
+    ] {
+        let graph = make_graph_helper("LpNormalization", &["x"], &["y"], vec![int_attr("p", p)]);
+        let eval = simple_eval(&graph, HashMap::from([("x".to_string(), x.clone())]))?;
+        assert_eq!(to_vec2_round(&eval["y"], 4)?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_conv_transpose() -> Result<()> {
+    // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ConvTranspose
+    let graph = make_graph_helper("ConvTranspose", &["x", "w"], &["y"], vec![]);
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::arange(0f32, 9., &Device::Cpu)?.reshape((1, 1, 3, 3))?,
+        ),
+        (
+            "w".to_string(),
+            Tensor::ones((1, 2, 3, 3), DType::F32, &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    let y = eval["y"].squeeze(0)?;
+    let expected = [
+        [0f32, 1., 3., 3., 2.],
+        [3., 8., 15., 12., 7.],
+        [9., 21., 36., 27., 15.],
+        [9., 20., 33., 24., 13.],
+        [6., 13., 21., 15., 8.],
+    ];
+    assert_eq!(y.to_vec3::<f32>()?, [expected, expected]);
+
+    // 1d with pads, output_padding and bias.
+    let graph = make_graph_helper(
+        "ConvTranspose",
+        &["x", "w", "b"],
+        &["y"],
+        vec![
+            ints_attr("strides", &[2]),
+            ints_attr("pads", &[1, 1]),
+            ints_attr("output_padding", &[1]),
+        ],
+    );
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[[[0f32, 1., 2.]]], &Device::Cpu)?,
+        ),
+        (
+            "w".to_string(),
+            Tensor::ones((1, 1, 3), DType::F32, &Device::Cpu)?,
+        ),
+        ("b".to_string(), Tensor::new(&[1f32], &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec3::<f32>()?, [[[1., 2., 2., 4., 3., 3.]]]);
+
+    // 2d with groups.
+    let graph = make_graph_helper(
+        "ConvTranspose",
+        &["x", "w"],
+        &["y"],
+        vec![int_attr("group", 2)],
+    );
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[1f32, 2.], &Device::Cpu)?.reshape((1, 2, 1, 1))?,
+        ),
+        (
+            "w".to_string(),
+            Tensor::new(&[3f32, 4.], &Device::Cpu)?.reshape((2, 1, 1, 1))?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].dims(), [1, 2, 1, 1]);
+    assert_eq!(eval["y"].flatten_all()?.to_vec1::<f32>()?, [3., 8.]);
+    Ok(())
+}
+
+#[test]
+fn test_quantize_dequantize_linear() -> Result<()> {
+    // Per-tensor uint8 quantization, the ties are rounded to even.
+    let graph = make_graph_helper("QuantizeLinear", &["x", "scale", "zp"], &["y"], vec![]);
+    let x = Tensor::new(&[0f32, 1., 2.5, 3., -1., 1000.], &Device::Cpu)?;
+    let inputs = HashMap::from([
+        ("x".to_string(), x),
+        ("scale".to_string(), Tensor::new(2f32, &Device::Cpu)?),
+        ("zp".to_string(), Tensor::new(128u8, &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec1::<u8>()?, [128, 128, 129, 130, 128, 255]);
+
+    let graph = make_graph_helper("DequantizeLinear", &["x", "scale", "zp"], &["y"], vec![]);
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[128u8, 130, 255], &Device::Cpu)?,
+        ),
+        ("scale".to_string(), Tensor::new(2f32, &Device::Cpu)?),
+        ("zp".to_string(), Tensor::new(128u8, &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec1::<f32>()?, [0., 4., 254.]);
+
+    // Per-axis int8 quantization, the int8 values are stored as i16.
+    let scale = Tensor::new(&[1f32, 0.5], &Device::Cpu)?;
+    let zp = Tensor::new(&[0i16, -1], &Device::Cpu)?;
+    let graph = make_graph_helper("QuantizeLinear", &["x", "scale", "zp"], &["y"], vec![]);
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?,
+        ),
+        ("scale".to_string(), scale.clone()),
+        ("zp".to_string(), zp.clone()),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec2::<i16>()?, [[1, 3], [3, 7]]);
+
+    let graph = make_graph_helper("DequantizeLinear", &["x", "scale", "zp"], &["y"], vec![]);
+    let inputs = HashMap::from([
+        ("x".to_string(), eval["y"].clone()),
+        ("scale".to_string(), scale),
+        ("zp".to_string(), zp),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec2::<f32>()?, [[1., 2.], [3., 4.]]);
+
+    // Blocked quantization along the last axis.
+    let graph = make_graph_helper(
+        "DequantizeLinear",
+        &["x", "scale"],
+        &["y"],
+        vec![int_attr("axis", 1), int_attr("block_size", 2)],
+    );
+    let inputs = HashMap::from([
+        ("x".to_string(), Tensor::new(&[[1u8, 2, 3]], &Device::Cpu)?),
+        (
+            "scale".to_string(),
+            Tensor::new(&[[1f32, 10.]], &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec2::<f32>()?, [[1., 2., 30.]]);
+    Ok(())
+}
+
+#[test]
+fn test_dynamic_quantize_linear() -> Result<()> {
+    // https://github.com/onnx/onnx/blob/main/docs/Operators.md#DynamicQuantizeLinear
+    let graph = make_graph_helper(
+        "DynamicQuantizeLinear",
+        &["x"],
+        &["y", "y_scale", "y_zero_point"],
+        vec![],
+    );
+    let x = Tensor::new(&[0f32, 2., -3., -2.5, 1.34, 0.5], &Device::Cpu)?;
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), x)]))?;
+    assert_eq!(eval["y"].to_vec1::<u8>()?, [153, 255, 0, 26, 221, 179]);
+    assert_eq!(eval["y_scale"].to_scalar::<f32>()?, 5. / 255.);
+    assert_eq!(eval["y_zero_point"].to_scalar::<u8>()?, 153);
+    Ok(())
+}
+
+#[test]
+fn test_matmul_integer() -> Result<()> {
+    // https://github.com/onnx/onnx/blob/main/docs/Operators.md#MatMulInteger
+    let graph = make_graph_helper(
+        "MatMulInteger",
+        &["a", "b", "a_zero_point", "b_zero_point"],
+        &["y"],
+        vec![],
+    );
+    let inputs = HashMap::from([
+        (
+            "a".to_string(),
+            Tensor::new(
+                &[[11u8, 7, 3], [10, 6, 2], [9, 5, 1], [8, 4, 0]],
+                &Device::Cpu,
+            )?,
+        ),
+        (
+            "b".to_string(),
+            Tensor::new(&[[1u8, 4], [2, 5], [3, 6]], &Device::Cpu)?,
+        ),
+        ("a_zero_point".to_string(), Tensor::new(12u8, &Device::Cpu)?),
+        ("b_zero_point".to_string(), Tensor::new(0u8, &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        eval["y"].to_vec2::<i64>()?,
+        [[-38, -83], [-44, -98], [-50, -113], [-56, -128]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_qlinear_matmul() -> Result<()> {
+    // https://github.com/onnx/onnx/blob/main/docs/Operators.md#QLinearMatMul
+    let graph = make_graph_helper(
+        "QLinearMatMul",
+        &[
+            "a", "a_scale", "a_zp", "b", "b_scale", "b_zp", "y_scale", "y_zp",
+        ],
+        &["y"],
+        vec![],
+    );
+    let a = Tensor::new(&[[208u8, 236, 0, 238], [3, 214, 255, 29]], &Device::Cpu)?;
+    let b = Tensor::new(
+        &[
+            [152u8, 51, 244],
+            [60, 26, 255],
+            [0, 127, 246],
+            [127, 254, 247],
+        ],
+        &Device::Cpu,
+    )?;
+    let inputs = HashMap::from([
+        ("a".to_string(), a),
+        ("a_scale".to_string(), Tensor::new(0.0066f32, &Device::Cpu)?),
+        ("a_zp".to_string(), Tensor::new(113u8, &Device::Cpu)?),
+        ("b".to_string(), b),
+        (
+            "b_scale".to_string(),
+            Tensor::new(0.00705f32, &Device::Cpu)?,
+        ),
+        ("b_zp".to_string(), Tensor::new(114u8, &Device::Cpu)?),
+        ("y_scale".to_string(), Tensor::new(0.0107f32, &Device::Cpu)?),
+        ("y_zp".to_string(), Tensor::new(118u8, &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec2::<u8>()?, [[168, 115, 255], [1, 66, 151]]);
+    Ok(())
+}
+
+#[test]
+fn test_qlinear_conv() -> Result<()> {
+    let graph = make_graph_helper(
+        "QLinearConv",
+        &[
+            "x", "x_scale", "x_zp", "w", "w_scale", "w_zp", "y_scale", "y_zp", "b",
+        ],
+        &["y"],
+        vec![],
+    );
+    let x = Tensor::new(&[[2u8, 4], [6, 8]], &Device::Cpu)?.reshape((1, 1, 2, 2))?;
+    // int8 weights quantized per output channel.
+    let w = Tensor::new(&[2i16], &Device::Cpu)?.reshape((1, 1, 1, 1))?;
+    let inputs = HashMap::from([
+        ("x".to_string(), x.clone()),
+        ("x_scale".to_string(), Tensor::new(0.5f32, &Device::Cpu)?),
+        ("x_zp".to_string(), Tensor::new(0u8, &Device::Cpu)?),
+        ("w".to_string(), w),
+        ("w_scale".to_string(), Tensor::new(&[0.5f32], &Device::Cpu)?),
+        ("w_zp".to_string(), Tensor::new(&[0i16], &Device::Cpu)?),
+        ("y_scale".to_string(), Tensor::new(0.25f32, &Device::Cpu)?),
+        ("y_zp".to_string(), Tensor::new(10u8, &Device::Cpu)?),
+        ("b".to_string(), Tensor::new(&[4i64], &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].flatten_all()?.to_vec1::<u8>()?, [18, 22, 26, 30]);
+
+    let graph = make_graph_helper("ConvInteger", &["x", "w", "x_zp"], &["y"], vec![]);
+    let inputs = HashMap::from([
+        ("x".to_string(), x),
+        (
+            "w".to_string(),
+            Tensor::new(&[3u8], &Device::Cpu)?.reshape((1, 1, 1, 1))?,
+        ),
+        ("x_zp".to_string(), Tensor::new(1u8, &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].flatten_all()?.to_vec1::<i64>()?, [3, 9, 15, 21]);
+    Ok(())
+}
+
+fn make_node(op_type: &str, inputs: &[&str], outputs: &[&str]) -> NodeProto {
+    NodeProto {
+        op_type: op_type.to_string(),
+        input: inputs.iter().map(|s| s.to_string()).collect(),
+        output: outputs.iter().map(|s| s.to_string()).collect(),
+        ..NodeProto::default()
+    }
+}
+
+fn make_graph(nodes: Vec<NodeProto>, inputs: &[&str], outputs: &[&str]) -> GraphProto {
+    let value_infos = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| ValueInfoProto {
+                name: name.to_string(),
+                ..ValueInfoProto::default()
+            })
+            .collect()
+    };
+    GraphProto {
+        node: nodes,
+        input: value_infos(inputs),
+        output: value_infos(outputs),
+        ..GraphProto::default()
+    }
+}
+
+fn graph_attr(name: &str, g: GraphProto) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Graph.into(),
+        g: Some(g),
+        ..AttributeProto::default()
+    }
+}
+
+#[test]
+fn test_sequence_ops() -> Result<()> {
+    let concat = NodeProto {
+        attribute: vec![int_attr("axis", 0)],
+        ..make_node("ConcatFromSequence", &["seq2"], &["concat"])
+    };
+    let stack = NodeProto {
+        attribute: vec![int_attr("axis", -1), int_attr("new_axis", 1)],
+        ..make_node("ConcatFromSequence", &["seq2"], &["stack"])
+    };
+    let graph = make_graph(
+        vec![
+            make_node("SequenceConstruct", &["a", "b"], &["seq"]),
+            make_node("SequenceInsert", &["seq", "c", "zero"], &["seq2"]),
+            make_node("SequenceAt", &["seq2", "minus_one"], &["at"]),
+            make_node("SequenceLength", &["seq2"], &["len"]),
+            make_node("SequenceErase", &["seq2", "zero"], &["erased"]),
+            concat,
+            stack,
+        ],
+        &["a", "b", "c", "zero", "minus_one"],
+        &["at", "len", "erased", "concat", "stack"],
+    );
+    let model = create_model_proto_with_graph(Some(graph));
+    let inputs = HashMap::from([
+        ("a".to_string(), Tensor::new(&[1f32, 2.], &Device::Cpu)?),
+        ("b".to_string(), Tensor::new(&[3f32, 4.], &Device::Cpu)?),
+        ("c".to_string(), Tensor::new(&[5f32, 6.], &Device::Cpu)?),
+        ("zero".to_string(), Tensor::new(0i64, &Device::Cpu)?),
+        ("minus_one".to_string(), Tensor::new(-1i64, &Device::Cpu)?),
+    ]);
+    // The erased output is a sequence.
+    assert!(simple_eval(&model, inputs.clone()).is_err());
+
+    let inputs = inputs.into_iter().map(|(k, v)| (k, v.into())).collect();
+    let eval = candle_onnx::simple_eval_values(&model, inputs)?;
+    assert_eq!(eval["at"].as_tensor()?.to_vec1::<f32>()?, [3., 4.]);
+    assert_eq!(eval["len"].as_tensor()?.to_scalar::<i64>()?, 3);
+    let erased = eval["erased"].as_sequence()?;
+    assert_eq!(erased.len(), 2);
+    assert_eq!(erased[0].to_vec1::<f32>()?, [1., 2.]);
+    assert_eq!(erased[1].to_vec1::<f32>()?, [3., 4.]);
+    assert_eq!(
+        eval["concat"].as_tensor()?.to_vec1::<f32>()?,
+        [5., 6., 1., 2., 3., 4.]
+    );
+    assert_eq!(
+        eval["stack"].as_tensor()?.to_vec2::<f32>()?,
+        [[5., 1., 3.], [6., 2., 4.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_loop() -> Result<()> {
+    // acc_out = acc_in + i, the loop stops when acc_out >= limit if there is a cond input.
+    let cast = NodeProto {
+        attribute: vec![int_attr("to", DataType::Float as i64)],
+        ..make_node("Cast", &["i"], &["i_f"])
+    };
+    let body = make_graph(
+        vec![
+            cast,
+            make_node("Add", &["acc_in", "i_f"], &["acc_out"]),
+            make_node("Less", &["acc_out", "limit"], &["cond_out"]),
+            make_node("Identity", &["acc_out"], &["scan_out"]),
+        ],
+        &["i", "cond_in", "acc_in"],
+        &["cond_out", "acc_out", "scan_out"],
+    );
+    let loop_graph = |inputs: &[&str]| {
+        let node = NodeProto {
+            attribute: vec![graph_attr("body", body.clone())],
+            ..make_node("Loop", inputs, &["acc", "scan"])
+        };
+        let inputs = inputs
+            .iter()
+            .copied()
+            .filter(|s| !s.is_empty())
+            .chain(["limit"])
+            .collect::<Vec<_>>();
+        create_model_proto_with_graph(Some(make_graph(vec![node], &inputs, &["acc", "scan"])))
+    };
+    let inputs = HashMap::from([
+        ("m".to_string(), Tensor::new(4i64, &Device::Cpu)?),
+        ("cond".to_string(), Tensor::new(1u8, &Device::Cpu)?),
+        ("acc0".to_string(), Tensor::new(0f32, &Device::Cpu)?),
+        ("limit".to_string(), Tensor::new(2.5f32, &Device::Cpu)?),
+    ]);
+
+    // Only the trip count applies.
+    let eval = simple_eval(&loop_graph(&["m", "", "acc0"]), inputs.clone())?;
+    assert_eq!(eval["acc"].to_scalar::<f32>()?, 6.);
+    assert_eq!(eval["scan"].to_vec1::<f32>()?, [0., 1., 3., 6.]);
+
+    // Only the condition applies.
+    let eval = simple_eval(&loop_graph(&["", "cond", "acc0"]), inputs.clone())?;
+    assert_eq!(eval["acc"].to_scalar::<f32>()?, 3.);
+    assert_eq!(eval["scan"].to_vec1::<f32>()?, [0., 1., 3.]);
+    Ok(())
+}
+
+#[test]
+fn test_scan() -> Result<()> {
+    // Cumulative sum over the rows of x.
+    let body = make_graph(
+        vec![
+            make_node("Add", &["s_in", "x_t"], &["s_out"]),
+            make_node("Identity", &["s_out"], &["y_t"]),
+        ],
+        &["s_in", "x_t"],
+        &["s_out", "y_t"],
+    );
+    let x = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], &Device::Cpu)?;
+    for (directions, expected) in [
+        (vec![0], [[1f32, 2.], [4., 6.], [9., 12.]]),
+        (vec![1], [[5., 6.], [8., 10.], [9., 12.]]),
+    ] {
+        let node = NodeProto {
+            attribute: vec![
+                graph_attr("body", body.clone()),
+                int_attr("num_scan_inputs", 1),
+                ints_attr("scan_input_directions", &directions),
+            ],
+            ..make_node("Scan", &["s0", "x"], &["s", "y"])
+        };
+        let graph = make_graph(vec![node], &["s0", "x"], &["s", "y"]);
+        let inputs = HashMap::from([
+            (
+                "s0".to_string(),
+                Tensor::zeros(2, DType::F32, &Device::Cpu)?,
+            ),
+            ("x".to_string(), x.clone()),
+        ]);
+        let eval = simple_eval(&create_model_proto_with_graph(Some(graph)), inputs)?;
+        assert_eq!(eval["s"].to_vec1::<f32>()?, [9., 12.]);
+        assert_eq!(eval["y"].to_vec2::<f32>()?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_scan_scope() -> Result<()> {
+    // The body has its own initializer and one of its outputs is a value from the outer scope,
+    // this value is still available to the nodes after the Scan.
+    let body = GraphProto {
+        initializer: vec![TensorProto {
+            name: "two".into(),
+            dims: vec![1],
+            data_type: DataType::Float as i32,
+            float_data: vec![2.],
+            ..Default::default()
+        }],
+        ..make_graph(
+            vec![
+                make_node("Add", &["s_in", "x_t"], &["s_out"]),
+                make_node("Mul", &["s_out", "two"], &["y_t"]),
+            ],
+            &["s_in", "x_t"],
+            &["s_out", "y_t", "bias"],
+        )
+    };
+    let node = NodeProto {
+        attribute: vec![graph_attr("body", body), int_attr("num_scan_inputs", 1)],
+        ..make_node("Scan", &["s0", "x"], &["s", "y", "b"])
+    };
+    let graph = make_graph(
+        vec![node, make_node("Add", &["s", "bias"], &["sb"])],
+        &["s0", "x", "bias"],
+        &["s", "y", "b", "sb"],
+    );
+    let model = create_model_proto_with_graph(Some(graph));
+    let inputs = HashMap::from([
+        (
+            "s0".to_string(),
+            Tensor::zeros(2, DType::F32, &Device::Cpu)?,
+        ),
+        (
+            "x".to_string(),
+            Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?,
+        ),
+        (
+            "bias".to_string(),
+            Tensor::new(&[10f32, 20.], &Device::Cpu)?,
+        ),
+    ]);
+    let compiled = candle_onnx::Model::new(&model, &Device::Cpu)?;
+    for eval in [simple_eval(&model, inputs.clone())?, compiled.run(inputs)?] {
+        assert_eq!(eval.len(), 4);
+        assert_eq!(eval["s"].to_vec1::<f32>()?, [4., 6.]);
+        assert_eq!(eval["y"].to_vec2::<f32>()?, [[2., 4.], [8., 12.]]);
+        assert_eq!(eval["b"].to_vec2::<f32>()?, [[10., 20.], [10., 20.]]);
+        assert_eq!(eval["sb"].to_vec1::<f32>()?, [14., 26.]);
+    }
+    Ok(())
+}
+
+fn str_attr(name: &str, s: &str) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::String.into(),
+        s: s.as_bytes().to_vec(),
+        ..AttributeProto::default()
+    }
+}
+
+fn float_attr(name: &str, f: f32) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Float.into(),
+        f,
+        ..AttributeProto::default()
+    }
+}
+
+fn with_opset(mut model: ModelProto, version: i64) -> ModelProto {
+    model.opset_import = vec![candle_onnx::onnx::OperatorSetIdProto {
+        domain: "".to_string(),
+        version,
+    }];
+    model
+}
+
+#[test]
+fn test_einsum() -> Result<()> {
+    let einsum = |equation: &str, inputs: Vec<Tensor>| -> Result<Tensor> {
+        let names = ["a", "b"];
+        let graph = make_graph_helper(
+            "Einsum",
+            &names[..inputs.len()],
+            &["y"],
+            vec![str_attr("equation", equation)],
+        );
+        let inputs = names
+            .iter()
+            .zip(inputs)
+            .map(|(name, t)| (name.to_string(), t))
+            .collect();
+        Ok(simple_eval(&graph, inputs)?.remove("y").unwrap())
+    };
+    let a = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    let b = Tensor::new(&[[5f32, 6.], [7., 8.]], &Device::Cpu)?;
+    let ys = einsum("ij,jk->ik", vec![a.clone(), b.clone()])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[19., 22.], [43., 50.]]);
+    // The implicit output uses the labels appearing once in alphabetical order.
+    let ys = einsum("ij,jk", vec![a.clone(), b.clone()])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[19., 22.], [43., 50.]]);
+    let ys = einsum("ij,kj->ik", vec![a.clone(), b.clone()])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[17., 23.], [39., 53.]]);
+    let ys = einsum("ij->", vec![a.clone()])?;
+    assert_eq!(ys.to_scalar::<f32>()?, 10.);
+    let ys = einsum("i,j->ij", vec![a.get(0)?, b.get(1)?])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[7., 8.], [14., 16.]]);
+    let xs = Tensor::arange(0f32, 12., &Device::Cpu)?.reshape((3, 2, 2))?;
+    let ys = einsum("...ij->...ji", vec![xs.clone()])?;
+    assert_eq!(ys.to_vec3::<f32>()?, xs.transpose(1, 2)?.to_vec3::<f32>()?);
+    let ys = einsum("bij,jk->bik", vec![xs.clone(), b.clone()])?;
+    assert_eq!(
+        ys.to_vec3::<f32>()?,
+        xs.broadcast_matmul(&b)?.to_vec3::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_topk() -> Result<()> {
+    let xs = Tensor::new(&[[0f32, 3., 1., 2.], [7., 4., 6., 5.]], &Device::Cpu)?;
+    let graph = make_graph_helper("TopK", &["x", "k"], &["values", "indices"], vec![]);
+    let inputs = HashMap::from([
+        ("x".to_string(), xs.clone()),
+        ("k".to_string(), Tensor::new(&[3i64], &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        eval["values"].to_vec2::<f32>()?,
+        [[3., 2., 1.], [7., 6., 5.]]
+    );
+    assert_eq!(eval["indices"].to_vec2::<i64>()?, [[1, 3, 2], [0, 2, 3]]);
+
+    // Before opset 10, k is an attribute.
+    let graph = make_graph_helper(
+        "TopK",
+        &["x"],
+        &["values", "indices"],
+        vec![int_attr("k", 1), int_attr("axis", 0)],
+    );
+    let graph = with_opset(graph, 1);
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs)]))?;
+    assert_eq!(eval["values"].to_vec2::<f32>()?, [[7., 4., 6., 5.]]);
+    assert_eq!(eval["indices"].to_vec2::<i64>()?, [[1, 1, 1, 1]]);
+    Ok(())
+}
+
+#[test]
+fn test_nonzero_and_mod() -> Result<()> {
+    let graph = make_graph_helper("NonZero", &["x"], &["y"], vec![]);
+    let xs = Tensor::new(&[[1f32, 0.], [1., 1.]], &Device::Cpu)?;
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs)]))?;
+    assert_eq!(eval["y"].to_vec2::<i64>()?, [[0, 1, 1], [0, 0, 1]]);
+
+    // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Mod
+    let inputs = HashMap::from([
+        (
+            "a".to_string(),
+            Tensor::new(&[-4i64, 7, 5, 4, -7, 8], &Device::Cpu)?,
+        ),
+        (
+            "b".to_string(),
+            Tensor::new(&[2i64, -3, 8, -2, 3, 5], &Device::Cpu)?,
+        ),
+    ]);
+    let graph = make_graph_helper("Mod", &["a", "b"], &["y"], vec![]);
+    let eval = simple_eval(&graph, inputs.clone())?;
+    assert_eq!(eval["y"].to_vec1::<i64>()?, [0, -2, 5, 0, 2, 3]);
+    let graph = make_graph_helper("Mod", &["a", "b"], &["y"], vec![int_attr("fmod", 1)]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec1::<i64>()?, [0, 1, 5, 0, -1, 3]);
+    Ok(())
+}
+
+#[test]
+fn test_softplus_mish() -> Result<()> {
+    let xs = Tensor::new(&[[-1f32, 0., 1.]], &Device::Cpu)?;
+    let graph = make_graph_helper("Softplus", &["x"], &["y"], vec![]);
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs.clone())]))?;
+    assert_eq!(to_vec2_round(&eval["y"], 4)?, [[0.3133, 0.6931, 1.3133]]);
+    let graph = make_graph_helper("Mish", &["x"], &["y"], vec![]);
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs)]))?;
+    assert_eq!(to_vec2_round(&eval["y"], 4)?, [[-0.3034, 0., 0.8651]]);
+    Ok(())
+}
+
+#[test]
+fn test_reduce_prod_l2() -> Result<()> {
+    let xs = Tensor::arange(1f32, 13., &Device::Cpu)?.reshape((3, 2, 2))?;
+    let graph = make_graph_helper("ReduceProd", &["x", "axes"], &["y"], vec![]);
+    let inputs = HashMap::from([
+        ("x".to_string(), xs.clone()),
+        ("axes".to_string(), Tensor::new(&[-2i64], &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        eval["y"].to_vec3::<f32>()?,
+        [[[3., 8.]], [[35., 48.]], [[99., 120.]]]
+    );
+
+    // Before opset 18, the axes are an attribute.
+    let graph = make_graph_helper(
+        "ReduceProd",
+        &["x"],
+        &["y"],
+        vec![ints_attr("axes", &[0, 2]), int_attr("keepdims", 0)],
+    );
+    let graph = with_opset(graph, 13);
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs.clone())]))?;
+    assert_eq!(eval["y"].to_vec1::<f32>()?, [5400., 88704.]);
+
+    let graph = make_graph_helper(
+        "ReduceL2",
+        &["x", "axes"],
+        &["y"],
+        vec![int_attr("keepdims", 0)],
+    );
+    let inputs = HashMap::from([
+        ("x".to_string(), xs),
+        ("axes".to_string(), Tensor::new(&[-1i64], &Device::Cpu)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        to_vec2_round(&eval["y"], 4)?,
+        [[2.2361, 5.], [7.8102, 10.6301], [13.4536, 16.2788]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_gather_nd_scatter_elements() -> Result<()> {
+    // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GatherND
+    let graph = make_graph_helper("GatherND", &["data", "indices"], &["y"], vec![]);
+    let inputs = HashMap::from([
+        (
+            "data".to_string(),
+            Tensor::new(&[[0f32, 1.], [2., 3.]], &Device::Cpu)?,
+        ),
+        (
+            "indices".to_string(),
+            Tensor::new(&[[0i64, 0], [1, -1]], &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec1::<f32>()?, [0., 3.]);
+    let graph = make_graph_helper(
+        "GatherND",
+        &["data", "indices"],
+        &["y"],
+        vec![int_attr("batch_dims", 1)],
+    );
+    let data = Tensor::arange(0f32, 8., &Device::Cpu)?.reshape((2, 2, 2))?;
+    let inputs = HashMap::from([
+        ("data".to_string(), data),
+        (
+            "indices".to_string(),
+            Tensor::new(&[[1i64], [0]], &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(eval["y"].to_vec2::<f32>()?, [[2., 3.], [4., 5.]]);
+
+    // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ScatterElements
+    let scatter = |reduction: &str, indices: &[[i64; 2]; 1]| -> Result<Tensor> {
+        let graph = make_graph_helper(
+            "ScatterElements",
+            &["data", "indices", "updates"],
+            &["y"],
+            vec![int_attr("axis", 1), str_attr("reduction", reduction)],
+        );
+        let inputs = HashMap::from([
+            (
+                "data".to_string(),
+                Tensor::new(&[[1f32, 2., 3., 4., 5.]], &Device::Cpu)?,
+            ),
+            ("indices".to_string(), Tensor::new(indices, &Device::Cpu)?),
+            (
+                "updates".to_string(),
+                Tensor::new(&[[1.5f32, 2.5]], &Device::Cpu)?,
+            ),
+        ]);
+        Ok(simple_eval(&graph, inputs)?.remove("y").unwrap())
+    };
+    let ys = scatter("none", &[[1, -2]])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[1., 1.5, 3., 2.5, 5.]]);
+    // The reductions are applied to the duplicated indices.
+    let ys = scatter("add", &[[1, -4]])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[1., 6., 3., 4., 5.]]);
+    let ys = scatter("mul", &[[1, -4]])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[1., 7.5, 3., 4., 5.]]);
+    let ys = scatter("max", &[[1, -4]])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[1., 2.5, 3., 4., 5.]]);
+    let ys = scatter("min", &[[1, -4]])?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[1., 1.5, 3., 4., 5.]]);
+    Ok(())
+}
+
+#[test]
+fn test_depth_to_space() -> Result<()> {
+    let xs = Tensor::arange(0f32, 8., &Device::Cpu)?.reshape((1, 8, 1, 1))?;
+    let depth_to_space = |mode: &str| -> Result<Tensor> {
+        let graph = make_graph_helper(
+            "DepthToSpace",
+            &["x"],
+            &["y"],
+            vec![int_attr("blocksize", 2), str_attr("mode", mode)],
+        );
+        let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs.clone())]))?;
+        Ok(eval["y"].squeeze(0)?)
+    };
+    let ys = depth_to_space("DCR")?;
+    assert_eq!(
+        ys.to_vec3::<f32>()?,
+        [[[0., 2.], [4., 6.]], [[1., 3.], [5., 7.]]]
+    );
+    assert_eq!(
+        depth_to_space("CRD")?.to_vec3::<f32>()?,
+        [[[0., 1.], [2., 3.]], [[4., 5.], [6., 7.]]]
+    );
+
+    // SpaceToDepth is the inverse of DepthToSpace with the DCR mode.
+    let graph = make_graph_helper(
+        "SpaceToDepth",
+        &["x"],
+        &["y"],
+        vec![int_attr("blocksize", 2)],
+    );
+    let inputs = HashMap::from([("x".to_string(), ys.unsqueeze(0)?)]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        eval["y"].flatten_all()?.to_vec1::<f32>()?,
+        [0., 1., 2., 3., 4., 5., 6., 7.]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_rms_normalization() -> Result<()> {
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[[1f32, 2., 3.], [2., 4., 6.]], &Device::Cpu)?,
+        ),
+        (
+            "scale".to_string(),
+            Tensor::new(&[1f32, 1., 2.], &Device::Cpu)?,
+        ),
+    ]);
+    let graph = make_graph_helper("RMSNormalization", &["x", "scale"], &["y"], vec![]);
+    let eval = simple_eval(&graph, inputs.clone())?;
+    assert_eq!(
+        to_vec2_round(&eval["y"], 4)?,
+        [[0.4629, 0.9258, 2.7775], [0.4629, 0.9258, 2.7775]]
+    );
+    // RMSNormalization is only available since opset 23.
+    assert!(simple_eval(&with_opset(graph, 22), inputs.clone()).is_err());
+
+    let graph = make_graph_helper(
+        "SimplifiedLayerNormalization",
+        &["x", "scale"],
+        &["y", "inv_std_var"],
+        vec![],
+    );
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        to_vec2_round(&eval["y"], 4)?,
+        [[0.4629, 0.9258, 2.7775], [0.4629, 0.9258, 2.7775]]
+    );
+    assert_eq!(
+        to_vec2_round(&eval["inv_std_var"], 4)?,
+        [[0.4629], [0.2315]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_rotary_embedding() -> Result<()> {
+    let xs = Tensor::new(&[[1f32, 2., 3., 4.], [5., 6., 7., 8.]], &Device::Cpu)?;
+    let cos_cache = Tensor::new(&[[1f32, 1.], [0.5, 0.5], [0., 0.]], &Device::Cpu)?;
+    let sin_cache = Tensor::new(&[[0f32, 0.], [0.5, 0.5], [1., 1.]], &Device::Cpu)?;
+    let rotary = |interleaved: i64| -> Result<Tensor> {
+        let graph = make_graph_helper(
+            "RotaryEmbedding",
+            &["x", "cos_cache", "sin_cache", "position_ids"],
+            &["y"],
+            vec![int_attr("interleaved", interleaved)],
+        );
+        let inputs = HashMap::from([
+            ("x".to_string(), xs.reshape((1, 1, 2, 4))?),
+            ("cos_cache".to_string(), cos_cache.clone()),
+            ("sin_cache".to_string(), sin_cache.clone()),
+            (
+                "position_ids".to_string(),
+                Tensor::new(&[[0i64, 2]], &Device::Cpu)?,
+            ),
+        ]);
+        let eval = simple_eval(&graph, inputs)?;
+        eval["y"].squeeze(0)?.squeeze(0)
+    };
+    assert_eq!(
+        rotary(0)?.to_vec2::<f32>()?,
+        [[1., 2., 3., 4.], [-7., -8., 5., 6.]]
+    );
+    assert_eq!(
+        rotary(1)?.to_vec2::<f32>()?,
+        [[1., 2., 3., 4.], [-6., 5., -8., 7.]]
+    );
+
+    // The com.microsoft version takes a 3d input and a position offset.
+    let mut graph = make_graph_helper(
+        "RotaryEmbedding",
+        &["x", "position_ids", "cos_cache", "sin_cache"],
+        &["y"],
+        vec![int_attr("num_heads", 1)],
+    );
+    if let Some(graph) = graph.graph.as_mut() {
+        graph.node[0].domain = "com.microsoft".to_string();
+    }
+    let inputs = HashMap::from([
+        ("x".to_string(), xs.unsqueeze(0)?),
+        ("cos_cache".to_string(), cos_cache),
+        ("sin_cache".to_string(), sin_cache),
+        (
+            "position_ids".to_string(),
+            Tensor::new(&[1i64], &Device::Cpu)?,
+        ),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        eval["y"].squeeze(0)?.to_vec2::<f32>()?,
+        [[-1., -1., 2., 3.], [-7., -8., 5., 6.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_attention() -> Result<()> {
+    let q = Tensor::new(&[[[[1f32, 0.], [0., 1.]]]], &Device::Cpu)?;
+    let v = Tensor::new(&[[[[1f32, 2.], [3., 4.]]]], &Device::Cpu)?;
+    let expected = [[1., 2.], [2.3395, 3.3395]];
+
+    let graph = make_graph_helper(
+        "Attention",
+        &["q", "k", "v"],
+        &["y", "present_key", "present_value", "qk"],
+        vec![
+            int_attr("is_causal", 1),
+            int_attr("qk_matmul_output_mode", 3),
+        ],
+    );
+    let inputs = HashMap::from([
+        ("q".to_string(), q.clone()),
+        ("k".to_string(), q.clone()),
+        ("v".to_string(), v.clone()),
+    ]);
+    let eval = simple_eval(&graph, inputs.clone())?;
+    assert_eq!(
+        to_vec2_round(&eval["y"].squeeze(0)?.squeeze(0)?, 4)?,
+        expected
+    );
+    assert_eq!(
+        to_vec2_round(&eval["qk"].squeeze(0)?.squeeze(0)?, 4)?,
+        [[1., 0.], [0.3302, 0.6698]]
+    );
+    assert_eq!(eval["present_key"].dims(), [1, 1, 2, 2]);
+    // Attention is only available since opset 23.
+    assert!(simple_eval(&with_opset(graph.clone(), 22), inputs.clone()).is_err());
+    // The com.microsoft Attention op is a different op, it does not run as the ai.onnx one.
+    let mut graph = graph;
+    if let Some(graph) = graph.graph.as_mut() {
+        graph.node[0].domain = "com.microsoft".to_string();
+    }
+    let err = simple_eval(&graph, inputs).unwrap_err();
+    assert!(
+        err.to_string().contains("com.microsoft::Attention"),
+        "{err}"
+    );
+
+    // The same attention using a boolean mask, 3d inputs and a past key and value.
+    let graph = make_graph_helper(
+        "Attention",
+        &["q", "k", "v", "mask", "past_key", "past_value"],
+        &["y", "present_key"],
+        vec![int_attr("q_num_heads", 1), int_attr("kv_num_heads", 1)],
+    );
+    let inputs = HashMap::from([
+        ("q".to_string(), q.squeeze(0)?),
+        ("k".to_string(), q.narrow(2, 1, 1)?.squeeze(0)?),
+        ("v".to_string(), v.narrow(2, 1, 1)?.squeeze(0)?),
+        (
+            "mask".to_string(),
+            Tensor::new(&[[1u8, 0], [1, 1]], &Device::Cpu)?,
+        ),
+        ("past_key".to_string(), q.narrow(2, 0, 1)?),
+        ("past_value".to_string(), v.narrow(2, 0, 1)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(to_vec2_round(&eval["y"].squeeze(0)?, 4)?, expected);
+    assert_eq!(eval["present_key"].dims(), [1, 1, 2, 2]);
+
+    // The com.microsoft MultiHeadAttention op with two heads.
+    let mut graph = make_graph_helper(
+        "MultiHeadAttention",
+        &["q", "k", "v"],
+        &["y"],
+        vec![int_attr("num_heads", 2), int_attr("unidirectional", 1)],
+    );
+    if let Some(graph) = graph.graph.as_mut() {
+        graph.node[0].domain = "com.microsoft".to_string();
+    }
+    let xs = Tensor::cat(&[&q, &q], 3)?.squeeze(0)?.squeeze(0)?;
+    let vs = Tensor::cat(&[&v, &v], 3)?.squeeze(0)?.squeeze(0)?;
+    let inputs = HashMap::from([
+        ("q".to_string(), xs.unsqueeze(0)?),
+        ("k".to_string(), xs.unsqueeze(0)?),
+        ("v".to_string(), vs.unsqueeze(0)?),
+    ]);
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(
+        to_vec2_round(&eval["y"].squeeze(0)?, 4)?,
+        [[1., 2., 1., 2.], [2.3395, 3.3395, 2.3395, 3.3395]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_contrib_ops() -> Result<()> {
+    let contrib_graph = |op_type: &str, inputs: &[&str], outputs: &[&str]| {
+        let mut graph = make_graph_helper(op_type, inputs, outputs, vec![]);
+        if let Some(graph) = graph.graph.as_mut() {
+            graph.node[0].domain = "com.microsoft".to_string();
+        }
+        graph
+    };
+    let xs = Tensor::new(&[[1f32, -1.]], &Device::Cpu)?;
+    let graph = contrib_graph("FastGelu", &["x"], &["y"]);
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs.clone())]))?;
+    assert_eq!(to_vec2_round(&eval["y"], 4)?, [[0.8412, -0.1588]]);
+    let graph = contrib_graph("QuickGelu", &["x"], &["y"]);
+    let eval = simple_eval(&graph, HashMap::from([("x".to_string(), xs)]))?;
+    assert_eq!(to_vec2_round(&eval["y"], 4)?, [[0.8458, -0.1542]]);
+
+    let inputs = HashMap::from([
+        (
+            "x".to_string(),
+            Tensor::new(&[[1f32, 2., 3.]], &Device::Cpu)?,
+        ),
+        (
+            "skip".to_string(),
+            Tensor::new(&[[1f32, 2., 3.]], &Device::Cpu)?,
+        ),
+        (
+            "gamma".to_string(),
+            Tensor::new(&[1f32, 1., 1.], &Device::Cpu)?,
+        ),
+        (
+            "beta".to_string(),
+            Tensor::new(&[0f32, 0., 1.], &Device::Cpu)?,
+        ),
+    ]);
+    let graph = contrib_graph(
+        "SkipLayerNormalization",
+        &["x", "skip", "gamma", "beta"],
+        &["y", "mean", "inv_std_var", "sum"],
+    );
+    let eval = simple_eval(&graph, inputs.clone())?;
+    assert_eq!(to_vec2_round(&eval["y"], 4)?, [[-1.2247, 0., 2.2247]]);
+    assert_eq!(eval["mean"].to_vec2::<f32>()?, [[4.]]);
+    assert_eq!(to_vec2_round(&eval["inv_std_var"], 4)?, [[0.6124]]);
+    assert_eq!(eval["sum"].to_vec2::<f32>()?, [[2., 4., 6.]]);
+    let graph = contrib_graph(
+        "SkipSimplifiedLayerNormalization",
+        &["x", "skip", "gamma"],
+        &["y"],
+    );
+    let eval = simple_eval(&graph, inputs)?;
+    assert_eq!(to_vec2_round(&eval["y"], 4)?, [[0.4629, 0.9258, 1.3887]]);
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Importance matrix computation.
+//!
+//! An importance matrix holds, for each linear layer of a model, the mean of the squared input
+//! activations for each input column. These values can be used to weight the quantization error
+//! when quantizing the layer weights, see `QTensor::quantize_imatrix`.
+//!
+//! The matrix is computed by running a model on some calibration text within
+//! [`Imatrix::collect`]. Models that support this record the inputs of their linear layers with
+//! [`record`], this is the case for the layers from `models::with_tracing` and for
+//! `models::quantized_llama`.
+//!
+//! ```ignore
+//! let mut imatrix = Imatrix::new();
+//! for chunk in tokens.chunks(512) {
+//!     let input = Tensor::new(chunk, &device)?.unsqueeze(0)?;
+//!     imatrix.collect(|| model.forward(&input, 0))?;
+//! }
+//! imatrix.save("imatrix.dat", "calibration.txt")?;
+//! ```
+use candle::quantized::imatrix_file::{self, ImatrixEntry};
+use candle::{DType, Result, Tensor, D};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    static COLLECTOR: RefCell<Option<Imatrix>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone)]
+struct Stats {
+    sum_sq: Vec<f64>,
+    n_rows: usize,
+    ncall: usize,
+}
+
+/// Accumulated statistics for the inputs of the linear layers, indexed by weight name.
+#[derive(Debug, Clone, Default)]
+pub struct Imatrix {
+    stats: HashMap<String, Stats>,
+}
+
+impl Imatrix {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accumulates the squared values of `xs`, the input of the linear layer `name`. The last
+    /// dimension of `xs` is the input column, all the other dimensions are flattened.
+    pub fn add(&mut self, name: &str, xs: &Tensor) -> Result<()> {
+        let n_cols = xs.dim(D::Minus1)?;
+        let n_rows = xs.elem_count() / n_cols;
+        let sum_sq = xs
+            .to_dtype(DType::F32)?
+            .reshape((n_rows, n_cols))?
+            .sqr()?
+            .sum(0)?
+            .to_vec1::<f32>()?;
+        let stats = self.stats.entry(name.to_string()).or_insert_with(|| Stats {
+            sum_sq: vec![0.; n_cols],
+            n_rows: 0,
+            ncall: 0,
+        });
+        if stats.sum_sq.len() != n_cols {
+            candle::bail!(
+                "imatrix: unexpected number of columns for {name}, {n_cols} <> {}",
+                stats.sum_sq.len()
+            )
+        }
+        for (acc, v) in stats.sum_sq.iter_mut().zip(sum_sq.iter()) {
+            *acc += *v as f64
+        }
+        stats.n_rows += n_rows;
+        stats.ncall += 1;
+        Ok(())
+    }
+
+    /// Runs `f` while recording the inputs of the linear layers into this importance matrix.
+    pub fn collect<T, F: FnOnce() -> Result<T>>(&mut self, f: F) -> Result<T> {
+        let prev = COLLECTOR.with(|c| c.replace(Some(std::mem::take(self))));
+        let res = f();
+        if let Some(imatrix) = COLLECTOR.with(|c| c.replace(prev)) {
+            *self = imatrix
+        }
+        res
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.is_empty()
+    }
+
+    /// The mean of the squared activations for each input column of a linear layer.
+    pub fn get(&self, name: &str) -> Option<Vec<f32>> {
+        self.stats.get(name).map(|s| {
+            let n_rows = s.n_rows.max(1) as f64;
+            s.sum_sq.iter().map(|v| (v / n_rows) as f32).collect()
+        })
+    }
+
+    /// The importance matrix entries, in the same format as returned by
+    /// `imatrix_file::load_imatrix`.
+    pub fn weights(&self) -> HashMap<String, Vec<f32>> {
+        self.stats
+            .keys()
+            .filter_map(|name| self.get(name).map(|v| (name.to_string(), v)))
+            .collect()
+    }
+
+    /// Writes the importance matrix in the llama.cpp format, `dataset` is the name of the
+    /// calibration dataset that gets stored in the file.
+    pub fn save<P: AsRef<std::path::Path>>(&self, p: P, dataset: &str) -> Result<()> {
+        let entries = self
+            .stats
+            .iter()
+            .map(|(name, s)| {
+                let entry = ImatrixEntry {
+                    values: self.get(name).unwrap_or_default(),
+                    ncall: s.ncall,
+                };
+                (name.to_string(), entry)
+            })
+            .collect();
+        imatrix_file::save_imatrix(p, &entries, dataset)
+    }
+}
+
+/// Records the input of the linear layer `name` if this is called within [`Imatrix::collect`],
+/// this is a no-op otherwise.
+pub fn record(name: &str, xs: &Tensor) -> Result<()> {
+    COLLECTOR.with(|c| match c.borrow_mut().as_mut() {
+        None => Ok(()),
+        Some(imatrix) => imatrix.add(name, xs),
+    })
+}
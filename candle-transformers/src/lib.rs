@@ -1,5 +1,6 @@
 pub mod fused_moe;
 pub mod generation;
+pub mod imatrix;
 pub mod models;
 pub mod object_detection;
 pub mod pipelines;
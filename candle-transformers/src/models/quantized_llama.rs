@@ -22,26 +22,29 @@ use crate::quantized_nn::RmsNorm;
 use candle::quantized::QTensor;
 use candle::quantized::{ggml_file, gguf_file};
 use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::kv_cache::KvState;
 use candle_nn::{Embedding, Module};
 
 pub const MAX_SEQ_LEN: usize = 4096;
 
-// QMatMul wrapper adding some tracing.
+// QMatMul wrapper adding some tracing and recording the inputs for importance matrices.
 #[derive(Debug, Clone)]
 struct QMatMul {
     inner: candle::quantized::QMatMul,
+    name: String,
     span: tracing::Span,
 }
 
 impl QMatMul {
-    fn from_qtensor(qtensor: QTensor) -> Result<Self> {
+    fn from_qtensor(qtensor: QTensor, name: String) -> Result<Self> {
         let inner = candle::quantized::QMatMul::from_qtensor(qtensor)?;
         let span = tracing::span!(tracing::Level::TRACE, "qmatmul");
-        Ok(Self { inner, span })
+        Ok(Self { inner, name, span })
     }
 
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
+        crate::imatrix::record(&self.name, xs)?;
         self.inner.forward(xs)
     }
 }
@@ -309,9 +312,18 @@ impl ModelWeights {
                 let feed_forward_w2 = ct.remove(&format!("{prefix}.feed_forward.w2.weight"))?;
                 let feed_forward_w3 = ct.remove(&format!("{prefix}.feed_forward.w3.weight"))?;
                 MlpOrMoe::Mlp(Mlp {
-                    feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                    feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                    feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+                    feed_forward_w1: QMatMul::from_qtensor(
+                        feed_forward_w1,
+                        format!("{prefix}.feed_forward.w1.weight"),
+                    )?,
+                    feed_forward_w2: QMatMul::from_qtensor(
+                        feed_forward_w2,
+                        format!("{prefix}.feed_forward.w2.weight"),
+                    )?,
+                    feed_forward_w3: QMatMul::from_qtensor(
+                        feed_forward_w3,
+                        format!("{prefix}.feed_forward.w3.weight"),
+                    )?,
                 })
             };
             let attention_norm = ct.remove(&format!("{prefix}.attention_norm.weight"))?;
@@ -320,10 +332,22 @@ impl ModelWeights {
             let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
             let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
             layers.push(LayerWeights {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_wq: QMatMul::from_qtensor(
+                    attention_wq,
+                    format!("{prefix}.attention.wq.weight"),
+                )?,
+                attention_wk: QMatMul::from_qtensor(
+                    attention_wk,
+                    format!("{prefix}.attention.wk.weight"),
+                )?,
+                attention_wv: QMatMul::from_qtensor(
+                    attention_wv,
+                    format!("{prefix}.attention.wv.weight"),
+                )?,
+                attention_wo: QMatMul::from_qtensor(
+                    attention_wo,
+                    format!("{prefix}.attention.wo.weight"),
+                )?,
                 attention_norm: RmsNorm::from_qtensor(attention_norm, 1e-5)?,
                 mlp_or_moe,
                 ffn_norm: RmsNorm::from_qtensor(ffn_norm, 1e-5)?,
@@ -345,7 +369,7 @@ impl ModelWeights {
             tok_embeddings: Embedding::new(tok_embeddings, ct.hparams.n_embd as usize),
             layers,
             norm,
-            output: QMatMul::from_qtensor(output)?,
+            output: QMatMul::from_qtensor(output, "output.weight".to_string())?,
             masks: HashMap::new(),
             span,
             span_output,
@@ -409,9 +433,18 @@ impl ModelWeights {
                 let feed_forward_w3 =
                     ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
                 MlpOrMoe::Mlp(Mlp {
-                    feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                    feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                    feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+                    feed_forward_w1: QMatMul::from_qtensor(
+                        feed_forward_w1,
+                        format!("{prefix}.ffn_gate.weight"),
+                    )?,
+                    feed_forward_w2: QMatMul::from_qtensor(
+                        feed_forward_w2,
+                        format!("{prefix}.ffn_down.weight"),
+                    )?,
+                    feed_forward_w3: QMatMul::from_qtensor(
+                        feed_forward_w3,
+                        format!("{prefix}.ffn_up.weight"),
+                    )?,
                 })
             } else {
                 let feed_forward_gate_inp =
@@ -425,14 +458,26 @@ impl ModelWeights {
                     let feed_forward_w3 =
                         ct.tensor(reader, &format!("{prefix}.ffn_up.{i}.weight"), device)?;
                     experts.push(Mlp {
-                        feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                        feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                        feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+                        feed_forward_w1: QMatMul::from_qtensor(
+                            feed_forward_w1,
+                            format!("{prefix}.ffn_gate.{i}.weight"),
+                        )?,
+                        feed_forward_w2: QMatMul::from_qtensor(
+                            feed_forward_w2,
+                            format!("{prefix}.ffn_down.{i}.weight"),
+                        )?,
+                        feed_forward_w3: QMatMul::from_qtensor(
+                            feed_forward_w3,
+                            format!("{prefix}.ffn_up.{i}.weight"),
+                        )?,
                     })
                 }
                 MlpOrMoe::MoE {
                     n_expert_used,
-                    feed_forward_gate_inp: QMatMul::from_qtensor(feed_forward_gate_inp)?,
+                    feed_forward_gate_inp: QMatMul::from_qtensor(
+                        feed_forward_gate_inp,
+                        format!("{prefix}.ffn_gate_inp.weight"),
+                    )?,
                     experts,
                 }
             };
@@ -443,10 +488,22 @@ impl ModelWeights {
             let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
             let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
             layers.push(LayerWeights {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_wq: QMatMul::from_qtensor(
+                    attention_wq,
+                    format!("{prefix}.attn_q.weight"),
+                )?,
+                attention_wk: QMatMul::from_qtensor(
+                    attention_wk,
+                    format!("{prefix}.attn_k.weight"),
+                )?,
+                attention_wv: QMatMul::from_qtensor(
+                    attention_wv,
+                    format!("{prefix}.attn_v.weight"),
+                )?,
+                attention_wo: QMatMul::from_qtensor(
+                    attention_wo,
+                    format!("{prefix}.attn_output.weight"),
+                )?,
                 attention_norm: RmsNorm::from_qtensor(attention_norm, rms_norm_eps)?,
                 mlp_or_moe,
                 ffn_norm: RmsNorm::from_qtensor(ffn_norm, rms_norm_eps)?,
@@ -468,24 +525,67 @@ impl ModelWeights {
             tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
             layers,
             norm,
-            output: QMatMul::from_qtensor(output)?,
+            output: QMatMul::from_qtensor(output, "output.weight".to_string())?,
             masks: HashMap::new(),
             span,
             span_output,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+    // The mask for `t` new positions following `index_pos` cached positions, only the masks
+    // without cached positions are kept around as the offset ones are rarely reused.
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        if index_pos == 0 {
+            if let Some(mask) = self.masks.get(&t) {
+                return Ok(mask.clone());
+            }
+        }
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..t + index_pos).map(move |j| u8::from(j > i + index_pos)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, t + index_pos), device)?;
+        if index_pos == 0 {
             self.masks.insert(t, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
+    }
+
+    /// A snapshot of the kv-cache of all the layers, `None` before the first call to `forward`.
+    pub fn kv_state(&self) -> Result<Option<KvState>> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| layer.kv_cache.clone())
+            .collect::<Option<Vec<_>>>();
+        layers.map(|layers| KvState::new(layers, 2)).transpose()
+    }
+
+    /// Restores the kv-cache of all the layers, the next call to `forward` should use
+    /// `state.seq_len()` as `index_pos`.
+    pub fn restore_kv_state(&mut self, state: &KvState) -> Result<()> {
+        if state.layers().len() != self.layers.len() || state.dim() != 2 {
+            candle::bail!(
+                "kv state with {} layers on dim {} for a model with {} layers",
+                state.layers().len(),
+                state.dim(),
+                self.layers.len()
+            )
+        }
+        for (layer, (k, v)) in self.layers.iter_mut().zip(state.layers().iter()) {
+            layer.kv_cache = Some((k.clone(), v.clone()))
+        }
+        Ok(())
+    }
+
+    /// Drops the cached positions past `seq_len`, e.g. to regenerate the last answer.
+    pub fn truncate_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            if let Some((k, v)) = layer.kv_cache.as_mut() {
+                *k = k.narrow(2, 0, seq_len)?;
+                *v = v.narrow(2, 0, seq_len)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
@@ -493,7 +593,7 @@ impl ModelWeights {
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.mask(seq_len, index_pos, x.device())?)
         };
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
@@ -36,6 +36,8 @@ impl Module for Embedding {
 #[derive(Debug, Clone)]
 pub struct Linear {
     inner: candle_nn::Linear,
+    // The weight name, used to record the inputs when computing an importance matrix.
+    name: Option<String>,
     span: tracing::Span,
 }
 
@@ -43,31 +45,45 @@ impl Linear {
     pub fn from_weights(weights: Tensor, bias: Option<Tensor>) -> Self {
         let inner = candle_nn::Linear::new(weights, bias);
         let span = tracing::span!(tracing::Level::TRACE, "linear");
-        Self { inner, span }
+        Self {
+            inner,
+            name: None,
+            span,
+        }
     }
 }
 
+fn weight_name(vb: &VarBuilder) -> String {
+    format!("{}.weight", vb.prefix())
+}
+
 pub fn linear_b(d1: usize, d2: usize, b: bool, vb: VarBuilder) -> Result<Linear> {
+    let name = Some(weight_name(&vb));
     let inner = candle_nn::linear_b(d1, d2, b, vb)?;
     let span = tracing::span!(tracing::Level::TRACE, "linear");
-    Ok(Linear { inner, span })
+    Ok(Linear { inner, name, span })
 }
 
 pub fn linear(d1: usize, d2: usize, vb: VarBuilder) -> Result<Linear> {
+    let name = Some(weight_name(&vb));
     let inner = candle_nn::linear(d1, d2, vb)?;
     let span = tracing::span!(tracing::Level::TRACE, "linear");
-    Ok(Linear { inner, span })
+    Ok(Linear { inner, name, span })
 }
 
 pub fn linear_no_bias(d1: usize, d2: usize, vb: VarBuilder) -> Result<Linear> {
+    let name = Some(weight_name(&vb));
     let inner = candle_nn::linear_no_bias(d1, d2, vb)?;
     let span = tracing::span!(tracing::Level::TRACE, "linear");
-    Ok(Linear { inner, span })
+    Ok(Linear { inner, name, span })
 }
 
 impl Module for Linear {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
+        if let Some(name) = self.name.as_ref() {
+            crate::imatrix::record(name, xs)?
+        }
         self.inner.forward(xs)
     }
 }
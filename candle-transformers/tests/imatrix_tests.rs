@@ -0,0 +1,40 @@
+use candle::quantized::imatrix_file::load_imatrix;
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_transformers::imatrix::{record, Imatrix};
+use candle_transformers::models::with_tracing::linear_no_bias;
+
+#[test]
+fn imatrix_collect() -> Result<()> {
+    let dev = &Device::Cpu;
+    let ws = [(
+        "layer.weight".to_string(),
+        Tensor::ones((2, 3), DType::F32, dev)?,
+    )];
+    let vb = candle_nn::VarBuilder::from_tensors(ws.into_iter().collect(), DType::F32, dev);
+    let linear = linear_no_bias(3, 2, vb.pp("layer"))?;
+
+    let xs = Tensor::new(&[[[1f32, 2., 3.], [-1., 0., 1.]]], dev)?;
+    // Nothing gets recorded outside of `collect`.
+    linear.forward(&xs)?;
+    let mut imatrix = Imatrix::new();
+    assert!(imatrix.is_empty());
+
+    let ys = imatrix.collect(|| linear.forward(&xs))?;
+    assert_eq!(ys.to_vec3::<f32>()?, [[[6., 6.], [0., 0.]]]);
+    imatrix.collect(|| record("other.weight", &Tensor::new(&[[2f32, 4.]], dev)?))?;
+    imatrix.collect(|| linear.forward(&xs.narrow(1, 0, 1)?))?;
+    assert_eq!(imatrix.len(), 2);
+    // Mean of the squares over the 3 rows that went through the layer.
+    assert_eq!(
+        imatrix.get("layer.weight"),
+        Some(vec![1., 8. / 3., 19. / 3.])
+    );
+    assert_eq!(imatrix.get("other.weight"), Some(vec![4., 16.]));
+
+    let tmp_file = std::env::temp_dir().join("candle-imatrix-test.dat");
+    imatrix.save(&tmp_file, "test")?;
+    let loaded = load_imatrix(&tmp_file)?;
+    std::fs::remove_file(&tmp_file)?;
+    assert_eq!(loaded, imatrix.weights());
+    Ok(())
+}
@@ -0,0 +1,578 @@
+//! Conversion of the Hugging Face model files to gguf: the model hyper-parameters from
+//! `config.json` and the vocabulary from `tokenizer.json` are stored as gguf metadata using the
+//! same keys as the llama.cpp converter, and the tensors are renamed to the gguf naming scheme.
+use candle::quantized::gguf_file::Value;
+use candle::{Device, Result, Tensor};
+use serde_json::Value as Json;
+use std::path::Path;
+
+/// Maps the tensor names used by the llama models in the safetensors format to the gguf ones,
+/// e.g. `model.layers.0.self_attn.q_proj.weight` to `blk.0.attn_q.weight`.
+pub fn gguf_tensor_name(name: &str) -> Option<String> {
+    match name {
+        "model.embed_tokens.weight" => return Some("token_embd.weight".to_string()),
+        "model.norm.weight" => return Some("output_norm.weight".to_string()),
+        "lm_head.weight" => return Some("output.weight".to_string()),
+        _ => {}
+    }
+    let name = name.strip_prefix("model.layers.")?;
+    let (layer_idx, name) = name.split_once('.')?;
+    let layer_idx: usize = layer_idx.parse().ok()?;
+    let (name, suffix) = name.rsplit_once('.')?;
+    let gguf_name = match name {
+        "self_attn.q_proj" => "attn_q",
+        "self_attn.k_proj" => "attn_k",
+        "self_attn.v_proj" => "attn_v",
+        "self_attn.o_proj" => "attn_output",
+        "self_attn.q_norm" => "attn_q_norm",
+        "self_attn.k_norm" => "attn_k_norm",
+        "mlp.gate_proj" => "ffn_gate",
+        "mlp.up_proj" => "ffn_up",
+        "mlp.down_proj" => "ffn_down",
+        "input_layernorm" => "attn_norm",
+        "post_attention_layernorm" => "ffn_norm",
+        _ => return None,
+    };
+    Some(format!("blk.{layer_idx}.{gguf_name}.{suffix}"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    /// Also used for the mistral models.
+    Llama,
+    Qwen2,
+    Qwen3,
+}
+
+impl Architecture {
+    fn from_model_type(model_type: &str) -> Result<Self> {
+        let arch = match model_type {
+            "llama" | "mistral" => Self::Llama,
+            "qwen2" => Self::Qwen2,
+            "qwen3" => Self::Qwen3,
+            _ => candle::bail!("unsupported model type {model_type}"),
+        };
+        Ok(arch)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Llama => "llama",
+            Self::Qwen2 => "qwen2",
+            Self::Qwen3 => "qwen3",
+        }
+    }
+}
+
+fn read_json<P: AsRef<Path>>(p: P) -> Result<Json> {
+    let p = p.as_ref();
+    let file = std::fs::File::open(p).map_err(|e| candle::Error::from(e).with_path(p))?;
+    serde_json::from_reader(std::io::BufReader::new(file)).map_err(candle::Error::wrap)
+}
+
+fn get_usize(json: &Json, key: &str) -> Result<Option<usize>> {
+    match json.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(v) => match v.as_u64() {
+            Some(v) => Ok(Some(v as usize)),
+            None => candle::bail!("unexpected value for {key}: {v}"),
+        },
+    }
+}
+
+fn get_f64(json: &Json, key: &str) -> Result<Option<f64>> {
+    match json.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(v) => match v.as_f64() {
+            Some(v) => Ok(Some(v)),
+            None => candle::bail!("unexpected value for {key}: {v}"),
+        },
+    }
+}
+
+fn required<T>(v: Result<Option<T>>, key: &str) -> Result<T> {
+    match v? {
+        None => candle::bail!("missing {key} in config.json"),
+        Some(v) => Ok(v),
+    }
+}
+
+/// Reorders the rows of the query and key projections so that the rotary embeddings apply to
+/// consecutive pairs of values rather than to the two halves of each head, this is the layout
+/// expected for the llama architecture in gguf files.
+pub fn permute_qk(w: &Tensor, n_head: usize) -> Result<Tensor> {
+    let dims = w.dims();
+    if dims.is_empty() || !dims[0].is_multiple_of(2 * n_head) {
+        candle::bail!(
+            "cannot permute a tensor of shape {:?} for {n_head} heads",
+            w.shape()
+        )
+    }
+    let mut new_dims = vec![n_head, 2, dims[0] / n_head / 2];
+    new_dims.extend_from_slice(&dims[1..]);
+    w.reshape(new_dims)?.transpose(1, 2)?.reshape(dims)
+}
+
+/// The llama3 rope scaling, the frequencies are divided by the factors from the
+/// `rope_freqs.weight` tensor.
+fn llama3_rope_freqs(rope_scaling: &Json, head_dim: usize, rope_theta: f64) -> Result<Tensor> {
+    let factor = get_f64(rope_scaling, "factor")?.unwrap_or(8.);
+    let low_freq_factor = get_f64(rope_scaling, "low_freq_factor")?.unwrap_or(1.);
+    let high_freq_factor = get_f64(rope_scaling, "high_freq_factor")?.unwrap_or(4.);
+    let old_context_len =
+        get_usize(rope_scaling, "original_max_position_embeddings")?.unwrap_or(8192) as f64;
+    let low_freq_wavelen = old_context_len / low_freq_factor;
+    let high_freq_wavelen = old_context_len / high_freq_factor;
+    let factors = (0..head_dim)
+        .step_by(2)
+        .map(|i| {
+            let freq = 1. / rope_theta.powf(i as f64 / head_dim as f64);
+            let wavelen = 2. * std::f64::consts::PI / freq;
+            let factor = if wavelen < high_freq_wavelen {
+                1.
+            } else if wavelen > low_freq_wavelen {
+                factor
+            } else {
+                let smooth = (old_context_len / wavelen - low_freq_factor)
+                    / (high_freq_factor - low_freq_factor);
+                1. / ((1. - smooth) / factor + smooth)
+            };
+            factor as f32
+        })
+        .collect::<Vec<_>>();
+    Tensor::new(factors, &Device::Cpu)
+}
+
+/// The gguf token types.
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_UNKNOWN: i32 = 2;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+const TOKEN_TYPE_UNUSED: i32 = 5;
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+/// Returns the `tokenizer.ggml.*` metadata for a `tokenizer.json` file. Byte-fallback BPE
+/// tokenizers are converted to the sentencepiece based "llama" model, the other BPE tokenizers
+/// to the byte-level "gpt2" model.
+fn tokenizer_metadata(
+    tokenizer: &Json,
+    arch: Architecture,
+    config: &Json,
+) -> Result<Vec<(String, Value)>> {
+    let model = &tokenizer["model"];
+    if model["type"].as_str() != Some("BPE") {
+        candle::bail!("unsupported tokenizer model {}", model["type"])
+    }
+    let is_spm = model["byte_fallback"].as_bool().unwrap_or(false);
+    let vocab = match model["vocab"].as_object() {
+        Some(vocab) => vocab,
+        None => candle::bail!("no vocab in tokenizer.json"),
+    };
+    let added_tokens = tokenizer["added_tokens"]
+        .as_array()
+        .map(|v| v.as_slice())
+        .unwrap_or_default();
+
+    let mut tokens: Vec<Option<(String, i32)>> = vec![];
+    let mut set_token = |id: u64, token: &str, token_type: i32| {
+        let id = id as usize;
+        if tokens.len() <= id {
+            tokens.resize(id + 1, None)
+        }
+        tokens[id] = Some((token.to_string(), token_type))
+    };
+    let unk_token = model["unk_token"].as_str();
+    for (token, id) in vocab.iter() {
+        let id = match id.as_u64() {
+            Some(id) => id,
+            None => candle::bail!("unexpected id for {token}: {id}"),
+        };
+        let token_type = if Some(token.as_str()) == unk_token {
+            TOKEN_TYPE_UNKNOWN
+        } else if is_spm && token.len() == 6 && token.starts_with("<0x") && token.ends_with('>') {
+            TOKEN_TYPE_BYTE
+        } else {
+            TOKEN_TYPE_NORMAL
+        };
+        set_token(id, token, token_type)
+    }
+    for added_token in added_tokens.iter() {
+        let (id, content) = match (added_token["id"].as_u64(), added_token["content"].as_str()) {
+            (Some(id), Some(content)) => (id, content),
+            _ => candle::bail!("unexpected added token {added_token}"),
+        };
+        let token_type = if added_token["special"].as_bool().unwrap_or(false) {
+            TOKEN_TYPE_CONTROL
+        } else {
+            TOKEN_TYPE_USER_DEFINED
+        };
+        set_token(id, content, token_type)
+    }
+    let vocab_size = get_usize(config, "vocab_size")?.unwrap_or(0);
+    if tokens.len() < vocab_size {
+        tokens.resize(vocab_size, None)
+    }
+    let (tokens, token_types): (Vec<_>, Vec<_>) = tokens
+        .into_iter()
+        .enumerate()
+        .map(|(id, token)| match token {
+            Some((token, token_type)) => (Value::String(token), Value::I32(token_type)),
+            None => (
+                Value::String(format!("[PAD{id}]")),
+                Value::I32(TOKEN_TYPE_UNUSED),
+            ),
+        })
+        .unzip();
+    let token_id = |token: &str| {
+        tokens
+            .iter()
+            .position(|t| matches!(t, Value::String(t) if t == token))
+    };
+
+    let mut metadata = vec![];
+    if is_spm {
+        metadata.push(("tokenizer.ggml.model", Value::String("llama".to_string())));
+        metadata.push(("tokenizer.ggml.pre", Value::String("default".to_string())));
+        // The sentencepiece vocabularies are sorted by decreasing score, so the negated ids
+        // preserve the merge priorities.
+        let scores = (0..tokens.len()).map(|i| Value::F32(-(i as f32))).collect();
+        metadata.push(("tokenizer.ggml.scores", Value::Array(scores)));
+    } else {
+        let pre = match arch {
+            Architecture::Llama => "llama-bpe",
+            Architecture::Qwen2 | Architecture::Qwen3 => "qwen2",
+        };
+        metadata.push(("tokenizer.ggml.model", Value::String("gpt2".to_string())));
+        metadata.push(("tokenizer.ggml.pre", Value::String(pre.to_string())));
+        let merges = model["merges"].as_array().map(|v| v.as_slice());
+        let merges = merges
+            .unwrap_or_default()
+            .iter()
+            .map(|merge| match merge {
+                Json::String(merge) => Ok(Value::String(merge.to_string())),
+                // Recent versions of tokenizers store the merges as pairs.
+                Json::Array(pair) => match (pair.first(), pair.get(1)) {
+                    (Some(Json::String(a)), Some(Json::String(b))) => {
+                        Ok(Value::String(format!("{a} {b}")))
+                    }
+                    _ => candle::bail!("unexpected merge {merge}"),
+                },
+                _ => candle::bail!("unexpected merge {merge}"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        metadata.push(("tokenizer.ggml.merges", Value::Array(merges)));
+    }
+
+    // The special token ids are taken from config.json, except for the unknown token.
+    let eos_token_id = match config.get("eos_token_id") {
+        Some(Json::Array(ids)) => ids.first().and_then(|v| v.as_u64()),
+        Some(id) => id.as_u64(),
+        None => None,
+    };
+    let bos_token_id = get_usize(config, "bos_token_id")?;
+    let special_tokens = [
+        ("tokenizer.ggml.bos_token_id", bos_token_id),
+        (
+            "tokenizer.ggml.eos_token_id",
+            eos_token_id.map(|v| v as usize),
+        ),
+        (
+            "tokenizer.ggml.unknown_token_id",
+            unk_token.and_then(token_id),
+        ),
+        (
+            "tokenizer.ggml.padding_token_id",
+            get_usize(config, "pad_token_id")?,
+        ),
+    ];
+    for (key, id) in special_tokens {
+        if let Some(id) = id {
+            metadata.push((key, Value::U32(id as u32)))
+        }
+    }
+    // The bos token is added if it appears in the post-processing template.
+    if let Some(Value::String(bos)) = bos_token_id.and_then(|id| tokens.get(id)) {
+        let add_bos = tokenizer["post_processor"]
+            .to_string()
+            .contains(bos.as_str());
+        metadata.push(("tokenizer.ggml.add_bos_token", Value::Bool(add_bos)));
+    }
+    metadata.push(("tokenizer.ggml.tokens", Value::Array(tokens)));
+    metadata.push(("tokenizer.ggml.token_type", Value::Array(token_types)));
+    Ok(metadata
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect())
+}
+
+/// A Hugging Face model configuration, used to convert the safetensors weights to gguf.
+#[derive(Debug)]
+pub struct HfModel {
+    arch: Architecture,
+    n_head: usize,
+    n_head_kv: usize,
+    metadata: Vec<(String, Value)>,
+    extra_tensors: Vec<(String, Tensor)>,
+}
+
+impl HfModel {
+    /// Reads `config.json` and, if provided, `tokenizer.json`. The chat template is read from the
+    /// `tokenizer_config.json` file in the same directory as `tokenizer.json` if it exists.
+    pub fn load(config: &Path, tokenizer: Option<&Path>) -> Result<Self> {
+        let cfg = read_json(config)?;
+        let model_type = match cfg["model_type"].as_str() {
+            None => candle::bail!("missing model_type in {config:?}"),
+            Some(model_type) => model_type,
+        };
+        let arch = Architecture::from_model_type(model_type)?;
+        let a = arch.name();
+
+        let hidden_size = required(get_usize(&cfg, "hidden_size"), "hidden_size")?;
+        let n_head = required(
+            get_usize(&cfg, "num_attention_heads"),
+            "num_attention_heads",
+        )?;
+        let n_head_kv = get_usize(&cfg, "num_key_value_heads")?.unwrap_or(n_head);
+        let head_dim = get_usize(&cfg, "head_dim")?.unwrap_or(hidden_size / n_head);
+        let rope_theta = get_f64(&cfg, "rope_theta")?.unwrap_or(10000.);
+        let u32_value = |key: &str| -> Result<Value> {
+            Ok(Value::U32(required(get_usize(&cfg, key), key)? as u32))
+        };
+
+        let name = match cfg["_name_or_path"].as_str() {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => config
+                .canonicalize()?
+                .parent()
+                .and_then(|p| p.file_name())
+                .map_or_else(|| a.to_string(), |p| p.to_string_lossy().into_owned()),
+        };
+        let mut metadata = vec![
+            (
+                "general.architecture".to_string(),
+                Value::String(a.to_string()),
+            ),
+            ("general.name".to_string(), Value::String(name)),
+            ("general.quantization_version".to_string(), Value::U32(2)),
+            (
+                format!("{a}.context_length"),
+                u32_value("max_position_embeddings")?,
+            ),
+            (
+                format!("{a}.embedding_length"),
+                Value::U32(hidden_size as u32),
+            ),
+            (format!("{a}.block_count"), u32_value("num_hidden_layers")?),
+            (
+                format!("{a}.feed_forward_length"),
+                u32_value("intermediate_size")?,
+            ),
+            (
+                format!("{a}.attention.head_count"),
+                Value::U32(n_head as u32),
+            ),
+            (
+                format!("{a}.attention.head_count_kv"),
+                Value::U32(n_head_kv as u32),
+            ),
+            (
+                format!("{a}.attention.key_length"),
+                Value::U32(head_dim as u32),
+            ),
+            (
+                format!("{a}.attention.value_length"),
+                Value::U32(head_dim as u32),
+            ),
+            (
+                format!("{a}.attention.layer_norm_rms_epsilon"),
+                Value::F32(required(get_f64(&cfg, "rms_norm_eps"), "rms_norm_eps")? as f32),
+            ),
+            (
+                format!("{a}.rope.dimension_count"),
+                Value::U32(head_dim as u32),
+            ),
+            (format!("{a}.rope.freq_base"), Value::F32(rope_theta as f32)),
+        ];
+        if let Some(vocab_size) = get_usize(&cfg, "vocab_size")? {
+            metadata.push((format!("{a}.vocab_size"), Value::U32(vocab_size as u32)))
+        }
+
+        let mut extra_tensors = vec![];
+        let rope_scaling = &cfg["rope_scaling"];
+        if !rope_scaling.is_null() {
+            let rope_type = rope_scaling["rope_type"]
+                .as_str()
+                .or(rope_scaling["type"].as_str());
+            match rope_type {
+                Some("llama3") if arch == Architecture::Llama => {
+                    let rope_freqs = llama3_rope_freqs(rope_scaling, head_dim, rope_theta)?;
+                    extra_tensors.push(("rope_freqs.weight".to_string(), rope_freqs))
+                }
+                Some(rope_type @ ("linear" | "yarn")) => {
+                    let factor = required(get_f64(rope_scaling, "factor"), "rope_scaling.factor")?;
+                    metadata.push((
+                        format!("{a}.rope.scaling.type"),
+                        Value::String(rope_type.to_string()),
+                    ));
+                    metadata.push((
+                        format!("{a}.rope.scaling.factor"),
+                        Value::F32(factor as f32),
+                    ));
+                    let original = "original_max_position_embeddings";
+                    if let Some(original) = get_usize(rope_scaling, original)? {
+                        metadata.push((
+                            format!("{a}.rope.scaling.original_context_length"),
+                            Value::U32(original as u32),
+                        ))
+                    }
+                }
+                rope_type => candle::bail!("unsupported rope scaling {rope_type:?}"),
+            }
+        }
+
+        if let Some(tokenizer) = tokenizer {
+            let tokenizer_json = read_json(tokenizer)?;
+            metadata.extend(tokenizer_metadata(&tokenizer_json, arch, &cfg)?);
+            let tokenizer_config = tokenizer.with_file_name("tokenizer_config.json");
+            if tokenizer_config.exists() {
+                let tokenizer_config = read_json(tokenizer_config)?;
+                if let Some(chat_template) = tokenizer_config["chat_template"].as_str() {
+                    metadata.push((
+                        "tokenizer.chat_template".to_string(),
+                        Value::String(chat_template.to_string()),
+                    ))
+                }
+            }
+        }
+        Ok(Self {
+            arch,
+            n_head,
+            n_head_kv,
+            metadata,
+            extra_tensors,
+        })
+    }
+
+    pub fn metadata(&self) -> &[(String, Value)] {
+        &self.metadata
+    }
+
+    /// Tensors that are not part of the safetensors weights but are expected in the gguf file,
+    /// e.g. `rope_freqs.weight` for the llama3 rope scaling.
+    pub fn extra_tensors(&self) -> &[(String, Tensor)] {
+        &self.extra_tensors
+    }
+
+    /// Returns the gguf name for a safetensors tensor together with the converted weights, or
+    /// `None` for tensors that are not used in gguf files, e.g. `rotary_emb.inv_freq`.
+    pub fn convert_tensor(&self, name: &str, tensor: Tensor) -> Result<Option<(String, Tensor)>> {
+        let gguf_name = match gguf_tensor_name(name) {
+            None => return Ok(None),
+            Some(gguf_name) => gguf_name,
+        };
+        let tensor = match self.arch {
+            Architecture::Llama if name.contains("self_attn.q_proj.") => {
+                permute_qk(&tensor, self.n_head)?
+            }
+            Architecture::Llama if name.contains("self_attn.k_proj.") => {
+                permute_qk(&tensor, self.n_head_kv)?
+            }
+            _ => tensor,
+        };
+        Ok(Some((gguf_name, tensor)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permute_qk_rows() -> Result<()> {
+        // Within each head, the rows from the first half are interleaved with the second half.
+        let w = Tensor::arange(0u32, 8, &Device::Cpu)?.reshape((8, 1))?;
+        let w = permute_qk(&w, 2)?;
+        assert_eq!(w.dims(), [8, 1]);
+        assert_eq!(w.flatten_all()?.to_vec1::<u32>()?, [0, 2, 1, 3, 4, 6, 5, 7]);
+        let w = Tensor::zeros((6, 2), candle::DType::F32, &Device::Cpu)?;
+        assert!(permute_qk(&w, 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn llama3_rope_scaling() -> Result<()> {
+        // With a head dim of 8 and theta 10000, the wavelengths are 2π, 20π, 200π and 2000π so
+        // that each of the three cases is covered with a 64 context length.
+        let rope_scaling = serde_json::json!({
+            "factor": 8.0,
+            "low_freq_factor": 1.0,
+            "high_freq_factor": 4.0,
+            "original_max_position_embeddings": 64,
+            "rope_type": "llama3"
+        });
+        let freqs = llama3_rope_freqs(&rope_scaling, 8, 10000.)?.to_vec1::<f32>()?;
+        assert_eq!(freqs.len(), 4);
+        assert_eq!(freqs[0], 1.);
+        assert!((freqs[1] - 7.6674).abs() < 1e-4, "{freqs:?}");
+        assert_eq!(freqs[2..], [8., 8.]);
+        Ok(())
+    }
+
+    #[test]
+    fn spm_tokenizer() -> Result<()> {
+        let tokenizer = serde_json::json!({
+            "added_tokens": [
+                {"id": 1, "content": "<s>", "special": true},
+                {"id": 2, "content": "</s>", "special": true}
+            ],
+            "post_processor": {"single": [{"SpecialToken": {"id": "<s>"}}]},
+            "model": {
+                "type": "BPE",
+                "byte_fallback": true,
+                "unk_token": "<unk>",
+                "vocab": {"<unk>": 0, "<s>": 1, "</s>": 2, "<0x00>": 3, "▁a": 4},
+                "merges": []
+            }
+        });
+        let config = serde_json::json!({"vocab_size": 6, "bos_token_id": 1, "eos_token_id": 2});
+        let metadata = tokenizer_metadata(&tokenizer, Architecture::Llama, &config)?;
+        let get = |key: &str| match metadata.iter().find(|(k, _)| k == key) {
+            None => candle::bail!("missing {key}"),
+            Some((_, v)) => Ok(v),
+        };
+        assert_eq!(get("tokenizer.ggml.model")?.to_string()?, "llama");
+        let tokens = get("tokenizer.ggml.tokens")?.to_vec()?;
+        let tokens = tokens
+            .iter()
+            .map(|v| v.to_string().map(|v| v.as_str()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(tokens, ["<unk>", "<s>", "</s>", "<0x00>", "▁a", "[PAD5]"]);
+        let scores = get("tokenizer.ggml.scores")?.to_vec()?;
+        let scores = scores
+            .iter()
+            .map(|v| v.to_f32())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(scores, [0., -1., -2., -3., -4., -5.]);
+        let token_types = get("tokenizer.ggml.token_type")?.to_vec()?;
+        let token_types = token_types
+            .iter()
+            .map(|v| v.to_i32())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            token_types,
+            [
+                TOKEN_TYPE_UNKNOWN,
+                TOKEN_TYPE_CONTROL,
+                TOKEN_TYPE_CONTROL,
+                TOKEN_TYPE_BYTE,
+                TOKEN_TYPE_NORMAL,
+                TOKEN_TYPE_UNUSED,
+            ]
+        );
+        assert_eq!(get("tokenizer.ggml.unknown_token_id")?.to_u32()?, 0);
+        assert_eq!(get("tokenizer.ggml.bos_token_id")?.to_u32()?, 1);
+        assert_eq!(get("tokenizer.ggml.eos_token_id")?.to_u32()?, 2);
+        assert!(get("tokenizer.ggml.add_bos_token")?.to_bool()?);
+        Ok(())
+    }
+}
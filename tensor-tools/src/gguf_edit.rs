@@ -0,0 +1,611 @@
+//! Edition of gguf files: metadata changes, tensor renames, splitting into shards and merging
+//! them back. The tensor data is always copied as is from the input files, without being
+//! dequantized nor fully loaded in memory.
+use candle::quantized::gguf_file::{self, Content, TensorInfo, Value};
+use candle::Result;
+use clap::{Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+
+const SPLIT_NO: &str = "split.no";
+const SPLIT_COUNT: &str = "split.count";
+const SPLIT_TENSORS_COUNT: &str = "split.tensors.count";
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum MetadataCommand {
+    /// Print the values for the given keys, or all the metadata if no key is specified.
+    Get { file: PathBuf, keys: Vec<String> },
+
+    /// Set the value for a key, the file is modified in place unless --out-file is used.
+    Set {
+        file: PathBuf,
+
+        key: String,
+
+        #[arg(required_unless_present = "value_file", conflicts_with = "value_file")]
+        value: Option<String>,
+
+        /// Read the value from a file, e.g. for chat templates.
+        #[arg(long)]
+        value_file: Option<PathBuf>,
+
+        /// The type of the value, defaults to the type of the existing value if any and to
+        /// string otherwise. For arrays, this is the type of the elements.
+        #[arg(long, value_enum)]
+        value_type: Option<MetadataType>,
+
+        /// The value is a JSON array, e.g. '["a", "b"]', this is the default when the existing
+        /// value is an array.
+        #[arg(long)]
+        array: bool,
+
+        #[arg(long)]
+        out_file: Option<PathBuf>,
+    },
+
+    /// Remove some keys, the file is modified in place unless --out-file is used.
+    Delete {
+        file: PathBuf,
+
+        #[arg(required = true)]
+        keys: Vec<String>,
+
+        #[arg(long)]
+        out_file: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    Bool,
+    String,
+}
+
+fn parse<T>(value: &str, value_type: MetadataType) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|err| {
+        candle::Error::msg(format!("cannot parse {value:?} as {value_type:?}: {err}"))
+    })
+}
+
+impl MetadataType {
+    fn of_value(value: &Value) -> Option<Self> {
+        let value_type = match value {
+            Value::U8(_) => Self::U8,
+            Value::I8(_) => Self::I8,
+            Value::U16(_) => Self::U16,
+            Value::I16(_) => Self::I16,
+            Value::U32(_) => Self::U32,
+            Value::I32(_) => Self::I32,
+            Value::U64(_) => Self::U64,
+            Value::I64(_) => Self::I64,
+            Value::F32(_) => Self::F32,
+            Value::F64(_) => Self::F64,
+            Value::Bool(_) => Self::Bool,
+            Value::String(_) => Self::String,
+            Value::Array(_) => return None,
+        };
+        Some(value_type)
+    }
+
+    fn parse(self, v: &str) -> Result<Value> {
+        let value = match self {
+            Self::U8 => Value::U8(parse(v, self)?),
+            Self::I8 => Value::I8(parse(v, self)?),
+            Self::U16 => Value::U16(parse(v, self)?),
+            Self::I16 => Value::I16(parse(v, self)?),
+            Self::U32 => Value::U32(parse(v, self)?),
+            Self::I32 => Value::I32(parse(v, self)?),
+            Self::U64 => Value::U64(parse(v, self)?),
+            Self::I64 => Value::I64(parse(v, self)?),
+            Self::F32 => Value::F32(parse(v, self)?),
+            Self::F64 => Value::F64(parse(v, self)?),
+            Self::Bool => Value::Bool(parse(v, self)?),
+            Self::String => Value::String(v.to_string()),
+        };
+        Ok(value)
+    }
+}
+
+/// Parses a metadata value, `value_type` and `array` default to the ones of the previous value
+/// for the key if any.
+fn parse_value(
+    key: &str,
+    value: &str,
+    value_type: Option<MetadataType>,
+    array: bool,
+    prev: Option<&Value>,
+) -> Result<Value> {
+    let (value_type, array) = match (value_type, prev) {
+        (Some(value_type), Some(Value::Array(_))) => (value_type, true),
+        (Some(value_type), _) => (value_type, array),
+        (None, None) => (MetadataType::String, array),
+        (None, Some(Value::Array(vs))) => match vs.first() {
+            None => (MetadataType::String, true),
+            Some(v) => match MetadataType::of_value(v) {
+                Some(value_type) => (value_type, true),
+                None => candle::bail!("setting nested array values is not supported, {key}"),
+            },
+        },
+        (None, Some(prev)) => match MetadataType::of_value(prev) {
+            Some(value_type) => (value_type, array),
+            None => candle::bail!("unsupported value type for {key}"),
+        },
+    };
+    if !array {
+        return value_type.parse(value);
+    }
+    let values: Vec<serde_json::Value> = serde_json::from_str(value)
+        .map_err(|err| candle::Error::msg(format!("{key} expects a JSON array: {err}")))?;
+    let values = values
+        .iter()
+        .map(|v| match v {
+            serde_json::Value::String(v) => value_type.parse(v),
+            serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+                candle::bail!("setting nested array values is not supported, {key}")
+            }
+            v => value_type.parse(&v.to_string()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Value::Array(values))
+}
+
+/// A tensor to be copied from one of the input files.
+struct TensorSource<'a> {
+    name: String,
+    file_idx: usize,
+    info: &'a TensorInfo,
+    tensor_data_offset: u64,
+}
+
+fn read_content(path: &Path) -> Result<(std::fs::File, Content)> {
+    let mut file = std::fs::File::open(path)?;
+    let content = Content::read(&mut file).map_err(|e| e.with_path(path))?;
+    Ok((file, content))
+}
+
+/// The tensors from `content` in the order in which they are stored in the file.
+fn tensors(content: &Content, file_idx: usize) -> Vec<TensorSource<'_>> {
+    let mut tensors = content
+        .tensor_infos
+        .iter()
+        .map(|(name, info)| TensorSource {
+            name: name.to_string(),
+            file_idx,
+            info,
+            tensor_data_offset: content.tensor_data_offset,
+        })
+        .collect::<Vec<_>>();
+    tensors.sort_by_key(|t| t.info.offset);
+    tensors
+}
+
+/// The metadata sorted by key, the split related keys are excluded.
+fn metadata(content: &Content) -> Vec<(&str, &Value)> {
+    let mut metadata = content
+        .metadata
+        .iter()
+        .filter(|(k, _)| !k.starts_with("split."))
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    metadata.sort_by(|a, b| a.0.cmp(b.0));
+    metadata
+}
+
+fn write_gguf(
+    out_file: &Path,
+    metadata: &[(&str, &Value)],
+    tensors: &[TensorSource],
+    files: &mut [std::fs::File],
+) -> Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(out_file)?);
+    let shapes = tensors
+        .iter()
+        .map(|t| (t.name.as_str(), t.info.ggml_dtype, &t.info.shape))
+        .collect::<Vec<_>>();
+    gguf_file::write_with(&mut out, metadata, &shapes, |idx, w| {
+        let t = &tensors[idx];
+        t.info
+            .copy_data(&mut files[t.file_idx], t.tensor_data_offset, w)
+    })?;
+    std::io::Write::flush(&mut out)?;
+    Ok(())
+}
+
+/// Runs `f` with the path of the file to write. When no output file is specified, `f` writes to
+/// a temporary file which replaces the input file once `f` has succeeded.
+fn with_out_file<F>(in_file: &Path, out_file: Option<&Path>, f: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    match out_file {
+        Some(out_file) if out_file != in_file => f(out_file),
+        _ => {
+            let mut tmp_file = in_file.as_os_str().to_owned();
+            tmp_file.push(".tmp");
+            let tmp_file = PathBuf::from(tmp_file);
+            if let Err(err) = f(&tmp_file) {
+                let _ = std::fs::remove_file(&tmp_file);
+                return Err(err);
+            }
+            std::fs::rename(&tmp_file, in_file)?;
+            Ok(())
+        }
+    }
+}
+
+fn print_value(value: &Value) {
+    match value {
+        Value::String(v) => println!("{v}"),
+        v => println!("{v:?}"),
+    }
+}
+
+pub fn run_metadata(command: MetadataCommand) -> Result<()> {
+    match command {
+        MetadataCommand::Get { file, keys } => {
+            let (_, content) = read_content(&file)?;
+            if keys.is_empty() {
+                let mut metadata = content.metadata.iter().collect::<Vec<_>>();
+                metadata.sort_by(|a, b| a.0.cmp(b.0));
+                for (key, value) in metadata.iter() {
+                    println!("{key}: {value:?}")
+                }
+            }
+            for key in keys.iter() {
+                let value = match content.metadata.get(key) {
+                    None => candle::bail!("no metadata for {key} in {file:?}"),
+                    Some(value) => value,
+                };
+                if keys.len() > 1 {
+                    print!("{key}: ")
+                }
+                print_value(value)
+            }
+        }
+        MetadataCommand::Set {
+            file,
+            key,
+            value,
+            value_file,
+            value_type,
+            array,
+            out_file,
+        } => with_out_file(&file, out_file.as_deref(), |out_file| {
+            let (in_file, content) = read_content(&file)?;
+            let value = match (value, value_file) {
+                (Some(value), _) => value,
+                (None, Some(value_file)) => std::fs::read_to_string(value_file)?,
+                (None, None) => candle::bail!("no value specified for {key}"),
+            };
+            let prev = content.metadata.get(&key);
+            let value = parse_value(&key, &value, value_type, array, prev)?;
+            let mut metadata = content
+                .metadata
+                .iter()
+                .filter(|(k, _)| *k != &key)
+                .map(|(k, v)| (k.as_str(), v))
+                .collect::<Vec<_>>();
+            metadata.push((key.as_str(), &value));
+            metadata.sort_by(|a, b| a.0.cmp(b.0));
+            write_gguf(out_file, &metadata, &tensors(&content, 0), &mut [in_file])
+        })?,
+        MetadataCommand::Delete {
+            file,
+            keys,
+            out_file,
+        } => with_out_file(&file, out_file.as_deref(), |out_file| {
+            let (in_file, content) = read_content(&file)?;
+            for key in keys.iter() {
+                if !content.metadata.contains_key(key) {
+                    candle::bail!("no metadata for {key} in {file:?}")
+                }
+            }
+            let mut metadata = content
+                .metadata
+                .iter()
+                .filter(|(k, _)| !keys.contains(k))
+                .map(|(k, v)| (k.as_str(), v))
+                .collect::<Vec<_>>();
+            metadata.sort_by(|a, b| a.0.cmp(b.0));
+            write_gguf(out_file, &metadata, &tensors(&content, 0), &mut [in_file])
+        })?,
+    }
+    Ok(())
+}
+
+fn rename_tensors<'a>(
+    content: &'a Content,
+    re: &fancy_regex::Regex,
+    replacement: &str,
+) -> Result<Vec<TensorSource<'a>>> {
+    let mut tensors = tensors(content, 0);
+    let mut names = std::collections::HashSet::new();
+    for tensor in tensors.iter_mut() {
+        let new_name = re.replace_all(&tensor.name, replacement).to_string();
+        if new_name != tensor.name {
+            println!("{} -> {new_name}", tensor.name);
+            tensor.name = new_name
+        }
+        if !names.insert(tensor.name.clone()) {
+            candle::bail!("multiple tensors would be named {}", tensor.name)
+        }
+    }
+    Ok(tensors)
+}
+
+/// Renames the tensors matching `pattern`, `replacement` can refer to the capture groups using
+/// `$1`, `$name` etc.
+pub fn run_rename(
+    file: &Path,
+    pattern: &str,
+    replacement: &str,
+    out_file: Option<&Path>,
+    dry_run: bool,
+) -> Result<()> {
+    let re = fancy_regex::Regex::new(pattern).map_err(candle::Error::wrap)?;
+    if dry_run {
+        let (_, content) = read_content(file)?;
+        rename_tensors(&content, &re, replacement)?;
+        return Ok(());
+    }
+    with_out_file(file, out_file, |out_file| {
+        let (in_file, content) = read_content(file)?;
+        let tensors = rename_tensors(&content, &re, replacement)?;
+        let metadata = content
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.as_str(), v))
+            .collect::<Vec<_>>();
+        write_gguf(out_file, &metadata, &tensors, &mut [in_file])
+    })
+}
+
+/// Parses a size in bytes with an optional K, M, or G suffix (powers of 1024).
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let (s, multiplier) = match s.char_indices().last() {
+        Some((idx, 'K' | 'k')) => (&s[..idx], 1 << 10),
+        Some((idx, 'M' | 'm')) => (&s[..idx], 1 << 20),
+        Some((idx, 'G' | 'g')) => (&s[..idx], 1 << 30),
+        _ => (s, 1),
+    };
+    match s.parse::<f64>() {
+        Ok(v) if v > 0. => Ok((v * multiplier as f64) as u64),
+        _ => Err(format!(
+            "invalid size {s:?}, expected a number with an optional K/M/G suffix"
+        )),
+    }
+}
+
+fn shard_path(prefix: &str, shard_idx: usize, shard_count: usize) -> PathBuf {
+    PathBuf::from(format!(
+        "{prefix}-{:05}-of-{shard_count:05}.gguf",
+        shard_idx + 1
+    ))
+}
+
+/// Splits a gguf file in shards using the llama.cpp naming scheme, `{prefix}-00001-of-00003.gguf`.
+/// The first shard holds all the metadata, the others only hold the split related keys.
+pub fn run_split(
+    in_file: &Path,
+    out_prefix: Option<&Path>,
+    max_tensors: Option<usize>,
+    max_size: Option<u64>,
+) -> Result<()> {
+    let (max_tensors, max_size) = match (max_tensors, max_size) {
+        (Some(_), Some(_)) => candle::bail!("only one of max-tensors and max-size can be set"),
+        (None, None) => (128, u64::MAX),
+        (max_tensors, max_size) => (
+            max_tensors.unwrap_or(usize::MAX),
+            max_size.unwrap_or(u64::MAX),
+        ),
+    };
+    if max_tensors == 0 {
+        candle::bail!("max-tensors should be positive")
+    }
+    let prefix = match out_prefix {
+        Some(prefix) => prefix.to_path_buf(),
+        None => in_file.with_extension(""),
+    };
+    let prefix = prefix.to_string_lossy();
+    let (in_file, content) = read_content(in_file)?;
+    let tensors = tensors(&content, 0);
+    let n_tensors = tensors.len();
+
+    let mut shards: Vec<Vec<TensorSource>> = vec![];
+    let mut shard_size = 0u64;
+    for tensor in tensors.into_iter() {
+        let size = tensor.info.size_in_bytes()? as u64;
+        match shards.last_mut() {
+            Some(shard) if shard.len() < max_tensors && shard_size + size <= max_size => {
+                shard_size += size;
+                shard.push(tensor)
+            }
+            _ => {
+                shard_size = size;
+                shards.push(vec![tensor])
+            }
+        }
+    }
+    if shards.is_empty() {
+        shards.push(vec![])
+    }
+
+    let shard_count = shards.len();
+    if shard_count > u16::MAX as usize {
+        candle::bail!("too many shards {shard_count}")
+    }
+    let split_count = Value::U16(shard_count as u16);
+    let split_tensors_count = Value::I32(n_tensors as i32);
+    let mut files = [in_file];
+    for (shard_idx, shard) in shards.iter().enumerate() {
+        let split_no = Value::U16(shard_idx as u16);
+        let mut metadata = if shard_idx == 0 {
+            metadata(&content)
+        } else {
+            vec![]
+        };
+        metadata.push((SPLIT_NO, &split_no));
+        metadata.push((SPLIT_COUNT, &split_count));
+        metadata.push((SPLIT_TENSORS_COUNT, &split_tensors_count));
+        let out_file = shard_path(&prefix, shard_idx, shard_count);
+        println!("{out_file:?}: {} tensors", shard.len());
+        write_gguf(&out_file, &metadata, shard, &mut files)?;
+    }
+    Ok(())
+}
+
+/// Merges the shards created by `run_split` or by llama.cpp, `in_file` is the first shard.
+pub fn run_merge(in_file: &Path, out_file: &Path) -> Result<()> {
+    let (first_file, first_content) = read_content(in_file)?;
+    let shard_count = match first_content.metadata.get(SPLIT_COUNT) {
+        None => candle::bail!("{in_file:?} is not a gguf shard, missing {SPLIT_COUNT}"),
+        Some(v) => v.to_u64()? as usize,
+    };
+    let suffix = format!("-00001-of-{shard_count:05}.gguf");
+    let in_file_str = in_file.to_string_lossy();
+    let prefix = match in_file_str.strip_suffix(&suffix) {
+        None => candle::bail!(
+            "{in_file:?} is not the first shard, expected a name ending with {suffix}"
+        ),
+        Some(prefix) => prefix,
+    };
+
+    let mut files = vec![first_file];
+    let mut contents = vec![first_content];
+    for shard_idx in 1..shard_count {
+        let (file, content) = read_content(&shard_path(prefix, shard_idx, shard_count))?;
+        files.push(file);
+        contents.push(content);
+    }
+    let mut tensors = vec![];
+    let mut names = std::collections::HashSet::new();
+    for (shard_idx, content) in contents.iter().enumerate() {
+        let split_no = content
+            .metadata
+            .get(SPLIT_NO)
+            .map(|v| v.to_u64())
+            .transpose()?;
+        if split_no != Some(shard_idx as u64) {
+            candle::bail!("unexpected {SPLIT_NO} for shard {shard_idx}: {split_no:?}")
+        }
+        for tensor in self::tensors(content, shard_idx) {
+            if !names.insert(tensor.name.clone()) {
+                candle::bail!("tensor {} is present in multiple shards", tensor.name)
+            }
+            tensors.push(tensor)
+        }
+    }
+    if let Some(n_tensors) = contents[0].metadata.get(SPLIT_TENSORS_COUNT) {
+        let n_tensors = match n_tensors {
+            Value::I32(v) => *v as usize,
+            v => v.to_u64()? as usize,
+        };
+        if n_tensors != tensors.len() {
+            candle::bail!("expected {n_tensors} tensors, got {}", tensors.len())
+        }
+    }
+    println!(
+        "{out_file:?}: {} tensors from {shard_count} shards",
+        tensors.len()
+    );
+    write_gguf(out_file, &metadata(&contents[0]), &tensors, &mut files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::quantized::{GgmlDType, QTensor};
+    use candle::{Device, Tensor};
+
+    #[test]
+    fn array_values() -> Result<()> {
+        let tokens = parse_value("tokens", r#"["a", "b"]"#, None, true, None)?;
+        assert_eq!(
+            tokens
+                .to_vec()?
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Result<Vec<_>>>()?,
+            ["a", "b"]
+        );
+        // The type of the elements defaults to the one of the existing array.
+        let prev = Value::Array(vec![Value::I32(0)]);
+        let types = parse_value("types", "[1, 3]", None, false, Some(&prev))?;
+        let types = types
+            .to_vec()?
+            .iter()
+            .map(|v| v.to_i32())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(types, [1, 3]);
+        let scores = parse_value("scores", "[0.5, -1]", Some(MetadataType::F32), true, None)?;
+        assert_eq!(scores.to_vec()?[1].to_f32()?, -1.);
+        assert!(parse_value("types", "[1.5]", None, false, Some(&prev)).is_err());
+        assert!(parse_value("types", "[[1]]", None, false, Some(&prev)).is_err());
+        assert!(parse_value("types", "1", None, false, Some(&prev)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn split_merge() -> Result<()> {
+        let cpu = &Device::Cpu;
+        let dir = std::env::temp_dir().join(format!("gguf-split-merge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let t1 = Tensor::arange(0f32, 256., cpu)?.reshape((2, 128))?;
+        let t1 = QTensor::quantize(&t1, GgmlDType::Q8_0)?;
+        let t2 = QTensor::quantize(&Tensor::new(&[1f32, 2., 3.], cpu)?, GgmlDType::F32)?;
+        let t3 = QTensor::quantize(&Tensor::new(&[4f32, 5.], cpu)?, GgmlDType::F32)?;
+        let arch = Value::String("llama".to_string());
+        let n_layers = Value::U32(2);
+        let in_file = dir.join("model.gguf");
+        let mut file = std::fs::File::create(&in_file)?;
+        gguf_file::write(
+            &mut file,
+            &[
+                ("general.architecture", &arch),
+                ("llama.block_count", &n_layers),
+            ],
+            &[("a", &t1), ("b", &t2), ("c", &t3)],
+        )?;
+        drop(file);
+
+        run_split(&in_file, None, Some(2), None)?;
+        let (_, first) = read_content(&dir.join("model-00001-of-00002.gguf"))?;
+        assert_eq!(first.tensor_infos.len(), 2);
+        assert_eq!(first.metadata[SPLIT_COUNT].to_u64()?, 2);
+        assert!(first.metadata.contains_key("general.architecture"));
+        let (_, second) = read_content(&dir.join("model-00002-of-00002.gguf"))?;
+        assert_eq!(second.tensor_infos.len(), 1);
+        assert_eq!(second.metadata[SPLIT_NO].to_u64()?, 1);
+        assert!(!second.metadata.contains_key("general.architecture"));
+
+        let out_file = dir.join("merged.gguf");
+        run_merge(&dir.join("model-00001-of-00002.gguf"), &out_file)?;
+        let (mut file, merged) = read_content(&out_file)?;
+        let mut keys = merged.metadata.keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["general.architecture", "llama.block_count"]);
+        assert_eq!(merged.metadata["llama.block_count"].to_u32()?, 2);
+        let a = merged.tensor(&mut file, "a", cpu)?;
+        assert_eq!(a.dtype(), GgmlDType::Q8_0);
+        assert_eq!(a.data()?, t1.data()?);
+        let b = merged.tensor(&mut file, "b", cpu)?.dequantize(cpu)?;
+        assert_eq!(b.to_vec1::<f32>()?, [1., 2., 3.]);
+        let c = merged.tensor(&mut file, "c", cpu)?.dequantize(cpu)?;
+        assert_eq!(c.to_vec1::<f32>()?, [4., 5.]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
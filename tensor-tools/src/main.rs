@@ -1,8 +1,131 @@
-use candle::quantized::{gguf_file, GgmlDType, QTensor};
-use candle::{Device, Result};
+use candle::quantized::{gguf_file, imatrix_file, GgmlDType, QTensor};
+use candle::{Device, Result, Tensor};
 use clap::{Parser, Subcommand, ValueEnum};
+use convert::gguf_tensor_name;
 use rayon::prelude::*;
 
+mod convert;
+mod gguf_edit;
+mod quality;
+mod recipe;
+
+/// Importance matrix entries, as generated by llama.cpp, indexed by tensor name.
+struct Imatrix {
+    entries: std::collections::HashMap<String, Vec<f32>>,
+}
+
+/// How the importance matrix was used when quantizing a tensor.
+#[derive(Debug, Clone, PartialEq)]
+enum ImatrixCoverage {
+    /// The tensor was quantized using the entry with the given name.
+    Covered(String),
+    /// No entry matches the tensor name.
+    Missing,
+    /// The entry with the given name does not have the expected number of values.
+    SizeMismatch(String, usize),
+    /// The target dtype does not support importance matrices.
+    Unsupported(GgmlDType),
+}
+
+impl Imatrix {
+    fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let entries = imatrix_file::load_imatrix(p)?;
+        Ok(Self { entries })
+    }
+
+    /// Returns the name of the matching entry, the tensor name is tried as is and then using the
+    /// gguf naming scheme for the usual llama safetensors names.
+    fn entry_name(&self, name: &str) -> Option<String> {
+        let gguf_name = gguf_tensor_name(name);
+        [Some(name.to_string()), gguf_name]
+            .into_iter()
+            .flatten()
+            .find(|name| self.entries.contains_key(name))
+    }
+
+    fn quantize(
+        &self,
+        name: &str,
+        tensor: &Tensor,
+        dtype: GgmlDType,
+    ) -> Result<(QTensor, ImatrixCoverage)> {
+        // Only the k-quants and the iq4 types implement the imatrix aware quantization.
+        let supported = matches!(
+            dtype,
+            GgmlDType::Q2K
+                | GgmlDType::Q3K
+                | GgmlDType::Q4K
+                | GgmlDType::Q5K
+                | GgmlDType::Q6K
+                | GgmlDType::IQ4NL
+                | GgmlDType::IQ4XS
+        );
+        if !supported {
+            let qtensor = QTensor::quantize(tensor, dtype)?;
+            return Ok((qtensor, ImatrixCoverage::Unsupported(dtype)));
+        }
+        let entry_name = match self.entry_name(name) {
+            None => return Ok((QTensor::quantize(tensor, dtype)?, ImatrixCoverage::Missing)),
+            Some(entry_name) => entry_name,
+        };
+        let weights = &self.entries[&entry_name];
+        if weights.len() != tensor.dim(candle::D::Minus1)? {
+            let qtensor = QTensor::quantize(tensor, dtype)?;
+            let coverage = ImatrixCoverage::SizeMismatch(entry_name, weights.len());
+            return Ok((qtensor, coverage));
+        }
+        let qtensor = QTensor::quantize_imatrix(tensor, weights, dtype)?;
+        Ok((qtensor, ImatrixCoverage::Covered(entry_name)))
+    }
+
+    fn report(&self, coverage: &[(&str, ImatrixCoverage)]) {
+        let mut coverage = coverage.to_vec();
+        coverage.sort_by(|a, b| a.0.cmp(b.0));
+        let covered = coverage
+            .iter()
+            .filter_map(|(_, c)| match c {
+                ImatrixCoverage::Covered(entry_name) => Some(entry_name.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "imatrix: {} entries, {}/{} quantized tensors covered",
+            self.entries.len(),
+            covered.len(),
+            coverage.len()
+        );
+        let covered = covered
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        for (name, coverage) in coverage.iter() {
+            match coverage {
+                ImatrixCoverage::Covered(entry_name) => {
+                    println!("  {name}: covered by {entry_name}")
+                }
+                ImatrixCoverage::Missing => println!("  {name}: no imatrix entry"),
+                ImatrixCoverage::SizeMismatch(entry_name, size) => {
+                    println!("  {name}: size mismatch for {entry_name} ({size} values)")
+                }
+                ImatrixCoverage::Unsupported(dtype) => {
+                    println!("  {name}: imatrix not supported for {dtype:?}")
+                }
+            }
+        }
+        let mut unused = self
+            .entries
+            .keys()
+            .filter(|k| !covered.contains(k.as_str()))
+            .collect::<Vec<_>>();
+        if !unused.is_empty() {
+            unused.sort();
+            println!("imatrix: {} unused entries", unused.len());
+            for name in unused {
+                println!("  {name}")
+            }
+        }
+    }
+}
+
 #[derive(ValueEnum, Debug, Clone)]
 enum QuantizationMode {
     /// The default quantization includes all 2d tensors, except the output tensor which always
@@ -11,20 +134,38 @@ enum QuantizationMode {
 }
 
 impl QuantizationMode {
-    fn quantize(&self, name: &str, tensor: QTensor, dtype: GgmlDType) -> Result<QTensor> {
+    fn quantize(
+        &self,
+        name: &str,
+        tensor: QTensor,
+        recipe: &recipe::Recipe,
+        n_layers: usize,
+        imatrix: Option<&Imatrix>,
+    ) -> Result<(QTensor, Option<ImatrixCoverage>)> {
         match self {
             Self::Llama => {
                 // Same behavior as the llama.cpp quantization.
                 let should_quantize = name.ends_with(".weight") && tensor.rank() == 2;
-                if should_quantize {
-                    let tensor = tensor.dequantize(&Device::Cpu)?;
-                    if name == "output.weight" {
-                        QTensor::quantize(&tensor, GgmlDType::Q6K)
-                    } else {
-                        QTensor::quantize(&tensor, dtype)
-                    }
+                let mode_dtype = if !should_quantize {
+                    None
+                } else if name == "output.weight" {
+                    Some(GgmlDType::Q6K)
                 } else {
-                    Ok(tensor)
+                    Some(recipe.default_dtype())
+                };
+                match recipe.dtype(name, tensor.shape().dims(), n_layers, mode_dtype)? {
+                    Some(dtype) => {
+                        println!("  quantizing {name} to {dtype:?}");
+                        let tensor = tensor.dequantize(&Device::Cpu)?;
+                        match imatrix {
+                            None => Ok((QTensor::quantize(&tensor, dtype)?, None)),
+                            Some(imatrix) => {
+                                let (tensor, coverage) = imatrix.quantize(name, &tensor, dtype)?;
+                                Ok((tensor, Some(coverage)))
+                            }
+                        }
+                    }
+                    None => Ok((tensor, None)),
                 }
             }
         }
@@ -51,8 +192,31 @@ enum Quantization {
     Q5k,
     Q6k,
     Q8k,
+    #[value(name = "iq4_nl")]
+    Iq4Nl,
+    #[value(name = "iq4_xs")]
+    Iq4Xs,
+    #[value(name = "tq1_0")]
+    Tq1_0,
+    #[value(name = "tq2_0")]
+    Tq2_0,
     F16,
     F32,
+    /// The llama.cpp K-quant mixtures, some tensors use more bits than the base K-quant.
+    #[value(name = "q3_k_s")]
+    Q3kS,
+    #[value(name = "q3_k_m")]
+    Q3kM,
+    #[value(name = "q3_k_l")]
+    Q3kL,
+    #[value(name = "q4_k_s")]
+    Q4kS,
+    #[value(name = "q4_k_m")]
+    Q4kM,
+    #[value(name = "q5_k_s")]
+    Q5kS,
+    #[value(name = "q5_k_m")]
+    Q5kM,
 }
 
 impl Quantization {
@@ -70,10 +234,31 @@ impl Quantization {
             Quantization::Q5k => GgmlDType::Q5K,
             Quantization::Q6k => GgmlDType::Q6K,
             Quantization::Q8k => GgmlDType::Q8K,
+            Quantization::Iq4Nl => GgmlDType::IQ4NL,
+            Quantization::Iq4Xs => GgmlDType::IQ4XS,
+            Quantization::Tq1_0 => GgmlDType::TQ1_0,
+            Quantization::Tq2_0 => GgmlDType::TQ2_0,
             Quantization::F16 => GgmlDType::F16,
             Quantization::F32 => GgmlDType::F32,
+            Quantization::Q3kS | Quantization::Q3kM | Quantization::Q3kL => GgmlDType::Q3K,
+            Quantization::Q4kS | Quantization::Q4kM => GgmlDType::Q4K,
+            Quantization::Q5kS | Quantization::Q5kM => GgmlDType::Q5K,
         }
     }
+
+    fn mixture(&self) -> Option<recipe::Mixture> {
+        let mixture = match self {
+            Quantization::Q3kS => recipe::Mixture::Q3kS,
+            Quantization::Q3kM => recipe::Mixture::Q3kM,
+            Quantization::Q3kL => recipe::Mixture::Q3kL,
+            Quantization::Q4kS => recipe::Mixture::Q4kS,
+            Quantization::Q4kM => recipe::Mixture::Q4kM,
+            Quantization::Q5kS => recipe::Mixture::Q5kS,
+            Quantization::Q5kM => recipe::Mixture::Q5kM,
+            _ => return None,
+        };
+        Some(mixture)
+    }
 }
 
 #[derive(ValueEnum, Debug, Clone)]
@@ -150,6 +335,26 @@ enum Command {
         /// Which tensor to quantize.
         #[arg(long, value_enum, default_value_t = QuantizationMode::Llama)]
         mode: QuantizationMode,
+
+        /// A recipe file, in TOML or JSON format, with rules mapping tensor name regexes to the
+        /// dtype to use for these tensors.
+        #[arg(long)]
+        recipe: Option<std::path::PathBuf>,
+
+        /// An importance matrix file, in the llama.cpp imatrix format, used to weight the
+        /// quantization error of the k-quants.
+        #[arg(long)]
+        imatrix: Option<std::path::PathBuf>,
+
+        /// The Hugging Face config.json for the safetensors weights. When set, the model
+        /// hyper-parameters are stored as gguf metadata and the tensors use the gguf names.
+        #[arg(long)]
+        config: Option<std::path::PathBuf>,
+
+        /// The Hugging Face tokenizer.json to store in the gguf metadata, defaults to the
+        /// tokenizer.json file next to config.json if there is one.
+        #[arg(long, requires = "config")]
+        tokenizer: Option<std::path::PathBuf>,
     },
 
     Dequantize {
@@ -160,6 +365,77 @@ enum Command {
         #[arg(long)]
         out_file: std::path::PathBuf,
     },
+
+    /// Read or edit the metadata of a gguf file.
+    Metadata {
+        #[command(subcommand)]
+        command: gguf_edit::MetadataCommand,
+    },
+
+    /// Rename the tensors of a gguf file using a regex, the file is modified in place unless
+    /// --out-file is used.
+    Rename {
+        file: std::path::PathBuf,
+
+        /// The regex matched against the tensor names.
+        pattern: String,
+
+        /// The replacement, capture groups can be referred to with $1, $name, etc.
+        replacement: String,
+
+        #[arg(long)]
+        out_file: Option<std::path::PathBuf>,
+
+        /// Only print the new tensor names.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Split a gguf file in shards named {prefix}-00001-of-00003.gguf etc.
+    Split {
+        /// The input file, in gguf format.
+        in_file: std::path::PathBuf,
+
+        /// The prefix for the shard names, defaults to the input file without its extension.
+        #[arg(long)]
+        out_prefix: Option<std::path::PathBuf>,
+
+        /// The maximum number of tensors per shard, defaults to 128 if --max-size is not used.
+        #[arg(long)]
+        max_tensors: Option<usize>,
+
+        /// The maximum size of the tensor data per shard, e.g. 500M or 4G.
+        #[arg(long, value_parser = gguf_edit::parse_size)]
+        max_size: Option<u64>,
+    },
+
+    /// Compare the dequantized tensors of a gguf file to the original weights and report the
+    /// quantization error for each tensor and dtype.
+    Quality {
+        /// The quantized file, in gguf format.
+        quantized: std::path::PathBuf,
+
+        /// The original weights, in safetensors format.
+        #[arg(long, required = true)]
+        source: Vec<std::path::PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = quality::ReportFormat::Text)]
+        format: quality::ReportFormat,
+
+        /// Write the report to a file rather than to stdout.
+        #[arg(long)]
+        out_file: Option<std::path::PathBuf>,
+    },
+
+    /// Merge the shards of a gguf file.
+    Merge {
+        /// The first shard, e.g. model-00001-of-00003.gguf.
+        in_file: std::path::PathBuf,
+
+        /// The output file, in gguf format.
+        #[arg(long)]
+        out_file: std::path::PathBuf,
+    },
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -398,7 +674,9 @@ fn run_ls(
 fn run_quantize_safetensors(
     in_files: &[std::path::PathBuf],
     out_file: std::path::PathBuf,
-    q: Quantization,
+    recipe: &recipe::Recipe,
+    imatrix: Option<&Imatrix>,
+    model: Option<&convert::HfModel>,
 ) -> Result<()> {
     let mut out_file = std::fs::File::create(out_file)?;
     let mut tensors = std::collections::HashMap::new();
@@ -406,32 +684,71 @@ fn run_quantize_safetensors(
         let in_tensors = candle::safetensors::load(in_file, &Device::Cpu)?;
         tensors.extend(in_tensors)
     }
+    if let Some(model) = model {
+        let mut gguf_tensors = std::collections::HashMap::new();
+        for (name, tensor) in tensors.into_iter() {
+            match model.convert_tensor(&name, tensor)? {
+                Some((gguf_name, tensor)) => {
+                    gguf_tensors.insert(gguf_name, tensor);
+                }
+                None => println!("  skipping {name}"),
+            }
+        }
+        gguf_tensors.extend(model.extra_tensors().iter().cloned());
+        tensors = gguf_tensors
+    }
     println!("tensors: {}", tensors.len());
 
-    let dtype = q.dtype();
-    let block_size = dtype.block_size();
-
+    let n_layers = recipe::n_layers(tensors.keys().map(|k| k.as_str()));
     let qtensors = tensors
         .into_par_iter()
         .map(|(name, tensor)| {
-            let should_quantize = tensor.rank() == 2 && tensor.dim(1)? % block_size == 0;
-            println!("  quantizing {name} {tensor:?} {should_quantize}");
-            let tensor = if should_quantize {
-                QTensor::quantize(&tensor, dtype)?
+            let mode_dtype = (tensor.rank() == 2).then_some(recipe.default_dtype());
+            let dtype = recipe.dtype(&name, tensor.dims(), n_layers, mode_dtype)?;
+            println!("  quantizing {name} {tensor:?} {dtype:?}");
+            let (tensor, coverage) = if let Some(dtype) = dtype {
+                match imatrix {
+                    None => (QTensor::quantize(&tensor, dtype)?, None),
+                    Some(imatrix) => {
+                        let (tensor, coverage) = imatrix.quantize(&name, &tensor, dtype)?;
+                        (tensor, Some(coverage))
+                    }
+                }
             } else {
-                QTensor::quantize(&tensor, GgmlDType::F32)?
+                (QTensor::quantize(&tensor, GgmlDType::F32)?, None)
             };
-            Ok((name, tensor))
+            Ok((name, tensor, coverage))
         })
         .collect::<Result<Vec<_>>>()?;
-    let qtensors = qtensors
+    if let Some(imatrix) = imatrix {
+        imatrix.report(&coverage(&qtensors))
+    }
+    let mut qtensors = qtensors
         .iter()
-        .map(|(k, v)| (k.as_str(), v))
+        .map(|(k, v, _)| (k.as_str(), v))
         .collect::<Vec<_>>();
-    gguf_file::write(&mut out_file, &[], &qtensors)?;
+    qtensors.sort_by(|a, b| a.0.cmp(b.0));
+    let metadata: Vec<(&str, &gguf_file::Value)> = match model {
+        None => vec![],
+        Some(model) => model
+            .metadata()
+            .iter()
+            .map(|(k, v)| (k.as_str(), v))
+            .collect(),
+    };
+    gguf_file::write(&mut out_file, &metadata, &qtensors)?;
     Ok(())
 }
 
+fn coverage<N: AsRef<str>>(
+    qtensors: &[(N, QTensor, Option<ImatrixCoverage>)],
+) -> Vec<(&str, ImatrixCoverage)> {
+    qtensors
+        .iter()
+        .filter_map(|(name, _, c)| c.as_ref().map(|c| (name.as_ref(), c.clone())))
+        .collect()
+}
+
 fn run_dequantize(
     in_file: std::path::PathBuf,
     out_file: std::path::PathBuf,
@@ -452,8 +769,10 @@ fn run_dequantize(
 fn run_quantize(
     in_files: &[std::path::PathBuf],
     out_file: std::path::PathBuf,
-    q: Quantization,
+    recipe: recipe::Recipe,
     qmode: QuantizationMode,
+    imatrix: Option<std::path::PathBuf>,
+    model: Option<convert::HfModel>,
     device: &Device,
 ) -> Result<()> {
     if in_files.is_empty() {
@@ -464,11 +783,24 @@ fn run_quantize(
             candle::bail!("the generated file cannot use the safetensors extension")
         }
     }
+    let imatrix = match imatrix {
+        None => None,
+        Some(imatrix) => Some(Imatrix::load(imatrix)?),
+    };
     if let Some(extension) = in_files[0].extension() {
         if extension == "safetensors" {
-            return run_quantize_safetensors(in_files, out_file, q);
+            return run_quantize_safetensors(
+                in_files,
+                out_file,
+                &recipe,
+                imatrix.as_ref(),
+                model.as_ref(),
+            );
         }
     }
+    if model.is_some() {
+        candle::bail!("--config can only be used when quantizing safetensors files")
+    }
 
     if in_files.len() != 1 {
         candle::bail!("only a single in-file can be used when quantizing gguf files")
@@ -480,21 +812,24 @@ fn run_quantize(
     let content = gguf_file::Content::read(&mut in_)?;
     println!("tensors: {}", content.tensor_infos.len());
 
-    let dtype = q.dtype();
+    let n_layers = recipe::n_layers(content.tensor_infos.keys().map(|k| k.as_str()));
     let qtensors = content
         .tensor_infos
         .par_iter()
         .map(|(name, _)| {
-            println!("  quantizing {name}");
             let mut in_file = std::fs::File::open(&in_files[0])?;
             let tensor = content.tensor(&mut in_file, name, device)?;
-            let tensor = qmode.quantize(name, tensor, dtype)?;
-            Ok((name, tensor))
+            let (tensor, coverage) =
+                qmode.quantize(name, tensor, &recipe, n_layers, imatrix.as_ref())?;
+            Ok((name, tensor, coverage))
         })
         .collect::<Result<Vec<_>>>()?;
+    if let Some(imatrix) = imatrix.as_ref() {
+        imatrix.report(&coverage(&qtensors))
+    }
     let qtensors = qtensors
         .iter()
-        .map(|(k, v)| (k.as_str(), v))
+        .map(|(k, v, _)| (k.as_str(), v))
         .collect::<Vec<_>>();
 
     let metadata = content
@@ -535,8 +870,49 @@ fn main() -> anyhow::Result<()> {
             out_file,
             quantization,
             mode,
-        } => run_quantize(&in_file, out_file, quantization, mode, &device)?,
+            recipe,
+            imatrix,
+            config,
+            tokenizer,
+        } => {
+            let recipe = match recipe {
+                None => recipe::Recipe::new(&quantization),
+                Some(recipe) => recipe::Recipe::load(recipe, &quantization)?,
+            };
+            let model = match config {
+                None => None,
+                Some(config) => {
+                    let tokenizer = tokenizer.or_else(|| {
+                        let tokenizer = config.with_file_name("tokenizer.json");
+                        tokenizer.exists().then_some(tokenizer)
+                    });
+                    Some(convert::HfModel::load(&config, tokenizer.as_deref())?)
+                }
+            };
+            run_quantize(&in_file, out_file, recipe, mode, imatrix, model, &device)?
+        }
         Command::Dequantize { in_file, out_file } => run_dequantize(in_file, out_file, &device)?,
+        Command::Metadata { command } => gguf_edit::run_metadata(command)?,
+        Command::Rename {
+            file,
+            pattern,
+            replacement,
+            out_file,
+            dry_run,
+        } => gguf_edit::run_rename(&file, &pattern, &replacement, out_file.as_deref(), dry_run)?,
+        Command::Split {
+            in_file,
+            out_prefix,
+            max_tensors,
+            max_size,
+        } => gguf_edit::run_split(&in_file, out_prefix.as_deref(), max_tensors, max_size)?,
+        Command::Merge { in_file, out_file } => gguf_edit::run_merge(&in_file, &out_file)?,
+        Command::Quality {
+            quantized,
+            source,
+            format,
+            out_file,
+        } => quality::run_quality(&source, &quantized, format, out_file.as_deref())?,
     }
     Ok(())
 }
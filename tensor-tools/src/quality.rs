@@ -0,0 +1,323 @@
+//! Quantization quality report, the tensors from a gguf file are dequantized and compared to
+//! the original weights from some safetensors files.
+use crate::convert::{gguf_tensor_name, permute_qk};
+use candle::quantized::{gguf_file, GgmlDType};
+use candle::{DType, Device, Result, Tensor};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+/// Accumulated statistics on the difference between the reference and the dequantized values.
+#[derive(Debug, Clone, Default)]
+struct ErrorStats {
+    n_tensors: usize,
+    elem_count: usize,
+    sum_sq_err: f64,
+    sum_sq_ref: f64,
+    sum_sq_q: f64,
+    dot: f64,
+    max_abs_err: f64,
+}
+
+impl ErrorStats {
+    fn new(reference: &Tensor, quantized: &Tensor) -> Result<Self> {
+        let sum_all = |t: Tensor| t.sum_all()?.to_scalar::<f64>();
+        let r = reference.to_dtype(DType::F64)?.flatten_all()?;
+        let q = quantized.to_dtype(DType::F64)?.flatten_all()?;
+        let diff = (&r - &q)?;
+        let max_abs_err = if r.elem_count() == 0 {
+            0.
+        } else {
+            diff.abs()?.max(0)?.to_scalar::<f64>()?
+        };
+        Ok(Self {
+            n_tensors: 1,
+            elem_count: r.elem_count(),
+            sum_sq_err: sum_all(diff.sqr()?)?,
+            sum_sq_ref: sum_all(r.sqr()?)?,
+            sum_sq_q: sum_all(q.sqr()?)?,
+            dot: sum_all((&r * &q)?)?,
+            max_abs_err,
+        })
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.n_tensors += other.n_tensors;
+        self.elem_count += other.elem_count;
+        self.sum_sq_err += other.sum_sq_err;
+        self.sum_sq_ref += other.sum_sq_ref;
+        self.sum_sq_q += other.sum_sq_q;
+        self.dot += other.dot;
+        self.max_abs_err = self.max_abs_err.max(other.max_abs_err);
+    }
+
+    fn rmse(&self) -> f64 {
+        (self.sum_sq_err / self.elem_count.max(1) as f64).sqrt()
+    }
+
+    fn cosine_similarity(&self) -> f64 {
+        let norm = (self.sum_sq_ref * self.sum_sq_q).sqrt();
+        if norm > 0. {
+            self.dot / norm
+        } else if self.sum_sq_ref == self.sum_sq_q {
+            // Both tensors are zero.
+            1.
+        } else {
+            0.
+        }
+    }
+
+    /// The signal to noise ratio in dB, this is infinite when there is no error.
+    fn snr_db(&self) -> f64 {
+        if self.sum_sq_err == 0. {
+            f64::INFINITY
+        } else {
+            10. * (self.sum_sq_ref / self.sum_sq_err).log10()
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        // Infinite values are serialized as null.
+        serde_json::json!({
+            "tensors": self.n_tensors,
+            "elem_count": self.elem_count,
+            "rmse": self.rmse(),
+            "max_abs_error": self.max_abs_err,
+            "cosine_similarity": self.cosine_similarity(),
+            "snr_db": self.snr_db(),
+        })
+    }
+}
+
+struct TensorReport {
+    name: String,
+    dtype: GgmlDType,
+    shape: candle::Shape,
+    stats: ErrorStats,
+}
+
+/// Returns the name of the reference tensor for each gguf tensor, the names are either identical
+/// or the gguf names obtained when converting the safetensors weights.
+fn reference_names<'a, I: Iterator<Item = &'a String>>(names: I) -> HashMap<String, String> {
+    let mut reference_names = HashMap::new();
+    for name in names {
+        if let Some(gguf_name) = gguf_tensor_name(name) {
+            reference_names
+                .entry(gguf_name)
+                .or_insert_with(|| name.to_string());
+        }
+        reference_names.insert(name.to_string(), name.to_string());
+    }
+    reference_names
+}
+
+pub fn run_quality(
+    source: &[PathBuf],
+    quantized: &Path,
+    format: ReportFormat,
+    out_file: Option<&Path>,
+) -> Result<()> {
+    let device = &Device::Cpu;
+    let references = unsafe { candle::safetensors::MmapedSafetensors::multi(source)? };
+    let reference_tensors = references.tensors();
+    let reference_names = reference_names(reference_tensors.iter().map(|(name, _)| name));
+
+    let mut file = std::fs::File::open(quantized)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(quantized))?;
+    // The q/k weights are permuted when converting the llama models.
+    let arch = content.metadata.get("general.architecture");
+    let head_counts = match arch.map(|v| v.to_string()).transpose()? {
+        Some(arch) if arch == "llama" => {
+            let head_count = |key: &str| match content.metadata.get(key) {
+                None => Ok(None),
+                Some(v) => v.to_u64().map(|v| Some(v as usize)),
+            };
+            let n_head = head_count("llama.attention.head_count")?;
+            let n_head_kv = head_count("llama.attention.head_count_kv")?.or(n_head);
+            n_head.zip(n_head_kv)
+        }
+        _ => None,
+    };
+
+    let mut names = content.tensor_infos.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut reports = vec![];
+    for name in names {
+        let reference_name = match reference_names.get(name.as_str()) {
+            None => {
+                eprintln!("no reference tensor for {name}");
+                continue;
+            }
+            Some(reference_name) => reference_name,
+        };
+        let qtensor = content.tensor(&mut file, name, device)?;
+        let mut reference = references.load(reference_name, device)?;
+        if let Some((n_head, n_head_kv)) = head_counts {
+            if reference_name != name {
+                if name.ends_with(".attn_q.weight") {
+                    reference = permute_qk(&reference, n_head)?
+                } else if name.ends_with(".attn_k.weight") {
+                    reference = permute_qk(&reference, n_head_kv)?
+                }
+            }
+        }
+        if reference.shape() != qtensor.shape() {
+            candle::bail!(
+                "shape mismatch for {name}, {:?} <> {:?}",
+                reference.shape(),
+                qtensor.shape()
+            )
+        }
+        let dequantized = qtensor.dequantize(device)?;
+        let stats = ErrorStats::new(&reference, &dequantized)?;
+        reports.push(TensorReport {
+            name: name.to_string(),
+            dtype: qtensor.dtype(),
+            shape: qtensor.shape().clone(),
+            stats,
+        })
+    }
+
+    let mut per_dtype: Vec<(GgmlDType, ErrorStats)> = vec![];
+    let mut total = ErrorStats::default();
+    for report in reports.iter() {
+        match per_dtype
+            .iter_mut()
+            .find(|(dtype, _)| *dtype == report.dtype)
+        {
+            Some((_, stats)) => stats.add(&report.stats),
+            None => per_dtype.push((report.dtype, report.stats.clone())),
+        }
+        total.add(&report.stats)
+    }
+    per_dtype.sort_by_key(|(dtype, _)| format!("{dtype:?}"));
+
+    let mut out: Box<dyn Write> = match out_file {
+        None => Box::new(std::io::stdout().lock()),
+        Some(out_file) => Box::new(std::io::BufWriter::new(std::fs::File::create(out_file)?)),
+    };
+    match format {
+        ReportFormat::Text => {
+            writeln!(
+                out,
+                "{:<40} {:<6} {:>12} {:>12} {:>10} {:>9}",
+                "tensor", "dtype", "rmse", "max-abs-err", "cosine", "snr-db"
+            )?;
+            let line = |name: &str, dtype: String, stats: &ErrorStats| {
+                format!(
+                    "{name:<40} {dtype:<6} {:>12.6e} {:>12.6e} {:>10.6} {:>9.2}",
+                    stats.rmse(),
+                    stats.max_abs_err,
+                    stats.cosine_similarity(),
+                    stats.snr_db()
+                )
+            };
+            for r in reports.iter() {
+                writeln!(out, "{}", line(&r.name, format!("{:?}", r.dtype), &r.stats))?
+            }
+            writeln!(out)?;
+            for (dtype, stats) in per_dtype.iter() {
+                let name = format!("{} tensors", stats.n_tensors);
+                writeln!(out, "{}", line(&name, format!("{dtype:?}"), stats))?
+            }
+            let name = format!("{} tensors", total.n_tensors);
+            writeln!(out, "{}", line(&name, "all".to_string(), &total))?
+        }
+        ReportFormat::Csv => {
+            writeln!(
+                out,
+                "kind,name,dtype,elem_count,rmse,max_abs_error,cosine_similarity,snr_db"
+            )?;
+            let mut row = |kind: &str, name: &str, dtype: String, stats: &ErrorStats| {
+                writeln!(
+                    out,
+                    "{kind},{name},{dtype},{},{},{},{},{}",
+                    stats.elem_count,
+                    stats.rmse(),
+                    stats.max_abs_err,
+                    stats.cosine_similarity(),
+                    stats.snr_db()
+                )
+            };
+            for r in reports.iter() {
+                row("tensor", &r.name, format!("{:?}", r.dtype), &r.stats)?
+            }
+            for (dtype, stats) in per_dtype.iter() {
+                let dtype = format!("{dtype:?}");
+                row("dtype", &dtype, dtype.clone(), stats)?
+            }
+            row("total", "all", "all".to_string(), &total)?
+        }
+        ReportFormat::Json => {
+            let tensors = reports
+                .iter()
+                .map(|r| {
+                    let mut json = r.stats.to_json();
+                    json["name"] = r.name.clone().into();
+                    json["dtype"] = format!("{:?}", r.dtype).into();
+                    json["shape"] = r.shape.dims().into();
+                    json
+                })
+                .collect::<Vec<_>>();
+            let dtypes = per_dtype
+                .iter()
+                .map(|(dtype, stats)| {
+                    let mut json = stats.to_json();
+                    json["dtype"] = format!("{dtype:?}").into();
+                    json
+                })
+                .collect::<Vec<_>>();
+            let report = serde_json::json!({
+                "tensors": tensors,
+                "dtypes": dtypes,
+                "total": total.to_json(),
+            });
+            serde_json::to_writer_pretty(&mut out, &report).map_err(candle::Error::wrap)?;
+            writeln!(out)?
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_stats() -> Result<()> {
+        let r = Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu)?;
+        let q = Tensor::new(&[1f32, 2., 3., 2.], &Device::Cpu)?;
+        let mut stats = ErrorStats::new(&r, &q)?;
+        assert_eq!(stats.rmse(), 1.);
+        assert_eq!(stats.max_abs_err, 2.);
+        // 22 / sqrt(30 * 18)
+        assert!((stats.cosine_similarity() - 0.946729).abs() < 1e-6);
+        // 10 * log10(30 / 4)
+        assert!((stats.snr_db() - 8.750613).abs() < 1e-6);
+
+        let same = ErrorStats::new(&r, &r)?;
+        assert_eq!(same.rmse(), 0.);
+        assert_eq!(same.max_abs_err, 0.);
+        assert!((same.cosine_similarity() - 1.).abs() < 1e-12);
+        assert_eq!(same.snr_db(), f64::INFINITY);
+
+        let zeros = Tensor::zeros(2, DType::F32, &Device::Cpu)?;
+        let zeros = ErrorStats::new(&zeros, &zeros)?;
+        assert_eq!(zeros.cosine_similarity(), 1.);
+        stats.add(&zeros);
+        assert_eq!(stats.n_tensors, 2);
+        assert_eq!(stats.elem_count, 6);
+        assert!((stats.rmse() - (4f64 / 6.).sqrt()).abs() < 1e-12);
+        assert_eq!(stats.max_abs_err, 2.);
+        Ok(())
+    }
+}
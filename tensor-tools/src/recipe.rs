@@ -0,0 +1,271 @@
+//! Per-tensor quantization recipes.
+//!
+//! A recipe file, in TOML or JSON format, holds a list of rules mapping a regex on the tensor
+//! names to the dtype to use for these tensors, the first matching rule applies.
+//!
+//! ```toml
+//! [[rules]]
+//! pattern = '^blk\.[0-3]\.attn_v\.weight$'
+//! dtype = "q8_0"
+//!
+//! [[rules]]
+//! pattern = 'token_embd'
+//! dtype = "f16"
+//! ```
+//!
+//! The tensors not matched by any rule use the K-quant mixture if any, e.g. `q4_k_m`, and the
+//! default quantization otherwise.
+use crate::{convert::gguf_tensor_name, Quantization};
+use candle::quantized::GgmlDType;
+use candle::Result;
+use clap::ValueEnum;
+
+/// The K-quant mixtures from llama.cpp, some tensors use a larger dtype than the base one, see
+/// `llama_tensor_get_type` in llama.cpp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mixture {
+    Q3kS,
+    Q3kM,
+    Q3kL,
+    Q4kS,
+    Q4kM,
+    Q5kS,
+    Q5kM,
+}
+
+fn use_more_bits(layer_idx: usize, n_layers: usize) -> bool {
+    layer_idx < n_layers / 8 || layer_idx >= 7 * n_layers / 8 || (layer_idx - n_layers / 8) % 3 == 2
+}
+
+impl Mixture {
+    /// The dtype for a weight when it differs from the base K-quant of the mixture.
+    fn tensor_dtype(&self, name: &str, n_layers: usize) -> Option<GgmlDType> {
+        use GgmlDType::{Q4K, Q5K, Q6K};
+        let name = gguf_tensor_name(name).unwrap_or_else(|| name.to_string());
+        if name == "output.weight" {
+            return Some(Q6K);
+        }
+        let (layer_idx, name) = name.strip_prefix("blk.")?.split_once('.')?;
+        let i = layer_idx.parse::<usize>().ok()?;
+        let n = n_layers;
+        let dtype = match (self, name) {
+            (Self::Q3kM, "attn_v.weight") if i < 2 => Q5K,
+            (Self::Q3kM, "attn_v.weight") => Q4K,
+            (Self::Q3kL, "attn_v.weight") => Q5K,
+            (Self::Q4kS, "attn_v.weight") if i < 4 => Q5K,
+            (Self::Q4kM | Self::Q5kM, "attn_v.weight") if use_more_bits(i, n) => Q6K,
+            (Self::Q3kM, "ffn_down.weight") if i < n / 16 => Q5K,
+            (Self::Q3kM, "ffn_down.weight") => Q4K,
+            (Self::Q3kL, "ffn_down.weight") => Q5K,
+            (Self::Q4kS, "ffn_down.weight") if i < n / 8 => Q5K,
+            (Self::Q4kM | Self::Q5kM, "ffn_down.weight") if use_more_bits(i, n) => Q6K,
+            (Self::Q3kM, "attn_output.weight") => Q4K,
+            (Self::Q3kL, "attn_output.weight") => Q5K,
+            _ => return None,
+        };
+        Some(dtype)
+    }
+}
+
+/// The number of layers, based on the `blk.{i}` and `model.layers.{i}` tensor name prefixes.
+pub fn n_layers<'a, I: Iterator<Item = &'a str>>(names: I) -> usize {
+    names
+        .filter_map(|name| {
+            let name = name
+                .strip_prefix("blk.")
+                .or_else(|| name.strip_prefix("model.layers."))?;
+            name.split_once('.')?.0.parse::<usize>().ok()
+        })
+        .max()
+        .map_or(0, |v| v + 1)
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct RuleFile {
+    pattern: String,
+    dtype: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct RecipeFile {
+    #[serde(default)]
+    rules: Vec<RuleFile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Recipe {
+    rules: Vec<(fancy_regex::Regex, GgmlDType)>,
+    mixture: Option<Mixture>,
+    default_dtype: GgmlDType,
+}
+
+impl Recipe {
+    /// A recipe without any rule, `q` is the default quantization.
+    pub fn new(q: &Quantization) -> Self {
+        Self {
+            rules: vec![],
+            mixture: q.mixture(),
+            default_dtype: q.dtype(),
+        }
+    }
+
+    /// Loads the rules from a recipe file, the format is inferred from the file extension.
+    pub fn load<P: AsRef<std::path::Path>>(p: P, q: &Quantization) -> Result<Self> {
+        let p = p.as_ref();
+        let data = std::fs::read_to_string(p)?;
+        let recipe: RecipeFile = match p.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&data).map_err(candle::Error::wrap)?,
+            Some("json") => serde_json::from_str(&data).map_err(candle::Error::wrap)?,
+            _ => candle::bail!("{p:?}: recipes should use a .toml or .json extension"),
+        };
+        let rules = recipe
+            .rules
+            .iter()
+            .map(|rule| {
+                let re = fancy_regex::Regex::new(&rule.pattern).map_err(candle::Error::wrap)?;
+                let dtype = match Quantization::from_str(&rule.dtype, true) {
+                    Ok(q) if q.mixture().is_none() => q.dtype(),
+                    _ => candle::bail!("{p:?}: unsupported dtype {}", rule.dtype),
+                };
+                Ok((re, dtype))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            rules,
+            ..Self::new(q)
+        })
+    }
+
+    /// The dtype used for the tensors quantized by the quantization mode.
+    pub fn default_dtype(&self) -> GgmlDType {
+        self.default_dtype
+    }
+
+    /// The dtype to use for a tensor, or `None` if the tensor should not be quantized.
+    /// `mode_dtype` is the dtype that the quantization mode would use for this tensor, `None` if
+    /// the mode would not quantize it. The mixture only applies to the tensors quantized by the
+    /// mode. Tensors whose last dimension is not a multiple of the dtype block size are not
+    /// quantized.
+    pub fn dtype(
+        &self,
+        name: &str,
+        dims: &[usize],
+        n_layers: usize,
+        mode_dtype: Option<GgmlDType>,
+    ) -> Result<Option<GgmlDType>> {
+        let mut dtype = None;
+        for (re, rule_dtype) in self.rules.iter() {
+            if re.is_match(name).map_err(candle::Error::wrap)? {
+                dtype = Some(*rule_dtype);
+                break;
+            }
+        }
+        let dtype = match (dtype, mode_dtype, self.mixture) {
+            (Some(dtype), _, _) => Some(dtype),
+            (None, Some(mode_dtype), Some(mixture)) => {
+                Some(mixture.tensor_dtype(name, n_layers).unwrap_or(mode_dtype))
+            }
+            (None, mode_dtype, _) => mode_dtype,
+        };
+        let dtype = match (dtype, dims.last()) {
+            (Some(dtype), Some(dim)) => dim.is_multiple_of(dtype.block_size()).then_some(dtype),
+            _ => None,
+        };
+        Ok(dtype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use GgmlDType::{F16, Q4K, Q5K, Q6K, Q8_0};
+
+    #[test]
+    fn layer_count() {
+        let names = [
+            "blk.0.attn_q.weight",
+            "blk.31.ffn_down.weight",
+            "output.weight",
+        ];
+        assert_eq!(n_layers(names.into_iter()), 32);
+        let names = ["model.layers.3.mlp.up_proj.weight", "lm_head.weight"];
+        assert_eq!(n_layers(names.into_iter()), 4);
+        assert_eq!(n_layers(["token_embd.weight"].into_iter()), 0);
+    }
+
+    #[test]
+    fn more_bits() {
+        // The first and last eighths of the layers, and every third layer in between.
+        let layers = (0..32)
+            .filter(|&i| use_more_bits(i, 32))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            layers,
+            [0, 1, 2, 3, 6, 9, 12, 15, 18, 21, 24, 27, 28, 29, 30, 31]
+        );
+    }
+
+    #[test]
+    fn mixture_dtypes() {
+        for mixture in [Mixture::Q4kM, Mixture::Q5kM] {
+            let dtype = |name: &str| mixture.tensor_dtype(name, 32);
+            assert_eq!(dtype("output.weight"), Some(Q6K));
+            assert_eq!(dtype("blk.0.attn_v.weight"), Some(Q6K));
+            assert_eq!(dtype("blk.5.attn_v.weight"), None);
+            assert_eq!(dtype("blk.6.attn_v.weight"), Some(Q6K));
+            assert_eq!(dtype("blk.31.ffn_down.weight"), Some(Q6K));
+            assert_eq!(dtype("blk.7.ffn_down.weight"), None);
+            assert_eq!(dtype("blk.0.attn_q.weight"), None);
+            // The safetensors names are mapped to the gguf ones.
+            assert_eq!(dtype("model.layers.0.self_attn.v_proj.weight"), Some(Q6K));
+        }
+        let dtype = |name: &str| Mixture::Q4kS.tensor_dtype(name, 32);
+        assert_eq!(dtype("blk.3.attn_v.weight"), Some(Q5K));
+        assert_eq!(dtype("blk.4.attn_v.weight"), None);
+        assert_eq!(dtype("blk.3.ffn_down.weight"), Some(Q5K));
+        assert_eq!(dtype("blk.4.ffn_down.weight"), None);
+    }
+
+    #[test]
+    fn recipe_dtype() -> Result<()> {
+        let recipe = Recipe::new(&crate::Quantization::Q4kM);
+        let dims = [4096, 4096];
+        let dtype = |name: &str, mode_dtype| recipe.dtype(name, &dims, 32, mode_dtype);
+        assert_eq!(dtype("blk.0.attn_v.weight", Some(Q4K))?, Some(Q6K));
+        assert_eq!(dtype("blk.5.attn_v.weight", Some(Q4K))?, Some(Q4K));
+        assert_eq!(dtype("blk.0.attn_norm.weight", None)?, None);
+        // The tensors that cannot be split in blocks are left unquantized.
+        let dtype = recipe.dtype("blk.0.attn_v.weight", &[4096, 100], 32, Some(Q4K))?;
+        assert_eq!(dtype, None);
+        assert_eq!(
+            recipe.dtype("blk.0.attn_v.weight", &[], 32, Some(Q4K))?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rule_precedence() -> Result<()> {
+        let re = |pattern: &str| fancy_regex::Regex::new(pattern).unwrap();
+        let recipe = Recipe {
+            rules: vec![
+                (re(r"^blk\.0\."), Q8_0),
+                (re("attn_v"), F16),
+                (re(".*"), Q5K),
+            ],
+            ..Recipe::new(&crate::Quantization::Q4kM)
+        };
+        let dims = [4096, 4096];
+        let dtype = |name: &str| recipe.dtype(name, &dims, 32, Some(Q4K));
+        // The first matching rule applies, and the rules take precedence over the mixture.
+        assert_eq!(dtype("blk.0.attn_v.weight")?, Some(Q8_0));
+        assert_eq!(dtype("blk.6.attn_v.weight")?, Some(F16));
+        assert_eq!(dtype("blk.6.ffn_down.weight")?, Some(Q5K));
+        // The rules also apply to the tensors that the mode would not quantize.
+        assert_eq!(
+            recipe.dtype("token_embd.weight", &dims, 32, None)?,
+            Some(Q5K)
+        );
+        Ok(())
+    }
+}